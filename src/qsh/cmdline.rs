@@ -1,24 +1,61 @@
 use std::{
-	net::Ipv6Addr,
+	net::IpAddr,
+	path::PathBuf,
 };
 
 use clap::{
 	Parser,
 };
 
+use qsh_common_types::endpoint::Endpoint;
+
 
 #[derive(Debug, Parser)]
 #[command(name = "qsh", version, about = "A quantum-safe alternative to SSH.", long_about = None)]
 pub struct Args {
 
-	/// IPv6 address to connect to
-	pub host: Ipv6Addr,
+	/// hostname, or IPv4/IPv6 address, to connect to
+	#[arg(required_unless_present_any = ["socket", "proxy_command"])]
+	pub host: Option<String>,
 
 	/// port to connect to
-	pub port: u16,
+	#[arg(required_unless_present_any = ["socket", "proxy_command"])]
+	pub port: Option<u16>,
+
+	/// connect through a Unix domain socket instead of over the network
+	#[arg(short, long, conflicts_with_all = ["host", "port", "proxy_command"])]
+	pub socket: Option<PathBuf>,
+
+	/// connect through the stdin/stdout of a shell command (like netcat) instead of over the network
+	#[arg(long, conflicts_with_all = ["host", "port"])]
+	pub proxy_command: Option<String>,
+
+	/// jump hosts (`host:port`, comma-separated) to go through on the way, in order
+	#[arg(short = 'J', long, value_delimiter = ',')]
+	pub jump: Vec<Endpoint>,
 
 	/// what application to run (default: `/bin/sh`)
 	#[arg(short, long, default_value_t = String::from("/bin/sh"))]
 	pub executable: String,
 
+} impl Args {
+
+	/// Where to connect to, from the arguments.
+	pub fn endpoint(&self) -> Endpoint {
+		if let Some(path) = &self.socket {
+			return Endpoint::Unix(path.clone());
+		} else if let Some(command) = &self.proxy_command {
+			return Endpoint::Command(command.clone());
+		} else {
+			// Addresses are used as-is, anything else gets looked up later:
+			let host: &str = self.host.as_deref().unwrap();
+			let port: u16 = self.port.unwrap();
+			if let Ok(addr) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+				return Endpoint::Inet((addr, port).into());
+			} else {
+				return Endpoint::Host { name: host.to_string(), port: port };
+			}
+		}
+	}
+
 }
\ No newline at end of file
@@ -4,7 +4,6 @@
 
 use std::{
 	collections::HashMap,
-	net::Ipv6Addr,
 	path::PathBuf,
 };
 use bincode::{
@@ -15,14 +14,17 @@ use tokio::{
 		self,
 		File,
 	},
-	io::{AsyncReadExt, AsyncWriteExt, Result},
+	io::{AsyncWriteExt, Result},
 	net::{
 		self,
 		UnixStream,
 	},
 };
 
-use qsh_common_types::ipc::*;
+use qsh_common_types::{
+	endpoint::Endpoint,
+	ipc::*,
+};
 
 /// A channel; one data stream.
 struct Channel {
@@ -49,7 +51,10 @@ struct Session {
 	id: u16,
 
 	// Remote host:
-	host: Ipv6Addr,
+	host: Endpoint,
+
+	// Jump hosts on the way there, in order:
+	jump: Vec<Endpoint>,
 
 	// The socket used to control the session:
 	socket: UnixStream,
@@ -74,21 +79,19 @@ pub struct Daemon {
 
 
 impl Daemon {
-	pub async fn new(path: PathBuf, host: Ipv6Addr, port: u16, executable: PathBuf) -> Result<Self> {
+	pub async fn new(path: PathBuf, host: Endpoint, jump: Vec<Endpoint>, executable: PathBuf) -> Result<Self> {
 		//! `path`: path to the socket in XDG_RUNTIME_DIR that manages the daemon.
-		//! `host`: IPv6 address of the remote host.
+		//! `host`: where the remote host is (address and port, or a socket path).
+		//! `jump`: hosts to jump through to get there, in order (may be empty).
 		
 		// Connect to the socket:
 		let mut socket: UnixStream = UnixStream::connect(path).await?;
 
 		// Send a session request:
-		socket.write_all(bincode::encode_to_vec(SessionRequest::new(host, port, &executable), IPC_BINCODE_CONFIG).unwrap().as_slice()).await?;
+		socket.write_all(bincode::encode_to_vec(SessionRequest::new(host.clone(), jump.clone(), &executable), IPC_BINCODE_CONFIG).unwrap().as_slice()).await?;
 
 		// Make new session struct:
-		socket.readable().await?;	// Wait for the socket to become readable.
-		let mut buffer: Vec<u8> = vec![0; socket.read_u64_le().await? as usize];
-		socket.read_exact(buffer.as_mut_slice()).await?;
-		let response: SessionAcknowledge = bincode::decode_from_slice(&buffer, IPC_BINCODE_CONFIG).unwrap().0;
+		let response: SessionAcknowledge = read_message(&mut socket).await?;
 		let mut channels: HashMap<u16, Channel> = HashMap::new();
 		
 		// Add stdin:
@@ -121,12 +124,18 @@ impl Daemon {
 			session: Session {
 				id: response.id,
 				host: host,
+				jump: jump,
 				socket: UnixStream::connect(response.socket_path).await?,
 				channels: channels,
 				executable: executable,
 			},
 		});
 	}
+
+	/// Waits for the session to end, and returns why (as told by `qshd`).
+	pub async fn wait(&mut self) -> Result<SessionEnded> {
+		return read_message(&mut self.session.socket).await;
+	}
 }
 
 /*
@@ -2,7 +2,9 @@ use clap::Parser;
 use std::{
 	env,
 	path,
+	process,
 };
+use qsh_common_types::disconnect::DisconnectReason;
 
 mod daemon;
 mod cmdline;
@@ -21,5 +23,19 @@ async fn main() {
 	socketpath.push("/qshd.socket");	// We're calling the socket "qshd.socket".
 
 	// Connect:
-	let service: Daemon = Daemon::new(socketpath, arguments.host, arguments.port, arguments.executable.into()).await.expect("failed to connect to qshd");
+	let mut service: Daemon = Daemon::new(socketpath, arguments.endpoint(), arguments.jump.clone(), arguments.executable.into()).await.expect("failed to connect to qshd");
+
+	// Once the session's over, say why (unless there's nothing to say), and exit accordingly:
+	match service.wait().await {
+		Ok(ended) => {
+			if ended.disconnect.reason != DisconnectReason::Normal || !ended.disconnect.message.is_empty() {
+				eprintln!("qsh: {}", ended.disconnect);
+			}
+			process::exit(ended.disconnect.reason.exit_code());
+		},
+		Err(e) => {
+			eprintln!("qsh: lost contact with qshd: {}", e);
+			process::exit(DisconnectReason::ConnectionLost.exit_code());
+		},
+	}
 }
\ No newline at end of file
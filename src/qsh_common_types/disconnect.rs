@@ -0,0 +1,158 @@
+/*!
+	Why a session ended.
+	Whichever end hangs up sends one of these to the other (encrypted, like everything else), and
+	`qshd` passes it on to `qsh`, which prints it and exits with its code. Reasons go over the wire
+	as numbers, so ones this version doesn't know about still come through (as `Other`).
+*/
+use std::fmt::{self, Display};
+use bincode::{
+	de::Decoder,
+	enc::Encoder,
+	error::{DecodeError, EncodeError},
+	Decode,
+	Encode,
+};
+
+
+/// The kinds of reason a session can end for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+
+	/// The user logged out, or the application on the other end finished.
+	Normal,
+
+	/// The user couldn't prove who they are, or isn't allowed in.
+	AuthenticationFailed,
+
+	/// The server's shutting down or restarting; try again later.
+	ServerShutdown,
+
+	/// Someone in charge of the server ended the session.
+	Administrative,
+
+	/// Nothing happened for too long.
+	IdleTimeout,
+
+	/// The other end said something that doesn't follow the protocol.
+	ProtocolError,
+
+	/// The other end ran out of something (connections, memory, etc).
+	ResourceShortage,
+
+	/// The link died, and couldn't be brought back (this one's never sent; it's what a session that just stopped looks like).
+	ConnectionLost,
+
+	/// A reason from a newer version, by its code.
+	Other(u16),
+
+} impl DisconnectReason {
+
+	/// The number this goes over the wire as.
+	pub fn code(&self) -> u16 {
+		return match self {
+			Self::Normal => 0,
+			Self::AuthenticationFailed => 1,
+			Self::ServerShutdown => 2,
+			Self::Administrative => 3,
+			Self::IdleTimeout => 4,
+			Self::ProtocolError => 5,
+			Self::ResourceShortage => 6,
+			Self::ConnectionLost => 7,
+			Self::Other(code) => *code,
+		};
+	}
+
+	/// The reason with the code `code`.
+	pub fn from_code(code: u16) -> Self {
+		return match code {
+			0 => Self::Normal,
+			1 => Self::AuthenticationFailed,
+			2 => Self::ServerShutdown,
+			3 => Self::Administrative,
+			4 => Self::IdleTimeout,
+			5 => Self::ProtocolError,
+			6 => Self::ResourceShortage,
+			7 => Self::ConnectionLost,
+			code => Self::Other(code),
+		};
+	}
+
+	/// What `qsh` exits with when its session ends for this reason (from `sysexits.h` where there's a fit, and 255 for lost connections, like `ssh`).
+	pub fn exit_code(&self) -> i32 {
+		return match self {
+			Self::Normal => 0,
+			Self::AuthenticationFailed => 77,	// EX_NOPERM
+			Self::ServerShutdown | Self::IdleTimeout | Self::ResourceShortage => 75,	// EX_TEMPFAIL
+			Self::Administrative => 69,	// EX_UNAVAILABLE
+			Self::ProtocolError => 76,	// EX_PROTOCOL
+			Self::ConnectionLost => 255,
+			Self::Other(_) => 1,
+		};
+	}
+
+} impl Display for DisconnectReason {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return match self {
+			Self::Normal => write!(f, "session closed"),
+			Self::AuthenticationFailed => write!(f, "authentication failed"),
+			Self::ServerShutdown => write!(f, "server shutting down"),
+			Self::Administrative => write!(f, "disconnected by an administrator"),
+			Self::IdleTimeout => write!(f, "idle for too long"),
+			Self::ProtocolError => write!(f, "protocol error"),
+			Self::ResourceShortage => write!(f, "out of resources"),
+			Self::ConnectionLost => write!(f, "connection lost"),
+			Self::Other(code) => write!(f, "disconnected (reason {})", code),
+		};
+	}
+
+} impl Encode for DisconnectReason {
+
+	fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+		return self.code().encode(encoder);
+	}
+
+} impl<Context> Decode<Context> for DisconnectReason {
+
+	fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+		return Ok(Self::from_code(u16::decode(decoder)?));
+	}
+
+}
+bincode::impl_borrow_decode!(DisconnectReason);
+
+
+/// A reason for hanging up, along with whatever the other end should be told about it.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Eq)]
+pub struct Disconnect {
+
+	pub reason: DisconnectReason,
+
+	/// For people, not programs (may be empty).
+	pub message: String,
+
+} impl Disconnect {
+
+	pub fn new<T: Into<String>>(reason: DisconnectReason, message: T) -> Self {
+		return Self {
+			reason: reason,
+			message: message.into(),
+		};
+	}
+
+	/// An ordinary, nothing-went-wrong hang-up.
+	pub fn normal() -> Self {
+		return Self::new(DisconnectReason::Normal, "");
+	}
+
+} impl Display for Disconnect {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.message.is_empty() {
+			return write!(f, "{}", self.reason);
+		} else {
+			return write!(f, "{}: {}", self.reason, self.message);
+		}
+	}
+
+}
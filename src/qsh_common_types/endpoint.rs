@@ -0,0 +1,88 @@
+/*!
+	Places to listen on or connect to.
+	Which kinds of endpoint make sense depends on the transport.
+*/
+use std::{
+	fmt::{self, Display},
+	net::SocketAddr,
+	path::PathBuf,
+	str::FromStr,
+};
+use bincode::{
+	Encode,
+	Decode,
+};
+use serde::Deserialize;
+
+#[derive(Encode, Decode, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+
+	/// An IPv4 or IPv6 address and port (TCP, QUIC, etc).
+	Inet(SocketAddr),
+
+	/// A hostname and port, to be looked up with the system resolver when it's time to connect.
+	Host { name: String, port: u16 },
+
+	/// A Unix domain socket, by path.
+	Unix(PathBuf),
+
+	/// A shell command, spoken to over its stdin/stdout (like OpenSSH's `ProxyCommand`).
+	Command(String),
+
+	/// This process's own stdin/stdout.
+	Stdio,
+
+	/// An in-process pipe, by name (for tests, and for embedding client and server in one program).
+	Memory(String),
+
+} impl Endpoint {
+
+	/// The address and port, for endpoints that are literally that (hostnames need resolving first).
+	pub fn socket_addr(&self) -> Option<SocketAddr> {
+		return match self {
+			Self::Inet(addr) => Some(*addr),
+			Self::Host { .. } | Self::Unix(_) | Self::Command(_) | Self::Stdio | Self::Memory(_) => None,
+		};
+	}
+
+}
+
+impl Display for Endpoint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return match self {
+			Self::Inet(addr) => write!(f, "{}", addr),
+			Self::Host { name, port } => write!(f, "{}:{}", name, port),
+			Self::Unix(path) => write!(f, "unix:{}", path.display()),
+			Self::Command(command) => write!(f, "command:{}", command),
+			Self::Stdio => write!(f, "stdio"),
+			Self::Memory(name) => write!(f, "memory:{}", name),
+		};
+	}
+}
+
+/// Parses what `Display` prints: `1.2.3.4:22`, `[::1]:22`, `example.com:22`, `unix:<path>`, `command:<command>`, `memory:<name>` or `stdio`.
+impl FromStr for Endpoint {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(path) = s.strip_prefix("unix:") {
+			return Ok(Self::Unix(PathBuf::from(path)));
+		} else if let Some(command) = s.strip_prefix("command:") {
+			return Ok(Self::Command(command.to_string()));
+		} else if let Some(name) = s.strip_prefix("memory:") {
+			return Ok(Self::Memory(name.to_string()));
+		} else if s == "stdio" {
+			return Ok(Self::Stdio);
+		} else if let Ok(addr) = s.parse::<SocketAddr>() {
+			return Ok(Self::Inet(addr));
+		}
+
+		// Must be a hostname, then:
+		let (name, port) = s.rsplit_once(':').ok_or(format!("`{}` is missing a port", s))?;
+		let port: u16 = port.parse().map_err(|_| { format!("`{}` is not a valid port", port) })?;
+		if name.is_empty() || name.contains(':') {
+			return Err(format!("`{}` is not a valid hostname", name));
+		}
+		return Ok(Self::Host { name: name.to_string(), port: port });
+	}
+}
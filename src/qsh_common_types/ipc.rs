@@ -1,18 +1,46 @@
 /*!
 	Stuff for IPC between `qsh` and `qshd`.
+	Every message goes over the socket as its length (a little-endian `u64`), then the message itself.
 */
 use std::{
 	path::PathBuf,
-	net::Ipv6Addr,
 };
 use bincode::{
 	Encode,
 	Decode,
 	config,
 };
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind};
+
+use crate::{
+	disconnect::Disconnect,
+	endpoint::Endpoint,
+};
 
 pub const IPC_BINCODE_CONFIG: config::Configuration = config::standard();
 
+/// The longest message either end will read; a length past this is garbage (or hostile), so it doesn't get allocated for.
+pub const MAX_IPC_MESSAGE_SIZE: usize = 1 << 16;
+
+/// Sends `message`, length first.
+pub async fn write_message<T: Encode, W: AsyncWrite + Unpin>(socket: &mut W, message: &T) -> io::Result<()> {
+	let encoded: Vec<u8> = bincode::encode_to_vec(message, IPC_BINCODE_CONFIG).map_err(|e| { Error::new(ErrorKind::InvalidInput, e) })?;
+	socket.write_u64_le(encoded.len() as u64).await?;
+	socket.write_all(&encoded).await?;
+	return socket.flush().await;
+}
+
+/// Reads a message sent with `write_message`, turning it down if it says it's longer than `MAX_IPC_MESSAGE_SIZE`.
+pub async fn read_message<T: Decode<()>, R: AsyncRead + Unpin>(socket: &mut R) -> io::Result<T> {
+	let length: u64 = socket.read_u64_le().await?;
+	if length > MAX_IPC_MESSAGE_SIZE as u64 {
+		return Err(Error::new(ErrorKind::InvalidData, format!("message of {} bytes is longer than the limit of {}", length, MAX_IPC_MESSAGE_SIZE)));
+	}
+	let mut buffer: Vec<u8> = vec![0; length as usize];
+	socket.read_exact(buffer.as_mut_slice()).await?;
+	return Ok(bincode::decode_from_slice(&buffer, IPC_BINCODE_CONFIG).map_err(|e| { Error::new(ErrorKind::InvalidData, e) })?.0);
+}
+
 #[derive(Encode, Decode)]
 pub enum ChannelDirection {
 	I,
@@ -30,19 +58,19 @@ pub enum ChannelType {
 pub struct SessionRequest {
 
 	// Who to connect to:
-	remote_host: Ipv6Addr,
+	remote: Endpoint,
 
-	// Port:
-	port: u16,
+	// Jump hosts to go through on the way, in order:
+	jump: Vec<Endpoint>,
 
 	// What to run on the other end:
 	execute: PathBuf,
 
 } impl SessionRequest {
-	pub fn new<T: Into<Ipv6Addr>, U: Into<PathBuf>>(remote_host: T, port:u16, execute: U) -> Self {
+	pub fn new<U: Into<PathBuf>>(remote: Endpoint, jump: Vec<Endpoint>, execute: U) -> Self {
 		return Self {
-			remote_host: remote_host.into(),
-			port: port,
+			remote: remote,
+			jump: jump,
 			execute: execute.into(),
 		};
 	}
@@ -78,6 +106,24 @@ pub struct SessionAcknowledge {
 	}
 }
 
+/// Sent from `qshd` to `qsh` over the session control socket when the session ends, saying why.
+#[derive(Encode, Decode)]
+pub struct SessionEnded {
+
+	pub disconnect: Disconnect,
+
+	// Whether it was the remote host that hung up (rather than `qshd`, or the network):
+	pub by_peer: bool,
+
+} impl SessionEnded {
+	pub fn new(disconnect: Disconnect, by_peer: bool) -> Self {
+		return Self {
+			disconnect: disconnect,
+			by_peer: by_peer,
+		};
+	}
+}
+
 /// Sent from `qsh` to `qshd` to create a new channel.
 #[derive(Encode, Decode)]
 pub struct ChannelRequest {
@@ -4,4 +4,6 @@
 */
 
 pub mod ipc;
-pub mod keys;
\ No newline at end of file
+pub mod keys;
+pub mod endpoint;
+pub mod disconnect;
\ No newline at end of file
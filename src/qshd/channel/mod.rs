@@ -43,9 +43,16 @@ struct ChannelConfig {
 
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub enum Implementation {
 	Lz4Flex,
+} impl Implementation {
+	/// What this is called in handshakes.
+	pub fn name(&self) -> &'static str {
+		return match self {
+			Self::Lz4Flex => "lz4",
+		};
+	}
 } /* impl Implementation {
 	pub fn generate(&self, config: ChannelConfig) -> impl Channel {
 		return match self {
@@ -0,0 +1,117 @@
+/*!
+	Socket activation: listening on sockets that were bound for us and passed in, as described by
+	`LISTEN_PID`/`LISTEN_FDS` (that's how systemd does it, and a few other supervisors copied it).
+	The service manager holds the port from boot, so clients can connect before we're even running,
+	and there's no startup race. Each `listen` claims the passed-in socket that's bound to its
+	configured endpoint; if there isn't one, it binds its own like usual.
+*/
+
+// External stuff:
+use std::{
+	env,
+	net::{SocketAddr, TcpListener},
+	os::{
+		fd::{FromRawFd, OwnedFd, RawFd},
+		unix::net::UnixListener,
+	},
+	path::Path,
+	process,
+	sync::{Mutex, OnceLock},
+};
+
+
+/// The first socket that gets passed in (0, 1 and 2 are stdin, stdout and stderr).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Passed-in sockets that haven't been claimed yet.
+static INHERITED: OnceLock<Mutex<Vec<OwnedFd>>> = OnceLock::new();
+
+
+/// Claims the passed-in TCP socket that's bound to `addr`, if there is one.
+pub(super) fn tcp(addr: SocketAddr) -> Option<TcpListener> {
+	return claim(|fd| { TcpListener::from(fd).local_addr().ok() == Some(addr) }).map(TcpListener::from);
+}
+
+/// Claims the passed-in Unix socket that's bound to `path`, if there is one.
+pub(super) fn unix(path: &Path) -> Option<UnixListener> {
+	return claim(|fd| {
+		return UnixListener::from(fd).local_addr().ok()
+			.and_then(|addr| { addr.as_pathname().map(|bound| { bound == path }) })
+			.unwrap_or(false);
+	}).map(UnixListener::from);
+}
+
+/// Takes the first passed-in socket that `matches` says yes to (it gets handed a duplicate to look at, so it can do what it likes with it).
+fn claim(matches: impl Fn(OwnedFd) -> bool) -> Option<OwnedFd> {
+	let mut inherited = inherited().lock().unwrap();
+	let index: usize = inherited.iter().position(|fd| { fd.try_clone().map(&matches).unwrap_or(false) })?;
+	return Some(inherited.remove(index));
+}
+
+/// The passed-in sockets, picked up from the environment the first time they're asked for.
+fn inherited() -> &'static Mutex<Vec<OwnedFd>> {
+	return INHERITED.get_or_init(|| { Mutex::new(from_env(env::var("LISTEN_PID").ok(), env::var("LISTEN_FDS").ok())) });
+}
+
+/// Takes ownership of the sockets that `LISTEN_PID` and `LISTEN_FDS` describe, if they're meant for this process.
+fn from_env(pid: Option<String>, fds: Option<String>) -> Vec<OwnedFd> {
+	// They're only ours if they were passed to this very process (rather than, say, our parent):
+	if pid.and_then(|pid| { pid.parse::<u32>().ok() }) != Some(process::id()) {
+		return Vec::new();
+	}
+	let count: RawFd = fds.and_then(|count| { count.parse().ok() }).unwrap_or(0);
+	return (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)).filter_map(|fd| {
+		// SAFETY: the service manager handed these to this process to own, and this only runs once, so nothing else has them.
+		let fd: OwnedFd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+		// They come without close-on-exec, so swap each one for a duplicate that has it (keeping them away from the shells we start):
+		return fd.try_clone().ok();
+	}).collect();
+}
+
+
+#[tokio::test]
+async fn test_socket_activation() {
+	use bytes::Bytes;
+	use std::net::Ipv6Addr;
+	use super::{
+		Connection,
+		ConnectionConfiguration,
+		Endpoint,
+		TcpConnection,
+	};
+
+	// Sockets meant for some other process (or none at all) don't get touched:
+	assert!(from_env(Some(String::from("1")), Some(String::from("2"))).is_empty());
+	assert!(from_env(Some(process::id().to_string()), Some(String::from("0"))).is_empty());
+	assert!(from_env(None, None).is_empty());
+
+	// A socket bound ahead of time, standing in for one that systemd passed in:
+	let addr: SocketAddr = (Ipv6Addr::LOCALHOST, 54395).into();
+	let bound: TcpListener = TcpListener::bind(addr).unwrap();
+	inherited().lock().unwrap().push(OwnedFd::from(bound));
+
+	// Nothing claims it unless it's bound to the right place:
+	assert!(tcp((Ipv6Addr::LOCALHOST, 54396).into()).is_none());
+	assert!(unix(Path::new("/nonexistent")).is_none());
+
+	// A server configured for that address picks it up instead of binding its own:
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet(addr),
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	assert!(inherited().lock().unwrap().is_empty());
+	tokio::task::spawn(async move {
+		let (_, tx, mut rx) = server.accept().await.unwrap();
+		tx.send(rx.recv().await.unwrap()).await.unwrap();
+	});
+
+	let mut client: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54397).into()),
+		..ConnectionConfiguration::default()
+	});
+	let (ctx, mut crx) = client.connect(Endpoint::Inet(addr)).await.unwrap();
+	ctx.send(Bytes::from_static(b"Activated.")).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), b"Activated.".to_vec());
+}
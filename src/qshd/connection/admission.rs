@@ -0,0 +1,296 @@
+/*!
+	Server side: letting new connections in, without letting any one of them hold up the rest.
+	Every accepted stream gets its own handshake task, so a slow (or malicious) client only ties up
+	itself. On top of that, there's a limit on how many handshakes can be in flight at once, a limit
+	on how many connections each source address can have open, and a deadline for getting through
+	the handshake; anything over a limit gets hung up on straight away.
+*/
+
+// External stuff:
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	sync::{
+		mpsc::{
+			self, Receiver, Sender,
+		},
+		OwnedSemaphorePermit, Semaphore,
+	},
+	task,
+	time::{self, Duration},
+};
+#[cfg(feature = "quic")]
+use tokio::sync::watch;
+use std::{
+	collections::HashMap,
+	io,
+	net::IpAddr,
+	pin::Pin,
+	sync::{Arc, Mutex},
+	task::{Context, Poll},
+};
+
+// Internal stuff:
+use super::{
+	error::Events,
+	mux::Multiplexer,
+	roaming::{Link, Sessions},
+	ConnectionConfiguration,
+	ConnectionError,
+	Endpoint,
+	TcpConnection,
+};
+#[cfg(feature = "quic")]
+use super::qsh_tcp::Keys;
+#[cfg(feature = "quic")]
+use crate::crypto::Secret;
+
+
+/// Length of the buffer of sessions waiting to be picked up by `Connection::accept`.
+const ACCEPTED_BUFFER_SIZE: usize = 256;
+
+/// A new session, ready for `Connection::accept_streams` to hand out: (remote host, streams).
+pub(super) type Accepted = (Endpoint, Multiplexer);
+
+/// How many connections each source address has open.
+type Addresses = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Where a stream's keys come from, when it's one of many over the same connection (see `QuicConnection`).
+pub(super) enum Sharing {
+
+	/// It's the first one: it does the handshake, and passes the secret on to the rest.
+	#[cfg(feature = "quic")]
+	First(watch::Sender<Option<Secret>>),
+
+	/// It's a later one: it waits for the first one's secret, and makes its keys from that.
+	#[cfg(feature = "quic")]
+	Later(watch::Receiver<Option<Secret>>),
+
+}
+
+
+/// Runs handshakes for a listening `Connection`, and passes the new sessions on to its `accept`.
+#[derive(Clone)]
+pub(super) struct Admission {
+
+	// One permit per handshake in flight:
+	handshakes: Arc<Semaphore>,
+
+	// Open connections, by source address:
+	addresses: Addresses,
+
+	// Where new sessions go:
+	accepted: Sender<Accepted>,
+
+	sessions: Sessions,
+	events: Events,
+	config: ConnectionConfiguration,
+
+} impl Admission {
+
+	/// Makes a new one for a `Connection` with this configuration, sessions and events. Also returns where its new sessions come out.
+	pub(super) fn new(config: &ConnectionConfiguration, sessions: &Sessions, events: &Events) -> (Self, Receiver<Accepted>) {
+		let (accepted_tx, accepted_rx) = mpsc::channel::<Accepted>(ACCEPTED_BUFFER_SIZE);
+		return (Self {
+			handshakes: Arc::new(Semaphore::new(config.max_handshakes)),
+			addresses: Arc::new(Mutex::new(HashMap::new())),
+			accepted: accepted_tx,
+			sessions: sessions.clone(),
+			events: events.clone(),
+			config: config.clone(),
+		}, accepted_rx);
+	}
+
+	/// How long new connections get to finish their handshake.
+	pub(super) fn handshake_timeout(&self) -> Duration {
+		return Duration::from_millis(self.config.handshake_timeout);
+	}
+
+	/// Waits until nobody's picking up new sessions anymore (the `Connection` was dropped), so that whatever's accepting them can stop.
+	pub(super) async fn closed(&self) {
+		self.accepted.closed().await;
+	}
+
+	/**
+		Runs the handshake over a newly accepted stream in the background, if the limits allow it; otherwise, drops it.
+		`peer`: who's on the other end.
+		`address`: where it came from, for transports where that means anything.
+		`sharing`: which stream it is, for transports that carry many over one connection.
+	*/
+	pub(super) fn spawn<W, R>(&self, tx: W, rx: R, peer: Endpoint, address: Option<IpAddr>, sharing: Option<Sharing>)
+	where
+		W: AsyncWrite + Unpin + Send + 'static,
+		R: AsyncRead + Unpin + Send + 'static,
+	{
+		// Check the limits:
+		let slot: Slot = if let Some(slot) = self.claim(address) {
+			slot
+		} else {
+			eprintln!("Server: turned away {}: too many connections from that address.", peer);
+			return;
+		};
+		let permit: OwnedSemaphorePermit = if let Ok(permit) = self.handshakes.clone().try_acquire_owned() {
+			permit
+		} else {
+			eprintln!("Server: turned away {}: too many handshakes in progress.", peer);
+			return;
+		};
+
+		// The slot's held until both halves of the stream are gone:
+		let slot: Arc<Slot> = Arc::new(slot);
+		let tx: Tracked<W> = Tracked { inner: tx, _slot: slot.clone() };
+		let rx: Tracked<R> = Tracked { inner: rx, _slot: slot };
+
+		let admission: Self = self.clone();
+		task::spawn(async move {
+			// Do the handshake, spin up the tasks, and see what session it's for, all before the deadline:
+			let deadline: Duration = admission.handshake_timeout();
+			let result = time::timeout(deadline, async {
+				let (config, events, tickets) = (&admission.config, &admission.events, admission.sessions.tickets());
+				let link: Link = match sharing {
+					None => TcpConnection::start(config, events, tickets, tx, rx, peer.clone()).await?,
+					#[cfg(feature = "quic")]
+					Some(Sharing::First(first)) => {
+						let link: Link = TcpConnection::start(config, events, tickets, tx, rx, peer.clone()).await?;
+						first.send_replace(Some(link.resumption.clone()));
+						link
+					},
+					#[cfg(feature = "quic")]
+					Some(Sharing::Later(mut first)) => {
+						let secret: Secret = first.wait_for(|secret| { secret.is_some() }).await
+							.map_err(|_| { ConnectionError::Handshake(String::from("the connection's first stream never finished its handshake")) })?
+							.clone().unwrap();
+						TcpConnection::start_with(config, events, Keys::Shared(&secret, false), tx, rx, peer.clone()).await?
+					},
+				};
+				return Ok::<_, ConnectionError>(admission.sessions.admit(link, &admission.config).await?);
+			}).await;
+			drop(permit);
+
+			match result {
+				Ok(Ok(Some(streams))) => {
+					let _ = admission.accepted.send((peer, streams)).await;
+				},
+				Ok(Ok(None)) => (),	// It resumed a session (or got connected through to somewhere else).
+				Ok(Err(e)) => eprintln!("Server: handshake with {} failed: {}", peer, e),
+				Err(_) => eprintln!("Server: {} took too long to finish its handshake.", peer),
+			}
+		});
+	}
+
+	/// Counts a new connection from `address`, unless it already has as many as it's allowed.
+	fn claim(&self, address: Option<IpAddr>) -> Option<Slot> {
+		if let Some(ip) = address && self.config.max_connections_per_address != 0 {
+			let mut addresses = self.addresses.lock().unwrap();
+			let count: &mut usize = addresses.entry(ip).or_insert(0);
+			if *count >= self.config.max_connections_per_address {
+				return None;
+			}
+			*count += 1;
+			return Some(Slot { address: Some((ip, self.addresses.clone())) });
+		}
+		return Some(Slot { address: None });
+	}
+
+}
+
+
+/// One connection's place in the per-address count; gives it back when dropped.
+struct Slot {
+	address: Option<(IpAddr, Addresses)>,
+}
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		if let Some((ip, addresses)) = &self.address {
+			let mut addresses = addresses.lock().unwrap();
+			if let Some(count) = addresses.get_mut(ip) {
+				*count -= 1;
+				if *count == 0 {
+					addresses.remove(ip);
+				}
+			}
+		}
+	}
+}
+
+/// Half of a stream, holding on to its connection's `Slot` for as long as it's around.
+struct Tracked<T> {
+	inner: T,
+	_slot: Arc<Slot>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		return Pin::new(&mut self.inner).poll_read(cx, buf);
+	}
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		return Pin::new(&mut self.inner).poll_write(cx, buf);
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		return Pin::new(&mut self.inner).poll_flush(cx);
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		return Pin::new(&mut self.inner).poll_shutdown(cx);
+	}
+}
+
+
+#[tokio::test]
+async fn test_admission() {
+	use bytes::Bytes;
+	use std::net::Ipv6Addr;
+	use tokio::{
+		io::AsyncReadExt,
+		net::TcpStream,
+		time::Instant,
+	};
+	use super::Connection;
+
+	let server_address: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54392).into());
+	let client_conf: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54393).into()),
+		..ConnectionConfiguration::default()
+	};
+
+	// A server with a short handshake deadline, that only lets each address have two connections:
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: server_address.clone(),
+		handshake_timeout: 300,
+		max_connections_per_address: 2,
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		while let Ok((_, tx, mut rx)) = server.accept().await {
+			// Simple echo server:
+			task::spawn(async move {
+				while let Some(data) = rx.recv().await {
+					tx.send(data).await.unwrap();
+				}
+			});
+		}
+	});
+
+	// A client that connects and then says nothing shouldn't hold up a real one:
+	let mut staller: TcpStream = TcpStream::connect(server_address.socket_addr().unwrap()).await.unwrap();
+	let started: Instant = Instant::now();
+	let mut client: TcpConnection = TcpConnection::new(client_conf.clone());
+	let (ctx, mut crx) = client.connect(server_address.clone()).await.unwrap();
+	ctx.send(Bytes::from_static(b"Not stuck behind anyone.")).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), b"Not stuck behind anyone.".to_vec());
+
+	// That's two connections from here, so a third gets hung up on straight away:
+	let mut extra: TcpStream = TcpStream::connect(server_address.socket_addr().unwrap()).await.unwrap();
+	let mut buf: Vec<u8> = Vec::new();
+	time::timeout(Duration::from_millis(200), extra.read_to_end(&mut buf)).await.unwrap().unwrap_or(0);
+
+	// And the staller gets hung up on once its deadline's up:
+	let _ = staller.read_to_end(&mut buf).await;
+	assert!(started.elapsed() >= Duration::from_millis(300));
+	assert!(started.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,262 @@
+/*!
+	Buffers for records on their way through a link.
+	A record picks up a type, an AEAD tag, and a length on its way out, and loses them on its way in.
+	`Buffer` keeps room free at the front (headroom) and back (tailroom) for them, so adding one
+	doesn't mean shifting the whole record over, or reallocating it to make it fit. Buffers come out
+	of a `BufferPool` that's shared between a link's tasks; when one's dropped (in whichever task
+	that happens), its memory goes back to the pool for the next record, instead of back to the allocator.
+	A buffer has one owner at a time while it's on its way through a link, and moves from task to task
+	(so encrypting it in place is fine). Once it's been decrypted, it gets frozen into reference-counted
+	`Bytes` for the application, without copying it; the memory goes back to the pool once the last
+	reference to it is dropped. Application data gets copied just once, on the way out: into a record's
+	buffer, since the record gets encrypted in place, and the session holds on to the application's
+	`Bytes` (another reference, not a copy) until the other end acknowledges them.
+*/
+
+// External stuff:
+use bytes::Bytes;
+use std::{
+	io::{self, Write},
+	iter,
+	mem,
+	ops::{Deref, DerefMut},
+	sync::{Arc, Mutex},
+};
+
+
+/// Room kept free at the front of every buffer (enough for a record's length and type).
+pub(super) const HEADROOM: usize = 16;
+
+/// Room kept free at the back of every buffer (enough for an AEAD tag).
+pub(super) const TAILROOM: usize = 16;
+
+/// Most spare buffers a pool holds on to.
+const POOL_SIZE: usize = 64;
+
+/// Buffers that have grown past this get dropped instead of pooled, so that one big record doesn't pin its memory forever.
+const MAX_POOLED_CAPACITY: usize = 256 * 1024;
+
+
+/// A record (or part of one) in memory, with room to grow at both ends.
+pub struct Buffer {
+
+	/// Headroom, then the contents.
+	bytes: Vec<u8>,
+
+	/// Where the contents start.
+	start: usize,
+
+	/// Where the memory goes back to once we're done with it, if anywhere.
+	pool: Option<BufferPool>,
+
+} impl Buffer {
+
+	/// Makes an empty buffer that doesn't belong to a pool.
+	pub fn new() -> Self {
+		return Self::with_storage(Vec::new(), None);
+	}
+
+	/// Lays out `bytes` (whatever's in it gets thrown away) as an empty buffer with headroom.
+	fn with_storage(mut bytes: Vec<u8>, pool: Option<BufferPool>) -> Self {
+		bytes.clear();
+		bytes.resize(HEADROOM, 0);
+		return Self {
+			bytes: bytes,
+			start: HEADROOM,
+			pool: pool,
+		};
+	}
+
+	/// Puts `header` in front of the contents (in the headroom, unless it's run out).
+	pub fn prepend(&mut self, header: &[u8]) {
+		if header.len() > self.start {
+			// Out of room; make some more (this means shifting everything, but it shouldn't happen):
+			let extra: usize = header.len() - self.start + HEADROOM;
+			self.bytes.splice(0..0, iter::repeat_n(0, extra));
+			self.start += extra;
+		}
+		self.start -= header.len();
+		self.bytes[self.start..self.start + header.len()].copy_from_slice(header);
+	}
+
+	/// Drops the first `count` bytes of the contents (they become headroom).
+	pub fn advance(&mut self, count: usize) {
+		assert!(count <= self.len(), "advanced past the end of a buffer");
+		self.start += count;
+	}
+
+	/// Adds `data` to the end of the contents (if it doesn't fit, the buffer grows with tailroom to spare after it).
+	pub fn extend_from_slice(&mut self, data: &[u8]) {
+		if self.bytes.capacity() - self.bytes.len() < data.len() {
+			self.bytes.reserve(data.len() + TAILROOM);
+		}
+		self.bytes.extend_from_slice(data);
+	}
+
+	/// Shortens the contents to `length` bytes (does nothing if they're already shorter).
+	pub fn truncate(&mut self, length: usize) {
+		self.bytes.truncate(self.start + length);
+	}
+
+	/// Makes the contents exactly `length` bytes long, filling any new ones with zeroes (to be read into).
+	pub fn resize(&mut self, length: usize) {
+		self.bytes.reserve((self.start + length + TAILROOM).saturating_sub(self.bytes.len()));
+		self.bytes.resize(self.start + length, 0);
+	}
+
+	/// Turns the contents into `Bytes` that can be shared, without copying them (the memory goes back to the pool once they're all dropped).
+	pub fn freeze(self) -> Bytes {
+		return Bytes::from_owner(self);
+	}
+
+} impl Drop for Buffer {
+
+	fn drop(&mut self) {
+		if let Some(pool) = self.pool.take() {
+			pool.put(mem::take(&mut self.bytes));
+		}
+	}
+
+} impl Default for Buffer {
+
+	fn default() -> Self {
+		return Self::new();
+	}
+
+} impl From<&[u8]> for Buffer {
+
+	fn from(data: &[u8]) -> Self {
+		let mut buffer: Self = Self::new();
+		buffer.extend_from_slice(data);
+		return buffer;
+	}
+
+} impl Deref for Buffer {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		return &self.bytes[self.start..];
+	}
+
+} impl DerefMut for Buffer {
+
+	fn deref_mut(&mut self) -> &mut [u8] {
+		return &mut self.bytes[self.start..];
+	}
+
+} impl AsRef<[u8]> for Buffer {
+
+	fn as_ref(&self) -> &[u8] {
+		return self;
+	}
+
+} impl AsMut<[u8]> for Buffer {
+
+	fn as_mut(&mut self) -> &mut [u8] {
+		return self;
+	}
+
+} impl Write for Buffer {
+
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		self.extend_from_slice(data);
+		return Ok(data.len());
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		return Ok(());
+	}
+
+}
+
+
+/// Spare buffers, shared between whoever's making and dropping them (cloning it just makes another handle to the same pool).
+#[derive(Clone, Default)]
+pub struct BufferPool(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl BufferPool {
+
+	/// Makes an empty pool.
+	pub fn new() -> Self {
+		return Self::default();
+	}
+
+	/// Takes an empty buffer out of the pool (or makes a new one, if there aren't any spare). It comes back when it's dropped.
+	pub fn take(&self) -> Buffer {
+		let bytes: Vec<u8> = self.0.lock().unwrap().pop().unwrap_or_default();
+		return Buffer::with_storage(bytes, Some(self.clone()));
+	}
+
+	/// Keeps `bytes` for later, unless the pool's full or they're too big to be worth keeping.
+	fn put(&self, bytes: Vec<u8>) {
+		if bytes.capacity() <= MAX_POOLED_CAPACITY {
+			let mut spare = self.0.lock().unwrap();
+			if spare.len() < POOL_SIZE {
+				spare.push(bytes);
+			}
+		}
+	}
+
+}
+
+
+#[tokio::test]
+async fn test_buffer() {
+	let pool: BufferPool = BufferPool::new();
+
+	// Headers go in front without moving what's already there, and come back off the same way:
+	let mut buffer: Buffer = pool.take();
+	buffer.extend_from_slice(b"payload");
+	let address: *const u8 = buffer.as_ptr();
+	buffer.prepend(b"type");
+	buffer.prepend(b"length");
+	assert_eq!(&buffer[..], b"lengthtypepayload");
+	assert_eq!(buffer[10..].as_ptr(), address);
+	buffer.advance(10);
+	assert_eq!(&buffer[..], b"payload");
+
+	// A header that doesn't fit in the headroom still works, it's just slower:
+	buffer.prepend(&[0xAA; HEADROOM * 2]);
+	assert_eq!(buffer.len(), HEADROOM * 2 + 7);
+	assert!(buffer.starts_with(&[0xAA; HEADROOM * 2]) && buffer.ends_with(b"payload"));
+	drop(buffer);
+
+	// Tags fit on the end without reallocating:
+	let mut buffer: Buffer = pool.take();
+	buffer.resize(1000);
+	let capacity: usize = buffer.bytes.capacity();
+	buffer.extend_from_slice(&[0_u8; TAILROOM]);
+	assert_eq!(buffer.bytes.capacity(), capacity);
+	buffer.truncate(1000);
+	assert_eq!(buffer.len(), 1000);
+
+	// Dropped buffers go back to the pool, and come back out empty (whichever handle to the pool they come out of):
+	drop(buffer);
+	assert_eq!(pool.0.lock().unwrap().len(), 1);
+	let buffer: Buffer = pool.clone().take();
+	assert!(buffer.is_empty() && buffer.bytes.capacity() >= 1000 + HEADROOM);
+	assert_eq!(pool.0.lock().unwrap().len(), 0);
+	drop(buffer);
+
+	// Except for really big ones:
+	let mut big: Buffer = pool.take();
+	big.resize(MAX_POOLED_CAPACITY * 2);
+	drop(big);
+	assert_eq!(pool.0.lock().unwrap().len(), 0);
+
+	// Ones from outside a pool just get freed:
+	drop(Buffer::from(&b"loose"[..]));
+	assert_eq!(pool.0.lock().unwrap().len(), 0);
+
+	// Frozen ones are shared rather than copied, and go back once the last reference is gone:
+	let mut buffer: Buffer = pool.take();
+	buffer.extend_from_slice(b"shared");
+	let address: *const u8 = buffer.as_ptr();
+	let frozen: Bytes = buffer.freeze();
+	let (copy, part): (Bytes, Bytes) = (frozen.clone(), frozen.slice(2..));
+	assert_eq!((copy.as_ptr(), &part[..]), (address, &b"ared"[..]));
+	drop((frozen, copy));
+	assert_eq!(pool.0.lock().unwrap().len(), 0);
+	drop(part);
+	assert_eq!(pool.0.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,207 @@
+/*!
+	What can go wrong with a connection, and what happens to one over its life.
+	Every link reports `Opened` once its handshake is done and `Closed` when it's gone, on its
+	`Connection`'s events channel (see `Connection::events`), so the server layer can keep track of
+	them without parsing stderr.
+*/
+
+// External stuff:
+use thiserror::Error;
+use tokio::{
+	io,
+	sync::broadcast,
+};
+use std::{
+	fmt::{self, Display},
+	sync::Arc,
+};
+
+// Internal stuff:
+use super::{
+	framing::FramingError,
+	Disconnect,
+	Endpoint,
+};
+
+
+/// How many events a slow listener can fall behind by before it starts missing some.
+pub(super) const EVENT_BUFFER_SIZE: usize = 64;
+
+/// Where a `Connection` sends its events.
+pub(super) type Events = broadcast::Sender<ConnectionEvent>;
+
+/// Makes a fresh events channel for a `Connection`.
+pub(super) fn new_events() -> Events {
+	return broadcast::channel(EVENT_BUFFER_SIZE).0;
+}
+
+/// Everything that can go wrong with a connection.
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+
+	/// The key exchange failed (the other end isn't speaking our protocol, or something's tampering with it).
+	#[error("handshake failed: {0}")]
+	Handshake(String),
+
+	/// The two ends don't have an algorithm of some kind in common.
+	#[error("no common {kind} algorithm (we offered {}, they offered {})", .ours.join(", "), .theirs.join(", "))]
+	NoCommonAlgorithm { kind: &'static str, ours: Vec<String>, theirs: Vec<String> },
+
+	/// Rekeying went wrong (the other end sent something out of turn, or the new key exchange failed).
+	#[error("rekey failed: {0}")]
+	Rekey(String),
+
+	/// A record couldn't be encrypted or decrypted.
+	#[error("crypto failure: {0}")]
+	Crypto(String),
+
+	/// A record didn't make sense (bad length, unknown type, etc).
+	#[error("framing error: {0}")]
+	Framing(#[from] FramingError),
+
+	/// The peer stopped sending heartbeats.
+	#[error("peer is dead (missed {0} heartbeats in a row)")]
+	PeerDead(u32),
+
+	/// Tried to accept connections without listening first.
+	#[error("not listening")]
+	NotListening,
+
+	/// Tried to connect somewhere while listening.
+	#[error("can't connect while listening")]
+	Listening,
+
+	/// The underlying transport failed.
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+
+}
+
+impl From<ConnectionError> for io::Error {
+	fn from(error: ConnectionError) -> Self {
+		return match error {
+			ConnectionError::Io(e) => e,
+			ConnectionError::PeerDead(_) => io::Error::new(io::ErrorKind::TimedOut, error),
+			ConnectionError::NotListening | ConnectionError::Listening => io::Error::new(io::ErrorKind::InvalidInput, error),
+			_ => io::Error::new(io::ErrorKind::InvalidData, error),
+		};
+	}
+}
+
+/// Why a link closed.
+#[derive(Clone, Debug)]
+pub enum CloseReason {
+
+	/// We hung up.
+	Local,
+
+	/// The peer hung up.
+	Remote,
+
+	/// Something went wrong.
+	Failed(Arc<ConnectionError>),
+
+}
+
+impl From<ConnectionError> for CloseReason {
+	fn from(error: ConnectionError) -> Self {
+		return Self::Failed(Arc::new(error));
+	}
+}
+
+impl Display for CloseReason {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return match self {
+			Self::Local => write!(f, "closed by us"),
+			Self::Remote => write!(f, "closed by the peer"),
+			Self::Failed(e) => write!(f, "{}", e),
+		};
+	}
+}
+
+/// Something that happened to one of a `Connection`'s links.
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+
+	/// A link finished its handshake.
+	Opened { peer: Endpoint },
+
+	/// A link closed, after sending and receiving this many bytes (on the wire, so including framing).
+	Closed { peer: Endpoint, reason: CloseReason, bytes_sent: u64, bytes_received: u64 },
+
+	/// A session ended (as opposed to one of its links), because one end hung up (`by_peer` says which), or because it was lost.
+	Disconnected { peer: Endpoint, disconnect: Disconnect, by_peer: bool },
+
+}
+
+
+#[tokio::test]
+async fn test_connection_events() {
+	use bytes::Bytes;
+	use std::net::Ipv6Addr;
+	use super::{
+		Connection,
+		ConnectionConfiguration,
+		TcpConnection,
+	};
+
+	let client_address: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54390).into());
+	let server_address: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54391).into());
+
+	// A server that echoes one message and hangs up:
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: server_address.clone(),
+		..ConnectionConfiguration::default()
+	});
+	let mut events: broadcast::Receiver<ConnectionEvent> = server.events();
+	server.listen().await.unwrap();
+	assert!(matches!(server.connect(server_address.clone()).await, Err(ConnectionError::Listening)));
+	let server = tokio::task::spawn(async move {
+		let (peer, tx, mut rx) = server.accept().await.unwrap();
+		tx.send(rx.recv().await.unwrap()).await.unwrap();
+		return peer;
+	});
+
+	// And a client, which shouldn't be accepting anything:
+	let mut client: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: client_address.clone(),
+		..ConnectionConfiguration::default()
+	});
+	assert!(matches!(client.accept().await, Err(ConnectionError::NotListening)));
+	let mut client_events: broadcast::Receiver<ConnectionEvent> = client.events();
+	let (ctx, mut crx) = client.connect(server_address).await.unwrap();
+	ctx.send(Bytes::from_static(b"ping")).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), b"ping".to_vec());
+	assert_eq!(server.await.unwrap(), client_address);
+
+	// The server should have seen the link open, the session end (it hung up), and then the link close cleanly, with the traffic counted:
+	match events.recv().await.unwrap() {
+		ConnectionEvent::Opened { peer } => assert_eq!(peer, client_address),
+		_ => panic!("expected the link to open first"),
+	}
+	match events.recv().await.unwrap() {
+		ConnectionEvent::Disconnected { peer, disconnect, by_peer } => {
+			assert_eq!(peer, client_address);
+			assert_eq!(disconnect, Disconnect::normal());
+			assert!(!by_peer);
+		},
+		_ => panic!("expected the session to end"),
+	}
+	match events.recv().await.unwrap() {
+		ConnectionEvent::Closed { peer, reason, bytes_sent, bytes_received } => {
+			assert_eq!(peer, client_address);
+			assert!(!matches!(reason, CloseReason::Failed(_)), "closed with {}", reason);
+			assert!(bytes_sent > 0 && bytes_received > 0);
+		},
+		_ => panic!("expected the link to close"),
+	}
+
+	// And the client should have been told why:
+	loop {
+		if let ConnectionEvent::Disconnected { disconnect, by_peer, .. } = client_events.recv().await.unwrap() {
+			assert_eq!(disconnect, Disconnect::normal());
+			assert!(by_peer);
+			break;
+		}
+	}
+}
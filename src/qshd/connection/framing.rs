@@ -0,0 +1,241 @@
+/*!
+	Record framing: how records are cut out of a byte stream.
+	Each record is its length (LEB128: seven bits a byte, low bits first, so small records only
+	spend a byte or two on it) followed by that many bytes. Lengths are read and checked against
+	the maximum record size before anything gets allocated for the record, since they arrive before
+	there's any way to tell whether the other end is who it says it is. Both ends say what their
+	maximum is at the very start of the handshake, and the smaller one goes for both directions.
+	The length goes into the record's headroom on the way out, so each record is written in one go.
+*/
+
+// External stuff:
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind};
+
+// Internal stuff:
+use super::{
+	Buffer,
+	BufferPool,
+	ConnectionConfiguration,
+	ConnectionError,
+};
+
+
+/// Smallest maximum record size either end may ask for (anything less can't fit a useful record).
+pub(super) const MIN_RECORD_SIZE: usize = 4096;
+
+/// Longest a length can be, encoded (enough for any `u64`).
+const MAX_LENGTH_SIZE: usize = 10;
+
+/// Everything that can be wrong with a record's framing.
+#[derive(Error, Debug)]
+pub enum FramingError {
+
+	/// The record is bigger than the negotiated maximum (it wasn't read, or sent).
+	#[error("record of {length} bytes is over the limit of {limit} bytes")]
+	Oversized { length: u64, limit: usize },
+
+	/// The stream ended partway through a record.
+	#[error("record cut off after {received} of {expected} bytes")]
+	Truncated { expected: usize, received: usize },
+
+	/// The stream ended partway through a record's length.
+	#[error("record length cut off")]
+	TruncatedLength,
+
+	/// The length isn't encoded the way it should be (too long, or with needless padding).
+	#[error("record length is badly encoded")]
+	BadLength,
+
+	/// The record decrypted fine, but it's of a type we don't know.
+	#[error("received a record of unknown type {0}")]
+	UnknownType(u8),
+
+}
+
+
+/// Reads and writes records on a byte stream, up to a maximum size. Clones share their pool of buffers.
+#[derive(Clone)]
+pub(super) struct Codec {
+	limit: usize,
+	pool: BufferPool,
+} impl Codec {
+
+	/// Makes a codec for records of up to `limit` bytes (or `MIN_RECORD_SIZE`, if that's bigger).
+	pub(super) fn new(limit: usize) -> Self {
+		return Self { limit: limit.max(MIN_RECORD_SIZE), pool: BufferPool::new() };
+	}
+
+	/// The pool that records get read into (and that records to write should come out of).
+	pub(super) fn pool(&self) -> &BufferPool {
+		return &self.pool;
+	}
+
+	/// The biggest record this will carry (as agreed with the other end).
+	pub(super) fn limit(&self) -> usize {
+		return self.limit;
+	}
+
+	/**
+		Tells the other end our maximum record size (from `config`), and hears its own. Comes before everything else on a new link.
+		Returns a codec for the smaller of the two.
+	*/
+	pub(super) async fn negotiate<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(config: &ConnectionConfiguration, tx: &mut W, rx: &mut R) -> Result<Self, ConnectionError> {
+		let ours: Self = Self::new(config.max_record_size);
+		let mut announcement: [u8; MAX_LENGTH_SIZE] = [0_u8; MAX_LENGTH_SIZE];
+		let size: usize = encode_length(ours.limit as u64, &mut announcement);
+		tx.write_all(&announcement[..size]).await?;
+		tx.flush().await?;
+
+		let theirs: u64 = decode_length(rx).await?.ok_or(FramingError::TruncatedLength)?;
+		if theirs < MIN_RECORD_SIZE as u64 {
+			return Err(ConnectionError::Handshake(format!("peer's maximum record size ({} bytes) is too small", theirs)));
+		}
+		return Ok(Self { limit: ours.limit.min(usize::try_from(theirs).unwrap_or(usize::MAX)), pool: ours.pool });
+	}
+
+	/// Writes `record` to `tx` (without flushing), with its length in front. Returns how many bytes that took, length included.
+	pub(super) async fn write<W: AsyncWrite + Unpin>(&self, tx: &mut W, record: &mut Buffer) -> Result<u64, ConnectionError> {
+		let size: usize = record.len();
+		if size > self.limit {
+			return Err(FramingError::Oversized { length: size as u64, limit: self.limit }.into());
+		}
+		let mut length: [u8; MAX_LENGTH_SIZE] = [0_u8; MAX_LENGTH_SIZE];
+		let length_size: usize = encode_length(size as u64, &mut length);
+		record.prepend(&length[..length_size]);
+		tx.write_all(record).await?;
+		return Ok(wire_size(size));
+	}
+
+	/// Reads the next record from `rx`, into a buffer from the pool. Returns `None` if the stream ended cleanly, in between records.
+	pub(super) async fn read<R: AsyncRead + Unpin>(&self, rx: &mut R) -> Result<Option<Buffer>, ConnectionError> {
+		// Check the length before allocating anything:
+		let length: u64 = if let Some(length) = decode_length(rx).await? {
+			length
+		} else {
+			return Ok(None);
+		};
+		if length > self.limit as u64 {
+			return Err(FramingError::Oversized { length: length, limit: self.limit }.into());
+		}
+
+		// Then read the record itself, keeping track of how much of it showed up:
+		let expected: usize = length as usize;
+		let mut record: Buffer = self.pool.take();
+		record.resize(expected);
+		let mut received: usize = 0;
+		while received < expected {
+			match rx.read(&mut record[received..]).await? {
+				0 => return Err(FramingError::Truncated { expected: expected, received: received }.into()),
+				count => received += count,
+			}
+		}
+		return Ok(Some(record));
+	}
+
+}
+
+/// How many bytes a record of `length` bytes takes up on the wire, length included.
+pub(super) fn wire_size(length: usize) -> u64 {
+	let mut prefix: u64 = 1;
+	let mut rest: u64 = (length as u64) >> 7;
+	while rest != 0 {
+		prefix += 1;
+		rest >>= 7;
+	}
+	return prefix + length as u64;
+}
+
+/// Writes `length` to the start of `out`, as LEB128. Returns how many bytes that took.
+fn encode_length(mut length: u64, out: &mut [u8; MAX_LENGTH_SIZE]) -> usize {
+	for (i, slot) in out.iter_mut().enumerate() {
+		let byte: u8 = (length & 0x7F) as u8;
+		length >>= 7;
+		if length == 0 {
+			*slot = byte;
+			return i + 1;
+		}
+		*slot = byte | 0x80;
+	}
+	unreachable!("a u64 fits in {} bytes of LEB128", MAX_LENGTH_SIZE);
+}
+
+/// Reads a LEB128 length from `rx`. Returns `None` if the stream ended before it started.
+async fn decode_length<R: AsyncRead + Unpin>(rx: &mut R) -> Result<Option<u64>, ConnectionError> {
+	let mut length: u64 = 0;
+	for i in 0..MAX_LENGTH_SIZE {
+		let byte: u8 = match rx.read_u8().await {
+			Ok(byte) => byte,
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof && i == 0 => return Ok(None),	// Hung up in between records.
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(FramingError::TruncatedLength.into()),
+			Err(e) => return Err(e.into()),
+		};
+
+		// The last byte can't overflow, and (past the first) can't be zero, since that'd just be padding:
+		let bits: u64 = (byte & 0x7F) as u64;
+		if (i == MAX_LENGTH_SIZE - 1 && bits > 1) || (i > 0 && byte == 0) {
+			return Err(FramingError::BadLength.into());
+		}
+		length |= bits << (7 * i);
+		if byte & 0x80 == 0 {
+			return Ok(Some(length));
+		}
+	}
+	return Err(FramingError::BadLength.into());
+}
+
+
+#[tokio::test]
+async fn test_framing() {
+	use tokio::io;
+
+	// Lengths are as short as they can be:
+	for (length, encoded) in [(0_u64, vec![0x00]), (127, vec![0x7F]), (128, vec![0x80, 0x01]), (300, vec![0xAC, 0x02]), (u64::MAX, vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01])] {
+		let mut out: [u8; MAX_LENGTH_SIZE] = [0_u8; MAX_LENGTH_SIZE];
+		let size: usize = encode_length(length, &mut out);
+		assert_eq!(&out[..size], encoded);
+		if length < 1 << 20 {
+			assert_eq!(wire_size(length as usize), size as u64 + length);
+		}
+		assert_eq!(decode_length(&mut &out[..size]).await.unwrap(), Some(length));
+	}
+
+	// Records make it through whole, and the stream ends cleanly:
+	let codec: Codec = Codec::new(MIN_RECORD_SIZE);
+	let mut wire: Vec<u8> = Vec::new();
+	for length in [0, 1, 200, MIN_RECORD_SIZE] {
+		assert_eq!(codec.write(&mut wire, &mut Buffer::from(&vec![7_u8; length][..])).await.unwrap(), wire_size(length));
+	}
+	let mut rx: &[u8] = &wire;
+	for length in [0, 1, 200, MIN_RECORD_SIZE] {
+		assert_eq!(&codec.read(&mut rx).await.unwrap().unwrap()[..], vec![7_u8; length]);
+	}
+	assert!(codec.read(&mut rx).await.unwrap().is_none());
+
+	// Too big to send:
+	assert!(matches!(codec.write(&mut Vec::new(), &mut Buffer::from(&vec![0_u8; MIN_RECORD_SIZE + 1][..])).await, Err(ConnectionError::Framing(FramingError::Oversized { .. }))));
+
+	// Claims to be a terabyte (turned away before anything gets allocated):
+	let mut huge: [u8; MAX_LENGTH_SIZE] = [0_u8; MAX_LENGTH_SIZE];
+	let size: usize = encode_length(1 << 40, &mut huge);
+	assert!(matches!(codec.read(&mut &huge[..size]).await, Err(ConnectionError::Framing(FramingError::Oversized { length: 0x10000000000, limit: MIN_RECORD_SIZE }))));
+
+	// Cut off partway through the record, and partway through the length:
+	assert!(matches!(codec.read(&mut &[0x05, 1, 2][..]).await, Err(ConnectionError::Framing(FramingError::Truncated { expected: 5, received: 2 }))));
+	assert!(matches!(codec.read(&mut &[0x80][..]).await, Err(ConnectionError::Framing(FramingError::TruncatedLength))));
+
+	// Padded or overflowing lengths:
+	assert!(matches!(codec.read(&mut &[0x81, 0x00][..]).await, Err(ConnectionError::Framing(FramingError::BadLength))));
+	assert!(matches!(codec.read(&mut &[0xFF; 11][..]).await, Err(ConnectionError::Framing(FramingError::BadLength))));
+
+	// Both ends end up with the smaller maximum:
+	let (mut a, mut b) = io::duplex(64);
+	let big: ConnectionConfiguration = ConnectionConfiguration { max_record_size: 1 << 20, ..ConnectionConfiguration::default() };
+	let small: ConnectionConfiguration = ConnectionConfiguration { max_record_size: 1 << 16, ..ConnectionConfiguration::default() };
+	let (a_codec, b_codec) = tokio::join!(
+		async { let (mut rx, mut tx) = io::split(&mut a); return Codec::negotiate(&big, &mut tx, &mut rx).await; },
+		async { let (mut rx, mut tx) = io::split(&mut b); return Codec::negotiate(&small, &mut tx, &mut rx).await; },
+	);
+	assert_eq!(a_codec.unwrap().limit, 1 << 16);
+	assert_eq!(b_codec.unwrap().limit, 1 << 16);
+}
@@ -0,0 +1,414 @@
+/*!
+	A network impairment simulator: sits on a link's byte stream (under `TcpConnection::start`, so every
+	transport's links go through it), and makes it behave like a bad network. Whatever gets read off the
+	stream in one go is one chunk to the simulator, and chunks get held up (latency, plus random jitter),
+	squeezed through a bandwidth cap, lost, delivered out of order, or cut off altogether, all according
+	to the configured odds. Losing or reordering a chunk garbles the records, so the link dies and the
+	session redials and resumes, just like it would over a really bad network.
+	Everything random comes from the configured seed (each link's generators are seeded from it, in the
+	order the links get made), so a run that turns up a problem can be repeated.
+	The defaults leave everything alone; set `impairment` in the configuration to turn it on.
+*/
+
+// External stuff:
+use rand::{
+	rngs::StdRng,
+	Rng, RngCore, SeedableRng,
+};
+use serde::Deserialize;
+use tokio::{
+	io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf},
+	sync::{
+		mpsc::{
+			self, Receiver, Sender
+		},
+		watch,
+	},
+	task,
+	time::{self, Duration, Instant},
+};
+use std::{
+	collections::VecDeque,
+	sync::{
+		Arc,
+		atomic::{AtomicU64, Ordering},
+	},
+};
+
+
+/// How much the pipe between a link and the simulator holds.
+const PIPE_BUFFER_SIZE: usize = 65536;
+
+/// The most that gets read off a stream in one go (so, the biggest a chunk gets).
+const CHUNK_SIZE: usize = 16384;
+
+/// How many chunks can be in the simulator at once, each way.
+const CHANNEL_BUFFER_SIZE: usize = 64;
+
+
+/// Settings for the impairment simulator.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ImpairmentConfiguration {
+
+	/// How many miliseconds every message is held up for.
+	#[serde(default = "default_latency")]
+	pub latency: u64,
+
+	/// Up to how many more miliseconds each message may be held up for, at random.
+	#[serde(default = "default_jitter")]
+	pub jitter: u64,
+
+	/// How many bytes a second get through each way (0 means no limit).
+	#[serde(default = "default_bandwidth")]
+	pub bandwidth: u64,
+
+	/// Odds of each message getting lost (0 to 1).
+	#[serde(default = "default_loss")]
+	pub loss: f64,
+
+	/// Odds of each message getting held back until after the next one (0 to 1).
+	#[serde(default = "default_reorder")]
+	pub reorder: f64,
+
+	/// Odds of the connection getting cut off, every time a message goes through it (0 to 1).
+	#[serde(default = "default_disconnect")]
+	pub disconnect: f64,
+
+	/// Seed for the random number generator.
+	#[serde(default = "default_seed")]
+	pub seed: u64,
+
+	// How many links have gone through the simulator with these settings (shared between copies of them):
+	#[serde(skip)]
+	links: Arc<AtomicU64>,
+
+}
+
+impl Default for ImpairmentConfiguration {
+	fn default() -> Self {
+		return Self {
+			latency: default_latency(),
+			jitter: default_jitter(),
+			bandwidth: default_bandwidth(),
+			loss: default_loss(),
+			reorder: default_reorder(),
+			disconnect: default_disconnect(),
+			seed: default_seed(),
+			links: Arc::new(AtomicU64::new(0)),
+		};
+	}
+}
+
+
+/**
+	Puts a link's byte stream through the simulator, both ways.
+	`tx`, `rx`: the stream as it came from the transport.
+	Returns the impaired (tx, rx), to run the link over instead.
+*/
+pub(super) fn stream<W, R>(settings: &ImpairmentConfiguration, tx: W, rx: R) -> (WriteHalf<DuplexStream>, ReadHalf<DuplexStream>)
+where
+	W: AsyncWrite + Unpin + Send + 'static,
+	R: AsyncRead + Unpin + Send + 'static,
+{
+	let mut rng: StdRng = StdRng::seed_from_u64(settings.seed.wrapping_add(settings.links.fetch_add(1, Ordering::Relaxed)));
+	let (near, far) = io::duplex(PIPE_BUFFER_SIZE);
+	let (far_rx, far_tx) = io::split(far);
+
+	// Cutting off either direction cuts off both:
+	let cut: Arc<watch::Sender<bool>> = Arc::new(watch::Sender::new(false));
+
+	// What the link sends goes through the simulator, then out over the real stream:
+	let (chunks, outbound) = mpsc::channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
+	let (delivered, outbound_delivered) = mpsc::channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
+	task::spawn(read_task(far_rx, chunks, cut.clone()));
+	task::spawn(impair_task(outbound, delivered, settings.clone(), StdRng::seed_from_u64(rng.next_u64()), cut.clone()));
+	task::spawn(write_task(outbound_delivered, tx, cut.clone()));
+
+	// And what comes in over the real stream goes through it the other way:
+	let (chunks, inbound) = mpsc::channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
+	let (delivered, inbound_delivered) = mpsc::channel::<Vec<u8>>(CHANNEL_BUFFER_SIZE);
+	task::spawn(read_task(rx, chunks, cut.clone()));
+	task::spawn(impair_task(inbound, delivered, settings.clone(), StdRng::seed_from_u64(rng.next_u64()), cut.clone()));
+	task::spawn(write_task(inbound_delivered, far_tx, cut));
+
+	let (rx, tx) = io::split(near);
+	return (tx, rx);
+}
+
+/// Reads whatever's there off `from`, and passes it on to `to` a chunk at a time, until `from` ends (or fails), nobody's listening, or the link's cut off.
+async fn read_task<R: AsyncRead + Unpin>(mut from: R, to: Sender<Vec<u8>>, cut: Arc<watch::Sender<bool>>) {
+	let mut cut_off: watch::Receiver<bool> = cut.subscribe();
+	let mut buffer: Vec<u8> = vec![0; CHUNK_SIZE];
+	loop {
+		let length: usize = tokio::select! {
+			read = from.read(&mut buffer) => match read {
+				Ok(0) => return,
+				Ok(length) => length,
+				Err(_) => {
+					cut.send_replace(true);
+					return;
+				},
+			},
+			_ = async { let _ = cut_off.wait_for(|cut| { *cut }).await; } => return,
+		};
+		if let Err(_) = to.send(buffer[..length].to_vec()).await {
+			return;
+		}
+	}
+}
+
+/// Writes out whatever comes out of `from` to `to` (flushing as it goes, since it's meant to be on the wire by then), then shuts `to` down, once `from`'s done or the link's cut off.
+async fn write_task<W: AsyncWrite + Unpin>(mut from: Receiver<Vec<u8>>, mut to: W, cut: Arc<watch::Sender<bool>>) {
+	let mut cut_off: watch::Receiver<bool> = cut.subscribe();
+	loop {
+		tokio::select! {
+			chunk = from.recv() => match chunk {
+				Some(chunk) => if let Err(_) = async { to.write_all(&chunk).await?; return to.flush().await; }.await {
+					cut.send_replace(true);
+					break;
+				},
+				None => break,
+			},
+			_ = async { let _ = cut_off.wait_for(|cut| { *cut }).await; } => break,
+		}
+	}
+	let _ = to.shutdown().await;
+}
+
+
+/**
+	Passes messages from `from` to `to`, impaired according to `settings`. Returns once `from` is closed and everything's been delivered, or once the link's cut off.
+	`rng`: where this direction's randomness comes from.
+	`cut`: shared by both directions of the link; set when either one cuts it off.
+*/
+async fn impair_task(mut from: Receiver<Vec<u8>>, to: Sender<Vec<u8>>, settings: ImpairmentConfiguration, mut rng: StdRng, cut: Arc<watch::Sender<bool>>) {
+	let mut cut_off: watch::Receiver<bool> = cut.subscribe();
+
+	// Messages on their way, with when they're due (in order), and one being held back, if there is one:
+	let mut queue: VecDeque<(Instant, Vec<u8>)> = VecDeque::new();
+	let mut held: Option<Vec<u8>> = None;
+
+	// When the bandwidth cap lets the next message start going through:
+	let mut free_at: Instant = Instant::now();
+
+	let mut open: bool = true;
+	while open || !queue.is_empty() {
+		let due: Option<Instant> = queue.front().map(|(due, _)| { *due });
+		tokio::select! {
+			// Take the next message, as long as there's room for it (otherwise, the sender has to wait, like it would for a real network):
+			message = from.recv(), if open && queue.len() < to.max_capacity() => {
+				let message: Vec<u8> = if let Some(message) = message {
+					message
+				} else {
+					// Nothing more's coming, so let go of whatever's being held back:
+					open = false;
+					if let Some(message) = held.take() {
+						let due: Instant = queue.back().map(|(due, _)| { *due }).unwrap_or_else(Instant::now);
+						queue.push_back((due, message));
+					}
+					continue;
+				};
+
+				// Roll the dice:
+				if rng.gen_bool(settings.disconnect.clamp(0.0, 1.0)) {
+					cut.send_replace(true);
+					return;
+				} else if rng.gen_bool(settings.loss.clamp(0.0, 1.0)) {
+					continue;
+				}
+
+				// Work out when it arrives: after the messages ahead of it are through the bandwidth cap, plus the latency and jitter (never before the one ahead of it, though):
+				let now: Instant = Instant::now();
+				let sent: Instant = if settings.bandwidth == 0 {
+					now
+				} else {
+					free_at.max(now) + Duration::from_secs_f64(message.len() as f64 / settings.bandwidth as f64)
+				};
+				free_at = sent;
+				let jitter: u64 = rng.gen_range(0..=settings.jitter);
+				let mut due: Instant = sent + Duration::from_millis(settings.latency + jitter);
+				if let Some((last, _)) = queue.back() {
+					due = due.max(*last);
+				}
+
+				// Maybe hold it back until after the next one; otherwise send it on its way (along with anything that was held back for it):
+				if let None = held && rng.gen_bool(settings.reorder.clamp(0.0, 1.0)) {
+					held = Some(message);
+				} else {
+					queue.push_back((due, message));
+					if let Some(message) = held.take() {
+						queue.push_back((due, message));
+					}
+				}
+			},
+
+			// Deliver whatever's due:
+			_ = time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+				let (_, message) = queue.pop_front().unwrap();
+				if let Err(_) = to.send(message).await {
+					// Nobody's listening on this end anymore, so the link's over:
+					cut.send_replace(true);
+					return;
+				}
+			},
+
+			_ = async { let _ = cut_off.wait_for(|cut| { *cut }).await; } => return,
+		}
+	}
+}
+
+
+fn default_latency() -> u64 {
+	return 0;
+}
+fn default_jitter() -> u64 {
+	return 0;
+}
+fn default_bandwidth() -> u64 {
+	return 0;
+}
+fn default_loss() -> f64 {
+	return 0.0;
+}
+fn default_reorder() -> f64 {
+	return 0.0;
+}
+fn default_disconnect() -> f64 {
+	return 0.0;
+}
+fn default_seed() -> u64 {
+	return 0;
+}
+
+
+#[cfg(feature = "memory")]
+#[tokio::test]
+async fn test_impairment() {
+	use bytes::Bytes;
+	use super::{
+		Connection,
+		ConnectionConfiguration,
+		ConnectionEvent,
+		Endpoint,
+		MemoryConnection,
+	};
+
+	// Sends the numbers 0 to 99 through the simulator with `settings`, and returns what comes out the other end, and how long it took:
+	async fn run(settings: ImpairmentConfiguration) -> (Vec<u8>, Duration) {
+		let (tx, from) = mpsc::channel::<Vec<u8>>(256);
+		let (to, mut far_rx) = mpsc::channel::<Vec<u8>>(256);
+		let rng: StdRng = StdRng::seed_from_u64(settings.seed);
+		task::spawn(impair_task(from, to, settings, rng, Arc::new(watch::Sender::new(false))));
+
+		let started: Instant = Instant::now();
+		task::spawn(async move {
+			for i in 0..100_u8 {
+				if let Err(_) = tx.send(vec![i; 100]).await {
+					return;	// Cut off.
+				}
+			}
+		});
+		let mut received: Vec<u8> = Vec::new();
+		while let Some(message) = far_rx.recv().await {
+			received.push(message[0]);
+		}
+		return (received, started.elapsed());
+	}
+
+	// Nothing configured, nothing changes:
+	let (received, _) = run(ImpairmentConfiguration::default()).await;
+	assert_eq!(received, (0..100).collect::<Vec<u8>>());
+
+	// Loss and reordering are random, but the same seed gets the same results every time:
+	let lossy: ImpairmentConfiguration = ImpairmentConfiguration {
+		loss: 0.2,
+		reorder: 0.2,
+		seed: 42,
+		..ImpairmentConfiguration::default()
+	};
+	let (received, _) = run(lossy.clone()).await;
+	assert!(received.len() < 100);
+	assert!(!received.is_sorted());
+	assert_eq!(run(lossy).await.0, received);
+
+	// 10 KB through a 20 KB/s cap, with 100ms of latency on top, takes at least 600ms:
+	let (received, elapsed) = run(ImpairmentConfiguration {
+		latency: 100,
+		jitter: 20,
+		bandwidth: 20000,
+		..ImpairmentConfiguration::default()
+	}).await;
+	assert_eq!(received, (0..100).collect::<Vec<u8>>());
+	assert!(elapsed >= Duration::from_millis(600));
+
+	// And once it's cut off, nothing gets through:
+	let (received, _) = run(ImpairmentConfiguration {
+		disconnect: 1.0,
+		..ImpairmentConfiguration::default()
+	}).await;
+	assert!(received.is_empty());
+
+	// Simple echo servers, over a real transport:
+	async fn echo(name: &str) -> Endpoint {
+		let endpoint: Endpoint = Endpoint::Memory(String::from(name));
+		let mut server: MemoryConnection = MemoryConnection::new(ConnectionConfiguration {
+			endpoint: endpoint.clone(),
+			..ConnectionConfiguration::default()
+		});
+		server.listen().await.unwrap();
+		task::spawn(async move {
+			let (_, tx, mut rx) = server.accept().await.unwrap();
+			while let Some(data) = rx.recv().await {
+				tx.send(data).await.unwrap();
+			}
+		});
+		return endpoint;
+	}
+
+	// With the client's links impaired, the round trip pays the latency both ways:
+	let mut client: MemoryConnection = MemoryConnection::new(ConnectionConfiguration {
+		impairment: Some(ImpairmentConfiguration {
+			latency: 100,
+			..ImpairmentConfiguration::default()
+		}),
+		..ConnectionConfiguration::default()
+	});
+	let (ctx, mut crx) = client.connect(echo("test_impairment").await).await.unwrap();
+	let started: Instant = Instant::now();
+	ctx.send(Bytes::from_static(b"Slowly.")).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), b"Slowly.".to_vec());
+	assert!(started.elapsed() >= Duration::from_millis(200));
+
+	// And when they keep getting cut off, the session redials and resumes, without losing anything (the first link has to make it through its handshake, though):
+	let endpoint: Endpoint = echo("test_impairment_cut").await;
+	let mut client: MemoryConnection = MemoryConnection::new(ConnectionConfiguration {
+		impairment: Some(ImpairmentConfiguration {
+			disconnect: 0.02,
+			seed: 7,
+			..ImpairmentConfiguration::default()
+		}),
+		..ConnectionConfiguration::default()
+	});
+	let (ctx, mut crx) = loop {
+		if let Ok(session) = client.connect(endpoint.clone()).await {
+			break session;
+		}
+	};
+	let mut events: tokio::sync::broadcast::Receiver<ConnectionEvent> = client.events();
+	let mut redialed: Option<u16> = None;
+	for i in 0..1000_u16 {
+		ctx.send(Bytes::copy_from_slice(&i.to_le_bytes())).await.unwrap();
+		assert_eq!(crx.recv().await.unwrap(), i.to_le_bytes().to_vec());
+		while let Ok(event) = events.try_recv() {
+			if let ConnectionEvent::Opened { .. } = event {
+				redialed = redialed.or(Some(i));
+			}
+		}
+
+		// Keep going for a bit after the first redial, to make sure it came back:
+		if redialed.is_some_and(|redialed| { i > redialed + 20 }) {
+			break;
+		}
+	}
+	assert!(redialed.is_some(), "the link never got cut off");
+}
@@ -0,0 +1,190 @@
+/*!
+	Jump hosts (like OpenSSH's `ProxyJump`): reaching a server through one or more others.
+	The client starts a session with the first jump host, logs in (see `login`), and asks it to "direct
+	connect" to the next host, which makes the host open a raw TCP stream there (if it's one of the
+	hosts it's configured to lead to) and pass bytes back and forth. The client then
+	runs a fresh, end-to-end handshake through that stream, so jump hosts only ever see ciphertext.
+	Longer chains repeat this from inside the previous hop.
+*/
+
+// External stuff:
+use bytes::{Bytes, BytesMut};
+use tokio::{
+	io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, Error, ErrorKind},
+	net::TcpStream,
+	sync::mpsc::{
+		Receiver, Sender
+	},
+	task,
+};
+use std::iter;
+
+// Internal stuff:
+use super::{
+	error::{self, Events},
+	resolve,
+	resumption::Tickets,
+	roaming::{Link, Sessions},
+	Connection,
+	ConnectionConfiguration,
+	ConnectionError,
+	Endpoint,
+	TcpConnection,
+};
+
+
+/// How many bytes to read from a stream at a time.
+const SPLICE_BUFFER_SIZE: usize = 16384;
+
+
+/**
+	Connects to `target` through every host in `via`, in order, as a client.
+	`connection`: used to reach the first jump host (so that one can be over any transport).
+	`config`: crypto settings for the handshakes with the later hosts.
+	Returns (tx, rx) for a session with `target`, just like `Connection::connect` does.
+	Only the link to the first host shows up in `connection`'s events; the ones tunnelled through it are the chain's own business.
+*/
+pub async fn connect_through<C: Connection<Error = ConnectionError>>(connection: &mut C, config: &ConnectionConfiguration, via: &[Endpoint], target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), ConnectionError> {
+	let (first, rest) = via.split_first().ok_or(Error::new(ErrorKind::InvalidInput, "no hosts to jump through"))?;
+	let events: Events = error::new_events();
+	let sessions: Sessions = Sessions::new(config, &events);
+
+	// The first hop gets a normal (roaming) session, so the whole chain survives the client's network changing:
+	let mut hops = rest.iter().cloned().chain(iter::once(target));
+	let mut next: Endpoint = hops.next().unwrap();
+	let (mut tx, mut rx) = connection.direct_connect(first.clone(), next.clone()).await?;
+
+	// Every later hop gets a handshake through the previous one's stream:
+	for hop in hops {
+		let link: Link = handshake(config, &events, sessions.tickets(), tx, rx, &next).await?;
+		(tx, rx) = sessions.open(link, Some(hop.clone()), None).await?.into_main();
+		next = hop;
+	}
+
+	// Same for the target, except that this one's for the application:
+	let link: Link = handshake(config, &events, sessions.tickets(), tx, rx, &next).await?;
+	return Ok(sessions.open(link, None, None).await?.into_main());
+}
+
+/// Runs the handshake with `peer` over a stream that's carried by (tx, rx).
+async fn handshake(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, tx: Sender<Bytes>, rx: Receiver<Bytes>, peer: &Endpoint) -> Result<Link, ConnectionError> {
+	let (ours, theirs): (DuplexStream, DuplexStream) = io::duplex(SPLICE_BUFFER_SIZE);
+	splice(theirs, tx, rx);
+	let (stream_rx, stream_tx) = io::split(ours);
+	return TcpConnection::start(config, events, tickets, stream_tx, stream_rx, peer.clone()).await;
+}
+
+
+/// Server side: opens the raw TCP stream for a (logged-in) direct connect request, if `target` is one of the configured ones.
+pub(super) async fn dial(config: &ConnectionConfiguration, target: &Endpoint) -> Result<TcpStream, Error> {
+	if !config.direct_connect_targets.contains(target) {
+		return Err(Error::new(ErrorKind::PermissionDenied, format!("direct connections to {} aren't allowed here", target)));
+	}
+	return resolve::connect(target, None).await;
+}
+
+/**
+	Joins `stream` to a pair of channels: bytes read from it go to `tx`, and whatever comes out of `rx` gets written to it.
+	When either side closes, so does the other.
+*/
+pub(super) fn splice<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, tx: Sender<Bytes>, mut rx: Receiver<Bytes>) {
+	let (mut reader, mut writer) = io::split(stream);
+
+	// Channel to stream:
+	task::spawn(async move {
+		while let Some(bytes) = rx.recv().await {
+			if let Err(_) = writer.write_all(&bytes).await {
+				break;
+			}
+		}
+		let _ = writer.shutdown().await;
+	});
+
+	// Stream to channel (each read gets split off and handed over as it is, and the memory's reused once it's been dropped):
+	task::spawn(async move {
+		let mut buf: BytesMut = BytesMut::with_capacity(SPLICE_BUFFER_SIZE);
+		loop {
+			buf.reserve(SPLICE_BUFFER_SIZE);
+			match reader.read_buf(&mut buf).await {
+				Ok(0) | Err(_) => break,
+				Ok(_) => if let Err(_) = tx.send(buf.split().freeze()).await {
+					break;
+				},
+			}
+		}
+	});
+}
+
+
+#[tokio::test]
+async fn test_jump_connection() {
+	use std::net::Ipv6Addr;
+	use super::login;
+
+	let message: &[u8] = b"Through two bastions and out the other side.";
+	let bastions: [Endpoint; 2] = [
+		Endpoint::Inet((Ipv6Addr::LOCALHOST, 54351).into()),
+		Endpoint::Inet((Ipv6Addr::LOCALHOST, 54352).into()),
+	];
+	let target: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54353).into());
+	let keys: tempfile::TempDir = tempfile::tempdir().unwrap();
+	let (identity, authorized) = (keys.path().join("identity"), keys.path().join("authorized"));
+	login::keygen(&identity, &authorized);
+
+	// Two jump hosts, which never hand out sessions of their own, and only lead to the next host along:
+	for (bastion, next) in bastions.iter().zip([&bastions[1], &target]) {
+		let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+			endpoint: bastion.clone(),
+			direct_connect_targets: vec![next.clone()],
+			authorized_keys: Some(authorized.clone()),
+			..ConnectionConfiguration::default()
+		});
+		server.listen().await.unwrap();
+		task::spawn(async move { server.accept().await });
+	}
+
+	// The target, which doesn't allow direct connections itself:
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: target.clone(),
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		while let Ok((_, tx, mut rx)) = server.accept().await {
+			// Simple echo server:
+			task::spawn(async move {
+				while let Some(data) = rx.recv().await {
+					tx.send(data).await.unwrap();
+				}
+			});
+		}
+	});
+
+	// Go through both bastions:
+	let client_conf: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54350).into()),
+		identity: Some(identity),
+		..ConnectionConfiguration::default()
+	};
+	let mut client: TcpConnection = TcpConnection::new(client_conf.clone());
+	let (ctx, mut crx) = connect_through(&mut client, &client_conf, &bastions, target.clone()).await.unwrap();
+	ctx.send(Bytes::copy_from_slice(message)).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), message.to_vec());
+
+	// The bastions won't lead anywhere else, though:
+	let error: ConnectionError = client.direct_connect(bastions[1].clone(), bastions[0].clone()).await.unwrap_err();
+	assert!(matches!(error, ConnectionError::Io(e) if e.kind() == ErrorKind::ConnectionRefused));
+
+	// Or lead anyone who hasn't logged in:
+	let mut stranger: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54354).into()),
+		identity: None,
+		..client_conf
+	});
+	let error: ConnectionError = stranger.direct_connect(bastions[0].clone(), bastions[1].clone()).await.unwrap_err();
+	assert!(matches!(error, ConnectionError::Io(e) if e.kind() == ErrorKind::ConnectionRefused && e.to_string().contains("log in")));
+
+	// And the target should turn down being used as a jump host:
+	let error: ConnectionError = client.direct_connect(target, bastions[0].clone()).await.unwrap_err();
+	assert!(matches!(error, ConnectionError::Io(e) if e.kind() == ErrorKind::ConnectionRefused));
+}
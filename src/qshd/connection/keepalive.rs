@@ -0,0 +1,182 @@
+/*!
+	Keepalive heartbeats, so that a peer that's gone quiet (crashed, unplugged, stuck behind a dead NAT
+	mapping) gets noticed, instead of leaving `recv_task` waiting on it forever. Heartbeats are records
+	like any other, so they're encrypted; they're just tagged so that the receiving end drops them
+	instead of passing them on. Each end says how often it sends them in its hello (see `negotiation`),
+	and the other end goes by that when deciding it's dead; one that doesn't send them never is.
+*/
+
+// External stuff:
+use tokio::{
+	time::{self, Duration, Instant, Interval, MissedTickBehavior},
+};
+use std::future::{self, Future};
+
+// Internal stuff:
+use super::{
+	ConnectionConfiguration,
+	ConnectionError,
+};
+
+
+/// Tag for records that carry application data.
+pub(super) const RECORD_DATA: u8 = 0;
+
+/// Tag for heartbeat records.
+pub(super) const RECORD_HEARTBEAT: u8 = 1;
+
+/// How often to send heartbeats, and how many can go missing before the peer is declared dead.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Keepalive {
+	interval: Duration,
+	misses: u32,
+} impl Keepalive {
+
+	/// The configured keepalive settings, or `None` if keepalives are turned off.
+	pub(super) fn new(config: &ConnectionConfiguration) -> Option<Self> {
+		return Self::expect(config, config.keepalive_interval);
+	}
+
+	/// How often we send heartbeats, in miliseconds, for our hello (0 if we don't).
+	pub(super) fn advertised(config: &ConnectionConfiguration) -> u64 {
+		return Self::new(config).map_or(0, |keepalive| { keepalive.interval.as_millis() as u64 });
+	}
+
+	/// What to expect from a peer that says it sends heartbeats every `interval` miliseconds, or `None` if it doesn't (or we never give up on peers).
+	pub(super) fn expect(config: &ConnectionConfiguration, interval: u64) -> Option<Self> {
+		if interval == 0 || config.keepalive_misses == 0 {
+			return None;
+		}
+		return Some(Self {
+			interval: Duration::from_millis(interval),
+			misses: config.keepalive_misses,
+		});
+	}
+
+	/// Ticks whenever it's time to send a heartbeat (the first one's one interval from now).
+	pub(super) fn ticker(&self) -> Interval {
+		let mut ticker: Interval = time::interval_at(Instant::now() + self.interval, self.interval);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+		return ticker;
+	}
+
+	/**
+		Runs `future`, unless the peer goes quiet for too long first.
+		Any record counts as a sign of life, so this should wrap reading one record.
+	*/
+	pub(super) async fn watch<F: Future<Output = Result<T, ConnectionError>>, T>(keepalive: Option<Self>, future: F) -> Result<T, ConnectionError> {
+		if let Some(keepalive) = keepalive {
+			return time::timeout(keepalive.interval * keepalive.misses, future).await
+				.map_err(|_| { ConnectionError::PeerDead(keepalive.misses) })?;
+		} else {
+			return future.await;
+		}
+	}
+
+}
+
+/// Waits for the next tick of `ticker`, or forever if there isn't one.
+pub(super) async fn tick(ticker: &mut Option<Interval>) {
+	if let Some(ticker) = ticker {
+		ticker.tick().await;
+	} else {
+		future::pending::<()>().await;
+	}
+}
+
+
+#[tokio::test]
+async fn test_keepalive() {
+	use tokio::{
+		io::{self, AsyncReadExt, AsyncWriteExt},
+		sync::mpsc::error::TryRecvError,
+		task,
+	};
+	use std::sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	};
+	use super::{
+		error::{self, Events},
+		resumption::Tickets,
+		roaming::Link,
+		Buffer,
+		CloseReason,
+		ConnectionEvent,
+		Endpoint,
+		TcpConnection,
+	};
+
+	/**
+		Links at both ends of an in-memory pipe: `a` sends heartbeats every 50 miliseconds, and `b` every `b_interval` (0 for never).
+		Setting the returned flag cuts off everything from `b` without closing anything, like a dead NAT mapping would.
+	*/
+	async fn link_pair(b_interval: u64, events: &Events) -> (Link, Link, Arc<AtomicBool>) {
+		let lively: ConnectionConfiguration = ConnectionConfiguration {
+			keepalive_interval: 50,
+			keepalive_misses: 3,
+			..ConnectionConfiguration::default()
+		};
+		let other: ConnectionConfiguration = ConnectionConfiguration {
+			keepalive_interval: b_interval,
+			..lively.clone()
+		};
+		let (a, a_far) = io::duplex(65536);
+		let (b, b_far) = io::duplex(65536);
+		let (mut a_far_rx, mut a_far_tx) = io::split(a_far);
+		let (mut b_far_rx, mut b_far_tx) = io::split(b_far);
+		let cut: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+		let relay_cut: Arc<AtomicBool> = cut.clone();
+		task::spawn(async move { let _ = io::copy(&mut a_far_rx, &mut b_far_tx).await; });
+		task::spawn(async move {
+			let mut chunk: [u8; 4096] = [0_u8; 4096];
+			while let Ok(length @ 1..) = b_far_rx.read(&mut chunk).await {
+				if !relay_cut.load(Ordering::Relaxed) && let Err(_) = a_far_tx.write_all(&chunk[..length]).await {
+					return;
+				}
+			}
+		});
+		let (a_rx, a_tx) = io::split(a);
+		let (b_rx, b_tx) = io::split(b);
+		let unwatched: Events = error::new_events();
+		let tickets: Tickets = Tickets::new(&lively);
+		let (a, b) = tokio::join!(
+			TcpConnection::start(&lively, events, &tickets, a_tx, a_rx, Endpoint::Command(String::from("b"))),
+			TcpConnection::start(&other, &unwatched, &tickets, b_tx, b_rx, Endpoint::Command(String::from("a"))),
+		);
+		return (a.unwrap(), b.unwrap(), cut);
+	}
+
+	// With heartbeats going both ways, an idle link stays up, and none of them show up as data:
+	let events: Events = error::new_events();
+	let (a, mut b, _cut) = link_pair(50, &events).await;
+	time::sleep(Duration::from_millis(400)).await;
+	assert!(matches!(b.rx.try_recv(), Err(TryRecvError::Empty)));
+	a.tx.send(Buffer::from(&b"still here"[..])).await.unwrap();
+	assert_eq!(&b.rx.recv().await.unwrap()[..], b"still here");
+
+	// A peer that says it doesn't send heartbeats (or sends them less often) isn't held to ours:
+	for b_interval in [0, 300] {
+		let (mut a, b, _cut) = link_pair(b_interval, &events).await;
+		time::sleep(Duration::from_millis(400)).await;
+		assert!(matches!(a.rx.try_recv(), Err(TryRecvError::Empty)));
+		b.tx.send(Buffer::from(&b"still here"[..])).await.unwrap();
+		assert_eq!(&a.rx.recv().await.unwrap()[..], b"still here");
+	}
+
+	// But one that goes quiet when it said it wouldn't gets declared dead, which closes the link:
+	let events: Events = error::new_events();
+	let mut closed = events.subscribe();
+	let (mut a, _b, cut) = link_pair(50, &events).await;
+	cut.store(true, Ordering::Relaxed);
+	assert!(time::timeout(Duration::from_secs(1), a.rx.recv()).await.unwrap().is_none());
+
+	// And says so once the link's let go of:
+	drop(a);
+	loop {
+		if let ConnectionEvent::Closed { reason, .. } = closed.recv().await.unwrap() {
+			assert!(matches!(reason, CloseReason::Failed(e) if matches!(*e, ConnectionError::PeerDead(3))));
+			break;
+		}
+	}
+}
@@ -0,0 +1,142 @@
+/*!
+	Logging in: a client proving which key it holds, over a link that's already encrypted.
+	The client signs the link's ID (which only the two ends of that one link know, so the signature is
+	no good on any other link) with its ML-DSA-87 key, as `qsh-keygen new` writes it. The server checks
+	the signature, and that the key is one of the public keys in its authorized keys directory (as
+	`qsh-keygen add` writes them). Anything a server only does for clients it knows (connecting through
+	to other hosts, handing out resumption tickets) needs a session that's logged in.
+*/
+
+// External stuff:
+use bincode::{
+	Decode,
+	Encode,
+};
+use fips204::{
+	ml_dsa_87::{PrivateKey, PublicKey, PK_LEN, SIG_LEN, SK_LEN},
+	traits::{SerDes, Signer, Verifier},
+};
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::SeedableRng;
+use sha2::{Digest, Sha256};
+use tokio::{
+	fs::{self, ReadDir},
+	io::{Error, ErrorKind},
+};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+// Internal stuff:
+use super::{
+	roaming::SessionId,
+	ConnectionConfiguration,
+};
+
+
+/// Goes along with what gets signed, so that login signatures can't be passed off as anything else.
+const LOGIN_CONTEXT: &[u8] = b"qsh login";
+
+/// Identifies the key a session logged in with (it's the hash of the public key).
+pub(super) type KeyId = [u8; 32];
+
+
+/// What a client sends to log in.
+#[derive(Encode, Decode)]
+pub(super) struct Login {
+
+	/// The client's public key.
+	key: Vec<u8>,
+
+	/// The link's ID, signed with the matching private key.
+	signature: Vec<u8>,
+
+} impl Login {
+
+	/**
+		Logs in to the link with ID `id`.
+		`identity`: the file holding the key to log in with (private key, then public key).
+	*/
+	pub(super) async fn sign(identity: &Path, id: &SessionId) -> Result<Self, Error> {
+		let contents: Zeroizing<Vec<u8>> = Zeroizing::new(fs::read(identity).await?);
+		if contents.len() != SK_LEN + PK_LEN {
+			return Err(Error::new(ErrorKind::InvalidData, format!("{} isn't an ML-DSA-87 key pair", identity.display())));
+		}
+		let (private, public) = contents.split_at(SK_LEN);
+		let private: PrivateKey = PrivateKey::try_from_bytes(private.try_into().unwrap()).map_err(|e| { Error::new(ErrorKind::InvalidData, e) })?;
+		let signature: [u8; SIG_LEN] = private.try_sign_with_rng(&mut ChaCha20Rng::from_entropy(), id, LOGIN_CONTEXT).map_err(|e| { Error::other(e) })?;
+		return Ok(Self {
+			key: public.to_vec(),
+			signature: signature.to_vec(),
+		});
+	}
+
+	/**
+		Checks that this is a good login to the link with ID `id`, from one of the keys in `authorized`.
+		`authorized`: a directory of public keys, one per file.
+		Returns the ID of the key it logged in with.
+	*/
+	pub(super) async fn verify(&self, authorized: &Path, id: &SessionId) -> Result<KeyId, Error> {
+		let denied = |reason: &str| { Error::new(ErrorKind::PermissionDenied, reason.to_string()) };
+
+		// It has to be one of ours:
+		let mut entries: ReadDir = fs::read_dir(authorized).await?;
+		let mut known: bool = false;
+		while let Some(entry) = entries.next_entry().await? {
+			if fs::read(entry.path()).await? == self.key {
+				known = true;
+				break;
+			}
+		}
+		if !known {
+			return Err(denied("that key isn't authorized here"));
+		}
+
+		// And it has to have signed this link:
+		let key: PublicKey = PublicKey::try_from_bytes(self.key.as_slice().try_into().map_err(|_| { denied("garbled key") })?).map_err(|_| { denied("garbled key") })?;
+		let signature: [u8; SIG_LEN] = self.signature.as_slice().try_into().map_err(|_| { denied("garbled signature") })?;
+		if !key.verify(id, &signature, LOGIN_CONTEXT) {
+			return Err(denied("bad login signature"));
+		}
+		return Ok(Sha256::digest(&self.key).into());
+	}
+
+}
+
+/// Server side: checks `login` to the link with ID `id` against the authorized keys in `config`. Returns the ID of the key it logged in with.
+pub(super) async fn authenticate(config: &ConnectionConfiguration, login: Option<Login>, id: &SessionId) -> Result<KeyId, Error> {
+	let login: Login = login.ok_or(Error::new(ErrorKind::PermissionDenied, "log in first"))?;
+	let authorized: &Path = config.authorized_keys.as_deref().ok_or(Error::new(ErrorKind::PermissionDenied, "nobody can log in here"))?;
+	return login.verify(authorized, id).await;
+}
+
+
+/// Writes a fresh key pair for tests: the pair to `identity` (like `qsh-keygen new`), and the public key into the `authorized` directory (like `qsh-keygen add`).
+#[cfg(test)]
+pub(super) fn keygen(identity: &Path, authorized: &Path) {
+	use fips204::ml_dsa_87;
+
+	let (public, private) = ml_dsa_87::try_keygen_with_rng(&mut ChaCha20Rng::from_entropy()).unwrap();
+	std::fs::write(identity, [&private.into_bytes()[..], &public.clone().into_bytes()[..]].concat()).unwrap();
+	std::fs::create_dir_all(authorized).unwrap();
+	std::fs::write(authorized.join(identity.file_name().unwrap()), public.into_bytes()).unwrap();
+}
+
+
+#[tokio::test]
+async fn test_login() {
+	let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+	let (identity, stranger, authorized) = (directory.path().join("identity"), directory.path().join("stranger"), directory.path().join("authorized"));
+	keygen(&identity, &authorized);
+	keygen(&stranger, &directory.path().join("elsewhere"));
+	let (id, other): (SessionId, SessionId) = ([1_u8; 32], [2_u8; 32]);
+
+	// A login from an authorized key checks out, and always comes out as the same key:
+	let login: Login = Login::sign(&identity, &id).await.unwrap();
+	let key: KeyId = login.verify(&authorized, &id).await.unwrap();
+	assert_eq!(Login::sign(&identity, &id).await.unwrap().verify(&authorized, &id).await.unwrap(), key);
+
+	// But it's no good on another link, or from a key that isn't authorized:
+	assert_eq!(login.verify(&authorized, &other).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+	let login: Login = Login::sign(&stranger, &id).await.unwrap();
+	assert_eq!(login.verify(&authorized, &id).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+}
@@ -3,30 +3,102 @@
 	Unbuffered.
 */
 // External stuff:
+use bytes::Bytes;
 use tokio::{
 	sync::{
+		broadcast,
 		mpsc::{
 			Sender,
 			Receiver,
 		},
 	},
 };
-use std::net::Ipv6Addr;
+use std::{
+	collections::HashMap,
+	net::{Ipv6Addr, SocketAddr},
+	path::PathBuf,
+};
 use serde::Deserialize;
 
+pub use qsh_common_types::{
+	disconnect::{Disconnect, DisconnectReason},
+	endpoint::Endpoint,
+};
+
 // Stuff from other modules:
 use super::{
+	channel,
 	crypto,
 	kex,
+	session,
 };
 
 // Module declarations go here:
+mod activation;
+mod admission;
+mod buffer;
+mod error;
+mod framing;
+mod impair;
+mod roaming;
+mod jump;
+mod keepalive;
+mod login;
+mod mux;
+mod negotiation;
+mod proxy;
+mod rekey;
+mod resolve;
+mod resumption;
+mod shaping;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+pub use buffer::{Buffer, BufferPool};
+pub use error::{CloseReason, ConnectionError, ConnectionEvent};
+pub use framing::FramingError;
+pub use impair::ImpairmentConfiguration;
+pub use jump::connect_through;
+pub use mux::{Multiplexer, Stream, StreamId, MAIN_STREAM};
+pub use negotiation::Algorithms;
+pub use proxy::{Credentials, ProxyConfiguration, ProxyProtocol};
+
 #[cfg(feature = "tcp")]
 mod qsh_tcp;
 
 #[cfg(feature = "tcp")]
 pub use qsh_tcp::TcpConnection;
 
+#[cfg(feature = "quic")]
+mod qsh_quic;
+
+#[cfg(feature = "quic")]
+pub use qsh_quic::QuicConnection;
+
+#[cfg(feature = "unix")]
+mod qsh_unix;
+
+#[cfg(feature = "unix")]
+pub use qsh_unix::UnixConnection;
+
+#[cfg(feature = "stdio")]
+mod qsh_stdio;
+
+#[cfg(feature = "stdio")]
+pub use qsh_stdio::StdioConnection;
+
+#[cfg(feature = "memory")]
+mod qsh_memory;
+
+#[cfg(feature = "memory")]
+pub use qsh_memory::MemoryConnection;
+
+#[cfg(feature = "websocket")]
+mod qsh_websocket;
+
+#[cfg(feature = "websocket")]
+pub use qsh_websocket::WebSocketConnection;
+
 
 pub trait Connection: Sized {
 	type Error;
@@ -38,69 +110,480 @@ pub trait Connection: Sized {
 	/// Make a new instance.
 	fn new(config: ConnectionConfiguration) -> Self;
 
-	/// Bind to an address and port with configuration `config`, as a server.
+	/// Bind to the configured endpoint, as a server.
 	async fn listen(&mut self) -> Result<(), Self::Error>;
 
-	/// Accept an incoming connection (server only). Returns (remote host, (tx, rx)).
-	async fn accept(&mut self) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Self::Error>;
+	/// Subscribe to the events of every link this makes or accepts (from now on).
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent>;
 
-	/// Connect to a server, as a client. Returns (tx, rx) on success.
-	async fn connect(&mut self, addr: Ipv6Addr, port: u16) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Self::Error>;
+	/// Accept an incoming connection (server only). Returns (remote host, streams), where streams is the session's `Multiplexer`.
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error>;
+
+	/// Connect to a server at `endpoint`, as a client. Returns the session's `Multiplexer` on success.
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error>;
+
+	/// Accept an incoming connection (server only). Returns (remote host, tx, rx) for the session's main stream.
+	async fn accept(&mut self) -> Result<(Endpoint, Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		let (peer, streams): (Endpoint, Multiplexer) = self.accept_streams().await?;
+		let (tx, rx) = streams.into_main();
+		return Ok((peer, tx, rx));
+	}
+
+	/// Connect to a server at `endpoint`, as a client. Returns (tx, rx) for the session's main stream on success.
+	async fn connect(&mut self, endpoint: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.connect_streams(endpoint).await?.into_main());
+	}
+
+	/// Ask the server at `via` to open a raw TCP stream to `target`, as a client. Returns (tx, rx) for that stream's bytes on success.
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error>;
+
+	/// Hang up every session this has open (and any it starts from now on), telling the other ends why. Returns once they've all drained and closed.
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error>;
 
 }
 
 
 /// Different types of connections available.
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub enum Implementation {
 	Tcp,
+	#[cfg(feature = "quic")]
+	Quic,
+	#[cfg(feature = "unix")]
+	Unix,
+	#[cfg(feature = "stdio")]
+	Stdio,
+	#[cfg(feature = "memory")]
+	Memory,
+	#[cfg(feature = "websocket")]
+	WebSocket,
 } impl Implementation {
 	pub fn generate(&self, config: ConnectionConfiguration) -> impl Connection {
 		return match self {
-			Self::Tcp => TcpConnection::new(config),
+			Self::Tcp => AnyConnection::Tcp(TcpConnection::new(config)),
+			#[cfg(feature = "quic")]
+			Self::Quic => AnyConnection::Quic(QuicConnection::new(config)),
+			#[cfg(feature = "unix")]
+			Self::Unix => AnyConnection::Unix(UnixConnection::new(config)),
+			#[cfg(feature = "stdio")]
+			Self::Stdio => AnyConnection::Stdio(StdioConnection::new(config)),
+			#[cfg(feature = "memory")]
+			Self::Memory => AnyConnection::Memory(MemoryConnection::new(config)),
+			#[cfg(feature = "websocket")]
+			Self::WebSocket => AnyConnection::WebSocket(WebSocketConnection::new(config)),
+		};
+	}
+}
+
+/// Whichever `Connection` the configuration asked for; this lets `Implementation::generate` pick one at runtime.
+pub enum AnyConnection {
+	Tcp(TcpConnection),
+	#[cfg(feature = "quic")]
+	Quic(QuicConnection),
+	#[cfg(feature = "unix")]
+	Unix(UnixConnection),
+	#[cfg(feature = "stdio")]
+	Stdio(StdioConnection),
+	#[cfg(feature = "memory")]
+	Memory(MemoryConnection),
+	#[cfg(feature = "websocket")]
+	WebSocket(WebSocketConnection),
+}
+
+impl Connection for AnyConnection {
+	type Error = ConnectionError;
+
+
+	fn new(config: ConnectionConfiguration) -> Self {
+		return match config.connection {
+			Implementation::Tcp => Self::Tcp(TcpConnection::new(config)),
+			#[cfg(feature = "quic")]
+			Implementation::Quic => Self::Quic(QuicConnection::new(config)),
+			#[cfg(feature = "unix")]
+			Implementation::Unix => Self::Unix(UnixConnection::new(config)),
+			#[cfg(feature = "stdio")]
+			Implementation::Stdio => Self::Stdio(StdioConnection::new(config)),
+			#[cfg(feature = "memory")]
+			Implementation::Memory => Self::Memory(MemoryConnection::new(config)),
+			#[cfg(feature = "websocket")]
+			Implementation::WebSocket => Self::WebSocket(WebSocketConnection::new(config)),
+		};
+	}
+
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return match self {
+			Self::Tcp(connection) => connection.events(),
+			#[cfg(feature = "quic")]
+			Self::Quic(connection) => connection.events(),
+			#[cfg(feature = "unix")]
+			Self::Unix(connection) => connection.events(),
+			#[cfg(feature = "stdio")]
+			Self::Stdio(connection) => connection.events(),
+			#[cfg(feature = "memory")]
+			Self::Memory(connection) => connection.events(),
+			#[cfg(feature = "websocket")]
+			Self::WebSocket(connection) => connection.events(),
 		};
 	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		return match self {
+			Self::Tcp(connection) => connection.listen().await,
+			#[cfg(feature = "quic")]
+			Self::Quic(connection) => connection.listen().await,
+			#[cfg(feature = "unix")]
+			Self::Unix(connection) => connection.listen().await,
+			#[cfg(feature = "stdio")]
+			Self::Stdio(connection) => connection.listen().await,
+			#[cfg(feature = "memory")]
+			Self::Memory(connection) => connection.listen().await,
+			#[cfg(feature = "websocket")]
+			Self::WebSocket(connection) => connection.listen().await,
+		};
+	}
+
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
+		return match self {
+			Self::Tcp(connection) => connection.accept_streams().await,
+			#[cfg(feature = "quic")]
+			Self::Quic(connection) => connection.accept_streams().await,
+			#[cfg(feature = "unix")]
+			Self::Unix(connection) => connection.accept_streams().await,
+			#[cfg(feature = "stdio")]
+			Self::Stdio(connection) => connection.accept_streams().await,
+			#[cfg(feature = "memory")]
+			Self::Memory(connection) => connection.accept_streams().await,
+			#[cfg(feature = "websocket")]
+			Self::WebSocket(connection) => connection.accept_streams().await,
+		};
+	}
+
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return match self {
+			Self::Tcp(connection) => connection.connect_streams(endpoint).await,
+			#[cfg(feature = "quic")]
+			Self::Quic(connection) => connection.connect_streams(endpoint).await,
+			#[cfg(feature = "unix")]
+			Self::Unix(connection) => connection.connect_streams(endpoint).await,
+			#[cfg(feature = "stdio")]
+			Self::Stdio(connection) => connection.connect_streams(endpoint).await,
+			#[cfg(feature = "memory")]
+			Self::Memory(connection) => connection.connect_streams(endpoint).await,
+			#[cfg(feature = "websocket")]
+			Self::WebSocket(connection) => connection.connect_streams(endpoint).await,
+		};
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return match self {
+			Self::Tcp(connection) => connection.direct_connect(via, target).await,
+			#[cfg(feature = "quic")]
+			Self::Quic(connection) => connection.direct_connect(via, target).await,
+			#[cfg(feature = "unix")]
+			Self::Unix(connection) => connection.direct_connect(via, target).await,
+			#[cfg(feature = "stdio")]
+			Self::Stdio(connection) => connection.direct_connect(via, target).await,
+			#[cfg(feature = "memory")]
+			Self::Memory(connection) => connection.direct_connect(via, target).await,
+			#[cfg(feature = "websocket")]
+			Self::WebSocket(connection) => connection.direct_connect(via, target).await,
+		};
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		return match self {
+			Self::Tcp(connection) => connection.disconnect(disconnect).await,
+			#[cfg(feature = "quic")]
+			Self::Quic(connection) => connection.disconnect(disconnect).await,
+			#[cfg(feature = "unix")]
+			Self::Unix(connection) => connection.disconnect(disconnect).await,
+			#[cfg(feature = "stdio")]
+			Self::Stdio(connection) => connection.disconnect(disconnect).await,
+			#[cfg(feature = "memory")]
+			Self::Memory(connection) => connection.disconnect(disconnect).await,
+			#[cfg(feature = "websocket")]
+			Self::WebSocket(connection) => connection.disconnect(disconnect).await,
+		};
+	}
+
 }
 
 /// Settings for the connection layer.
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct ConnectionConfiguration {
 
-	/// What address to listen on.
-	#[serde(default = "default_addr")]
-	pub addr: Ipv6Addr,
-
-	/// What port to listen on.
-	#[serde(default = "default_port")]
-	pub port: u16,
+	/// Where to listen (servers), or where to bind before connecting (clients).
+	#[serde(default = "default_endpoint")]
+	pub endpoint: Endpoint,
 
 	/// Allowed types of connection.
 	#[serde(default = "default_allowed_connection")]
 	connection: Implementation,
 
-	/// Allowed encryption types.
+	/// Allowed encryption types, most preferred first.
 	#[serde(default = "default_allowed_crypto")]
-	crypto: crypto::Implementation,
+	crypto: Vec<crypto::Implementation>,
 
-	/// Allowed key exchange types.
+	/// Allowed key exchange types, most preferred first.
 	#[serde(default = "default_allowed_kex")]
-	kex: kex::Implementation,
+	kex: Vec<kex::Implementation>,
+
+	/// Allowed signature schemes, most preferred first.
+	#[serde(default = "default_allowed_signatures")]
+	signatures: Vec<session::Implementation>,
+
+	/// Allowed compression types, most preferred first.
+	#[serde(default = "default_allowed_compression")]
+	compression: Vec<channel::Implementation>,
 
+	/// Hosts that logged-in clients may reach through this server, as a jump host (empty means it isn't one).
+	#[serde(default = "default_direct_connect_targets")]
+	pub direct_connect_targets: Vec<Endpoint>,
+
+	/// The key pair to log in with, as `qsh-keygen new` writes it (clients only; without one, sessions don't log in).
+	#[serde(default = "default_identity")]
+	pub identity: Option<PathBuf>,
+
+	/// A directory of public keys that may log in, as `qsh-keygen add` writes them (servers only; without one, nobody can).
+	#[serde(default = "default_authorized_keys")]
+	pub authorized_keys: Option<PathBuf>,
+
+	/// Proxy to dial out through (clients only).
+	#[serde(default = "default_proxy")]
+	pub proxy: Option<ProxyConfiguration>,
+
+	/// How many miliseconds to wait between heartbeats (0 turns them off).
+	#[serde(default = "default_keepalive_interval")]
+	pub keepalive_interval: u64,
+
+	/// How many heartbeats in a row can go missing before the peer is declared dead.
+	#[serde(default = "default_keepalive_misses")]
+	pub keepalive_misses: u32,
+
+	/// How many handshakes can be in progress at once (servers only); connections past that get hung up on.
+	#[serde(default = "default_max_handshakes")]
+	pub max_handshakes: usize,
+
+	/// How many connections each source address can have open at once (servers only; 0 means no limit).
+	#[serde(default = "default_max_connections_per_address")]
+	pub max_connections_per_address: usize,
+
+	/// How many miliseconds a new connection gets to finish its handshake (servers only).
+	#[serde(default = "default_handshake_timeout")]
+	pub handshake_timeout: u64,
+
+	/// How many bytes a second each connection may carry each way (0 means no limit).
+	#[serde(default = "default_rate_limit")]
+	pub rate_limit: u64,
+
+	/// How many bytes can go in one burst, when there's a rate limit.
+	#[serde(default = "default_rate_limit_burst")]
+	pub rate_limit_burst: u64,
+
+	/// Rate limits for particular peers, by endpoint or just host (servers see clients by address; clients see servers as they dialed them).
+	#[serde(default = "default_rate_limits")]
+	pub rate_limits: HashMap<String, u64>,
+
+	/// Whether the sessions this opens are interactive, and so shouldn't count against the rate limit (clients only).
+	#[serde(default = "default_interactive")]
+	pub interactive: bool,
+
+	/// How many bytes a second of interactive records each connection may carry each way, when there's a rate limit (they get this instead of counting against it; 0 means they don't).
+	#[serde(default = "default_interactive_rate_limit")]
+	pub interactive_rate_limit: u64,
+
+	/// Largest record to send or receive, in bytes (the smaller of the two ends' goes); application messages have to fit in one, encrypted.
+	#[serde(default = "default_max_record_size")]
+	pub max_record_size: usize,
+
+	/// Path to ask for when upgrading to WebSocket (clients only; it's up to the reverse proxy to route it to qshd).
+	#[serde(default = "default_websocket_path")]
+	pub websocket_path: String,
+
+	/// Whether to talk TLS to the server (or rather, the reverse proxy in front of it) before upgrading to WebSocket (clients only).
+	#[serde(default = "default_websocket_tls")]
+	pub websocket_tls: bool,
+
+	/// Whether TCP servers also take WebSocket upgrades on their port (with this off, it takes a `WebSocket` server on a port of its own).
+	#[serde(default = "default_accept_websocket")]
+	pub accept_websocket: bool,
+
+	/// Makes the connection behave like a bad network, for testing (see `impair`); leave this out for a real one.
+	#[serde(default = "default_impairment")]
+	pub impairment: Option<ImpairmentConfiguration>,
+
+	/// How many miliseconds a session that's hanging up waits for the other end to send what it has left, and hang up too.
+	#[serde(default = "default_drain_timeout")]
+	pub drain_timeout: u64,
+
+	/// How many bytes to send under one key before rekeying (0 means no limit).
+	#[serde(default = "default_rekey_bytes")]
+	pub rekey_bytes: u64,
+
+	/// How many records to send under one key before rekeying (0 means no limit).
+	#[serde(default = "default_rekey_records")]
+	pub rekey_records: u64,
+
+	/// How many minutes to use one key for before rekeying (0 means no limit).
+	#[serde(default = "default_rekey_minutes")]
+	pub rekey_minutes: u64,
+
+	/// How many miliseconds a resumption ticket is good for (servers only; 0 turns them off).
+	#[serde(default = "default_ticket_lifetime")]
+	pub ticket_lifetime: u64,
+
+	/// How many miliseconds to seal resumption tickets with one key before moving on to a new one (servers only; 0 means never).
+	#[serde(default = "default_ticket_key_rotation")]
+	pub ticket_key_rotation: u64,
+
+}
+
+impl Default for ConnectionConfiguration {
+	fn default() -> Self {
+		return Self {
+			endpoint: default_endpoint(),
+			connection: default_allowed_connection(),
+			crypto: default_allowed_crypto(),
+			kex: default_allowed_kex(),
+			signatures: default_allowed_signatures(),
+			compression: default_allowed_compression(),
+			direct_connect_targets: default_direct_connect_targets(),
+			identity: default_identity(),
+			authorized_keys: default_authorized_keys(),
+			proxy: default_proxy(),
+			keepalive_interval: default_keepalive_interval(),
+			keepalive_misses: default_keepalive_misses(),
+			max_handshakes: default_max_handshakes(),
+			max_connections_per_address: default_max_connections_per_address(),
+			handshake_timeout: default_handshake_timeout(),
+			rate_limit: default_rate_limit(),
+			rate_limit_burst: default_rate_limit_burst(),
+			rate_limits: default_rate_limits(),
+			interactive: default_interactive(),
+			interactive_rate_limit: default_interactive_rate_limit(),
+			max_record_size: default_max_record_size(),
+			websocket_path: default_websocket_path(),
+			websocket_tls: default_websocket_tls(),
+			accept_websocket: default_accept_websocket(),
+			impairment: default_impairment(),
+			drain_timeout: default_drain_timeout(),
+			rekey_bytes: default_rekey_bytes(),
+			rekey_records: default_rekey_records(),
+			rekey_minutes: default_rekey_minutes(),
+			ticket_lifetime: default_ticket_lifetime(),
+			ticket_key_rotation: default_ticket_key_rotation(),
+		};
+	}
+}
+
+
+/// The address and port of `endpoint`, for transports that can't do anything else.
+fn inet(endpoint: &Endpoint) -> Result<SocketAddr, tokio::io::Error> {
+	return endpoint.socket_addr().ok_or(tokio::io::Error::new(tokio::io::ErrorKind::InvalidInput, format!("{} is not an address and port", endpoint)));
 }
 
 
 fn default_allowed_connection() -> Implementation {
 	return Implementation::Tcp;
 }
-fn default_allowed_crypto() -> crypto::Implementation {
-	return crypto::Implementation::AesGcm;
+fn default_allowed_crypto() -> Vec<crypto::Implementation> {
+	return vec![crypto::Implementation::AesGcm];
+}
+pub(crate) fn default_allowed_kex() -> Vec<kex::Implementation> {
+	return vec![
+		#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+		kex::Implementation::X25519MlKem768,
+		#[cfg(feature = "ml-kem")]
+		kex::Implementation::MlKem768,
+		#[cfg(feature = "ml-kem")]
+		kex::Implementation::MlKem1024,
+		#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+		kex::Implementation::X25519Kyberlib,
+		#[cfg(feature = "kyberlib")]
+		kex::Implementation::Kyberlib,
+	];
+}
+fn default_allowed_signatures() -> Vec<session::Implementation> {
+	return vec![session::Implementation::Fips204];
+}
+fn default_allowed_compression() -> Vec<channel::Implementation> {
+	return vec![channel::Implementation::Lz4Flex];
+}
+fn default_endpoint() -> Endpoint {
+	return Endpoint::Inet((Ipv6Addr::LOCALHOST, 54321).into());
+}
+fn default_direct_connect_targets() -> Vec<Endpoint> {
+	return Vec::new();
+}
+fn default_identity() -> Option<PathBuf> {
+	return None;
+}
+fn default_authorized_keys() -> Option<PathBuf> {
+	return None;
+}
+fn default_proxy() -> Option<ProxyConfiguration> {
+	return None;
+}
+fn default_keepalive_interval() -> u64 {
+	return 15000;
+}
+fn default_keepalive_misses() -> u32 {
+	return 3;
+}
+fn default_max_handshakes() -> usize {
+	return 64;
+}
+fn default_max_connections_per_address() -> usize {
+	return 16;
+}
+fn default_handshake_timeout() -> u64 {
+	return 10000;
+}
+fn default_rate_limit() -> u64 {
+	return 0;
+}
+fn default_rate_limit_burst() -> u64 {
+	return 65536;
+}
+fn default_rate_limits() -> HashMap<String, u64> {
+	return HashMap::new();
+}
+fn default_interactive() -> bool {
+	return false;
+}
+fn default_interactive_rate_limit() -> u64 {
+	return 65536;
+}
+fn default_max_record_size() -> usize {
+	return 1 << 20;
+}
+fn default_websocket_path() -> String {
+	return String::from("/");
+}
+fn default_websocket_tls() -> bool {
+	return false;
+}
+fn default_accept_websocket() -> bool {
+	return false;
+}
+fn default_impairment() -> Option<ImpairmentConfiguration> {
+	return None;
+}
+fn default_drain_timeout() -> u64 {
+	return 5000;
+}
+fn default_rekey_bytes() -> u64 {
+	return 1 << 30;
+}
+fn default_rekey_records() -> u64 {
+	return 1 << 24;
 }
-fn default_allowed_kex() -> kex::Implementation {
-	return kex::Implementation::Kyberlib;
+fn default_rekey_minutes() -> u64 {
+	return 60;
 }
-fn default_addr() -> Ipv6Addr {
-	return Ipv6Addr::LOCALHOST;
+fn default_ticket_lifetime() -> u64 {
+	return 86400000;
 }
-fn default_port() -> u16 {
-	return 54321;
+fn default_ticket_key_rotation() -> u64 {
+	return 3600000;
 }
\ No newline at end of file
@@ -0,0 +1,543 @@
+/*!
+	Many logical streams over one connection.
+	Every record gets tagged with a stream ID, and streams are opened and closed with control records,
+	so stdin, stdout, stderr (and whatever else) can share one authenticated session instead of each
+	needing its own handshake. Clients number their streams with odd IDs and servers with even ones,
+	so both ends can open streams without stepping on each other; a peer that opens a stream with one of
+	our IDs (or one that's already open) gets cut off.
+	Each stream has a window: neither end sends more than so many messages on it before the other end's
+	application has taken some off it (and said so, with credit for that many more). So a stream that
+	isn't being read stalls whoever's sending on it, without holding up any other stream, or piling up
+	in memory; a peer that goes over a window anyway gets cut off. A stream whose application stops
+	reading it altogether gets reset, which makes the `Sender` at the other end fail.
+
+	Every session runs over one of these (see `roaming`), so it lives on through the session's link
+	changing. Its main stream is open at both ends from the start, and it's what `Connection::accept`
+	and `connect` hand out; the session lasts as long as that one does, so closing it (dropping either
+	end) hangs the whole session up. `Connection::accept_streams` and `connect_streams` hand out the
+	multiplexer itself, for more streams than that. When the session hangs up (for whatever reason),
+	every stream still sends what's queued on it first.
+*/
+
+// External stuff:
+use bincode::{
+	self,
+	config::{self, Configuration},
+	Decode,
+	Encode,
+};
+use bytes::Bytes;
+use tokio::{
+	io::{Error, ErrorKind},
+	sync::{
+		mpsc::{
+			self, error::TrySendError, Receiver, Sender, WeakSender,
+		},
+		oneshot, watch, Semaphore,
+	},
+	task,
+};
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+};
+
+
+/// Encoding used for everything in this module.
+const FRAME_BINCODE_CONFIG: Configuration = config::standard();
+
+/// Length of each stream's channel buffers.
+const CHANNEL_BUFFER_SIZE: usize = 256;
+
+/// How many messages can be on their way over a stream, before the other end's application has taken any of them.
+const WINDOW: u32 = 256;
+
+/// How many messages the application takes off a stream before the other end gets credit for them (so that it doesn't take a frame per message).
+const CREDIT_BATCH: u32 = WINDOW / 4;
+
+/// Identifies a stream within one connection.
+pub type StreamId = u32;
+
+/// The session's main stream, which is open from the start (nobody else gets this ID).
+pub const MAIN_STREAM: StreamId = 0;
+
+/// One stream: its ID, and (tx, rx) for it.
+pub type Stream = (StreamId, Sender<Bytes>, Receiver<Bytes>);
+
+/// Which end of the connection we are; decides which stream IDs are ours to hand out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+	Client,
+	Server,
+}
+
+/// Everything sent over a multiplexed connection.
+#[derive(Encode, Decode)]
+enum Frame {
+
+	/// A new stream, opened by the sender.
+	Open { id: StreamId },
+
+	/// Data on a stream (which follows the frame).
+	Data { id: StreamId },
+
+	/// The sender is done sending on a stream (this one doesn't make it over the connection for the main stream, which hangs up the whole session instead).
+	Close { id: StreamId },
+
+	/// The sender isn't taking anything more on a stream (its application stopped reading it), so stop sending on it.
+	Reset { id: StreamId },
+
+	/// The sender's application has taken `count` more messages off a stream, so that many more can be sent on it.
+	Credit { id: StreamId, count: u32 },
+
+}
+
+/// Every stream that's open (in either direction), shared by the multiplexer's tasks.
+#[derive(Default)]
+struct Table {
+
+	// Where data received on each stream goes, until the other end's done sending on it:
+	inbound: HashMap<StreamId, Sender<Bytes>>,
+
+	// How many more messages we can send on each stream, and a way to stop sending on it (dropping it), until we're done:
+	outbound: HashMap<StreamId, (Arc<Semaphore>, oneshot::Sender<()>)>,
+
+}
+
+type Streams = Arc<Mutex<Table>>;
+
+
+/// Splits one (tx, rx) pair into any number of streams.
+pub struct Multiplexer {
+
+	// Frames to send (with the data that follows them), from every stream:
+	frames: Sender<(Frame, Bytes)>,
+
+	// Streams the other end opened:
+	incoming: Receiver<Stream>,
+
+	// Every stream that's open:
+	streams: Streams,
+
+	// The next ID to hand out (wider than an ID, so that running out doesn't wrap around to ones that are in use):
+	next_id: AtomicU64,
+
+	// (tx, rx) for the main stream, until it's taken:
+	main: Option<(Sender<Bytes>, Receiver<Bytes>)>,
+
+	// Whether the session's hanging up:
+	closing: watch::Receiver<bool>,
+
+	// Every stream's forwarding task holds one of these until it's sent what was queued on it:
+	flushing: WeakSender<()>,
+
+} impl Multiplexer {
+
+	/**
+		Starts multiplexing over a session's (tx, rx), with the main stream open.
+		`side`: which end this is (the two ends have to pick different sides).
+		`finish`: goes off (with a `()`) when the session starts hanging up; from then on, every stream sends what's queued on it, and then `tx` gets dropped.
+	*/
+	pub(super) fn new(tx: Sender<Bytes>, rx: Receiver<Bytes>, side: Side, finish: oneshot::Receiver<()>) -> Self {
+		let (frames_tx, frames_rx) = mpsc::channel::<(Frame, Bytes)>(CHANNEL_BUFFER_SIZE);
+		let (incoming_tx, incoming_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+		let (closing_tx, closing_rx) = watch::channel(false);
+		let (flushing_tx, flushed) = mpsc::channel::<()>(1);
+		let flushing: WeakSender<()> = flushing_tx.downgrade();
+		let streams: Streams = Arc::new(Mutex::new(Table::default()));
+		let main: (Sender<Bytes>, Receiver<Bytes>) = Self::attach(MAIN_STREAM, &streams, &frames_tx, &closing_rx, &flushing);
+		drop(flushing_tx);
+		task::spawn(Self::send_task(tx, frames_rx, finish, closing_tx, flushed));
+		task::spawn(Self::recv_task(rx, side, streams.clone(), frames_tx.clone(), incoming_tx, closing_rx.clone(), flushing.clone()));
+		return Self {
+			frames: frames_tx,
+			incoming: incoming_rx,
+			streams: streams,
+			next_id: AtomicU64::new(match side { Side::Client => 1, Side::Server => 2 }),
+			main: Some(main),
+			closing: closing_rx,
+			flushing: flushing,
+		};
+	}
+
+	/// Takes (tx, rx) for the session's main stream; `None` if that's been done already.
+	pub fn take_main(&mut self) -> Option<(Sender<Bytes>, Receiver<Bytes>)> {
+		return self.main.take();
+	}
+
+	/// (tx, rx) for the main stream of a session that's only just started, and nothing else (streams the other end opens get turned away).
+	pub(super) fn into_main(mut self) -> (Sender<Bytes>, Receiver<Bytes>) {
+		return self.take_main().expect("nothing's had a new session's main stream yet");
+	}
+
+	/// Opens a new stream. Returns its ID, and (tx, rx) for it, or an error if the connection's out of IDs.
+	pub async fn open(&self) -> Result<Stream, Error> {
+		let id: StreamId = StreamId::try_from(self.next_id.fetch_add(2, Ordering::Relaxed)).map_err(|_| {
+			Error::new(ErrorKind::QuotaExceeded, "no stream IDs left on this connection")
+		})?;
+
+		// Set it up before announcing it (so that nothing the other end sends on it gets missed), and announce it before anything gets sent on it:
+		let (outbound_tx, inbound_rx) = Self::attach(id, &self.streams, &self.frames, &self.closing, &self.flushing);
+		self.frames.send((Frame::Open { id: id }, Bytes::new())).await.map_err(|_| { Error::from(ErrorKind::BrokenPipe) })?;
+		return Ok((id, outbound_tx, inbound_rx));
+	}
+
+	/// Waits for the other end to open a stream. Returns its ID, and (tx, rx) for it, or `None` once the connection is gone.
+	pub async fn accept(&mut self) -> Option<Stream> {
+		return self.incoming.recv().await;
+	}
+
+	/// Sets up our end of stream `id`, with a full window each way, and spawns its tasks. Returns the application's (tx, rx) for it.
+	fn attach(id: StreamId, streams: &Streams, frames: &Sender<(Frame, Bytes)>, closing: &watch::Receiver<bool>, flushing: &WeakSender<()>) -> (Sender<Bytes>, Receiver<Bytes>) {
+		let (queue_tx, queue_rx) = mpsc::channel::<Bytes>(WINDOW as usize);
+		let (inbound_tx, inbound_rx) = mpsc::channel::<Bytes>(CHANNEL_BUFFER_SIZE);
+		let (outbound_tx, outbound_rx) = mpsc::channel::<Bytes>(CHANNEL_BUFFER_SIZE);
+		let credit: Arc<Semaphore> = Arc::new(Semaphore::new(WINDOW as usize));
+		let (reset_tx, reset_rx) = oneshot::channel::<()>();
+		{
+			let mut streams = streams.lock().unwrap();
+			streams.inbound.insert(id, queue_tx);
+			streams.outbound.insert(id, (credit.clone(), reset_tx));
+		}
+		task::spawn(Self::deliver_task(id, queue_rx, inbound_tx, streams.clone(), frames.clone()));
+		task::spawn(Self::forward_task(id, outbound_rx, credit, reset_rx, (closing.clone(), flushing.upgrade()), streams.clone(), frames.clone()));
+		return (outbound_tx, inbound_rx);
+	}
+
+	/// Passes what arrives on stream `id` on to the application, giving the other end credit as the application takes it.
+	async fn deliver_task(id: StreamId, mut queue: Receiver<Bytes>, inbound: Sender<Bytes>, streams: Streams, frames: Sender<(Frame, Bytes)>) {
+		let mut taken: u32 = 0;
+		while let Some(payload) = queue.recv().await {
+			if let Err(_) = inbound.send(payload).await {
+				// The application stopped reading, so anything more would only go to waste; tell the other end to stop sending (or hang up, if it's the main stream):
+				streams.lock().unwrap().inbound.remove(&id);
+				let _ = frames.send((if id == MAIN_STREAM { Frame::Close { id: id } } else { Frame::Reset { id: id } }, Bytes::new())).await;
+				return;
+			}
+			taken += 1;
+			if taken == CREDIT_BATCH {
+				if let Err(_) = frames.send((Frame::Credit { id: id, count: taken }, Bytes::new())).await {
+					return;
+				}
+				taken = 0;
+			}
+		}
+	}
+
+	/**
+		Tags everything the application sends on stream `id` with `id`, as far as the other end's credit goes; closes the stream once the application's done
+		(or the session's `closing`, and what's already queued has gone), or stops if the other end resets it. Holds on to `_flushing` until then.
+	*/
+	async fn forward_task(id: StreamId, mut outbound: Receiver<Bytes>, credit: Arc<Semaphore>, mut reset: oneshot::Receiver<()>, (mut closing, _flushing): (watch::Receiver<bool>, Option<Sender<()>>), streams: Streams, frames: Sender<(Frame, Bytes)>) {
+		loop {
+			// (Returning drops `outbound`, so the application's `Sender` fails from then on.)
+			let payload: Bytes = tokio::select! {
+				biased;
+				payload = outbound.recv() => match payload {
+					Some(payload) => payload,
+					None => break,
+				},
+				_ = &mut reset => return,
+				// Once nothing more can be sent, `outbound` hands over what's left and then runs dry (so this doesn't come up again):
+				_ = closing.wait_for(|closing| { *closing }) => {
+					outbound.close();
+					continue;
+				},
+			};
+
+			// Wait until the other end has room for it:
+			tokio::select! {
+				permit = credit.acquire() => match permit {
+					Ok(permit) => permit.forget(),
+					Err(_) => return,
+				},
+				_ = &mut reset => return,
+			}
+			if let Err(_) = frames.send((Frame::Data { id: id }, payload)).await {
+				return;
+			}
+		}
+
+		// The application's done sending on this stream:
+		streams.lock().unwrap().outbound.remove(&id);
+		let _ = frames.send((Frame::Close { id: id }, Bytes::new())).await;
+	}
+
+	/**
+		Encodes frames from every stream onto the connection, each followed by its data, until the main stream closes.
+		Once `finish` goes off, this tells every stream that the session's `closing` instead, and carries on until they've all sent what's queued on them (`flushed`).
+	*/
+	async fn send_task(tx: Sender<Bytes>, mut frames: Receiver<(Frame, Bytes)>, mut finish: oneshot::Receiver<()>, closing: watch::Sender<bool>, mut flushed: Receiver<()>) {
+		let (mut heard, mut finishing): (bool, bool) = (false, false);
+		loop {
+			let (frame, payload): (Frame, Bytes) = tokio::select! {
+				biased;
+				frame = frames.recv() => match frame {
+					Some(frame) => frame,
+					None => return,
+				},
+				result = &mut finish, if !heard => {
+					// (If it's dropped without a word, the session's gone altogether, and there's nobody left to send anything to.)
+					heard = true;
+					if let Ok(()) = result {
+						finishing = true;
+						closing.send_replace(true);
+					}
+					continue;
+				},
+				_ = flushed.recv(), if finishing => return,
+			};
+
+			// Dropping `tx` hangs up the session, once it's sent everything before this (unless it's already hanging up, and the other streams aren't done yet):
+			if let Frame::Close { id: MAIN_STREAM } = frame {
+				if finishing {
+					continue;
+				}
+				return;
+			}
+			let mut bytes: Vec<u8> = match bincode::encode_to_vec(frame, FRAME_BINCODE_CONFIG) {
+				Ok(bytes) => bytes,
+				Err(e) => {
+					eprintln!("failed to encode a stream frame: {}", e);
+					return;
+				},
+			};
+			bytes.extend_from_slice(&payload);
+			if let Err(_) = tx.send(Bytes::from(bytes)).await {
+				return;
+			}
+		}
+	}
+
+	/**
+		Decodes frames from the connection, and hands them out to their streams (`side` is which end we are).
+		This never waits on a stream (or on sending anything), so that one stream can't hold up the rest.
+	*/
+	async fn recv_task(mut rx: Receiver<Bytes>, side: Side, streams: Streams, frames: Sender<(Frame, Bytes)>, incoming: Sender<Stream>, closing: watch::Receiver<bool>, flushing: WeakSender<()>) {
+		let ours: StreamId = match side { Side::Client => 1, Side::Server => 0 };	// What our IDs come to, mod 2.
+		while let Some(bytes) = rx.recv().await {
+			let (frame, length): (Frame, usize) = match bincode::decode_from_slice(&bytes, FRAME_BINCODE_CONFIG) {
+				Ok(decoded) => decoded,
+				Err(e) => {
+					eprintln!("garbled stream frame, closing the connection: {}", e);
+					break;
+				},
+			};
+			match frame {
+				Frame::Open { id } => {
+					// It has to be one of their IDs, and not one that's in use:
+					let in_use: bool = {
+						let streams = streams.lock().unwrap();
+						streams.inbound.contains_key(&id) || streams.outbound.contains_key(&id)
+					};
+					if id % 2 == ours || id == MAIN_STREAM || in_use {
+						eprintln!("the other end opened stream {}, which isn't theirs to open, closing the connection", id);
+						break;
+					}
+					// If nobody's accepting streams (or not fast enough), the one we just made gets dropped, which closes it again:
+					let (outbound_tx, inbound_rx) = Self::attach(id, &streams, &frames, &closing, &flushing);
+					let _ = incoming.try_send((id, outbound_tx, inbound_rx));
+				},
+				Frame::Data { id } => {
+					// The other end can't send more than the window before it gets credit, so there's room for it (the data's handed over as it is, not copied):
+					let mut streams = streams.lock().unwrap();
+					match streams.inbound.get(&id).map(|stream| { stream.try_send(bytes.slice(length..)) }) {
+						Some(Err(TrySendError::Full(_))) => {
+							eprintln!("the other end went over stream {}'s window, closing the connection", id);
+							break;
+						},
+						Some(Err(TrySendError::Closed(_))) => {
+							// The application stopped reading this stream (and the other end's been told):
+							streams.inbound.remove(&id);
+						},
+						Some(Ok(())) | None => (),	// No stream means we've reset it, and this was already on its way.
+					}
+				},
+				Frame::Close { id } => {
+					streams.lock().unwrap().inbound.remove(&id);
+				},
+				Frame::Reset { id } => {
+					streams.lock().unwrap().outbound.remove(&id);
+				},
+				Frame::Credit { id, count } => {
+					// It can't give back more than it's been sent:
+					let streams = streams.lock().unwrap();
+					if let Some((credit, _)) = streams.outbound.get(&id) {
+						if credit.available_permits() + count as usize > WINDOW as usize {
+							eprintln!("the other end gave more credit than stream {}'s window, closing the connection", id);
+							break;
+						}
+						credit.add_permits(count as usize);
+					}
+				},
+			}
+		}
+
+		// The connection's gone, so every stream is done (each one's application still gets whatever had already arrived):
+		let mut streams = streams.lock().unwrap();
+		streams.inbound.clear();
+		streams.outbound.clear();
+	}
+
+}
+
+
+#[tokio::test]
+async fn test_multiplexer() {
+	use std::net::Ipv6Addr;
+	use tokio::time::{self, Duration};
+	use super::{
+		Connection,
+		ConnectionConfiguration,
+		Endpoint,
+		TcpConnection,
+	};
+
+	// One session, over TCP:
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54381).into()),
+		..ConnectionConfiguration::default()
+	});
+	let mut client: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54380).into()),
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	let server = task::spawn(async move { server.accept_streams().await.unwrap() });
+	let mut client: Multiplexer = client.connect_streams(Endpoint::Inet((Ipv6Addr::LOCALHOST, 54381).into())).await.unwrap();
+	let (_, mut server): (Endpoint, Multiplexer) = server.await.unwrap();
+
+	// The server echoes on every stream (the main one included), and opens one of its own to say hello:
+	let echo = task::spawn(async move {
+		let (main_tx, mut main_rx) = server.take_main().unwrap();
+		task::spawn(async move {
+			while let Some(data) = main_rx.recv().await {
+				main_tx.send(data).await.unwrap();
+			}
+		});
+		let (_, hello_tx, _hello_rx) = server.open().await.unwrap();
+		hello_tx.send(Bytes::from_static(b"hello")).await.unwrap();
+		while let Some((_, tx, mut rx)) = server.accept().await {
+			task::spawn(async move {
+				while let Some(data) = rx.recv().await {
+					tx.send(data).await.unwrap();
+				}
+			});
+		}
+	});
+
+	// Three streams (stdin, stdout, stderr, say), interleaved:
+	let mut streams: Vec<Stream> = Vec::new();
+	for _ in 0..3 {
+		streams.push(client.open().await.unwrap());
+	}
+	assert_eq!(streams.iter().map(|(id, _, _)| { *id }).collect::<Vec<StreamId>>(), vec![1, 3, 5]);
+	for (id, tx, _) in streams.iter().rev() {
+		tx.send(Bytes::copy_from_slice(&id.to_le_bytes())).await.unwrap();
+	}
+	for (id, _, rx) in &mut streams {
+		assert_eq!(rx.recv().await.unwrap(), id.to_le_bytes().to_vec());
+	}
+
+	// And the server's stream:
+	let (id, _, mut hello_rx) = client.accept().await.unwrap();
+	assert_eq!(id, 2);
+	assert_eq!(hello_rx.recv().await.unwrap(), b"hello".to_vec());
+
+	// The main stream is there alongside them, and closing it hangs up the whole session:
+	let (main_tx, mut main_rx) = client.take_main().unwrap();
+	assert!(client.take_main().is_none());
+	main_tx.send(Bytes::from_static(b"main")).await.unwrap();
+	assert_eq!(main_rx.recv().await.unwrap(), b"main".to_vec());
+	drop(main_tx);
+	time::timeout(Duration::from_secs(10), echo).await.unwrap().unwrap();
+	assert!(streams[0].2.recv().await.is_none());
+
+	// Makes a multiplexer for `side` that we can send frames to directly (and that sends its own frames nowhere):
+	let raw = |side: Side| -> (Multiplexer, Sender<Bytes>, Receiver<Bytes>) {
+		let (frames_tx, rx) = mpsc::channel::<Bytes>(CHANNEL_BUFFER_SIZE * 2);
+		let (tx, sent) = mpsc::channel::<Bytes>(CHANNEL_BUFFER_SIZE);
+		return (Multiplexer::new(tx, rx, side, oneshot::channel().1), frames_tx, sent);
+	};
+	let frame = |frame: Frame, payload: &[u8]| -> Bytes { Bytes::from([bincode::encode_to_vec(frame, FRAME_BINCODE_CONFIG).unwrap(), payload.to_vec()].concat()) };
+
+	// A peer that opens one of our streams gets cut off, rather than taking it over:
+	let (mut mux, frames, _sent) = raw(Side::Client);
+	let (id, _, mut ours) = mux.open().await.unwrap();
+	frames.send(frame(Frame::Open { id: id }, &[])).await.unwrap();
+	assert!(ours.recv().await.is_none());
+	assert!(mux.accept().await.is_none());
+
+	// Same for opening one of theirs twice:
+	let (mut mux, frames, _sent) = raw(Side::Server);
+	frames.send(frame(Frame::Open { id: 1 }, &[])).await.unwrap();
+	frames.send(frame(Frame::Open { id: 1 }, &[])).await.unwrap();
+	let (_, _, mut first) = mux.accept().await.unwrap();
+	assert!(first.recv().await.is_none());
+	assert!(mux.accept().await.is_none());
+
+	// Reads the frame at the front of what a multiplexer sent:
+	let decode = |bytes: Bytes| -> Frame { bincode::decode_from_slice(&bytes, FRAME_BINCODE_CONFIG).unwrap().0 };
+
+	// Sending stops once the other end's window is full, and picks back up as it gives credit:
+	let (mux, frames, mut sent) = raw(Side::Client);
+	let (id, tx, _rx) = mux.open().await.unwrap();
+	for _ in 0..=WINDOW {
+		tx.send(Bytes::from_static(b"data")).await.unwrap();
+	}
+	assert!(matches!(decode(sent.recv().await.unwrap()), Frame::Open { .. }));
+	for _ in 0..WINDOW {
+		assert!(matches!(decode(sent.recv().await.unwrap()), Frame::Data { .. }));
+	}
+	assert!(time::timeout(Duration::from_millis(100), sent.recv()).await.is_err());
+	frames.send(frame(Frame::Credit { id: id, count: 1 }, &[])).await.unwrap();
+	assert!(matches!(decode(sent.recv().await.unwrap()), Frame::Data { .. }));
+
+	// But a peer that gives more credit than it could owe gets cut off:
+	frames.send(frame(Frame::Credit { id: id, count: WINDOW + 1 }, &[])).await.unwrap();
+	time::timeout(Duration::from_secs(5), tx.closed()).await.unwrap();
+
+	// A stream that isn't being read doesn't hold up the others, and gives credit back as it's read:
+	let (mut mux, frames, mut sent) = raw(Side::Server);
+	frames.send(frame(Frame::Open { id: 1 }, &[])).await.unwrap();
+	frames.send(frame(Frame::Open { id: 3 }, &[])).await.unwrap();
+	let ((_, _slow_tx, mut slow), (_, _fast_tx, mut fast)) = (mux.accept().await.unwrap(), mux.accept().await.unwrap());
+	for _ in 0..WINDOW {
+		frames.send(frame(Frame::Data { id: 1 }, &[0])).await.unwrap();
+	}
+	frames.send(frame(Frame::Data { id: 3 }, b"fast")).await.unwrap();
+	assert_eq!(fast.recv().await.unwrap(), b"fast".to_vec());
+	for _ in 0..WINDOW {
+		slow.recv().await.unwrap();
+	}
+	for _ in 0..WINDOW / CREDIT_BATCH {
+		assert!(matches!(decode(sent.recv().await.unwrap()), Frame::Credit { id: 1, count: CREDIT_BATCH }));
+	}
+
+	// Once the application stops reading a stream, it gets reset:
+	drop(slow);
+	frames.send(frame(Frame::Data { id: 1 }, &[0])).await.unwrap();
+	assert!(matches!(decode(sent.recv().await.unwrap()), Frame::Reset { id: 1 }));
+
+	// And a peer that goes over a stream's window (however much of it's made it to the application) gets cut off:
+	for _ in 0..WINDOW as usize + CHANNEL_BUFFER_SIZE + 2 {
+		let _ = frames.send(frame(Frame::Data { id: 3 }, &[0])).await;
+	}
+	assert!(mux.accept().await.is_none());
+
+	// When the other end resets a stream, sending on it fails:
+	let (mux, frames, _sent) = raw(Side::Client);
+	let (id, tx, _rx) = mux.open().await.unwrap();
+	frames.send(frame(Frame::Reset { id: id }, &[])).await.unwrap();
+	time::timeout(Duration::from_secs(5), tx.closed()).await.unwrap();
+
+	// Once the IDs run out, opening a stream fails, instead of wrapping around to IDs that might still be in use:
+	let (mux, _frames, _sent) = raw(Side::Client);
+	mux.next_id.store(StreamId::MAX as u64 - 2, Ordering::Relaxed);
+	assert_eq!(mux.open().await.unwrap().0, StreamId::MAX - 2);
+	assert_eq!(mux.open().await.unwrap().0, StreamId::MAX);
+	assert_eq!(mux.open().await.err().unwrap().kind(), ErrorKind::QuotaExceeded);
+}
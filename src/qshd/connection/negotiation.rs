@@ -0,0 +1,215 @@
+/*!
+	Algorithm negotiation: the first thing on a new link, after agreeing on record sizes.
+	Each end says which key exchanges, ciphers, signature schemes and compressions it supports, most
+	preferred first (by name, so a peer with algorithms we've never heard of is fine). Both ends then
+	pick the same thing from what they have in common, without either one having to be in charge: the
+	algorithm with the best combined rank in both lists wins, and ties go to the name that sorts first.
+	The two hellos go into the key schedule afterwards, so that nobody in the middle can quietly take
+	options out of them. They also carry a fresh nonce from each end, and a resumption ticket, from
+	clients that have one (see `resumption`).
+*/
+
+// External stuff:
+use bincode::{
+	self,
+	config::{self, Configuration},
+	Decode,
+	Encode,
+};
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Error, ErrorKind};
+use std::fmt::{self, Display};
+
+// Internal stuff:
+use super::{
+	framing::Codec,
+	keepalive::Keepalive,
+	Buffer,
+	ConnectionConfiguration,
+	ConnectionError,
+};
+use crate::{
+	channel,
+	crypto::{self, Transcript, SECRET_LEN},
+	kex,
+	session,
+};
+
+
+/// Encoding used for hellos.
+const HELLO_BINCODE_CONFIG: Configuration = config::standard();
+
+
+/// What each end says it supports, most preferred first.
+#[derive(Encode, Decode)]
+struct Hello {
+	kex: Vec<String>,
+	crypto: Vec<String>,
+	signatures: Vec<String>,
+	compression: Vec<String>,
+
+	// Something fresh, so that the keys are too, even if they come from a ticket:
+	nonce: [u8; SECRET_LEN],
+
+	// The ticket we're presenting (empty if we aren't):
+	ticket: Vec<u8>,
+
+	// How often we send heartbeats, in miliseconds (0 if we don't):
+	keepalive: u64,
+}
+
+/// What the other end's hello said about itself, besides which algorithms it supports.
+pub(super) struct Theirs {
+
+	/// The resumption ticket it presented (empty if it didn't).
+	pub(super) ticket: Vec<u8>,
+
+	/// How often it sends heartbeats, in miliseconds (0 if it doesn't).
+	pub(super) keepalive: u64,
+
+}
+
+
+/// The algorithms both ends of a link agreed on.
+#[derive(Clone)]
+pub struct Algorithms {
+	pub kex: kex::Implementation,
+	pub crypto: crypto::Implementation,
+	pub signature: session::Implementation,
+	pub compression: channel::Implementation,
+} impl Algorithms {
+
+	/**
+		Tells the other end what we support (from `config`), hears what it does, and picks.
+		`ticket`: a resumption ticket to present (empty for none).
+		Returns what was picked, a hash of both hellos for the key schedule, and what else the other end said.
+	*/
+	pub(super) async fn negotiate<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(config: &ConnectionConfiguration, codec: &Codec, ticket: &[u8], tx: &mut W, rx: &mut R) -> Result<(Self, [u8; SECRET_LEN], Theirs), ConnectionError> {
+		let mut ours: Hello = Hello {
+			kex: config.kex.iter().map(|kex| { kex.name().to_string() }).collect(),
+			crypto: config.crypto.iter().map(|crypto| { crypto.name().to_string() }).collect(),
+			signatures: config.signatures.iter().map(|signature| { signature.name().to_string() }).collect(),
+			compression: config.compression.iter().map(|compression| { compression.name().to_string() }).collect(),
+			nonce: [0_u8; SECRET_LEN],
+			ticket: ticket.to_vec(),
+			keepalive: Keepalive::advertised(config),
+		};
+		ChaCha20Rng::from_entropy().fill_bytes(&mut ours.nonce);
+		let mut hello: Buffer = codec.pool().take();
+		bincode::encode_into_std_write(&ours, &mut hello, HELLO_BINCODE_CONFIG).map_err(|e| { Error::other(e) })?;
+		let sent: Vec<u8> = hello.to_vec();
+		codec.write(tx, &mut hello).await?;
+		tx.flush().await?;
+
+		let received: Buffer = codec.read(rx).await?.ok_or(Error::from(ErrorKind::UnexpectedEof))?;
+		let (theirs, _): (Hello, usize) = bincode::decode_from_slice(&received, HELLO_BINCODE_CONFIG).map_err(|e| { ConnectionError::Handshake(format!("garbled hello: {}", e)) })?;
+
+		let algorithms: Self = Self {
+			kex: pick("key exchange", &config.kex, &theirs.kex, kex::Implementation::name)?,
+			crypto: pick("cipher", &config.crypto, &theirs.crypto, crypto::Implementation::name)?,
+			signature: pick("signature scheme", &config.signatures, &theirs.signatures, session::Implementation::name)?,
+			compression: pick("compression", &config.compression, &theirs.compression, channel::Implementation::name)?,
+		};
+
+		// Both ends need the same hash, so the hellos go in in an order they agree on:
+		let (low, high) = if sent[..] < received[..] { (&sent[..], &received[..]) } else { (&received[..], &sent[..]) };
+		let mut transcript: Transcript = Transcript::new();
+		transcript.append(b"hello", low);
+		transcript.append(b"hello", high);
+		return Ok((algorithms, transcript.hash(), Theirs { ticket: theirs.ticket, keepalive: theirs.keepalive }));
+	}
+
+} impl Display for Algorithms {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return write!(f, "{}, {}, {}, {}", self.kex.name(), self.crypto.name(), self.signature.name(), self.compression.name());
+	}
+
+}
+
+/**
+	Picks an algorithm from both ends' lists: the one with the best combined rank, with ties going to the name that sorts first.
+	`kind`: what sort of algorithm this is, for the error.
+	`name`: what each of ours is called.
+*/
+fn pick<T: Clone>(kind: &'static str, ours: &[T], theirs: &[String], name: fn(&T) -> &'static str) -> Result<T, ConnectionError> {
+	return ours.iter().enumerate()
+		.filter_map(|(our_rank, algorithm)| {
+			let their_rank: usize = theirs.iter().position(|theirs| { theirs == name(algorithm) })?;
+			return Some((our_rank + their_rank, name(algorithm), algorithm));
+		})
+		.min_by_key(|(rank, name, _)| { (*rank, *name) })
+		.map(|(_, _, algorithm)| { algorithm.clone() })
+		.ok_or_else(|| { ConnectionError::NoCommonAlgorithm {
+			kind: kind,
+			ours: ours.iter().map(|algorithm| { name(algorithm).to_string() }).collect(),
+			theirs: theirs.to_vec(),
+		} });
+}
+
+
+#[tokio::test]
+async fn test_negotiation() {
+	use tokio::io;
+	use super::default_allowed_kex;
+
+	// Both ends pick the same thing, whichever end's asking:
+	let names = |names: &[&str]| -> Vec<String> { names.iter().map(|name| { name.to_string() }).collect() };
+	let ours: Vec<&'static str> = vec!["a", "b", "c"];
+	let pick_name = |ours: &[&'static str], theirs: &[&str]| { pick("test", ours, &names(theirs), |name| { *name }) };
+	assert_eq!(pick_name(&ours, &["c", "b", "a"]).unwrap(), "a");	// Every option ties, so it comes down to the names.
+	assert_eq!(pick_name(&["c", "b", "a"], &ours).unwrap(), "a");
+	assert_eq!(pick_name(&ours, &["x", "c", "b"]).unwrap(), "b");	// Ranks 1 + 2, vs 2 + 1 for "c".
+	assert_eq!(pick_name(&ours, &["c"]).unwrap(), "c");
+	assert!(matches!(pick_name(&ours, &["x", "y"]), Err(ConnectionError::NoCommonAlgorithm { kind: "test", .. })));
+
+	// Runs negotiation between two configurations over an in-memory pipe:
+	async fn negotiate(a: &ConnectionConfiguration, b: &ConnectionConfiguration) -> (Result<(Algorithms, [u8; SECRET_LEN], Theirs), ConnectionError>, Result<(Algorithms, [u8; SECRET_LEN], Theirs), ConnectionError>) {
+		let (a_stream, b_stream) = io::duplex(65536);
+		let (mut a_rx, mut a_tx) = io::split(a_stream);
+		let (mut b_rx, mut b_tx) = io::split(b_stream);
+		let codec: Codec = Codec::new(a.max_record_size);
+		return tokio::join!(
+			Algorithms::negotiate(a, &codec, b"ticket", &mut a_tx, &mut a_rx),
+			Algorithms::negotiate(b, &codec, &[], &mut b_tx, &mut b_rx),
+		);
+	}
+
+	// Ends that have something in common agree on it (whichever key exchanges are built in, and whatever order they're in), and on the hash;
+	// each also hears the other's ticket and heartbeat interval:
+	let a: ConnectionConfiguration = ConnectionConfiguration {
+		keepalive_interval: 250,
+		..ConnectionConfiguration::default()
+	};
+	let mut b_kex: Vec<kex::Implementation> = default_allowed_kex();
+	b_kex.reverse();
+	let b: ConnectionConfiguration = ConnectionConfiguration {
+		kex: b_kex,
+		keepalive_interval: 0,
+		..ConnectionConfiguration::default()
+	};
+	let (a_result, b_result) = negotiate(&a, &b).await;
+	let ((a_algorithms, a_hash, a_theirs), (b_algorithms, b_hash, b_theirs)) = (a_result.unwrap(), b_result.unwrap());
+	assert_eq!(a_algorithms.to_string(), b_algorithms.to_string());
+	assert_eq!(a_hash, b_hash);
+	assert_eq!((&a_theirs.ticket[..], &b_theirs.ticket[..]), (&b""[..], &b"ticket"[..]));
+	assert_eq!((a_theirs.keepalive, b_theirs.keepalive), (0, 250));
+
+	// Ones that don't both say so, clearly:
+	#[cfg(feature = "ml-kem")]
+	{
+		let a: ConnectionConfiguration = ConnectionConfiguration {
+			kex: vec![kex::Implementation::MlKem768],
+			..ConnectionConfiguration::default()
+		};
+		let c: ConnectionConfiguration = ConnectionConfiguration {
+			kex: vec![kex::Implementation::MlKem1024],
+			..ConnectionConfiguration::default()
+		};
+		let (a_result, c_result) = negotiate(&a, &c).await;
+		assert!(matches!(a_result, Err(ConnectionError::NoCommonAlgorithm { kind: "key exchange", .. })));
+		let error: String = c_result.err().unwrap().to_string();
+		assert!(error.contains("ml-kem-1024") && error.contains("ml-kem-768"), "{}", error);
+	}
+}
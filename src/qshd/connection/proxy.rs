@@ -0,0 +1,316 @@
+/*!
+	Outbound proxies (SOCKS5 and HTTP `CONNECT`), for networks that don't let clients connect directly.
+	The client connects to the proxy, asks it for a tunnel to the server, and only then starts the
+	handshake; the proxy just passes the (encrypted) bytes along.
+*/
+
+// External stuff:
+use base64::{
+	engine::general_purpose::STANDARD as BASE64,
+	Engine,
+};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind};
+use std::net::IpAddr;
+
+// Internal stuff:
+use super::Endpoint;
+
+
+/// Longest HTTP response header we'll put up with from a proxy.
+const HTTP_HEADER_LIMIT: usize = 8192;
+
+/// Settings for an outbound proxy.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProxyConfiguration {
+
+	/// Which protocol the proxy speaks.
+	pub protocol: ProxyProtocol,
+
+	/// Where the proxy is.
+	pub endpoint: Endpoint,
+
+	/// Username and password to give the proxy, if it wants them.
+	#[serde(default = "default_credentials")]
+	pub credentials: Option<Credentials>,
+
+}
+
+/// Proxy protocols available.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocol {
+	Socks5,
+	HttpConnect,
+}
+
+/// A username and password for a proxy.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Credentials {
+	pub username: String,
+	pub password: String,
+}
+
+
+/**
+	Asks the proxy on the other end of `stream` for a tunnel to `target`.
+	Once this returns `Ok`, everything written to `stream` goes to `target`.
+*/
+pub(super) async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, proxy: &ProxyConfiguration, target: &Endpoint) -> Result<(), Error> {
+	return match proxy.protocol {
+		ProxyProtocol::Socks5 => socks5(stream, proxy.credentials.as_ref(), target).await,
+		ProxyProtocol::HttpConnect => http_connect(stream, proxy.credentials.as_ref(), target).await,
+	};
+}
+
+/// SOCKS5 (RFC 1928), with username/password authentication (RFC 1929) if there are credentials.
+async fn socks5<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, credentials: Option<&Credentials>, target: &Endpoint) -> Result<(), Error> {
+
+	// Offer an authentication method (version 5, one method: none, or username/password):
+	let method: u8 = if let Some(_) = credentials { 0x02 } else { 0x00 };
+	stream.write_all(&[0x05, 0x01, method]).await?;
+	stream.flush().await?;
+	let mut choice: [u8; 2] = [0_u8; 2];
+	stream.read_exact(&mut choice).await?;
+	if choice[0] != 0x05 {
+		return Err(Error::new(ErrorKind::InvalidData, "proxy doesn't speak SOCKS5"));
+	} else if choice[1] != method {
+		return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy won't accept our authentication method"));
+	}
+
+	// Log in, if need be:
+	if let Some(credentials) = credentials {
+		let username: &[u8] = credentials.username.as_bytes();
+		let password: &[u8] = credentials.password.as_bytes();
+		let too_long = |_| { Error::new(ErrorKind::InvalidInput, "SOCKS5 usernames and passwords can't be longer than 255 bytes") };
+		let mut login: Vec<u8> = vec![0x01, u8::try_from(username.len()).map_err(too_long)?];
+		login.extend_from_slice(username);
+		login.push(u8::try_from(password.len()).map_err(too_long)?);
+		login.extend_from_slice(password);
+		stream.write_all(&login).await?;
+		stream.flush().await?;
+		let mut status: [u8; 2] = [0_u8; 2];
+		stream.read_exact(&mut status).await?;
+		if status[1] != 0x00 {
+			return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy rejected our username and password"));
+		}
+	}
+
+	// Ask for the tunnel (version 5, CONNECT, reserved, then the address; hostnames are left for the proxy to look up):
+	let mut request: Vec<u8> = vec![0x05, 0x01, 0x00];
+	let port: u16 = match target {
+		Endpoint::Inet(addr) => {
+			match addr.ip() {
+				IpAddr::V4(ip) => {
+					request.push(0x01);
+					request.extend_from_slice(&ip.octets());
+				},
+				IpAddr::V6(ip) => {
+					request.push(0x04);
+					request.extend_from_slice(&ip.octets());
+				},
+			}
+			addr.port()
+		},
+		Endpoint::Host { name, port } => {
+			request.push(0x03);
+			request.push(u8::try_from(name.len()).map_err(|_| { Error::new(ErrorKind::InvalidInput, "SOCKS5 hostnames can't be longer than 255 bytes") })?);
+			request.extend_from_slice(name.as_bytes());
+			*port
+		},
+		_ => return Err(Error::new(ErrorKind::InvalidInput, format!("can't reach {} through a proxy", target))),
+	};
+	request.extend_from_slice(&port.to_be_bytes());
+	stream.write_all(&request).await?;
+	stream.flush().await?;
+
+	// Read the reply, which ends with the address the proxy bound (we don't need it, but it has to be read out):
+	let mut reply: [u8; 4] = [0_u8; 4];
+	stream.read_exact(&mut reply).await?;
+	if reply[1] != 0x00 {
+		return Err(Error::new(ErrorKind::ConnectionRefused, format!("SOCKS5 proxy couldn't connect to {}: {}", target, socks5_reply(reply[1]))));
+	}
+	let address_length: usize = match reply[3] {
+		0x01 => 4,
+		0x03 => stream.read_u8().await? as usize,
+		0x04 => 16,
+		_ => return Err(Error::new(ErrorKind::InvalidData, "SOCKS5 proxy replied with an unknown address type")),
+	};
+	let mut bound: Vec<u8> = vec![0_u8; address_length + 2];
+	stream.read_exact(&mut bound).await?;
+	return Ok(());
+}
+
+/// What a SOCKS5 reply code means.
+fn socks5_reply(code: u8) -> &'static str {
+	return match code {
+		0x01 => "general failure",
+		0x02 => "not allowed by ruleset",
+		0x03 => "network unreachable",
+		0x04 => "host unreachable",
+		0x05 => "connection refused",
+		0x06 => "TTL expired",
+		0x07 => "command not supported",
+		0x08 => "address type not supported",
+		_ => "unknown error",
+	};
+}
+
+/// HTTP `CONNECT` (RFC 9110), with basic authentication if there are credentials.
+async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, credentials: Option<&Credentials>, target: &Endpoint) -> Result<(), Error> {
+	if let Endpoint::Unix(_) | Endpoint::Command(_) | Endpoint::Stdio | Endpoint::Memory(_) = target {
+		return Err(Error::new(ErrorKind::InvalidInput, format!("can't reach {} through a proxy", target)));
+	}
+
+	// Send the request:
+	let mut request: String = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+	if let Some(credentials) = credentials {
+		let token: String = BASE64.encode(format!("{}:{}", credentials.username, credentials.password));
+		request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+	}
+	request.push_str("\r\n");
+	stream.write_all(request.as_bytes()).await?;
+	stream.flush().await?;
+
+	// Read the response header one byte at a time, so that nothing past it (which belongs to the handshake) gets eaten:
+	let mut header: Vec<u8> = Vec::new();
+	while !header.ends_with(b"\r\n\r\n") {
+		if header.len() >= HTTP_HEADER_LIMIT {
+			return Err(Error::new(ErrorKind::InvalidData, "HTTP proxy's response header is too long"));
+		}
+		header.push(stream.read_u8().await?);
+	}
+
+	// Only the status code matters:
+	let status_line: String = String::from_utf8_lossy(&header).lines().next().unwrap_or_default().to_string();
+	let status: u16 = status_line.split_whitespace().nth(1).and_then(|code| { code.parse().ok() })
+		.ok_or(Error::new(ErrorKind::InvalidData, format!("HTTP proxy sent a garbled status line: {}", status_line)))?;
+	return match status {
+		200..=299 => Ok(()),
+		407 => Err(Error::new(ErrorKind::PermissionDenied, format!("HTTP proxy wants authentication: {}", status_line))),
+		_ => Err(Error::new(ErrorKind::ConnectionRefused, format!("HTTP proxy couldn't connect to {}: {}", target, status_line))),
+	};
+}
+
+
+fn default_credentials() -> Option<Credentials> {
+	return None;
+}
+
+
+#[tokio::test]
+async fn test_proxy_negotiation() {
+	use tokio::{
+		io,
+		net::{TcpListener, TcpStream},
+		task,
+	};
+	use bytes::Bytes;
+	use std::net::{Ipv6Addr, SocketAddr};
+	use super::{
+		Connection,
+		ConnectionConfiguration,
+		TcpConnection,
+	};
+
+	// A tiny SOCKS5 proxy that wants "user"/"hunter2":
+	async fn socks5_stand_in(mut client: TcpStream) {
+		let mut greeting: [u8; 3] = [0_u8; 3];
+		client.read_exact(&mut greeting).await.unwrap();
+		assert_eq!(greeting, [0x05, 0x01, 0x02]);
+		client.write_all(&[0x05, 0x02]).await.unwrap();
+		let mut login: Vec<u8> = vec![0_u8; 2];
+		client.read_exact(&mut login).await.unwrap();
+		let mut username: Vec<u8> = vec![0_u8; login[1] as usize];
+		client.read_exact(&mut username).await.unwrap();
+		let mut password: Vec<u8> = vec![0_u8; client.read_u8().await.unwrap() as usize];
+		client.read_exact(&mut password).await.unwrap();
+		assert_eq!((username.as_slice(), password.as_slice()), (&b"user"[..], &b"hunter2"[..]));
+		client.write_all(&[0x01, 0x00]).await.unwrap();
+		let mut request: [u8; 4 + 16 + 2] = [0_u8; 22];
+		client.read_exact(&mut request).await.unwrap();
+		assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x04]);
+		let target: SocketAddr = (Ipv6Addr::from(<[u8; 16]>::try_from(&request[4..20]).unwrap()), u16::from_be_bytes([request[20], request[21]])).into();
+		let mut server: TcpStream = TcpStream::connect(target).await.unwrap();
+		client.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0]).await.unwrap();
+		let _ = io::copy_bidirectional(&mut client, &mut server).await;
+	}
+
+	// And a tiny HTTP proxy that wants the same:
+	async fn http_stand_in(mut client: TcpStream) {
+		let mut header: Vec<u8> = Vec::new();
+		while !header.ends_with(b"\r\n\r\n") {
+			header.push(client.read_u8().await.unwrap());
+		}
+		let header: String = String::from_utf8(header).unwrap();
+		let target: SocketAddr = header.split_whitespace().nth(1).unwrap().parse().unwrap();
+		if !header.contains(&format!("Proxy-Authorization: Basic {}\r\n", BASE64.encode("user:hunter2"))) {
+			client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+			return;
+		}
+		let mut server: TcpStream = TcpStream::connect(target).await.unwrap();
+		client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+		let _ = io::copy_bidirectional(&mut client, &mut server).await;
+	}
+
+	let socks5_listener: TcpListener = TcpListener::bind((Ipv6Addr::LOCALHOST, 54361)).await.unwrap();
+	task::spawn(async move {
+		while let Ok((client, _)) = socks5_listener.accept().await {
+			task::spawn(socks5_stand_in(client));
+		}
+	});
+	let http_listener: TcpListener = TcpListener::bind((Ipv6Addr::LOCALHOST, 54362)).await.unwrap();
+	task::spawn(async move {
+		while let Ok((client, _)) = http_listener.accept().await {
+			task::spawn(http_stand_in(client));
+		}
+	});
+
+	// The server:
+	let target: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54363).into());
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: target.clone(),
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		while let Ok((_, tx, mut rx)) = server.accept().await {
+			// Simple echo server:
+			task::spawn(async move {
+				while let Some(data) = rx.recv().await {
+					tx.send(data).await.unwrap();
+				}
+			});
+		}
+	});
+
+	// Clients going through each proxy (and one that forgot its password):
+	let credentials: Credentials = Credentials { username: String::from("user"), password: String::from("hunter2") };
+	let proxies: [(ProxyProtocol, u16, Option<Credentials>, u16); 3] = [
+		(ProxyProtocol::Socks5, 54361, Some(credentials.clone()), 54364),
+		(ProxyProtocol::HttpConnect, 54362, Some(credentials), 54365),
+		(ProxyProtocol::HttpConnect, 54362, None, 54366),
+	];
+	for (protocol, port, credentials, local) in proxies {
+		let authenticated: bool = credentials.is_some();
+		let mut client: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+			endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, local).into()),
+			proxy: Some(ProxyConfiguration {
+				protocol: protocol,
+				endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, port).into()),
+				credentials: credentials,
+			}),
+			..ConnectionConfiguration::default()
+		});
+		match client.connect(target.clone()).await {
+			Ok((ctx, mut crx)) => {
+				assert!(authenticated);
+				ctx.send(Bytes::from_static(b"Via a proxy.")).await.unwrap();
+				assert_eq!(crx.recv().await.unwrap(), b"Via a proxy.".to_vec());
+			},
+			Err(e) => {
+				assert!(!authenticated);
+				assert!(matches!(e, super::ConnectionError::Io(e) if e.kind() == ErrorKind::PermissionDenied));
+			},
+		}
+	}
+}
@@ -0,0 +1,244 @@
+/*!
+	A way to transport data within one process, over in-memory pipes.
+	Servers listen on a name instead of a port, and clients connect to that name. Everything past
+	that (the key exchange, encryption, sessions) is the same as over a socket, so tests can run the
+	whole client/server stack without binding ports or waiting on the network, and programs that
+	embed both ends don't have to go through the OS to talk to themselves.
+*/
+
+// External stuff:
+use bytes::Bytes;
+use tokio::{
+	io::{self, DuplexStream, Error, ErrorKind, ReadHalf, WriteHalf},
+	sync::{
+		broadcast,
+		mpsc::{
+			self, Receiver, Sender
+		},
+	},
+	task,
+};
+use std::{
+	collections::HashMap,
+	sync::{Mutex, OnceLock},
+};
+
+// Internal stuff:
+use super::{
+	admission::{Accepted, Admission},
+	error::{self, ConnectionError, ConnectionEvent, Events},
+	resumption::Tickets,
+	roaming::{Link, Redial, Sessions},
+	Connection,
+	ConnectionConfiguration,
+	Disconnect,
+	Endpoint,
+	Multiplexer,
+	TcpConnection,
+};
+
+
+/// How many bytes each direction of a pipe can hold before the writer has to wait.
+const PIPE_BUFFER_SIZE: usize = 65536;
+
+/// How many pipes can be waiting on a listener before new connections have to wait.
+const LISTEN_BACKLOG: usize = 1024;
+
+/// Listeners in this process, by name.
+static LISTENERS: OnceLock<Mutex<HashMap<String, Sender<DuplexStream>>>> = OnceLock::new();
+
+
+pub struct MemoryConnection {
+	incoming: Option<Receiver<Accepted>>,
+	sessions: Sessions,
+	events: Events,
+	config: ConnectionConfiguration,
+} impl MemoryConnection {
+
+	/// The name of `endpoint`.
+	fn name(endpoint: &Endpoint) -> Result<&str, Error> {
+		if let Endpoint::Memory(name) = endpoint {
+			return Ok(name);
+		} else {
+			return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not an in-memory endpoint", endpoint)));
+		}
+	}
+
+	/// Opens a pipe to the listener called `name`, and runs the handshake.
+	async fn dial(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, name: &str) -> Result<Link, ConnectionError> {
+		// Find the listener:
+		let refused = || { Error::new(ErrorKind::ConnectionRefused, format!("nothing is listening on memory:{}", name)) };
+		let listener: Sender<DuplexStream> = listeners().lock().unwrap().get(name).cloned().ok_or_else(refused)?;
+
+		// Hand it one end of a new pipe (this fails if it's stopped listening):
+		let (near, far) = io::duplex(PIPE_BUFFER_SIZE);
+		listener.send(far).await.map_err(|_| { refused() })?;
+
+		// Do the handshake over the other end, and spin up the tasks:
+		let (rx, tx): (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) = io::split(near);
+		return TcpConnection::start(config, events, tickets, tx, rx, Endpoint::Memory(name.to_string())).await;
+	}
+
+	/// Accepts pipes on `endpoint`, handing each one off to its own handshake task, until the `MemoryConnection` is dropped.
+	async fn accept_task(mut pipes: Receiver<DuplexStream>, admission: Admission, endpoint: Endpoint) {
+		loop {
+			let pipe: DuplexStream = tokio::select! {
+				pipe = pipes.recv() => match pipe {
+					Some(pipe) => pipe,
+					None => return,
+				},
+				_ = admission.closed() => return,
+			};
+			eprintln!("Server: new connection on {}.", endpoint);
+
+			// Split the pipe into a sender and a receiver (it's all in memory, so there's no point buffering them):
+			let (rx, tx) = io::split(pipe);
+			admission.spawn(tx, rx, endpoint.clone(), None, None);
+		}
+	}
+
+	/// Starts a session with the server at `endpoint` (connected through to `target`, if there is one).
+	async fn open(&mut self, endpoint: Endpoint, target: Option<Endpoint>) -> Result<Multiplexer, ConnectionError> {
+
+		// First, make sure that this isn't supposed to be a server:
+		if let None = self.incoming {
+			// All good? Connect:
+			let name: String = Self::name(&endpoint)?.to_string();
+			let link: Link = Self::dial(&self.config, &self.events, self.sessions.tickets(), &name).await?;
+
+			// If the link drops, we'll dial again and resume the session:
+			let config: ConnectionConfiguration = self.config.clone();
+			let events: Events = self.events.clone();
+			let tickets: Tickets = self.sessions.tickets().clone();
+			let redial: Redial = Box::new(move || {
+				let config: ConnectionConfiguration = config.clone();
+				let events: Events = events.clone();
+				let tickets: Tickets = tickets.clone();
+				let name: String = name.clone();
+				return Box::pin(async move { Self::dial(&config, &events, &tickets, &name).await });
+			});
+			return Ok(self.sessions.open(link, target, Some(redial)).await?);
+		} else {
+			// If this `struct` shouldn't be connecting:
+			return Err(ConnectionError::Listening);
+		}
+
+	}
+
+}
+
+impl Connection for MemoryConnection {
+	type Error = ConnectionError;
+
+
+	fn new(config: ConnectionConfiguration) -> Self {
+		let events: Events = error::new_events();
+		return Self {
+			incoming: None,
+			sessions: Sessions::new(&config, &events),
+			events: events,
+			config: config,
+		};
+	}
+
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return self.events.subscribe();
+	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		// Take the name, unless something else is still listening on it:
+		let name: String = Self::name(&self.config.endpoint)?.to_string();
+		let (pipes_tx, pipes_rx) = mpsc::channel::<DuplexStream>(LISTEN_BACKLOG);
+		{
+			let mut listeners = listeners().lock().unwrap();
+			if let Some(listener) = listeners.get(&name) && !listener.is_closed() {
+				return Err(Error::new(ErrorKind::AddrInUse, format!("something is already listening on {}", self.config.endpoint)).into());
+			}
+			listeners.insert(name, pipes_tx);
+		}
+
+		// Start accepting connections in the background:
+		let (admission, incoming) = Admission::new(&self.config, &self.sessions, &self.events);
+		task::spawn(Self::accept_task(pipes_rx, admission, self.config.endpoint.clone()));
+		self.incoming = Some(incoming);
+		eprintln!("Server listening on {}.", self.config.endpoint);
+		return Ok(());
+	}
+
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
+
+		// Check if we're supposed to be listening:
+		if let Some(incoming) = &mut self.incoming {
+			// Wait for a connection that's made it through its handshake (ones that resume an existing session don't show up here):
+			return incoming.recv().await.ok_or(Error::other("stopped accepting connections").into());
+		} else {
+			// If this `struct` _shouldn't_ be listening:
+			return Err(ConnectionError::NotListening);
+		}
+
+	}
+
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return self.open(endpoint, None).await;
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.open(via, Some(target)).await?.into_main());
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		self.sessions.disconnect(disconnect).await;
+		return Ok(());
+	}
+
+}
+
+/// The listeners, made the first time they're asked for.
+fn listeners() -> &'static Mutex<HashMap<String, Sender<DuplexStream>>> {
+	return LISTENERS.get_or_init(|| { Mutex::new(HashMap::new()) });
+}
+
+
+#[tokio::test]
+async fn test_memory_connection() {
+	let message: &[u8] = b"Never left the building.";
+	let endpoint: Endpoint = Endpoint::Memory(String::from("test_memory_connection"));
+	let config: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: endpoint.clone(),
+		connection: super::Implementation::Memory,
+		..ConnectionConfiguration::default()
+	};
+
+	// Nothing's listening yet:
+	let mut client: MemoryConnection = MemoryConnection::new(config.clone());
+	match client.connect(endpoint.clone()).await {
+		Err(ConnectionError::Io(e)) if e.kind() == ErrorKind::ConnectionRefused => (),
+		_ => panic!("connected to nothing"),
+	}
+
+	// Make a server (no waiting for it to start; it's listening as soon as `listen` returns):
+	let mut server: MemoryConnection = MemoryConnection::new(config.clone());
+	server.listen().await.unwrap();
+
+	// Only one server gets the name:
+	match MemoryConnection::new(config.clone()).listen().await {
+		Err(ConnectionError::Io(e)) if e.kind() == ErrorKind::AddrInUse => (),
+		_ => panic!("two servers listening on the same name"),
+	}
+
+	// Runs the server:
+	task::spawn(async move {
+		let (peer, tx, mut rx) = server.accept().await.unwrap();
+		assert_eq!(peer, Endpoint::Memory(String::from("test_memory_connection")));
+
+		// Simple echo server:
+		while let Some(data) = rx.recv().await {
+			tx.send(data).await.unwrap();
+		}
+	});
+
+	// The client goes through the key exchange and encryption just like it would over a socket:
+	let (ctx, mut crx) = client.connect(endpoint).await.unwrap();
+	ctx.send(Bytes::copy_from_slice(message)).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), message.to_vec());
+}
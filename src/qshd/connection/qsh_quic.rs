@@ -0,0 +1,387 @@
+/*!
+	A way to transport data over QUIC.
+	Every call to `connect` opens a new stream; if there's already a QUIC connection to that
+	host, it gets reused, so many channels can share one connection without head-of-line blocking.
+	The first stream over each connection runs the usual key exchange, so we don't rely on QUIC's TLS
+	for privacy (the certificates here are throwaway and never verified); every later stream gets its
+	keys from the secret that first one settled on, and fresh nonces from both ends, the same way a
+	link resumed from a ticket does (see `resumption`), instead of a whole key exchange of its own.
+	If the first stream's handshake fails, so does the connection.
+*/
+
+// External stuff:
+use quinn::{
+	self,
+	crypto::rustls::QuicClientConfig,
+	ClientConfig, Endpoint as QuicEndpoint, RecvStream, SendStream, ServerConfig,
+};
+use rustls::{
+	self,
+	client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	crypto::{self as rustls_crypto, CryptoProvider},
+	pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+	DigitallySignedStruct, SignatureScheme,
+};
+use bytes::Bytes;
+use tokio::{
+	io::{Error, ErrorKind},
+	sync::{
+		broadcast,
+		mpsc::{
+			Receiver, Sender,
+		},
+		watch,
+	},
+	task,
+	time::{self, Duration},
+};
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+};
+
+// Internal stuff:
+use super::{
+	admission::{Accepted, Admission, Sharing},
+	error::{self, ConnectionError, ConnectionEvent, Events},
+	inet,
+	qsh_tcp::Keys,
+	resolve,
+	resumption::Tickets,
+	roaming::{Link, Redial, Sessions},
+	Connection,
+	ConnectionConfiguration,
+	Disconnect,
+	Endpoint,
+	Multiplexer,
+	TcpConnection,
+};
+use crate::crypto::Secret;
+
+
+/// Name that the server's throwaway certificate is issued to.
+const SERVER_NAME: &str = "qsh";
+
+/// QUIC connections we've made, by where they go, each with the secret that later streams over it get their keys from (clients only).
+type Connections = Arc<Mutex<HashMap<SocketAddr, (quinn::Connection, Secret)>>>;
+
+pub struct QuicConnection {
+
+	// The QUIC endpoint (either a server or a client one):
+	endpoint: Option<QuicEndpoint>,
+
+	// New sessions, on streams that have been opened by remote hosts (server only):
+	incoming: Option<Receiver<Accepted>>,
+
+	// Connections that we've already made, so that they can be reused (client only):
+	connections: Connections,
+
+	// Sessions that streams can resume (server only):
+	sessions: Sessions,
+
+	// Where links report opening and closing:
+	events: Events,
+
+	config: ConnectionConfiguration,
+
+} impl QuicConnection {
+
+	/// Makes the server side's QUIC configuration, with a freshly generated self-signed certificate.
+	fn server_config() -> Result<ServerConfig, Error> {
+		let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).map_err(|e| { Error::other(e) })?;
+		let cert: CertificateDer<'static> = certified.cert.der().clone();
+		let key: PrivateKeyDer<'static> = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()).into();
+		return ServerConfig::with_single_cert(vec![cert], key).map_err(|e| { Error::other(e) });
+	}
+
+	/// Makes the client side's QUIC configuration, which accepts any certificate (see the module documentation).
+	fn client_config() -> Result<ClientConfig, Error> {
+		let provider: Arc<CryptoProvider> = Arc::new(rustls_crypto::ring::default_provider());
+		let crypto: rustls::ClientConfig = rustls::ClientConfig::builder_with_provider(provider.clone())
+			.with_protocol_versions(&[&rustls::version::TLS13])
+			.map_err(|e| { Error::other(e) })?
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+			.with_no_client_auth();
+		return Ok(ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).map_err(|e| { Error::other(e) })?)));
+	}
+
+	/// Opens a new stream over `connection`, with keys from `secret` (what its first stream settled on).
+	async fn open_stream(config: &ConnectionConfiguration, events: &Events, connection: &quinn::Connection, secret: &Secret) -> Result<Link, ConnectionError> {
+		let (tx, rx) = connection.open_bi().await.map_err(|e| { Error::other(e) })?;
+		return TcpConnection::start_with(config, events, Keys::Shared(secret, true), tx, rx, Endpoint::Inet(connection.remote_address())).await;
+	}
+
+	/// Makes a brand new QUIC connection to `remote` from `endpoint`, runs the handshake over its first stream, and keeps it in `connections` for later streams.
+	async fn dial(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, connections: &Connections, endpoint: &QuicEndpoint, remote: SocketAddr) -> Result<Link, ConnectionError> {
+		let connection: quinn::Connection = endpoint
+			.connect(remote, SERVER_NAME).map_err(|e| { Error::other(e) })?
+			.await.map_err(|e| { Error::other(e) })?;
+		let (tx, rx) = connection.open_bi().await.map_err(|e| { Error::other(e) })?;
+		match TcpConnection::start(config, events, tickets, tx, rx, Endpoint::Inet(remote)).await {
+			Ok(link) => {
+				connections.lock().unwrap().insert(remote, (connection, link.resumption.clone()));
+				return Ok(link);
+			},
+			Err(e) => {
+				// Without a first stream, there's nothing for later ones to get their keys from:
+				connection.close(0_u32.into(), b"handshake failed");
+				return Err(e);
+			},
+		}
+	}
+
+	/// Accepts streams from one QUIC connection, handing each one off to its own handshake task.
+	async fn stream_task(connection: quinn::Connection, admission: Admission) {
+		let address: SocketAddr = connection.remote_address();
+
+		// The first stream does the handshake, and later ones get their keys from it; so if it fails, the whole connection goes:
+		let (first, keyed) = watch::channel::<Option<Secret>>(None);
+		let mut first: Option<watch::Sender<Option<Secret>>> = Some(first);
+		let mut watched: watch::Receiver<Option<Secret>> = keyed.clone();
+		let watcher: quinn::Connection = connection.clone();
+		task::spawn(async move {
+			if let Err(_) = watched.wait_for(|secret| { secret.is_some() }).await {
+				watcher.close(0_u32.into(), b"handshake failed");
+			}
+		});
+
+		loop {
+			let (tx, rx): (SendStream, RecvStream) = tokio::select! {
+				stream = connection.accept_bi() => match stream {
+					Ok(stream) => stream,
+					Err(_) => break,
+				},
+				_ = admission.closed() => break,	// Nobody's accepting anymore.
+			};
+			eprintln!("Server: new stream from {}.", &address);
+			let sharing: Sharing = if let Some(first) = first.take() { Sharing::First(first) } else { Sharing::Later(keyed.clone()) };
+			admission.spawn(tx, rx, Endpoint::Inet(address), Some(address.ip()), Some(sharing));
+		}
+		eprintln!("Server: QUIC connection from {} closed.", address);
+		return;
+	}
+
+	/// Accepts QUIC connections, spinning up a `stream_task` for each one (the QUIC handshake gets the same deadline as ours).
+	async fn connection_task(endpoint: QuicEndpoint, admission: Admission, deadline: Duration) {
+		loop {
+			let incoming: quinn::Incoming = tokio::select! {
+				incoming = endpoint.accept() => match incoming {
+					Some(incoming) => incoming,
+					None => return,
+				},
+				_ = admission.closed() => return,
+			};
+			let admission: Admission = admission.clone();
+			task::spawn(async move {
+				match time::timeout(deadline, incoming).await {
+					Ok(Ok(connection)) => {
+						eprintln!("Server: new QUIC connection from {}.", connection.remote_address());
+						Self::stream_task(connection, admission).await;
+					},
+					Ok(Err(e)) => eprintln!("Server: failed to accept a QUIC connection: {}", e),
+					Err(_) => eprintln!("Server: a QUIC connection took too long to finish its handshake."),
+				}
+			});
+		}
+	}
+
+	/// Starts a session over a new stream to the server at `endpoint` (connected through to `target`, if there is one).
+	async fn open(&mut self, endpoint: Endpoint, target: Option<Endpoint>) -> Result<Multiplexer, ConnectionError> {
+
+		// First, make sure that this isn't supposed to be a server:
+		if let None = self.incoming {
+			// Set up the client endpoint, if that hasn't happened yet:
+			let bound: SocketAddr = inet(&self.config.endpoint)?;
+			if let None = self.endpoint {
+				let mut local: QuicEndpoint = QuicEndpoint::client(bound)?;
+				local.set_default_client_config(Self::client_config()?);
+				self.endpoint = Some(local);
+			}
+
+			// Look up the server, and pick an address that our endpoint can reach (it's bound to just the one family):
+			let remote: SocketAddr = resolve::resolve(&endpoint).await?.into_iter()
+				.find(|addr| { addr.is_ipv4() == bound.is_ipv4() })
+				.ok_or(Error::new(ErrorKind::AddrNotAvailable, format!("{} has no addresses in the same family as {}", endpoint, bound)))?;
+
+			// Open a new stream for this channel, over an existing connection to this host if it's still alive, otherwise over a new one:
+			let local: QuicEndpoint = self.endpoint.clone().unwrap();
+			let existing: Option<(quinn::Connection, Secret)> = self.connections.lock().unwrap().get(&remote)
+				.filter(|(connection, _)| { connection.close_reason().is_none() })
+				.cloned();
+			let link: Link = match existing {
+				Some((connection, secret)) => Self::open_stream(&self.config, &self.events, &connection, &secret).await?,
+				None => Self::dial(&self.config, &self.events, self.sessions.tickets(), &self.connections, &local, remote).await?,
+			};
+
+			// If the stream drops, we'll make a new connection (for later streams to use too) and resume the session over that:
+			let config: ConnectionConfiguration = self.config.clone();
+			let events: Events = self.events.clone();
+			let tickets: Tickets = self.sessions.tickets().clone();
+			let connections: Connections = self.connections.clone();
+			let redial: Redial = Box::new(move || {
+				let config: ConnectionConfiguration = config.clone();
+				let events: Events = events.clone();
+				let tickets: Tickets = tickets.clone();
+				let connections: Connections = connections.clone();
+				let local: QuicEndpoint = local.clone();
+				return Box::pin(async move { Self::dial(&config, &events, &tickets, &connections, &local, remote).await });
+			});
+			return Ok(self.sessions.open(link, target, Some(redial)).await?);
+		} else {
+			// If this `struct` shouldn't be connecting:
+			return Err(ConnectionError::Listening);
+		}
+
+	}
+
+}
+
+impl Connection for QuicConnection {
+	type Error = ConnectionError;
+
+
+	fn new(config: ConnectionConfiguration) -> Self {
+		let events: Events = error::new_events();
+		return Self {
+			endpoint: None,
+			incoming: None,
+			connections: Arc::new(Mutex::new(HashMap::new())),
+			sessions: Sessions::new(&config, &events),
+			events: events,
+			config: config,
+		};
+	}
+
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return self.events.subscribe();
+	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		// Bind the endpoint:
+		let endpoint: QuicEndpoint = QuicEndpoint::server(Self::server_config()?, inet(&self.config.endpoint)?)?;
+
+		// Start accepting connections in the background:
+		let (admission, incoming) = Admission::new(&self.config, &self.sessions, &self.events);
+		task::spawn(Self::connection_task(endpoint.clone(), admission, Duration::from_millis(self.config.handshake_timeout)));
+
+		self.endpoint = Some(endpoint);
+		self.incoming = Some(incoming);
+		eprintln!("Server listening (QUIC) on {}.", self.config.endpoint);
+		return Ok(());
+	}
+
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
+
+		// Check if we're supposed to be listening:
+		if let Some(incoming) = &mut self.incoming {
+			// Wait for a stream that's made it through its handshake (ones that resume an existing session don't show up here):
+			return incoming.recv().await.ok_or(Error::other("QUIC endpoint closed").into());
+		} else {
+			// If this `struct` _shouldn't_ be listening:
+			return Err(ConnectionError::NotListening);
+		}
+
+	}
+
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return self.open(endpoint, None).await;
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.open(via, Some(target)).await?.into_main());
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		self.sessions.disconnect(disconnect).await;
+		return Ok(());
+	}
+
+}
+
+
+/// Certificate "verifier" that accepts anything; signatures are still checked, so that the TLS handshake itself is sound.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+
+	fn verify_server_cert(&self, _end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+		return Ok(ServerCertVerified::assertion());
+	}
+
+	fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+		return rustls_crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms);
+	}
+
+	fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+		return rustls_crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms);
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		return self.0.signature_verification_algorithms.supported_schemes();
+	}
+
+}
+
+
+#[tokio::test]
+async fn test_quic_connection() {
+	use std::net::Ipv6Addr;
+
+	let messages: [&[u8]; 2] = [b"First stream.", b"Second stream, same connection."];
+
+	// Need a configuration first:
+	let client_conf: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54330).into()),
+		connection: super::Implementation::Quic,
+		crypto: vec![crate::crypto::Implementation::AesGcm],
+		..ConnectionConfiguration::default()
+	};
+	let server_conf: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54331).into()),
+		connection: super::Implementation::Quic,
+		crypto: vec![crate::crypto::Implementation::AesGcm],
+		..ConnectionConfiguration::default()
+	};
+
+	// Make a server and a client:
+	let mut server: QuicConnection = QuicConnection::new(server_conf);
+	let mut client: QuicConnection = QuicConnection::new(client_conf);
+
+	// Runs the server, echoing on every stream it gets:
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		while let Ok((_, tx, mut rx)) = server.accept().await {
+			task::spawn(async move {
+				while let Some(data) = rx.recv().await {
+					tx.send(data).await.unwrap();
+				}
+			});
+		}
+	});
+
+	// Open two channels to the same server:
+	let (ctx_a, mut crx_a) = client.connect(Endpoint::Inet((Ipv6Addr::LOCALHOST, 54331).into())).await.unwrap();
+	let (ctx_b, mut crx_b) = client.connect(Endpoint::Inet((Ipv6Addr::LOCALHOST, 54331).into())).await.unwrap();
+	assert_eq!(client.connections.lock().unwrap().len(), 1);	// Both should be on one QUIC connection.
+
+	ctx_b.send(Bytes::copy_from_slice(messages[1])).await.unwrap();
+	ctx_a.send(Bytes::copy_from_slice(messages[0])).await.unwrap();
+	assert_eq!(crx_a.recv().await.unwrap(), messages[0].to_vec());
+	assert_eq!(crx_b.recv().await.unwrap(), messages[1].to_vec());
+
+	// If the connection goes, the sessions carry on over a new one, which is kept for later streams:
+	let (old, _) = client.connections.lock().unwrap().values().next().cloned().unwrap();
+	old.close(0_u32.into(), b"test");
+	ctx_a.send(Bytes::copy_from_slice(messages[0])).await.unwrap();
+	assert_eq!(crx_a.recv().await.unwrap(), messages[0].to_vec());
+	let (new, _) = client.connections.lock().unwrap().values().next().cloned().unwrap();
+	assert_ne!(new.stable_id(), old.stable_id());
+	assert!(new.close_reason().is_none());
+	let (ctx_c, mut crx_c) = client.connect(Endpoint::Inet((Ipv6Addr::LOCALHOST, 54331).into())).await.unwrap();
+	ctx_c.send(Bytes::copy_from_slice(messages[1])).await.unwrap();
+	assert_eq!(crx_c.recv().await.unwrap(), messages[1].to_vec());
+	assert_eq!(client.connections.lock().unwrap().len(), 1);
+}
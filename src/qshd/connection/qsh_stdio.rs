@@ -0,0 +1,219 @@
+/*!
+	A way to transport data over standard I/O, like OpenSSH's `ProxyCommand`.
+	Clients run a helper (netcat, a serial bridge, a bastion tool, etc) and talk through its stdin/stdout.
+	Servers can do the reverse ("inetd mode"): serve exactly one connection on their own stdin/stdout,
+	so whatever launched them owns the actual transport. Note that stdout belongs to the connection then,
+	so everything else has to go to stderr.
+*/
+
+// External stuff:
+use bytes::Bytes;
+use tokio::{
+	io::{self, BufReader, BufWriter, Error, ErrorKind, Stdin, Stdout},
+	process::{Child, ChildStdin, ChildStdout, Command},
+	sync::{
+		broadcast,
+		mpsc::{
+			Receiver, Sender
+		},
+	},
+	task,
+};
+use std::process::Stdio;
+
+// Internal stuff:
+use super::{
+	error::{self, ConnectionError, ConnectionEvent, Events},
+	resumption::Tickets,
+	roaming::{Link, Redial, Sessions},
+	Connection,
+	ConnectionConfiguration,
+	Disconnect,
+	Endpoint,
+	Multiplexer,
+	TcpConnection,
+};
+
+
+pub struct StdioConnection {
+
+	// Whether we're serving on our own stdin/stdout:
+	listening: bool,
+
+	// Whether that one connection has been handed out yet:
+	served: bool,
+
+	sessions: Sessions,
+	events: Events,
+	config: ConnectionConfiguration,
+
+} impl StdioConnection {
+
+	/// Runs `command` through the shell, and runs the handshake over its stdin/stdout.
+	async fn dial(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, command: &str) -> Result<Link, ConnectionError> {
+		// Start the helper, leaving its stderr alone so the user can see what it says:
+		let mut child: Child = Command::new("sh").arg("-c").arg(command)
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::inherit())
+			.kill_on_drop(true)
+			.spawn()?;
+		let tx: BufWriter<ChildStdin> = BufWriter::new(child.stdin.take().unwrap());
+		let rx: BufReader<ChildStdout> = BufReader::new(child.stdout.take().unwrap());
+
+		// Keep an eye on it (this also keeps it from getting killed early):
+		let name: String = command.to_string();
+		task::spawn(async move {
+			match child.wait().await {
+				Ok(status) => eprintln!("proxy command `{}` exited: {}", name, status),
+				Err(e) => eprintln!("failed to wait on proxy command `{}`: {}", name, e),
+			}
+		});
+
+		// Do the handshake, and spin up the tasks:
+		return TcpConnection::start(config, events, tickets, tx, rx, Endpoint::Command(command.to_string())).await;
+	}
+
+	/// Starts a session with the server behind the command in `endpoint` (connected through to `target`, if there is one).
+	async fn open(&mut self, endpoint: Endpoint, target: Option<Endpoint>) -> Result<Multiplexer, ConnectionError> {
+
+		// First, make sure that this isn't supposed to be a server:
+		if !self.listening {
+			let command: String = if let Endpoint::Command(command) = endpoint {
+				command
+			} else {
+				return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a command", endpoint)).into());
+			};
+
+			// All good? Start the helper:
+			let link: Link = Self::dial(&self.config, &self.events, self.sessions.tickets(), &command).await?;
+
+			// If it dies, we'll run it again and resume the session:
+			let config: ConnectionConfiguration = self.config.clone();
+			let events: Events = self.events.clone();
+			let tickets: Tickets = self.sessions.tickets().clone();
+			let redial: Redial = Box::new(move || {
+				let config: ConnectionConfiguration = config.clone();
+				let events: Events = events.clone();
+				let tickets: Tickets = tickets.clone();
+				let command: String = command.clone();
+				return Box::pin(async move { Self::dial(&config, &events, &tickets, &command).await });
+			});
+			return Ok(self.sessions.open(link, target, Some(redial)).await?);
+		} else {
+			// If this `struct` shouldn't be connecting:
+			return Err(ConnectionError::Listening);
+		}
+
+	}
+
+}
+
+impl Connection for StdioConnection {
+	type Error = ConnectionError;
+
+
+	fn new(config: ConnectionConfiguration) -> Self {
+		let events: Events = error::new_events();
+		return Self {
+			listening: false,
+			served: false,
+			sessions: Sessions::new(&config, &events),
+			events: events,
+			config: config,
+		};
+	}
+
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return self.events.subscribe();
+	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		// Only stdin/stdout can be listened on:
+		if let Endpoint::Stdio = self.config.endpoint {
+			self.listening = true;
+			eprintln!("Server serving one connection on stdin/stdout.");
+			return Ok(());
+		} else {
+			return Err(Error::new(ErrorKind::InvalidInput, format!("can't listen on {} with standard I/O", self.config.endpoint)).into());
+		}
+	}
+
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
+
+		// Check if we're supposed to be listening:
+		if self.listening {
+
+			// There's only the one connection:
+			if self.served {
+				return Err(Error::new(ErrorKind::NotConnected, "stdin/stdout only carries one connection").into());
+			}
+			self.served = true;
+
+			// Buffer our own stdin/stdout:
+			let tx: BufWriter<Stdout> = BufWriter::new(io::stdout());
+			let rx: BufReader<Stdin> = BufReader::new(io::stdin());
+
+			// Do the handshake, spin up the tasks, and see what session it's for (a resume can't go anywhere, since this process is the only one that knew about it):
+			let link: Link = TcpConnection::start(&self.config, &self.events, self.sessions.tickets(), tx, rx, Endpoint::Stdio).await?;
+			let streams: Multiplexer = self.sessions.admit(link, &self.config).await?.ok_or(Error::new(ErrorKind::NotFound, "stdin/stdout didn't carry a new session"))?;
+			return Ok((Endpoint::Stdio, streams));
+		} else {
+			// If this `struct` _shouldn't_ be listening:
+			return Err(ConnectionError::NotListening);
+		}
+
+	}
+
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return self.open(endpoint, None).await;
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.open(via, Some(target)).await?.into_main());
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		self.sessions.disconnect(disconnect).await;
+		return Ok(());
+	}
+
+}
+
+
+#[tokio::test]
+async fn test_stdio_connection() {
+	use std::net::Ipv6Addr;
+	use super::TcpConnection;
+
+	let message: &[u8] = b"Through a helper process.";
+
+	// A normal TCP server:
+	let server_conf: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54340).into()),
+		connection: super::Implementation::Tcp,
+		crypto: vec![crate::crypto::Implementation::AesGcm],
+		..ConnectionConfiguration::default()
+	};
+	let mut server: TcpConnection = TcpConnection::new(server_conf.clone());
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		let (_, tx, mut rx) = server.accept().await.unwrap();
+
+		// Simple echo server:
+		while let Some(data) = rx.recv().await {
+			tx.send(data).await.unwrap();
+		}
+	});
+
+	// And a client that reaches it through bash's `/dev/tcp`, standing in for netcat:
+	let client_conf: ConnectionConfiguration = ConnectionConfiguration {
+		connection: super::Implementation::Stdio,
+		..server_conf
+	};
+	let mut client: StdioConnection = StdioConnection::new(client_conf);
+	let proxy: Endpoint = Endpoint::Command(String::from("bash -c 'exec 3<>/dev/tcp/::1/54340; cat <&3 & exec cat >&3'"));
+	let (ctx, mut crx) = client.connect(proxy).await.unwrap();
+	ctx.send(Bytes::copy_from_slice(message)).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), message.to_vec());
+}
@@ -3,41 +3,104 @@
 */
 
 // External stuff:
+use bytes::Bytes;
 use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, Error},
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, Error},
 	net::{
-		tcp::{ OwnedReadHalf, OwnedWriteHalf, }, TcpListener, TcpSocket
+		tcp::{ OwnedReadHalf, OwnedWriteHalf, }, TcpListener, TcpSocket, TcpStream
 	},
 	sync::mpsc::{
 			self, Receiver, Sender
 	},
-	task,
+	sync::broadcast,
+	task::{self, JoinHandle},
+	time::{self, Duration, Interval},
+};
+use std::{
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	vec,
 };
-use std::{net::Ipv6Addr, vec};
 
 // Internal stuff:
-use super::{Connection, ConnectionConfiguration};
+use super::{
+	activation,
+	admission::{Accepted, Admission},
+	buffer::{Buffer, BufferPool},
+	error::{self, CloseReason, ConnectionError, ConnectionEvent, Events},
+	framing::{self, Codec, FramingError},
+	impair,
+	inet,
+	keepalive::{self, Keepalive},
+	negotiation::Algorithms,
+	proxy,
+	rekey::{self, Rekey, RekeyReceiver},
+	resolve,
+	resumption::{self, Ticket, Tickets},
+	roaming::{self, Link, Redial, SessionId, Sessions},
+	shaping::{self, Shaper},
+	Connection,
+	ConnectionConfiguration,
+	Disconnect,
+	Endpoint,
+	Multiplexer,
+};
+#[cfg(feature = "websocket")]
+use super::WebSocketConnection;
 use crate::{
-	crypto::{Encryptor, Decryptor},
+	core::server::notify::{self, State},
+	crypto::{Encryptor, Decryptor, KeySchedule, Secret, Transcript},
 	kex::KeyExchanger,
 };
 
 
+/// Goes into the key schedule along with the handshake, so that keys from one version of the protocol never match another's.
+const HANDSHAKE_CONTEXT: &[u8] = b"qsh handshake v1";
+
+/// How many connections the OS may queue up for us before turning new ones away.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// How long to back off for when accepting fails (say, because we're out of file descriptors).
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Where a link's keys come from.
+pub(super) enum Keys<'a> {
+
+	/// A key exchange; unless one end presents a ticket that the other takes (see `resumption`).
+	Handshake(&'a Tickets),
+
+	/// A secret both ends already have, from an earlier link over the same connection (see `QuicConnection`), and whether we're the end that dialled.
+	#[cfg(feature = "quic")]
+	Shared(&'a Secret, bool),
+
+}
+
 pub struct TcpConnection {
-	listener: Option<TcpListener>,
+	incoming: Option<Receiver<Accepted>>,
+	sessions: Sessions,
+	events: Events,
 	config: ConnectionConfiguration,
 } impl TcpConnection {
 
-	/// This performs the key exchange, returning the resulting encryptor/decryptor pair, or an error.
-	async fn exchange_keys(&self, tx: &mut BufWriter<OwnedWriteHalf>, rx: &mut BufReader<OwnedReadHalf>) -> Result<(impl Encryptor + use<>, impl Decryptor + use<>), Error> {
-		
+	/**
+		This performs the key exchange, returning the resulting key schedule and the session ID, or an error.
+		It only needs a byte stream in each direction, so other transports run the very same handshake.
+		`algorithms`: the key exchange and cipher to use (both ends have to have agreed on them already).
+		`context`: whatever the two ends already agreed on, which the keys get bound to along with the exchange itself.
+	*/
+	pub(super) async fn exchange_keys<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(algorithms: &Algorithms, context: &[u8], tx: &mut W, rx: &mut R) -> Result<(KeySchedule, SessionId), ConnectionError> {
+
 		// First we need to make two key exchange objects:
-		let mut i_kex = self.config.kex.generate();
-		let mut o_kex = self.config.kex.generate();
+		let mut i_kex = algorithms.kex.generate();
+		let mut o_kex = algorithms.kex.generate();
 
 		// We'll send `i_kex`'s public key first, then `o_kex`'s:
-		tx.write(&i_kex.get_local_pubkey()).await?;
-		tx.write(&o_kex.get_local_pubkey()).await?;
+		let (i_pubkey, o_pubkey): (Vec<u8>, Vec<u8>) = (i_kex.get_local_pubkey(), o_kex.get_local_pubkey());
+		tx.write_all(&i_pubkey).await?;
+		tx.write_all(&o_pubkey).await?;
 		tx.flush().await?;
 
 		// Then, we read out the remote public keys:
@@ -47,230 +110,481 @@ pub struct TcpConnection {
 		rx.read_exact(&mut i_pubkey_buf).await?;	// and vis-versa.
 
 		// And set the remote public key on the key exchangers:
-		i_kex.set_remote_pubkey(&i_pubkey_buf).map_err(|e| { Error::other(e) })?;
-		o_kex.set_remote_pubkey(&o_pubkey_buf).map_err(|e| { Error::other(e) })?;
+		i_kex.set_remote_pubkey(&i_pubkey_buf).map_err(|e| { ConnectionError::Handshake(e.to_string()) })?;
+		o_kex.set_remote_pubkey(&o_pubkey_buf).map_err(|e| { ConnectionError::Handshake(e.to_string()) })?;
 
 		// Now we need to actually initiate the key exchange, starting with the client init step:
-		tx.write(&o_kex.client_init().map_err(|e| { Error::other(e.to_string()) })?).await?;	// Send a client init.
+		let o_client_init: Vec<u8> = o_kex.client_init().map_err(|e| { ConnectionError::Handshake(e.to_string()) })?;
+		tx.write_all(&o_client_init).await?;	// Send a client init.
 		tx.flush().await?;
 		let mut i_remote_client_init_buf: Vec<u8> = vec![0_u8; i_kex.get_client_init_length()];	// For holding the client's client init.
 		rx.read_exact(&mut i_remote_client_init_buf).await?;	// Receive it.
 
 		// Do the server init step:
-		tx.write(&i_kex.server_init(&i_remote_client_init_buf).map_err(|e| { Error::other(e.to_string()) })?).await?;	// Send a server init.
+		let i_server_init: Vec<u8> = i_kex.server_init(&i_remote_client_init_buf).map_err(|e| { ConnectionError::Handshake(e.to_string()) })?;
+		tx.write_all(&i_server_init).await?;	// Send a server init.
 		tx.flush().await?;
 		let mut o_remote_server_init_buf: Vec<u8> = vec![0_u8; o_kex.get_server_init_length()];	// For holding the client's server init.
 		rx.read_exact(&mut o_remote_server_init_buf).await?;
 
 		// Do the client confirm step:
-		o_kex.client_confirm(&o_remote_server_init_buf).map_err(|e| { Error::other(e.to_string()) })?;	// Done with key exchange!
-
-		// Make the keys/crypto thingies:
-		return Ok(self.config.crypto.generate(i_kex, o_kex));
+		o_kex.client_confirm(&o_remote_server_init_buf).map_err(|e| { ConnectionError::Handshake(e.to_string()) })?;	// Done with key exchange!
+
+		// Write down both exchanges, each the way its initiator sees it, so that the keys are bound to all of it:
+		let mut ours: Transcript = Transcript::new();	// The one we started (`o_kex`).
+		ours.append(b"initiator key", &o_pubkey);
+		ours.append(b"responder key", &o_pubkey_buf);
+		ours.append(b"client init", &o_client_init);
+		ours.append(b"server init", &o_remote_server_init_buf);
+		let mut theirs: Transcript = Transcript::new();	// The one they started (`i_kex`).
+		theirs.append(b"initiator key", &i_pubkey_buf);
+		theirs.append(b"responder key", &i_pubkey);
+		theirs.append(b"client init", &i_remote_client_init_buf);
+		theirs.append(b"server init", &i_server_init);
+		let schedule: KeySchedule = KeySchedule::new((&ours, o_kex.shared_secret()), (&theirs, i_kex.shared_secret()), context);
+
+		// Bind the session to this handshake:
+		let id: SessionId = roaming::session_id(i_kex.shared_secret(), o_kex.shared_secret());
+		return Ok((schedule, id));
 	}
 
 	/**
-		Used to spin up a send task.
-		`tx`: socket to send on.
+		Used to spin up a send task. Returns why it stopped.
+		`tx`: socket (or any other byte stream) to send on.
 		`ch`: channel to read out of.
-		`en`: `Encryptor` to use.
+		`codec`: frames the records.
+		`sent`: counts the bytes that go out.
+		`keepalive`: how often to send heartbeats, if at all.
+		`shaper`: holds data records back to the rate limit.
+		`rekey`: encrypts records, and rekeys every so often.
 	*/
-	async fn send_task<T: Encryptor>(tx: &mut BufWriter<OwnedWriteHalf>, ch: &mut Receiver<Vec<u8>>, en: &mut T) {
-		if let Err(e) = loop {
-			// Read byte vectors out of the channel, until the other end is dropped:
-			if let Some(mut byte_vec) = ch.recv().await {
-				if let Err(e) = en.encrypt(&mut byte_vec, b"").map_err(|e| { Error::other(e.to_string()) }) {
-					// Attempt to encrypt the data ☝🏻
-					break Err(e);
-				} else if let Err(e) = tx.write_u64_le(byte_vec.len().try_into().unwrap()).await {
-					// Send the length ☝🏻
-					break Err(e);
-				} else if let Err(e) = tx.write(&byte_vec).await {
-					// Send the data ☝🏻
-					break Err(e);
-				} else if let Err(e) = tx.flush().await {
-					// Flush the buffer ☝🏻
-					break Err(e);
-				}
-			} else {
-				// Run if the channel is closed:
-				break tx.shutdown().await;
+	pub(super) async fn send_task<W: AsyncWrite + Unpin, T: Encryptor>(tx: &mut W, ch: &mut Receiver<Buffer>, mut rekey: Rekey<T>, codec: Codec, sent: &AtomicU64, keepalive: Option<Keepalive>, mut shaper: Shaper) -> CloseReason {
+		let mut ticker: Option<Interval> = keepalive.map(|keepalive| { keepalive.ticker() });
+		loop {
+			// Read buffers out of the channel until the other end is dropped, sending heartbeats and rekeying in between:
+			let mut record: Buffer = tokio::select! {
+				data = ch.recv() => {
+					if let Some(mut record) = data {
+						// Wait for our turn, if this is shaped:
+						let tag: u8 = shaper.tag();
+						shaper.pass(tag, record.len()).await;
+						record.prepend(&[tag]);
+						record
+					} else {
+						// Run if the channel is closed:
+						return match tx.shutdown().await {
+							Ok(()) => CloseReason::Local,
+							Err(e) => ConnectionError::from(e).into(),
+						};
+					}
+				},
+				_ = keepalive::tick(&mut ticker) => {
+					let mut heartbeat: Buffer = codec.pool().take();
+					heartbeat.extend_from_slice(&[keepalive::RECORD_HEARTBEAT]);
+					heartbeat
+				},
+				record = rekey.next(codec.pool()) => match record {
+					Ok(Some(record)) => record,
+					Ok(None) => continue,
+					Err(e) => return e.into(),
+				},
+			};
+
+			// Attempt to encrypt the data:
+			if let Err(e) = rekey.seal(&mut record) {
+				return e.into();
+			}
+
+			// Send the record, and flush the buffer:
+			match codec.write(tx, &mut record).await {
+				Ok(length) => sent.fetch_add(length, Ordering::Relaxed),
+				Err(e) => return e.into(),
+			};
+			if let Err(e) = tx.flush().await {
+				return ConnectionError::from(e).into();
 			}
-		} {
-			// This gets run if an error occures in the loop:
-			eprintln!("an error occured on a sender task connected to {}: {}", tx.get_ref().peer_addr().unwrap(), e);
-		} else {
-			// This gets run if all is dandy:
-			eprintln!("Closed sender task connected to {}", tx.get_ref().peer_addr().unwrap());
 		}
-		return;
 	}
 
 	/**
-		Used to spin up a receive task.
-		`rx`: socket to receive on.
+		Used to spin up a receive task. Returns why it stopped.
+		`rx`: socket (or any other byte stream) to receive on.
 		`ch`: channel to send to.
-		`de`: `Decryptor` to use.
+		`codec`: frames the records (into buffers from its pool).
+		`received`: counts the bytes that come in.
+		`keepalive`: how long the other end may stay quiet before it's declared dead, if there's a limit.
+		`shaper`: holds off reading past data records until the rate limit allows.
+		`rekey`: decrypts records, and switches keys when the other end does.
 	*/
-	async fn recv_task<T: Decryptor>(rx: &mut BufReader<OwnedReadHalf>, ch: &mut Sender<Vec<u8>>, de: &mut T) {
-		if let Err(e) = loop {
-			// First, read the length of the next message:
-			if let Ok(message_length) = rx.read_u64_le().await {
-				// Make a buffer to store the message:
-				let mut buf: Vec<u8> = vec![0_u8; message_length.try_into().unwrap()];
-				// Read it:
-				if let Err(e) = rx.read_exact(&mut buf).await {
-					// If receiving fails, exit the loop:
-					break Err(e);
-				} else if let Err(e) = de.decrypt(&mut buf, b"").map_err(|e| { Error::other(e.to_string()) }) {
-					// Try to decrypt the message, and if it fails:
-					break Err(e);
-				} else if let Err(_) = ch.send(buf).await {
-					// Try to send the decrypted message down the channel, and if that fails, then the `Receiver` must have been dropped, so the connection should be closed:
-					break Ok(());
-				}
-			} else {
-				// Run if there's an error reading the length from the socket:
-				break Err(Error::last_os_error());
+	pub(super) async fn recv_task<R: AsyncRead + Unpin, T: Decryptor>(rx: &mut R, ch: &mut Sender<Buffer>, mut rekey: RekeyReceiver<T>, codec: Codec, received: &AtomicU64, keepalive: Option<Keepalive>, mut shaper: Shaper) -> CloseReason {
+		loop {
+			// Read the next record, as long as the peer's still alive:
+			let mut buf: Buffer = match Keepalive::watch(keepalive, codec.read(rx)).await {
+				Ok(Some(buf)) => buf,
+				Ok(None) => return CloseReason::Remote,	// Hung up in between records.
+				Err(e) => return e.into(),
+			};
+			received.fetch_add(framing::wire_size(buf.len()), Ordering::Relaxed);
+
+			// Try to decrypt the message:
+			if let Err(e) = rekey.open(&mut buf) {
+				return e.into();
+			}
+			match buf.first() {
+				Some(&tag @ (keepalive::RECORD_DATA | shaping::RECORD_INTERACTIVE)) => {
+					// Wait for our turn before reading any further, if this is shaped:
+					buf.advance(1);
+					shaper.pass(tag, buf.len()).await;
+					if let Err(_) = ch.send(buf).await {
+						// Try to send the decrypted message down the channel, and if that fails, then the `Receiver` must have been dropped, so the connection should be closed:
+						return CloseReason::Local;
+					}
+				},
+				Some(&keepalive::RECORD_HEARTBEAT) => (),	// Just a sign of life.
+				Some(&rekey::RECORD_REKEY) => {
+					buf.advance(1);
+					if let Err(e) = rekey.receive(buf).await {
+						return e.into();
+					}
+				},
+				Some(&tag) => return ConnectionError::from(FramingError::UnknownType(tag)).into(),
+				None => return ConnectionError::from(FramingError::Truncated { expected: 1, received: 0 }).into(),
 			}
-		} {
-			// Run if an error occures in the loop above:
-			eprintln!("an error occured on a receiver task connected to {}: {}", rx.get_ref().peer_addr().unwrap(), e);
-		} else {
-			// If all is well:
-			eprintln!("closed receiver task connected to {}", rx.get_ref().peer_addr().unwrap());
 		}
-		return;
 	}
 
-}
-
-impl Connection for TcpConnection {
-	type Error = tokio::io::Error;
+	/**
+		Runs the handshake over an already-connected byte stream, then spawns the send and receive tasks.
+		The key exchange gets skipped if we're holding a ticket from `peer` that it takes (see `resumption`).
+		The stream goes through the impairment simulator first, if that's turned on (see `impair`).
+		`events`: where to report the link opening and closing.
+		`tickets`: the tickets to present (clients) or redeem (servers).
+		`peer`: who's on the other end.
+		Returns the resulting link to the remote host.
+	*/
+	pub(super) async fn start<W, R>(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, tx: W, rx: R, peer: Endpoint) -> Result<Link, ConnectionError>
+	where
+		W: AsyncWrite + Unpin + Send + 'static,
+		R: AsyncRead + Unpin + Send + 'static,
+	{
+		return Self::start_with(config, events, Keys::Handshake(tickets), tx, rx, peer).await;
+	}
 
+	/// Like `start`, but with the keys coming from wherever `keys` says.
+	pub(super) async fn start_with<W, R>(config: &ConnectionConfiguration, events: &Events, keys: Keys<'_>, tx: W, rx: R, peer: Endpoint) -> Result<Link, ConnectionError>
+	where
+		W: AsyncWrite + Unpin + Send + 'static,
+		R: AsyncRead + Unpin + Send + 'static,
+	{
+		if let Some(settings) = &config.impairment {
+			let (tx, rx) = impair::stream(settings, tx, rx);
+			return Self::establish(config, events, keys, tx, rx, peer).await;
+		} else {
+			return Self::establish(config, events, keys, tx, rx, peer).await;
+		}
+	}
 
-	fn new(config: ConnectionConfiguration) -> Self {
-		return Self {
-			listener: None,
-			config: config,
+	/// Does the actual work of `start`, over the stream as it's going to be used.
+	async fn establish<W, R>(config: &ConnectionConfiguration, events: &Events, keys: Keys<'_>, mut tx: W, mut rx: R, peer: Endpoint) -> Result<Link, ConnectionError>
+	where
+		W: AsyncWrite + Unpin + Send + 'static,
+		R: AsyncRead + Unpin + Send + 'static,
+	{
+		// Agree on how big records can get and which algorithms to use (presenting a ticket, if we've got one and we're going to need it):
+		let codec: Codec = Codec::negotiate(config, &mut tx, &mut rx).await?;
+		let ours: Option<Ticket> = match keys {
+			Keys::Handshake(tickets) => tickets.take(&peer),
+			#[cfg(feature = "quic")]
+			Keys::Shared(..) => None,
+		};
+		let (algorithms, hellos, theirs) = Algorithms::negotiate(config, &codec, ours.as_ref().map(|ticket| { ticket.sealed() }).unwrap_or_default(), &mut tx, &mut rx).await?;
+		eprintln!("Negotiated {} with {}.", algorithms, peer);
+		let context: Vec<u8> = [HANDSHAKE_CONTEXT, &(codec.limit() as u64).to_le_bytes(), &hellos].concat();
+
+		// Then make the keys/crypto thingies, from the shared secret if there is one, the ticket if it was taken, or from a key exchange if neither:
+		let secret: Option<(Secret, bool)> = match keys {
+			Keys::Handshake(tickets) => {
+				let settled: Option<(Secret, bool)> = resumption::settle(&codec, tickets, ours, &theirs.ticket, &mut tx, &mut rx).await?;
+				if let Some(_) = settled {
+					eprintln!("Resumed from a ticket with {}.", peer);
+				}
+				settled
+			},
+			#[cfg(feature = "quic")]
+			Keys::Shared(secret, dialled) => Some((secret.clone(), dialled)),
+		};
+		let (schedule, id) = match secret {
+			Some((secret, presenter)) => {
+				let schedule: KeySchedule = KeySchedule::resume(&secret, presenter, &context);
+				let id: SessionId = roaming::session_id(&schedule.resumption()[..], schedule.transcript());
+				(schedule, id)
+			},
+			None => Self::exchange_keys(&algorithms, &context, &mut tx, &mut rx).await?,
 		};
+		let resumption: Secret = schedule.resumption();
+		let (encryptor, decryptor) = algorithms.crypto.generate(&schedule);
+		let (send_rekey, recv_rekey) = Rekey::new(config, &algorithms.kex, &schedule, encryptor, decryptor);
+
+		// Make the channels:
+		let (send_sender, mut send_receiver) = mpsc::channel::<Buffer>(Self::CHANNEL_BUFFER_SIZE);
+		let (mut recv_sender, recv_receiver) = mpsc::channel::<Buffer>(Self::CHANNEL_BUFFER_SIZE);
+
+		// Spawn the tasks, counting what goes over the wire:
+		let sent: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+		let received: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+		let send_sent: Arc<AtomicU64> = sent.clone();
+		let recv_received: Arc<AtomicU64> = received.clone();
+		let (heartbeat, watch): (Option<Keepalive>, Option<Keepalive>) = (Keepalive::new(config), Keepalive::expect(config, theirs.keepalive));
+		let (outbound, inbound) = Shaper::pair(config, &peer);
+		let pool: BufferPool = codec.pool().clone();
+		let limit: usize = codec.limit() - 1 - algorithms.crypto.overhead();	// Less the record's tag, and what encrypting it adds.
+		let send_codec: Codec = codec.clone();
+		let send: JoinHandle<CloseReason> = task::spawn(async move { Self::send_task(&mut tx, &mut send_receiver, send_rekey, send_codec, &send_sent, heartbeat, outbound).await });	// Send task.
+		let recv: JoinHandle<CloseReason> = task::spawn(async move { Self::recv_task(&mut rx, &mut recv_sender, recv_rekey, codec, &recv_received, watch, inbound).await });	// Receive task.
+
+		// Report it (it's fine if nobody's listening), and keep an eye on it until it closes:
+		let _ = events.send(ConnectionEvent::Opened { peer: peer.clone() });
+		task::spawn(Self::supervise(send, recv, events.clone(), peer.clone(), sent, received));
+
+		// Return the link:
+		return Ok(Link {
+			tx: send_sender,
+			rx: recv_receiver,
+			pool: pool,
+			id: id,
+			peer: peer,
+			resumption: resumption,
+			limit: limit,
+			partial: Vec::new(),
+		});
 	}
 
-	async fn listen(&mut self) -> Result<(), Self::Error> {
-		// Bind a socket and set the field with it:
-		let sock: TcpSocket = TcpSocket::new_v6()?;	// IPv6.
-		sock.set_reuseport(true)?;	// So that all connections can use the same port.
-		sock.bind((self.config.addr, self.config.port).into())?;	// Actually bind it.
-		self.listener = Some(sock.listen(1)?);
-		eprintln!("Server listening on [{}]:{}.", self.config.addr, self.config.port);
-		return Ok(());
+	/// Waits for both of a link's tasks to finish, then reports why it closed (an error from either one wins over a clean close).
+	async fn supervise(mut send: JoinHandle<CloseReason>, mut recv: JoinHandle<CloseReason>, events: Events, peer: Endpoint, sent: Arc<AtomicU64>, received: Arc<AtomicU64>) {
+		let (first, second) = tokio::select! {
+			reason = &mut send => (reason, recv.await),
+			reason = &mut recv => (reason, send.await),
+		};
+		let [first, second] = [first, second].map(|reason| {
+			reason.unwrap_or_else(|e| { ConnectionError::Io(Error::other(e)).into() })
+		});
+		let reason: CloseReason = match (first, second) {
+			(CloseReason::Failed(e), _) | (_, CloseReason::Failed(e)) => CloseReason::Failed(e),
+			(first, _) => first,
+		};
+		eprintln!("Closed link to {}: {}.", peer, reason);
+		let _ = events.send(ConnectionEvent::Closed {
+			peer: peer,
+			reason: reason,
+			bytes_sent: sent.load(Ordering::Relaxed),
+			bytes_received: received.load(Ordering::Relaxed),
+		});
 	}
 
-	async fn accept(&mut self) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Self::Error> {
-		
-		// Check if we're supposed to be listening:
-		if let Some(listener) = &self.listener {
-
-			// If we are, accept one connection:
-			let (stream, address) = listener.accept().await?;
+	/**
+		Accepts connections, handing each one off to its own handshake task, until the `TcpConnection` is dropped.
+		`websocket`: whether clients may also come in through WebSocket upgrades, on the same port.
+	*/
+	async fn accept_task(listener: TcpListener, admission: Admission, websocket: bool) {
+		loop {
+			let (stream, address) = tokio::select! {
+				accepted = listener.accept() => match accepted {
+					Ok(accepted) => accepted,
+					Err(e) => {
+						eprintln!("Server: failed to accept a connection: {}", e);
+						time::sleep(ACCEPT_ERROR_DELAY).await;
+						continue;
+					},
+				},
+				_ = admission.closed() => return,
+			};
 			eprintln!("Server: new connection from {}.", &address);
 
-			// And split the stream into a sender and a receiver:
+			// Let the WebSocket side see whether it's one of its own, if need be:
+			#[cfg(feature = "websocket")]
+			if websocket {
+				WebSocketConnection::admit(stream, address, &admission, true);
+				continue;
+			}
+			#[cfg(not(feature = "websocket"))]
+			let _ = websocket;
+
+			// Split the stream into a sender and a receiver, and buffer them:
 			let (rx_u, tx_u) = stream.into_split();
-			let mut tx: BufWriter<OwnedWriteHalf> = BufWriter::new(tx_u);
-			let mut rx: BufReader<OwnedReadHalf> = BufReader::new(rx_u);
+			let tx: BufWriter<OwnedWriteHalf> = BufWriter::new(tx_u);
+			let rx: BufReader<OwnedReadHalf> = BufReader::new(rx_u);
+			admission.spawn(tx, rx, Endpoint::Inet(address), Some(address.ip()), None);
+		}
+	}
 
-			// Make the keys/crypto thingies:
-			let (mut encryptor, mut decryptor) = self.exchange_keys(&mut tx, &mut rx).await?;
+	/// Connects to `remote`, through the configured proxy if there is one.
+	pub(super) async fn stream(config: &ConnectionConfiguration, remote: &Endpoint) -> Result<TcpStream, ConnectionError> {
+		// Connect from the configured address and port, if it's in the right family (the proxy gets to resolve `remote` itself, if we're going through one):
+		let local: Option<SocketAddr> = config.endpoint.socket_addr();
+		let stream: TcpStream = if let Some(proxy) = &config.proxy {
+			let mut stream: TcpStream = resolve::connect(&proxy.endpoint, local).await?;
+			proxy::negotiate(&mut stream, proxy, remote).await?;
+			stream
+		} else {
+			resolve::connect(remote, local).await?
+		};
+		return Ok(stream);
+	}
 
-			// Make the channels:
-			let (send_sender, mut send_receiver) = mpsc::channel::<Vec<u8>>(Self::CHANNEL_BUFFER_SIZE);
-			let (mut recv_sender, recv_receiver) = mpsc::channel::<Vec<u8>>(Self::CHANNEL_BUFFER_SIZE);
+	/// Binds a listening socket to `local`, or takes the one the service manager bound for us, if there is one.
+	pub(super) fn bind(local: SocketAddr) -> Result<TcpListener, ConnectionError> {
+		if let Some(listener) = activation::tcp(local) {
+			listener.set_nonblocking(true)?;
+			eprintln!("Server: using the socket passed in for {}.", local);
+			return Ok(TcpListener::from_std(listener)?);
+		} else {
+			let sock: TcpSocket = resolve::socket(&local)?;	// IPv4 or IPv6, whichever the address is.
+			sock.set_reuseport(true)?;	// So that all connections can use the same port.
+			sock.bind(local)?;	// Actually bind it.
+			return Ok(sock.listen(LISTEN_BACKLOG)?);
+		}
+	}
 
-			// Spawn the tasks, and add their abort handles to the internal vector:
-			task::spawn(async move { Self::send_task(&mut tx, &mut send_receiver, &mut encryptor).await });	// Send task.
-			task::spawn(async move { Self::recv_task(&mut rx, &mut recv_sender, &mut decryptor).await });	// Receive task.
+	/// Connects to `remote` (through the configured proxy, if there is one), and runs the handshake.
+	async fn dial(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, remote: &Endpoint) -> Result<Link, ConnectionError> {
+		let stream: TcpStream = Self::stream(config, remote).await?;
 
-			// Return the channels:
-			return Ok((send_sender, recv_receiver));
+		// Split the stream:
+		let (rx_u, tx_u) = stream.into_split();
+
+		// And buffer them:
+		let tx: BufWriter<OwnedWriteHalf> = BufWriter::new(tx_u);
+		let rx: BufReader<OwnedReadHalf> = BufReader::new(rx_u);
+
+		// Do the handshake, and spin up the tasks:
+		return Self::start(config, events, tickets, tx, rx, remote.clone()).await;
+	}
+
+	/// Starts a session with the server at `endpoint` (connected through to `target`, if there is one).
+	async fn open(&mut self, endpoint: Endpoint, target: Option<Endpoint>) -> Result<Multiplexer, ConnectionError> {
+
+		// First, make sure that this isn't supposed to be a server:
+		if let None = self.incoming {
+			// All good? Connect:
+			let link: Link = Self::dial(&self.config, &self.events, self.sessions.tickets(), &endpoint).await?;
+
+			// If the connection drops, we'll dial the same place again (looking it up again, if it's a hostname) and resume the session:
+			let config: ConnectionConfiguration = self.config.clone();
+			let events: Events = self.events.clone();
+			let tickets: Tickets = self.sessions.tickets().clone();
+			let redial: Redial = Box::new(move || {
+				let config: ConnectionConfiguration = config.clone();
+				let events: Events = events.clone();
+				let tickets: Tickets = tickets.clone();
+				let endpoint: Endpoint = endpoint.clone();
+				return Box::pin(async move { Self::dial(&config, &events, &tickets, &endpoint).await });
+			});
+			return Ok(self.sessions.open(link, target, Some(redial)).await?);
 		} else {
-			// If this `struct` _shouldn't_ be listening:
-			panic!("this `TcpConnection` is not listening!");
+			// If this `struct` shouldn't be connecting:
+			return Err(ConnectionError::Listening);
 		}
 
 	}
 
-	async fn connect(&mut self, addr: Ipv6Addr, port: u16) -> Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>), Self::Error> {
-		
-		// First, make sure that this isn't supposed to be a server:
-		if let None = self.listener {
-			// All good? Continue by setting up a socket:
-			let sock: TcpSocket = TcpSocket::new_v6()?;	// IPv6.
-			sock.set_reuseport(true)?;	// So that multiple connections can use the same port.
-			sock.bind((self.config.addr, self.config.port).into())?;	// Use the configured listen address and port to connect to the remote server.
+}
 
-			// Now connect, and split the stream:
-			let (rx_u, tx_u) = sock.connect((addr, port).into()).await?.into_split();
+impl Connection for TcpConnection {
+	type Error = ConnectionError;
 
-			// And buffer them:
-			let mut tx: BufWriter<OwnedWriteHalf> = BufWriter::new(tx_u);
-			let mut rx: BufReader<OwnedReadHalf> = BufReader::new(rx_u);
 
-			// Make the encryptor and decryptor:
-			let (mut encryptor, mut decryptor) = self.exchange_keys(&mut tx, &mut rx).await?;
+	fn new(config: ConnectionConfiguration) -> Self {
+		let events: Events = error::new_events();
+		return Self {
+			incoming: None,
+			sessions: Sessions::new(&config, &events),
+			events: events,
+			config: config,
+		};
+	}
 
-			// Make the channels:
-			let (send_sender, mut send_receiver) = mpsc::channel::<Vec<u8>>(Self::CHANNEL_BUFFER_SIZE);
-			let (mut recv_sender, recv_receiver) = mpsc::channel::<Vec<u8>>(Self::CHANNEL_BUFFER_SIZE);
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return self.events.subscribe();
+	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		let listener: TcpListener = Self::bind(inet(&self.config.endpoint)?)?;
+
+		// Start accepting connections in the background:
+		let (admission, incoming) = Admission::new(&self.config, &self.sessions, &self.events);
+		task::spawn(Self::accept_task(listener, admission, self.config.accept_websocket));
+		self.incoming = Some(incoming);
+		eprintln!("Server listening on {}.", self.config.endpoint);
+		if let Err(e) = notify::notify(State::Ready) {
+			eprintln!("Failed to tell the service manager that we're ready: {}", e);
+		}
+		return Ok(());
+	}
 
-			// Spawn the tasks, adding their abort handles to the vector:
-			task::spawn(async move { Self::send_task(&mut tx, &mut send_receiver, &mut encryptor).await });	// Send task.
-			task::spawn(async move { Self::recv_task(&mut rx, &mut recv_sender, &mut decryptor).await });	// Receive task.
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
 
-			return Ok((send_sender, recv_receiver));
+		// Check if we're supposed to be listening:
+		if let Some(incoming) = &mut self.incoming {
+			// Wait for a connection that's made it through its handshake (ones that resume an existing session don't show up here):
+			return incoming.recv().await.ok_or(Error::other("stopped accepting connections").into());
 		} else {
-			// If this `struct` shouldn't be connecting:
-			panic!("this `TcpConnection` should not be connecting!");
+			// If this `struct` _shouldn't_ be listening:
+			return Err(ConnectionError::NotListening);
 		}
 
 	}
 
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return self.open(endpoint, None).await;
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.open(via, Some(target)).await?.into_main());
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		if self.incoming.is_some() && let Err(e) = notify::notify(State::Stopping) {
+			eprintln!("Failed to tell the service manager that we're stopping: {}", e);
+		}
+		self.sessions.disconnect(disconnect).await;
+		return Ok(());
+	}
+
 }
 
 
 #[tokio::test]
 async fn test_tcp_connection() {
+	use std::net::Ipv6Addr;
 
 	let message: &[u8] = b"The missile knows where it is at all times; it knows this because it knows where it isn't.";
 	eprintln!("Client's message: {}", str::from_utf8(message).unwrap());
 
 	// Need a configuration first:
 	let client_conf: ConnectionConfiguration = ConnectionConfiguration {
-		addr: Ipv6Addr::LOCALHOST,
-		port: 54320,
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54320).into()),
 		connection: super::Implementation::Tcp,
-		crypto: crate::crypto::Implementation::AesGcm,
-		kex: crate::kex::Implementation::Kyberlib,
+		crypto: vec![crate::crypto::Implementation::AesGcm],
+		..ConnectionConfiguration::default()
 	};
 	let server_conf: ConnectionConfiguration = ConnectionConfiguration {
-		addr: Ipv6Addr::LOCALHOST,
-		port: 54321,
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54321).into()),
 		connection: super::Implementation::Tcp,
-		crypto: crate::crypto::Implementation::AesGcm,
-		kex: crate::kex::Implementation::Kyberlib,
+		crypto: vec![crate::crypto::Implementation::AesGcm],
+		..ConnectionConfiguration::default()
 	};
 
 	// Make a server:
 	let mut server: TcpConnection = TcpConnection::new(server_conf);
-	
+
 	// Make a client:
 	let mut client: TcpConnection = TcpConnection::new(client_conf);
 
-	// Runs the server:
+	// Runs the server (it's listening as soon as `listen` returns, so there's no need to wait for it):
+	server.listen().await.unwrap();
 	task::spawn(async move {
-		server.listen().await.unwrap();
-		let (tx, mut rx) = server.accept().await.unwrap();
-		
+		let (_, tx, mut rx) = server.accept().await.unwrap();
+
 		// Simple echo server:
 		while let Some(data) = rx.recv().await {
 			eprintln!("Server received: {}", str::from_utf8(&data).unwrap());
@@ -278,12 +592,88 @@ async fn test_tcp_connection() {
 		}
 		return;
 	});
-	tokio::time::sleep(std::time::Duration::from_secs(1)).await;	// So that the server has time to start.
 
 	// Runs a client:
-	let (ctx, mut crx) = client.connect(Ipv6Addr::LOCALHOST, 54321).await.unwrap();
-	ctx.send(message.to_vec()).await.unwrap();
-	let response: Vec<u8> = crx.recv().await.unwrap();
+	let (ctx, mut crx) = client.connect(Endpoint::Inet((Ipv6Addr::LOCALHOST, 54321).into())).await.unwrap();
+	ctx.send(Bytes::copy_from_slice(message)).await.unwrap();
+	let response: Bytes = crx.recv().await.unwrap();
 	eprintln!("Client heard: {}", str::from_utf8(&response).unwrap());
 	assert_eq!(response, message.to_vec());
-}
\ No newline at end of file
+}
+
+/**
+	Bulk throughput over TCP on the loopback interface, with the whole stack (encryption, framing, sessions) in the way.
+	Then, for comparison, the part that pooling changed on its own: getting records off the wire and into the application's
+	hands, the old way (a fresh `Vec` for every record, copied into another one for the application) and the pooled way
+	(a buffer out of the pool, frozen into `Bytes`).
+	It's a benchmark rather than a test, so it only runs when asked for:
+	`cargo test --release --bin qshd bench_tcp_throughput -- --ignored --nocapture`
+*/
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_tcp_throughput() {
+	use std::net::Ipv6Addr;
+	use tokio::time::Instant;
+
+	const CHUNK_SIZE: usize = 64 * 1024;
+	const TOTAL_SIZE: usize = 1 << 30;
+
+	let conf = |port: u16| {
+		return ConnectionConfiguration {
+			endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, port).into()),
+			..ConnectionConfiguration::default()
+		};
+	};
+	let mut server: TcpConnection = TcpConnection::new(conf(54411));
+	server.listen().await.unwrap();
+	let sink = task::spawn(async move {
+		let (_, _tx, mut rx) = server.accept().await.unwrap();
+		let mut received: usize = 0;
+		while received < TOTAL_SIZE {
+			received += rx.recv().await.unwrap().len();
+		}
+	});
+
+	// Time how long it takes the server to get it all:
+	let mut client: TcpConnection = TcpConnection::new(conf(54410));
+	let (ctx, _crx) = client.connect(Endpoint::Inet((Ipv6Addr::LOCALHOST, 54411).into())).await.unwrap();
+	let started: Instant = Instant::now();
+	for _ in 0..TOTAL_SIZE / CHUNK_SIZE {
+		ctx.send(Bytes::from(vec![0x55_u8; CHUNK_SIZE])).await.unwrap();
+	}
+	sink.await.unwrap();
+	let elapsed: Duration = started.elapsed();
+	eprintln!("{} MiB in {:.2?}: {:.1} MiB/s", TOTAL_SIZE >> 20, elapsed, (TOTAL_SIZE >> 20) as f64 / elapsed.as_secs_f64());
+
+	// Makes `TOTAL_SIZE` bytes worth of records with `deliver`, and hands them to another task to be dropped (like the application would), returning how many MiB/s that comes to:
+	const RECORD_SIZE: usize = 16 * 1024;
+	async fn rate<T: Send + 'static>(deliver: impl Fn(&[u8]) -> T) -> f64 {
+		let wire: Vec<u8> = vec![0x55_u8; RECORD_SIZE];
+		let (tx, mut rx) = mpsc::channel::<T>(TcpConnection::CHANNEL_BUFFER_SIZE);
+		let application = task::spawn(async move {
+			while let Some(record) = rx.recv().await {
+				drop(std::hint::black_box(record));
+			}
+		});
+		let started: Instant = Instant::now();
+		for _ in 0..TOTAL_SIZE / RECORD_SIZE {
+			tx.send(deliver(std::hint::black_box(&wire))).await.unwrap();
+		}
+		drop(tx);
+		application.await.unwrap();
+		return (TOTAL_SIZE >> 20) as f64 / started.elapsed().as_secs_f64();
+	}
+	let unpooled: f64 = rate(|wire: &[u8]| {
+		let mut record: Vec<u8> = vec![0; RECORD_SIZE];
+		record.copy_from_slice(wire);
+		return std::hint::black_box(record).to_vec();
+	}).await;
+	let pool: BufferPool = BufferPool::new();
+	let pooled: f64 = rate(|wire: &[u8]| {
+		let mut record: Buffer = pool.take();
+		record.resize(RECORD_SIZE);
+		record.copy_from_slice(wire);
+		return std::hint::black_box(record).freeze();
+	}).await;
+	eprintln!("Records to the application: {:.1} MiB/s unpooled, {:.1} MiB/s pooled ({:.2}x).", unpooled, pooled, pooled / unpooled);
+}
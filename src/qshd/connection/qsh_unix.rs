@@ -0,0 +1,236 @@
+/*!
+	A way to transport data over Unix domain sockets.
+	Handy when the server's in a container or VM, and the host reaches it through a bind-mounted socket.
+*/
+
+// External stuff:
+use bytes::Bytes;
+use tokio::{
+	fs,
+	io::{BufReader, BufWriter, Error, ErrorKind},
+	net::{
+		unix::{ OwnedReadHalf, OwnedWriteHalf, }, UnixListener, UnixStream
+	},
+	sync::{
+		broadcast,
+		mpsc::{
+			Receiver, Sender
+		},
+	},
+	task,
+	time::{self, Duration},
+};
+use std::path::{Path, PathBuf};
+
+// Internal stuff:
+use super::{
+	activation,
+	admission::{Accepted, Admission},
+	error::{self, ConnectionError, ConnectionEvent, Events},
+	resumption::Tickets,
+	roaming::{Link, Redial, Sessions},
+	Connection,
+	ConnectionConfiguration,
+	Disconnect,
+	Endpoint,
+	Multiplexer,
+	TcpConnection,
+};
+
+
+/// How long to back off for when accepting fails (say, because we're out of file descriptors).
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+pub struct UnixConnection {
+	incoming: Option<Receiver<Accepted>>,
+	sessions: Sessions,
+	events: Events,
+	config: ConnectionConfiguration,
+} impl UnixConnection {
+
+	/// The socket path of `endpoint`.
+	fn path(endpoint: &Endpoint) -> Result<&Path, Error> {
+		if let Endpoint::Unix(path) = endpoint {
+			return Ok(path);
+		} else {
+			return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a Unix socket", endpoint)));
+		}
+	}
+
+	/// Connects to the socket at `path`, and runs the handshake.
+	async fn dial(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, path: &Path) -> Result<Link, ConnectionError> {
+		// Connect, and split the stream:
+		let (rx_u, tx_u) = UnixStream::connect(path).await?.into_split();
+
+		// And buffer them:
+		let tx: BufWriter<OwnedWriteHalf> = BufWriter::new(tx_u);
+		let rx: BufReader<OwnedReadHalf> = BufReader::new(rx_u);
+
+		// Do the handshake, and spin up the tasks:
+		return TcpConnection::start(config, events, tickets, tx, rx, Endpoint::Unix(path.to_path_buf())).await;
+	}
+
+	/// Accepts connections on `endpoint`, handing each one off to its own handshake task, until the `UnixConnection` is dropped.
+	async fn accept_task(listener: UnixListener, admission: Admission, endpoint: Endpoint) {
+		loop {
+			let stream: UnixStream = tokio::select! {
+				accepted = listener.accept() => match accepted {
+					Ok((stream, _)) => stream,
+					Err(e) => {
+						eprintln!("Server: failed to accept a connection: {}", e);
+						time::sleep(ACCEPT_ERROR_DELAY).await;
+						continue;
+					},
+				},
+				_ = admission.closed() => return,
+			};
+			eprintln!("Server: new connection on {}.", endpoint);
+
+			// Split the stream into a sender and a receiver, and buffer them (these don't have a useful remote address, so we name them after the socket):
+			let (rx_u, tx_u) = stream.into_split();
+			let tx: BufWriter<OwnedWriteHalf> = BufWriter::new(tx_u);
+			let rx: BufReader<OwnedReadHalf> = BufReader::new(rx_u);
+			admission.spawn(tx, rx, endpoint.clone(), None, None);
+		}
+	}
+
+	/// Starts a session with the server at `endpoint` (connected through to `target`, if there is one).
+	async fn open(&mut self, endpoint: Endpoint, target: Option<Endpoint>) -> Result<Multiplexer, ConnectionError> {
+
+		// First, make sure that this isn't supposed to be a server:
+		if let None = self.incoming {
+			// All good? Connect:
+			let path: PathBuf = Self::path(&endpoint)?.to_path_buf();
+			let link: Link = Self::dial(&self.config, &self.events, self.sessions.tickets(), &path).await?;
+
+			// If the connection drops (say, the server restarted), we'll dial again and resume the session:
+			let config: ConnectionConfiguration = self.config.clone();
+			let events: Events = self.events.clone();
+			let tickets: Tickets = self.sessions.tickets().clone();
+			let redial: Redial = Box::new(move || {
+				let config: ConnectionConfiguration = config.clone();
+				let events: Events = events.clone();
+				let tickets: Tickets = tickets.clone();
+				let path: PathBuf = path.clone();
+				return Box::pin(async move { Self::dial(&config, &events, &tickets, &path).await });
+			});
+			return Ok(self.sessions.open(link, target, Some(redial)).await?);
+		} else {
+			// If this `struct` shouldn't be connecting:
+			return Err(ConnectionError::Listening);
+		}
+
+	}
+
+}
+
+impl Connection for UnixConnection {
+	type Error = ConnectionError;
+
+
+	fn new(config: ConnectionConfiguration) -> Self {
+		let events: Events = error::new_events();
+		return Self {
+			incoming: None,
+			sessions: Sessions::new(&config, &events),
+			events: events,
+			config: config,
+		};
+	}
+
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return self.events.subscribe();
+	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		let path: &Path = Self::path(&self.config.endpoint)?;
+
+		// Use the socket the service manager bound for us, if there is one:
+		let listener: UnixListener = if let Some(listener) = activation::unix(path) {
+			listener.set_nonblocking(true)?;
+			eprintln!("Server: using the socket passed in for {}.", self.config.endpoint);
+			UnixListener::from_std(listener)?
+		} else {
+			// A socket left over from last time would make binding fail, so clear it out (but don't touch anything else):
+			if let Ok(metadata) = fs::symlink_metadata(path).await {
+				use std::os::unix::fs::FileTypeExt;
+				if metadata.file_type().is_socket() {
+					fs::remove_file(path).await?;
+				}
+			}
+			UnixListener::bind(path)?
+		};
+
+		// Start accepting connections in the background:
+		let (admission, incoming) = Admission::new(&self.config, &self.sessions, &self.events);
+		task::spawn(Self::accept_task(listener, admission, self.config.endpoint.clone()));
+		self.incoming = Some(incoming);
+		eprintln!("Server listening on {}.", self.config.endpoint);
+		return Ok(());
+	}
+
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
+
+		// Check if we're supposed to be listening:
+		if let Some(incoming) = &mut self.incoming {
+			// Wait for a connection that's made it through its handshake (ones that resume an existing session don't show up here):
+			return incoming.recv().await.ok_or(Error::other("stopped accepting connections").into());
+		} else {
+			// If this `struct` _shouldn't_ be listening:
+			return Err(ConnectionError::NotListening);
+		}
+
+	}
+
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return self.open(endpoint, None).await;
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.open(via, Some(target)).await?.into_main());
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		self.sessions.disconnect(disconnect).await;
+		return Ok(());
+	}
+
+}
+
+
+#[tokio::test]
+async fn test_unix_connection() {
+
+	let message: &[u8] = b"Over a socket in the filesystem.";
+	let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+	let socket: Endpoint = Endpoint::Unix(directory.path().join("qshd.socket"));
+
+	// Need a configuration first (clients don't bind anything, so theirs only matters for the crypto):
+	let server_conf: ConnectionConfiguration = ConnectionConfiguration {
+		endpoint: socket.clone(),
+		connection: super::Implementation::Unix,
+		crypto: vec![crate::crypto::Implementation::AesGcm],
+		..ConnectionConfiguration::default()
+	};
+	let client_conf: ConnectionConfiguration = server_conf.clone();
+
+	// Make a server and a client:
+	let mut server: UnixConnection = UnixConnection::new(server_conf);
+	let mut client: UnixConnection = UnixConnection::new(client_conf);
+
+	// Runs the server:
+	server.listen().await.unwrap();
+	tokio::task::spawn(async move {
+		let (_, tx, mut rx) = server.accept().await.unwrap();
+
+		// Simple echo server:
+		while let Some(data) = rx.recv().await {
+			tx.send(data).await.unwrap();
+		}
+	});
+
+	// Runs a client:
+	let (ctx, mut crx) = client.connect(socket).await.unwrap();
+	ctx.send(Bytes::copy_from_slice(message)).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), message.to_vec());
+}
@@ -0,0 +1,304 @@
+/*!
+	A way to transport data over WebSocket, for servers that can only be reached through an HTTP(S) reverse proxy.
+	The client connects over TCP (and TLS, if the proxy wants HTTPS), asks for an upgrade to WebSocket,
+	then runs the usual handshake and records inside binary frames (see `websocket`). Servers can take
+	upgrades on a port of their own with this, or on their raw TCP port (see `accept_websocket`).
+*/
+
+// External stuff:
+use rustls::{
+	crypto::ring as rustls_ring,
+	pki_types::ServerName,
+	ClientConfig, RootCertStore,
+};
+use bytes::Bytes;
+use tokio::{
+	io::{self, AsyncRead, AsyncWrite, BufReader, BufWriter, DuplexStream, Error, ErrorKind, ReadHalf, WriteHalf},
+	net::{TcpListener, TcpStream},
+	sync::{
+		broadcast,
+		mpsc::{
+			Receiver, Sender
+		},
+	},
+	task,
+	time::{self, Duration, Instant},
+};
+use tokio_rustls::TlsConnector;
+use std::{
+	net::SocketAddr,
+	sync::Arc,
+};
+
+// Internal stuff:
+use super::{
+	admission::{Accepted, Admission},
+	error::{self, ConnectionError, ConnectionEvent, Events},
+	inet,
+	resumption::Tickets,
+	roaming::{Link, Redial, Sessions},
+	websocket::{self, Role},
+	Connection,
+	ConnectionConfiguration,
+	Disconnect,
+	Endpoint,
+	Multiplexer,
+	TcpConnection,
+};
+
+
+/// How long to back off for when accepting fails (say, because we're out of file descriptors).
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait between looks at the start of a connection, while working out whether it's a WebSocket upgrade.
+const SNIFF_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct WebSocketConnection {
+	incoming: Option<Receiver<Accepted>>,
+	sessions: Sessions,
+	events: Events,
+	config: ConnectionConfiguration,
+} impl WebSocketConnection {
+
+	/// Connects to `remote` (over TLS, if configured), upgrades to WebSocket, and runs the handshake.
+	async fn dial(config: &ConnectionConfiguration, events: &Events, tickets: &Tickets, remote: &Endpoint) -> Result<Link, ConnectionError> {
+		let stream: TcpStream = TcpConnection::stream(config, remote).await?;
+		let bridge: DuplexStream = if config.websocket_tls {
+			let host: ServerName<'static> = match remote {
+				Endpoint::Inet(addr) => ServerName::from(addr.ip()),
+				Endpoint::Host { name, .. } => ServerName::try_from(name.clone()).map_err(|e| { Error::new(ErrorKind::InvalidInput, e) })?,
+				_ => return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a host and port", remote)).into()),
+			};
+			Self::upgrade(Self::tls().connect(host, stream).await?, config, remote).await?
+		} else {
+			Self::upgrade(stream, config, remote).await?
+		};
+
+		// Split the bridge, and do the handshake over it:
+		let (rx, tx): (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) = io::split(bridge);
+		return TcpConnection::start(config, events, tickets, tx, rx, remote.clone()).await;
+	}
+
+	/// Asks the server on `stream` to switch to WebSocket. Returns the bridge to carry the connection over.
+	async fn upgrade<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut stream: S, config: &ConnectionConfiguration, remote: &Endpoint) -> Result<DuplexStream, Error> {
+		websocket::upgrade(&mut stream, &remote.to_string(), &config.websocket_path).await?;
+		return Ok(websocket::bridge(stream, Role::Client));
+	}
+
+	/// Makes a TLS connector that trusts the usual certificate authorities (reverse proxies have real certificates).
+	fn tls() -> TlsConnector {
+		let roots: RootCertStore = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+		let mut tls: ClientConfig = ClientConfig::builder_with_provider(Arc::new(rustls_ring::default_provider()))
+			.with_safe_default_protocol_versions()
+			.unwrap()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		tls.alpn_protocols = vec![b"http/1.1".to_vec()];	// WebSocket upgrades only work over HTTP/1.1.
+		return TlsConnector::from(Arc::new(tls));
+	}
+
+	/**
+		Server side: takes the WebSocket upgrade on a newly accepted `stream` in the background, then hands it to `admission`.
+		`raw`: whether it may be a raw qsh connection instead (when we share a port with `TcpConnection`); those get handed over as they are.
+	*/
+	pub(super) fn admit(mut stream: TcpStream, address: SocketAddr, admission: &Admission, raw: bool) {
+		let admission: Admission = admission.clone();
+		task::spawn(async move {
+			let deadline: Instant = Instant::now() + admission.handshake_timeout();
+			let upgraded: Result<bool, Error> = time::timeout_at(deadline, async {
+				// See how the client starts, if it could go either way:
+				if raw && !Self::sniff(&stream).await? {
+					return Ok(false);
+				}
+				websocket::accept(&mut stream).await?;
+				return Ok(true);
+			}).await.unwrap_or(Err(Error::from(ErrorKind::TimedOut)));
+			match upgraded {
+				Ok(true) => {
+					let (rx, tx) = io::split(websocket::bridge(stream, Role::Server));
+					admission.spawn(tx, rx, Endpoint::Inet(address), Some(address.ip()), None);
+				},
+				Ok(false) => {
+					let (rx, tx) = stream.into_split();
+					admission.spawn(BufWriter::new(tx), BufReader::new(rx), Endpoint::Inet(address), Some(address.ip()), None);
+				},
+				Err(e) => eprintln!("Server: WebSocket upgrade from {} failed: {}", address, e),
+			}
+		});
+	}
+
+	/// Waits for the first few bytes of `stream` (without taking them), and returns whether they're the start of a WebSocket upgrade.
+	async fn sniff(stream: &TcpStream) -> Result<bool, Error> {
+		let mut start: [u8; 4] = [0_u8; 4];
+		loop {
+			let length: usize = stream.peek(&mut start).await?;
+			if length == 0 {
+				return Err(Error::from(ErrorKind::UnexpectedEof));
+			} else if length == start.len() || !b"GET ".starts_with(&start[..length]) {
+				// Either it's all there, or it's already gone off script:
+				return Ok(websocket::is_upgrade(&start[..length]));
+			}
+			time::sleep(SNIFF_INTERVAL).await;	// Not enough to go on yet.
+		}
+	}
+
+	/// Accepts connections, handing each one off to be upgraded, until the `WebSocketConnection` is dropped.
+	async fn accept_task(listener: TcpListener, admission: Admission) {
+		loop {
+			let (stream, address) = tokio::select! {
+				accepted = listener.accept() => match accepted {
+					Ok(accepted) => accepted,
+					Err(e) => {
+						eprintln!("Server: failed to accept a connection: {}", e);
+						time::sleep(ACCEPT_ERROR_DELAY).await;
+						continue;
+					},
+				},
+				_ = admission.closed() => return,
+			};
+			eprintln!("Server: new connection from {}.", &address);
+			Self::admit(stream, address, &admission, false);
+		}
+	}
+
+	/// Starts a session with the server at `endpoint` (connected through to `target`, if there is one).
+	async fn open(&mut self, endpoint: Endpoint, target: Option<Endpoint>) -> Result<Multiplexer, ConnectionError> {
+
+		// First, make sure that this isn't supposed to be a server:
+		if let None = self.incoming {
+			// All good? Connect:
+			let link: Link = Self::dial(&self.config, &self.events, self.sessions.tickets(), &endpoint).await?;
+
+			// If the connection drops, we'll dial the same place again and resume the session:
+			let config: ConnectionConfiguration = self.config.clone();
+			let events: Events = self.events.clone();
+			let tickets: Tickets = self.sessions.tickets().clone();
+			let redial: Redial = Box::new(move || {
+				let config: ConnectionConfiguration = config.clone();
+				let events: Events = events.clone();
+				let tickets: Tickets = tickets.clone();
+				let endpoint: Endpoint = endpoint.clone();
+				return Box::pin(async move { Self::dial(&config, &events, &tickets, &endpoint).await });
+			});
+			return Ok(self.sessions.open(link, target, Some(redial)).await?);
+		} else {
+			// If this `struct` shouldn't be connecting:
+			return Err(ConnectionError::Listening);
+		}
+
+	}
+
+}
+
+impl Connection for WebSocketConnection {
+	type Error = ConnectionError;
+
+
+	fn new(config: ConnectionConfiguration) -> Self {
+		let events: Events = error::new_events();
+		return Self {
+			incoming: None,
+			sessions: Sessions::new(&config, &events),
+			events: events,
+			config: config,
+		};
+	}
+
+	fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+		return self.events.subscribe();
+	}
+
+	async fn listen(&mut self) -> Result<(), Self::Error> {
+		let listener: TcpListener = TcpConnection::bind(inet(&self.config.endpoint)?)?;
+
+		// Start accepting connections in the background:
+		let (admission, incoming) = Admission::new(&self.config, &self.sessions, &self.events);
+		task::spawn(Self::accept_task(listener, admission));
+		self.incoming = Some(incoming);
+		eprintln!("Server listening for WebSocket upgrades on {}.", self.config.endpoint);
+		return Ok(());
+	}
+
+	async fn accept_streams(&mut self) -> Result<(Endpoint, Multiplexer), Self::Error> {
+
+		// Check if we're supposed to be listening:
+		if let Some(incoming) = &mut self.incoming {
+			// Wait for a connection that's made it through its handshake (ones that resume an existing session don't show up here):
+			return incoming.recv().await.ok_or(Error::other("stopped accepting connections").into());
+		} else {
+			// If this `struct` _shouldn't_ be listening:
+			return Err(ConnectionError::NotListening);
+		}
+
+	}
+
+	async fn connect_streams(&mut self, endpoint: Endpoint) -> Result<Multiplexer, Self::Error> {
+		return self.open(endpoint, None).await;
+	}
+
+	async fn direct_connect(&mut self, via: Endpoint, target: Endpoint) -> Result<(Sender<Bytes>, Receiver<Bytes>), Self::Error> {
+		return Ok(self.open(via, Some(target)).await?.into_main());
+	}
+
+	async fn disconnect(&mut self, disconnect: Disconnect) -> Result<(), Self::Error> {
+		self.sessions.disconnect(disconnect).await;
+		return Ok(());
+	}
+
+}
+
+
+#[tokio::test]
+async fn test_websocket_connection() {
+	use std::net::Ipv6Addr;
+
+	// Simple echo server, for each session:
+	fn echo(tx: Sender<Bytes>, mut rx: Receiver<Bytes>) {
+		task::spawn(async move {
+			while let Some(data) = rx.recv().await {
+				tx.send(data).await.unwrap();
+			}
+		});
+	}
+	async fn round_trip<C: Connection<Error = ConnectionError>>(mut client: C, server: Endpoint) {
+		let (ctx, mut crx) = client.connect(server).await.unwrap();
+		ctx.send(Bytes::from_static(b"Through the front door.")).await.unwrap();
+		assert_eq!(crx.recv().await.unwrap(), b"Through the front door.".to_vec());
+	}
+	let client_conf = |port: u16| {
+		return ConnectionConfiguration {
+			endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, port).into()),
+			..ConnectionConfiguration::default()
+		};
+	};
+
+	// A server on a port of its own:
+	let dedicated: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54401).into());
+	let mut server: WebSocketConnection = WebSocketConnection::new(ConnectionConfiguration {
+		endpoint: dedicated.clone(),
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		while let Ok((_, tx, rx)) = server.accept().await {
+			echo(tx, rx);
+		}
+	});
+	round_trip(WebSocketConnection::new(client_conf(54400)), dedicated).await;
+
+	// And a TCP server that takes both raw connections and WebSocket upgrades on the same port:
+	let shared: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54402).into());
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: shared.clone(),
+		accept_websocket: true,
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	task::spawn(async move {
+		while let Ok((_, tx, rx)) = server.accept().await {
+			echo(tx, rx);
+		}
+	});
+	round_trip(WebSocketConnection::new(client_conf(54403)), shared.clone()).await;
+	round_trip(TcpConnection::new(client_conf(54404)), shared).await;
+}
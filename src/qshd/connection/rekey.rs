@@ -0,0 +1,342 @@
+/*!
+	Rekeying, so that a long-lived link doesn't use the same keys for its whole life. After enough
+	bytes, records or minutes under one key, the send task starts a fresh key exchange (with whichever
+	algorithm the handshake settled on) over the encrypted link, as records of its own:
+
+	- `OFFER`: the offering end's public key.
+	- `ANSWER`: the answering end's public key, and a client init to the offered one.
+	- `FINISH`: the offering end's server init. It has the new secret now, so it switches keys right after this record.
+	- `SWITCH`: the answering end has the new secret too, and switches keys right after this record.
+
+	The new secret gets mixed into the next generation of both traffic secrets (see `TrafficSecret::rekey`).
+	Each end switches the key it sends with right after saying so, and the other end switches the key it
+	receives with right after reading that; so every record is in exactly one key, and data keeps flowing
+	the whole time. If both ends offer at once, the offer with the bigger public key goes ahead. That's
+	why `Rekey` and `RekeyReceiver` hold the link's `Encryptor` and `Decryptor`: all records go through
+	them, so they can switch keys at exactly the right record.
+*/
+
+// External stuff:
+use tokio::{
+	sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+	time::{self, Duration, Instant},
+};
+use std::{
+	future,
+	mem,
+};
+
+// Internal stuff:
+use super::{
+	Buffer,
+	BufferPool,
+	ConnectionConfiguration,
+	ConnectionError,
+};
+use crate::{
+	crypto::{Decryptor, Encryptor, KeySchedule, TrafficSecret},
+	kex::{self, AnyKeyExchanger, KeyExchanger},
+};
+
+
+/// Tag for rekeying records.
+pub(super) const RECORD_REKEY: u8 = 3;
+
+/// Steps of a rekey (the byte after the tag).
+const STEP_OFFER: u8 = 0;
+const STEP_ANSWER: u8 = 1;
+const STEP_FINISH: u8 = 2;
+const STEP_SWITCH: u8 = 3;
+
+/// Where a rekey is up to.
+enum State {
+
+	/// Not rekeying.
+	Idle,
+
+	/// We offered, and are waiting for an answer.
+	Offered(AnyKeyExchanger),
+
+	/// We answered, and are waiting for the other end to finish.
+	Answered(AnyKeyExchanger),
+
+}
+
+
+/// The send task's side of rekeying: it encrypts records, runs the exchanges, and keeps track of how much each key's been used.
+pub(super) struct Rekey<T: Encryptor> {
+
+	// What records get encrypted with, and what to switch it to after the next one (if anything):
+	en: T,
+	switch: Option<TrafficSecret>,
+
+	// Which key exchange to use:
+	kex: kex::Implementation,
+
+	// How much a key can be used for (0 means no limit):
+	max_bytes: u64,
+	max_records: u64,
+	max_age: Option<Duration>,
+
+	// How much the current key's been used:
+	bytes: u64,
+	records: u64,
+	since: Instant,
+
+	// The current traffic secrets:
+	send: TrafficSecret,
+	recv: TrafficSecret,
+
+	// Where the current rekey is up to:
+	state: State,
+
+	// Rekeying records from the receive task, and where to send it new secrets:
+	messages: UnboundedReceiver<Buffer>,
+	secrets: UnboundedSender<TrafficSecret>,
+
+} impl<T: Encryptor> Rekey<T> {
+
+	/**
+		Makes one for a link whose handshake came up with `schedule`, along with the receive task's side.
+		`kex`: the key exchange the handshake used.
+		`en`, `de`: the link's ciphers, keyed from `schedule`.
+	*/
+	pub(super) fn new<D: Decryptor>(config: &ConnectionConfiguration, kex: &kex::Implementation, schedule: &KeySchedule, en: T, de: D) -> (Self, RekeyReceiver<D>) {
+		let (messages_tx, messages_rx) = mpsc::unbounded_channel::<Buffer>();
+		let (secrets_tx, secrets_rx) = mpsc::unbounded_channel::<TrafficSecret>();
+		let rekey: Self = Self {
+			en: en,
+			switch: None,
+			kex: kex.clone(),
+			max_bytes: config.rekey_bytes,
+			max_records: config.rekey_records,
+			max_age: if config.rekey_minutes == 0 { None } else { Some(Duration::from_secs(config.rekey_minutes * 60)) },
+			bytes: 0,
+			records: 0,
+			since: Instant::now(),
+			send: schedule.send().clone(),
+			recv: schedule.recv().clone(),
+			state: State::Idle,
+			messages: messages_rx,
+			secrets: secrets_tx,
+		};
+		return (rekey, RekeyReceiver { de: de, messages: messages_tx, secrets: secrets_rx });
+	}
+
+	/// Encrypts `record` in place, then switches keys if that was the last record under the old one.
+	pub(super) fn seal(&mut self, record: &mut Buffer) -> Result<(), ConnectionError> {
+		self.en.encrypt(record, b"").map_err(|e| { ConnectionError::Crypto(e.to_string()) })?;
+		self.bytes += record.len() as u64;
+		self.records += 1;
+		if let Some(secret) = self.switch.take() {
+			self.en = T::new(&secret);
+			self.bytes = 0;
+			self.records = 0;
+			self.since = Instant::now();
+		}
+		return Ok(());
+	}
+
+	/**
+		Waits until there's something to send: an offer, once the current key's been used enough, or the next step of a rekey the other end's in on.
+		Returns `None` when there's nothing to send after all (a message that's been dealt with already). This is cancel safe.
+		The record has to be the next one through `seal`, since it might be the last one under the current key.
+		`pool`: where to get buffers for records.
+	*/
+	pub(super) async fn next(&mut self, pool: &BufferPool) -> Result<Option<Buffer>, ConnectionError> {
+		// When it's time to offer, if we're not already rekeying:
+		let due: Option<Instant> = match self.state {
+			State::Idle if self.max_bytes != 0 && self.bytes >= self.max_bytes => Some(Instant::now()),
+			State::Idle if self.max_records != 0 && self.records >= self.max_records => Some(Instant::now()),
+			State::Idle => self.max_age.map(|age| { self.since + age }),
+			_ => None,
+		};
+		let message: Option<Buffer> = tokio::select! {
+			Some(message) = self.messages.recv() => Some(message),
+			_ = async { if let Some(due) = due { time::sleep_until(due).await } else { future::pending::<()>().await } } => None,
+		};
+
+		// Offer, if that's what woke us up:
+		let Some(message) = message else {
+			let kex: AnyKeyExchanger = self.kex.generate();
+			let record: Buffer = record(pool, STEP_OFFER, &[&kex.get_local_pubkey()]);
+			self.state = State::Offered(kex);
+			return Ok(Some(record));
+		};
+		let Some((&step, payload)) = message.split_first() else {
+			return Err(ConnectionError::Rekey(String::from("empty message")));
+		};
+		match (step, mem::replace(&mut self.state, State::Idle)) {
+			(STEP_OFFER, State::Offered(ours)) if ours.get_local_pubkey()[..] > payload[..] => {
+				// We both offered at once, and ours goes ahead:
+				self.state = State::Offered(ours);
+				return Ok(None);
+			},
+			(STEP_OFFER, State::Idle | State::Offered(_)) => {
+				// Answer with a client init to their key:
+				let mut kex: AnyKeyExchanger = self.kex.generate();
+				kex.set_remote_pubkey(payload).map_err(|e| { ConnectionError::Rekey(e.to_string()) })?;
+				let client_init: Vec<u8> = kex.client_init().map_err(ConnectionError::Rekey)?;
+				let record: Buffer = record(pool, STEP_ANSWER, &[&kex.get_local_pubkey(), &client_init]);
+				self.state = State::Answered(kex);
+				return Ok(Some(record));
+			},
+			(STEP_ANSWER, State::Offered(mut kex)) => {
+				// Finish, and switch keys after that:
+				let (pubkey, client_init) = payload.split_at_checked(kex.get_public_key_length()).ok_or(ConnectionError::Rekey(String::from("truncated answer")))?;
+				kex.set_remote_pubkey(pubkey).map_err(|e| { ConnectionError::Rekey(e.to_string()) })?;
+				let server_init: Vec<u8> = kex.server_init(client_init).map_err(ConnectionError::Rekey)?;
+				self.advance(kex.shared_secret());
+				return Ok(Some(record(pool, STEP_FINISH, &[&server_init])));
+			},
+			(STEP_FINISH, State::Answered(mut kex)) => {
+				// They've switched, so we can too:
+				kex.client_confirm(payload).map_err(ConnectionError::Rekey)?;
+				self.advance(kex.shared_secret());
+				return Ok(Some(record(pool, STEP_SWITCH, &[])));
+			},
+			(step, _) => return Err(ConnectionError::Rekey(format!("unexpected step {}", step))),
+		}
+	}
+
+	/// Moves both traffic secrets on to the next generation with `fresh` mixed in, hands the receive task its one, and switches to ours after the next record.
+	fn advance(&mut self, fresh: &[u8]) {
+		self.send = self.send.rekey(fresh);
+		self.recv = self.recv.rekey(fresh);
+		let _ = self.secrets.send(self.recv.clone());	// It takes it once the other end says it's switched.
+		self.switch = Some(self.send.clone());
+	}
+
+}
+
+
+/// The receive task's side of rekeying: it decrypts records, passes rekeying ones on to the send task, and gets new keys back from it.
+pub(super) struct RekeyReceiver<T: Decryptor> {
+	de: T,
+	messages: UnboundedSender<Buffer>,
+	secrets: UnboundedReceiver<TrafficSecret>,
+} impl<T: Decryptor> RekeyReceiver<T> {
+
+	/// Decrypts `record` in place.
+	pub(super) fn open(&mut self, record: &mut Buffer) -> Result<(), ConnectionError> {
+		return self.de.decrypt(record, b"").map_err(|e| { ConnectionError::Crypto(e.to_string()) });
+	}
+
+	/// Deals with a rekeying record (with its tag taken off), switching keys if the other end has after it.
+	pub(super) async fn receive(&mut self, message: Buffer) -> Result<(), ConnectionError> {
+		let step: Option<u8> = message.first().copied();
+		if step != Some(STEP_SWITCH) {
+			let _ = self.messages.send(message);	// If the send task's gone, so is the link.
+		}
+		if let Some(STEP_FINISH | STEP_SWITCH) = step {
+			let secret: TrafficSecret = self.secrets.recv().await.ok_or(ConnectionError::Rekey(String::from("switched keys without a rekey")))?;
+			self.de = T::new(&secret);
+		}
+		return Ok(());
+	}
+
+}
+
+/// Makes a rekeying record for `step`, out of `parts`.
+fn record(pool: &BufferPool, step: u8, parts: &[&[u8]]) -> Buffer {
+	let mut record: Buffer = pool.take();
+	record.extend_from_slice(&[RECORD_REKEY, step]);
+	for part in parts {
+		record.extend_from_slice(part);
+	}
+	return record;
+}
+
+
+#[tokio::test]
+async fn test_rekey() {
+	use tokio::io;
+	use super::{
+		error::{self, Events},
+		resumption::Tickets,
+		roaming::Link,
+		default_allowed_kex,
+		Endpoint,
+		TcpConnection,
+	};
+	use crate::crypto::{AesGcmDecryptor, AesGcmEncryptor, Transcript};
+
+	// Both ends of a (pretend) handshake:
+	let mut alice_started: Transcript = Transcript::new();
+	alice_started.append(b"initiator key", b"alice");
+	let mut bob_started: Transcript = Transcript::new();
+	bob_started.append(b"initiator key", b"bob");
+	let alice_schedule: KeySchedule = KeySchedule::new((&alice_started, b"one"), (&bob_started, b"two"), b"");
+	let bob_schedule: KeySchedule = KeySchedule::new((&bob_started, b"two"), (&alice_started, b"one"), b"");
+	let config: ConnectionConfiguration = ConnectionConfiguration {
+		rekey_records: 2,
+		..ConnectionConfiguration::default()
+	};
+	let pool: BufferPool = BufferPool::new();
+	let exchange: kex::Implementation = default_allowed_kex().remove(0);
+	let (mut alice, mut alice_receiver) = Rekey::new(&config, &exchange, &alice_schedule, AesGcmEncryptor::new(alice_schedule.send()), AesGcmDecryptor::new(alice_schedule.recv()));
+	let (mut bob, mut bob_receiver) = Rekey::new(&config, &exchange, &bob_schedule, AesGcmEncryptor::new(bob_schedule.send()), AesGcmDecryptor::new(bob_schedule.recv()));
+
+	// Sends a record from one end to the other, the way the tasks would; returns what came out, if it was data:
+	async fn deliver(mut record: Buffer, from: &mut Rekey<AesGcmEncryptor>, to: &mut RekeyReceiver<AesGcmDecryptor>) -> Option<Buffer> {
+		from.seal(&mut record).unwrap();
+		to.open(&mut record).unwrap();
+		if record[0] != RECORD_REKEY {
+			return Some(record);
+		}
+		record.advance(1);
+		to.receive(record).await.unwrap();
+		return None;
+	}
+
+	// Nothing happens until a key's been used enough:
+	deliver(Buffer::from(&b"data"[..]), &mut alice, &mut bob_receiver).await;
+	assert!(time::timeout(Duration::from_millis(50), alice.next(&pool)).await.is_err());
+
+	// Then Alice offers, Bob answers, and Alice finishes (switching keys):
+	deliver(Buffer::from(&b"data"[..]), &mut alice, &mut bob_receiver).await;
+	let offer: Buffer = alice.next(&pool).await.unwrap().unwrap();
+	deliver(offer, &mut alice, &mut bob_receiver).await;
+	let answer: Buffer = bob.next(&pool).await.unwrap().unwrap();
+	deliver(answer, &mut bob, &mut alice_receiver).await;
+	let finish: Buffer = alice.next(&pool).await.unwrap().unwrap();
+
+	// Bob switches his receive key right after the finish, and his send key right after saying he has too:
+	let (_, switch) = tokio::join!(deliver(finish, &mut alice, &mut bob_receiver), bob.next(&pool));
+	deliver(switch.unwrap().unwrap(), &mut bob, &mut alice_receiver).await;
+	assert_eq!(alice.send.generation(), 1);
+	assert_eq!(bob.recv.generation(), 1);
+
+	// Both ways still work, under new keys:
+	assert_eq!(&deliver(Buffer::from(&b"to bob"[..]), &mut alice, &mut bob_receiver).await.unwrap()[..], b"to bob");
+	assert_eq!(&deliver(Buffer::from(&b"to alice"[..]), &mut bob, &mut alice_receiver).await.unwrap()[..], b"to alice");
+
+	// If both ends offer at once, only one offer goes ahead:
+	deliver(Buffer::from(&b"data"[..]), &mut alice, &mut bob_receiver).await;
+	deliver(Buffer::from(&b"data"[..]), &mut bob, &mut alice_receiver).await;
+	let (alice_offer, bob_offer) = (alice.next(&pool).await.unwrap().unwrap(), bob.next(&pool).await.unwrap().unwrap());
+	deliver(alice_offer, &mut alice, &mut bob_receiver).await;
+	deliver(bob_offer, &mut bob, &mut alice_receiver).await;
+	let (alice_reply, bob_reply) = (alice.next(&pool).await.unwrap(), bob.next(&pool).await.unwrap());
+	assert!(alice_reply.is_some() != bob_reply.is_some());
+
+	// And over a real link, data keeps flowing through plenty of rekeys:
+	let (a, b) = io::duplex(65536);
+	let (a_rx, a_tx) = io::split(a);
+	let (b_rx, b_tx) = io::split(b);
+	let events: Events = error::new_events();
+	let tickets: Tickets = Tickets::new(&config);
+	let (a, b) = tokio::join!(
+		TcpConnection::start(&config, &events, &tickets, a_tx, a_rx, Endpoint::Command(String::from("b"))),
+		TcpConnection::start(&config, &events, &tickets, b_tx, b_rx, Endpoint::Command(String::from("a"))),
+	);
+	let (mut a, mut b): (Link, Link) = (a.unwrap(), b.unwrap());
+	for i in 0..100_u32 {
+		a.tx.send(Buffer::from(&i.to_le_bytes()[..])).await.unwrap();
+		b.tx.send(Buffer::from(&(i * 2).to_le_bytes()[..])).await.unwrap();
+	}
+	for i in 0..100_u32 {
+		assert_eq!(&b.rx.recv().await.unwrap()[..], &i.to_le_bytes());
+		assert_eq!(&a.rx.recv().await.unwrap()[..], &(i * 2).to_le_bytes());
+	}
+}
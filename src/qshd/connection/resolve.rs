@@ -0,0 +1,152 @@
+/*!
+	Turning endpoints into addresses, and picking one of them to connect to.
+	Hostnames go through the system resolver. When a host has several addresses (say, both A and AAAA
+	records), connection attempts race each other "happy eyeballs" style (RFC 8305), so that one broken
+	address family doesn't hold everything up.
+*/
+
+// External stuff:
+use tokio::{
+	io::{Error, ErrorKind},
+	net::{self, TcpSocket, TcpStream},
+	task::JoinSet,
+	time::{self, Duration},
+};
+use std::{
+	future::Future,
+	net::SocketAddr,
+};
+
+// Internal stuff:
+use super::Endpoint;
+
+
+/// How long to give a connection attempt before starting the next one alongside it.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+
+/// All the addresses `endpoint` stands for, looking up hostnames with the system resolver.
+pub(super) async fn resolve(endpoint: &Endpoint) -> Result<Vec<SocketAddr>, Error> {
+	let addrs: Vec<SocketAddr> = match endpoint {
+		Endpoint::Inet(addr) => vec![*addr],
+		Endpoint::Host { name, port } => net::lookup_host((name.as_str(), *port)).await?.collect(),
+		_ => return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a host and port", endpoint))),
+	};
+	if addrs.is_empty() {
+		return Err(Error::new(ErrorKind::NotFound, format!("{} has no addresses", endpoint)));
+	}
+	return Ok(addrs);
+}
+
+/// Makes an unconnected TCP socket of the right family for `addr`.
+pub(super) fn socket(addr: &SocketAddr) -> Result<TcpSocket, Error> {
+	return if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() };
+}
+
+/**
+	Connects to `endpoint` over TCP, racing its addresses against each other.
+	`local`: address to bind to first, for the attempts that are in its family (the rest let the OS pick).
+*/
+pub(super) async fn connect(endpoint: &Endpoint, local: Option<SocketAddr>) -> Result<TcpStream, Error> {
+	let addrs: Vec<SocketAddr> = resolve(endpoint).await?;
+	return happy_eyeballs(addrs, move |remote| {
+		async move {
+			let sock: TcpSocket = socket(&remote)?;
+			if let Some(local) = local && local.is_ipv4() == remote.is_ipv4() {
+				sock.set_reuseport(true)?;	// So that multiple connections can use the same port.
+				sock.bind(local)?;
+			}
+			return sock.connect(remote).await;
+		}
+	}).await;
+}
+
+/**
+	Runs `attempt` on each of `addrs`, starting a new one every `ATTEMPT_DELAY` (or as soon as one fails), and returns the first success.
+	IPv6 and IPv4 addresses take turns, starting with IPv6. The other attempts get dropped once one succeeds.
+*/
+pub(super) async fn happy_eyeballs<T, F, Fut>(addrs: Vec<SocketAddr>, mut attempt: F) -> Result<T, Error>
+where
+	F: FnMut(SocketAddr) -> Fut,
+	Fut: Future<Output = Result<T, Error>> + Send + 'static,
+	T: Send + 'static,
+{
+	// Interleave the families, keeping the resolver's order within each:
+	let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|addr| { addr.is_ipv6() });
+	let mut v6 = v6.into_iter();
+	let mut v4 = v4.into_iter();
+	let mut order: Vec<SocketAddr> = Vec::new();
+	loop {
+		match (v6.next(), v4.next()) {
+			(None, None) => break,
+			(a, b) => order.extend(a.into_iter().chain(b)),
+		}
+	}
+	let mut pending = order.into_iter().peekable();
+
+	let mut attempts: JoinSet<Result<T, Error>> = JoinSet::new();
+	let mut last_error: Error = Error::new(ErrorKind::NotFound, "no addresses to try");
+	loop {
+		// Start the next attempt, unless we've run out:
+		if let Some(addr) = pending.next() {
+			attempts.spawn(attempt(addr));
+		} else if attempts.is_empty() {
+			return Err(last_error);
+		}
+
+		// Then wait for one to finish, or for it to be time to start another:
+		tokio::select! {
+			Some(result) = attempts.join_next() => match result {
+				Ok(Ok(value)) => return Ok(value),
+				Ok(Err(e)) => last_error = e,
+				Err(e) => last_error = Error::other(e),
+			},
+			_ = time::sleep(ATTEMPT_DELAY), if pending.peek().is_some() => (),
+		}
+	}
+}
+
+
+#[tokio::test]
+async fn test_dual_stack() {
+	use bytes::Bytes;
+	use std::net::{Ipv4Addr, Ipv6Addr};
+	use super::{
+		Connection,
+		ConnectionConfiguration,
+		TcpConnection,
+	};
+
+	// A server that only listens on IPv4:
+	let mut server: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv4Addr::LOCALHOST, 54371).into()),
+		..ConnectionConfiguration::default()
+	});
+	server.listen().await.unwrap();
+	tokio::task::spawn(async move {
+		while let Ok((_, tx, mut rx)) = server.accept().await {
+			// Simple echo server:
+			tokio::task::spawn(async move {
+				while let Some(data) = rx.recv().await {
+					tx.send(data).await.unwrap();
+				}
+			});
+		}
+	});
+
+	// The IPv6 address goes first and gets refused, so the IPv4 one has to win:
+	let dead: SocketAddr = (Ipv6Addr::LOCALHOST, 54379).into();
+	let live: SocketAddr = (Ipv4Addr::LOCALHOST, 54372).into();
+	let _listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(live).await.unwrap();
+	let stream: TcpStream = happy_eyeballs(vec![live, dead], TcpStream::connect).await.unwrap();
+	assert_eq!(stream.peer_addr().unwrap(), live);
+
+	// And a client bound to IPv6 should still reach it by name:
+	let mut client: TcpConnection = TcpConnection::new(ConnectionConfiguration {
+		endpoint: Endpoint::Inet((Ipv6Addr::LOCALHOST, 54370).into()),
+		..ConnectionConfiguration::default()
+	});
+	let (ctx, mut crx) = client.connect(Endpoint::Host { name: String::from("localhost"), port: 54371 }).await.unwrap();
+	ctx.send(Bytes::from_static(b"Hello, IPv4.")).await.unwrap();
+	assert_eq!(crx.recv().await.unwrap(), b"Hello, IPv4.".to_vec());
+}
@@ -0,0 +1,365 @@
+/*!
+	Resumption tickets, so that coming back to a server doesn't cost a whole new key exchange.
+	Once a logged-in session's started (or resumed), the server seals the link's resumption secret (see
+	`KeySchedule::resumption`) into a ticket, under a key that only it knows, and sends it over; the
+	client keeps it along with the secret, for the next time it dials the same place. Presenting the
+	ticket in the next link's hello is all the server needs to get the secret back, so both ends can
+	go straight to keys made from it (and the fresh nonces in both hellos), without a key exchange:
+	one round trip instead of several. The server says whether it took the ticket right after the
+	hellos; if it didn't (it's expired, it's been used already, or the server's restarted since), the
+	link falls back to the usual key exchange.
+
+	Each ticket's good for one link: the server remembers which ones it's taken until they expire, and
+	the client throws each one away once it's presented it. The server also moves on to a new ticket
+	key every so often, keeping old ones only as long as tickets sealed with them could still be good.
+*/
+
+// External stuff:
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use bincode::{
+	self,
+	config::{self, Configuration},
+	Decode,
+	Encode,
+};
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use tokio::{
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt, Error, ErrorKind},
+	time::{Duration, Instant},
+};
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{Arc, Mutex},
+};
+use zeroize::Zeroizing;
+
+// Internal stuff:
+use super::{
+	framing::Codec,
+	Buffer,
+	ConnectionConfiguration,
+	ConnectionError,
+	Endpoint,
+};
+use crate::crypto::{Secret, SECRET_LEN};
+
+
+/// Encoding used for what's sealed inside tickets.
+const TICKET_BINCODE_CONFIG: Configuration = config::standard();
+
+/// Length of the ID that makes each ticket single use.
+const TICKET_ID_LEN: usize = 16;
+
+/// Length of a ticket key's ID, at the start of each ticket.
+const KEY_ID_LEN: usize = 4;
+
+/// Length of the nonce each ticket's sealed with.
+const NONCE_LEN: usize = 12;
+
+/// What the issuer says after the hellos, when it's been presented a ticket.
+const VERDICT_TAKEN: u8 = 1;
+const VERDICT_REFUSED: u8 = 0;
+
+
+/// A ticket as the server sends it.
+#[derive(Encode, Decode)]
+pub(super) struct Issued {
+
+	/// The sealed ticket.
+	ticket: Vec<u8>,
+
+	/// How many miliseconds it's good for.
+	lifetime: u64,
+
+}
+
+/// What's sealed inside a ticket.
+#[derive(Encode, Decode)]
+struct Contents {
+	id: [u8; TICKET_ID_LEN],
+	secret: [u8; SECRET_LEN],
+
+	// When it expires, in miliseconds since the issuer's epoch:
+	expires: u64,
+}
+
+/// A ticket a client's holding on to, with the secret it carries.
+pub(super) struct Ticket {
+	ticket: Vec<u8>,
+	secret: Secret,
+	expires: Instant,
+}
+
+/// A key for sealing tickets.
+struct TicketKey {
+	id: u32,
+	cipher: Aes256Gcm,
+	created: Instant,
+}
+
+
+/**
+	Every ticket a `Connection` knows about: servers issue and redeem them, and clients hold on to them, by who they're from.
+	Clones share everything.
+*/
+#[derive(Clone)]
+pub(super) struct Tickets(Arc<Mutex<State>>);
+
+struct State {
+
+	// How long tickets are good for, and how long to seal them with one key (servers only):
+	lifetime: Duration,
+	rotation: Duration,
+
+	// What ticket expiries count from:
+	epoch: Instant,
+
+	// Keys for sealing tickets, oldest first, and the ID for the next one:
+	keys: VecDeque<TicketKey>,
+	next_key: u32,
+
+	// IDs of the tickets that have been redeemed, and when they expire (after that, they'd be turned away anyway):
+	redeemed: HashMap<[u8; TICKET_ID_LEN], Instant>,
+
+	// Tickets we're holding, by who issued them (clients only):
+	held: HashMap<Endpoint, Ticket>,
+
+	// Generator for keys, IDs and nonces:
+	random: ChaCha20Rng,
+
+}
+
+impl Tickets {
+
+	/// Makes an empty set, with settings from `config`.
+	pub(super) fn new(config: &ConnectionConfiguration) -> Self {
+		return Self(Arc::new(Mutex::new(State {
+			lifetime: Duration::from_millis(config.ticket_lifetime),
+			rotation: if config.ticket_key_rotation == 0 { Duration::MAX } else { Duration::from_millis(config.ticket_key_rotation) },
+			epoch: Instant::now(),
+			keys: VecDeque::new(),
+			next_key: 0,
+			redeemed: HashMap::new(),
+			held: HashMap::new(),
+			random: ChaCha20Rng::from_entropy(),
+		})));
+	}
+
+	/// Seals `secret` into a new ticket (servers only). Returns `None` if tickets are turned off.
+	pub(super) fn issue(&self, secret: &Secret) -> Option<Issued> {
+		let mut state = self.0.lock().unwrap();
+		if state.lifetime.is_zero() {
+			return None;
+		}
+
+		// Move on to a new key, if it's time:
+		let now: Instant = Instant::now();
+		state.rotate(now);
+		if state.keys.back().is_none_or(|key| { now - key.created >= state.rotation }) {
+			let mut key: Zeroizing<[u8; 32]> = Zeroizing::new([0_u8; 32]);
+			state.random.fill_bytes(key.as_mut_slice());
+			let id: u32 = state.next_key;
+			state.next_key = id.wrapping_add(1);
+			state.keys.push_back(TicketKey { id: id, cipher: Aes256Gcm::new(key.as_slice().into()), created: now });
+		}
+
+		// Seal it:
+		let mut contents: Contents = Contents {
+			id: [0_u8; TICKET_ID_LEN],
+			secret: **secret,
+			expires: (now + state.lifetime - state.epoch).as_millis() as u64,
+		};
+		state.random.fill_bytes(&mut contents.id);
+		let plaintext: Zeroizing<Vec<u8>> = Zeroizing::new(bincode::encode_to_vec(&contents, TICKET_BINCODE_CONFIG).ok()?);
+		contents.secret.fill(0);
+		let mut nonce: [u8; NONCE_LEN] = [0_u8; NONCE_LEN];
+		state.random.fill_bytes(&mut nonce);
+		let key: &TicketKey = state.keys.back()?;
+		let sealed: Vec<u8> = key.cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_slice()).ok()?;
+		return Some(Issued {
+			ticket: [&key.id.to_le_bytes()[..], &nonce, &sealed].concat(),
+			lifetime: state.lifetime.as_millis() as u64,
+		});
+	}
+
+	/// Gets the secret back out of `ticket`, if it's one of ours, it hasn't expired, and it hasn't been redeemed before (servers only).
+	pub(super) fn redeem(&self, ticket: &[u8]) -> Option<Secret> {
+		let mut state = self.0.lock().unwrap();
+		let now: Instant = Instant::now();
+		state.rotate(now);
+
+		// Open it with whichever key it's sealed with:
+		let (key_id, rest) = ticket.split_at_checked(KEY_ID_LEN)?;
+		let (nonce, sealed) = rest.split_at_checked(NONCE_LEN)?;
+		let key_id: u32 = u32::from_le_bytes(key_id.try_into().ok()?);
+		let key: &TicketKey = state.keys.iter().find(|key| { key.id == key_id })?;
+		let plaintext: Zeroizing<Vec<u8>> = Zeroizing::new(key.cipher.decrypt(Nonce::from_slice(nonce), sealed).ok()?);
+		let (mut contents, _): (Contents, usize) = bincode::decode_from_slice(&plaintext, TICKET_BINCODE_CONFIG).ok()?;
+		let secret: Secret = Zeroizing::new(contents.secret);
+		contents.secret.fill(0);
+
+		// It's only good once, and not for long:
+		let expires: Instant = state.epoch + Duration::from_millis(contents.expires);
+		state.redeemed.retain(|_, expires| { *expires > now });
+		if expires <= now || state.redeemed.insert(contents.id, expires).is_some() {
+			return None;
+		}
+		return Some(secret);
+	}
+
+	/// Holds on to `issued` from `peer`, which carries `secret`, for the next time we dial it (clients only).
+	pub(super) fn hold(&self, peer: &Endpoint, issued: Issued, secret: Secret) {
+		let ticket: Ticket = Ticket {
+			ticket: issued.ticket,
+			secret: secret,
+			expires: Instant::now() + Duration::from_millis(issued.lifetime),
+		};
+		self.0.lock().unwrap().held.insert(peer.clone(), ticket);
+	}
+
+	/// Takes the ticket we're holding from `peer`, if there's one that's still good (clients only; it's gone after this).
+	pub(super) fn take(&self, peer: &Endpoint) -> Option<Ticket> {
+		return self.0.lock().unwrap().held.remove(peer).filter(|ticket| { ticket.expires > Instant::now() });
+	}
+
+}
+
+impl State {
+
+	/// Drops keys that no ticket sealed with could still be good.
+	fn rotate(&mut self, now: Instant) {
+		let retired: Duration = self.rotation.saturating_add(self.lifetime);
+		while let Some(key) = self.keys.front() && now - key.created >= retired {
+			self.keys.pop_front();
+		}
+	}
+
+}
+
+impl Ticket {
+
+	/// The sealed ticket, to present.
+	pub(super) fn sealed(&self) -> &[u8] {
+		return &self.ticket;
+	}
+
+}
+
+
+/**
+	Settles whether a link picks up from a ticket, right after the hellos: whoever was presented one says whether they took it.
+	`ours`: the ticket we presented, if we did.
+	`theirs`: the ticket the other end presented (empty if they didn't).
+	Returns the secret to make keys from, and whether we're the end that presented it; or `None` if there's going to be a key exchange.
+*/
+pub(super) async fn settle<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(codec: &Codec, tickets: &Tickets, ours: Option<Ticket>, theirs: &[u8], tx: &mut W, rx: &mut R) -> Result<Option<(Secret, bool)>, ConnectionError> {
+	match (ours, theirs.is_empty()) {
+		(Some(ticket), true) => {
+			// Hear whether they took ours:
+			let verdict: Buffer = codec.read(rx).await?.ok_or(Error::from(ErrorKind::UnexpectedEof))?;
+			return match verdict[..] {
+				[VERDICT_TAKEN] => Ok(Some((ticket.secret, true))),
+				[VERDICT_REFUSED] => Ok(None),
+				_ => Err(ConnectionError::Handshake(String::from("garbled ticket verdict"))),
+			};
+		},
+		(None, false) => {
+			// Tell them whether we took theirs:
+			let secret: Option<Secret> = tickets.redeem(theirs);
+			let mut verdict: Buffer = codec.pool().take();
+			verdict.extend_from_slice(&[if secret.is_some() { VERDICT_TAKEN } else { VERDICT_REFUSED }]);
+			codec.write(tx, &mut verdict).await?;
+			tx.flush().await?;
+			return Ok(secret.map(|secret| { (secret, false) }));
+		},
+		_ => return Ok(None),	// Neither of us presented one (or both of us did, which nobody should).
+	}
+}
+
+
+#[tokio::test]
+async fn test_tickets() {
+	use tokio::{io, time};
+	use super::{
+		error,
+		roaming::Link,
+		TcpConnection,
+	};
+
+	let config: ConnectionConfiguration = ConnectionConfiguration {
+		ticket_lifetime: 1000,
+		ticket_key_rotation: 500,
+		..ConnectionConfiguration::default()
+	};
+	let server: Tickets = Tickets::new(&config);
+	let client: Tickets = Tickets::new(&config);
+	let peer: Endpoint = Endpoint::Command(String::from("server"));
+	let secret: Secret = Zeroizing::new([7_u8; SECRET_LEN]);
+
+	// A ticket gets its secret back, but only the once:
+	let issued: Issued = server.issue(&secret).unwrap();
+	client.hold(&peer, issued, secret.clone());
+	let ticket: Ticket = client.take(&peer).unwrap();
+	assert!(client.take(&peer).is_none());
+	assert_eq!(server.redeem(ticket.sealed()).unwrap(), secret);
+	assert!(server.redeem(ticket.sealed()).is_none());
+
+	// Tampered-with tickets (and other servers' tickets) get turned away:
+	let mut tampered: Vec<u8> = server.issue(&secret).unwrap().ticket;
+	*tampered.last_mut().unwrap() ^= 1;
+	assert!(server.redeem(&tampered).is_none());
+	assert!(Tickets::new(&config).redeem(&server.issue(&secret).unwrap().ticket).is_none());
+
+	// Tickets still work after the key's rotated, until they expire:
+	let old: Vec<u8> = server.issue(&secret).unwrap().ticket;
+	let expiring: Vec<u8> = server.issue(&secret).unwrap().ticket;
+	time::sleep(Duration::from_millis(600)).await;
+	let new: Vec<u8> = server.issue(&secret).unwrap().ticket;
+	assert_ne!(old[..KEY_ID_LEN], new[..KEY_ID_LEN]);
+	assert!(server.redeem(&old).is_some());
+	time::sleep(Duration::from_millis(500)).await;
+	assert!(server.redeem(&expiring).is_none());
+	assert!(server.redeem(&new).is_some());
+
+	// Clients let go of them once they've expired, too:
+	client.hold(&peer, server.issue(&secret).unwrap(), secret.clone());
+	time::sleep(Duration::from_millis(1010)).await;
+	assert!(client.take(&peer).is_none());
+
+	// And none get issued when they're turned off:
+	assert!(Tickets::new(&ConnectionConfiguration { ticket_lifetime: 0, ..config.clone() }).issue(&secret).is_none());
+
+	// Over real links, a ticket from one link's secret gets the next one going without a key exchange, and data flows:
+	async fn link_pair(config: &ConnectionConfiguration, client: &Tickets, server: &Tickets) -> (Link, Link) {
+		let (a, b) = io::duplex(65536);
+		let (a_rx, a_tx) = io::split(a);
+		let (b_rx, b_tx) = io::split(b);
+		let events = error::new_events();
+		let (a, b) = tokio::join!(
+			TcpConnection::start(config, &events, client, a_tx, a_rx, Endpoint::Command(String::from("server"))),
+			TcpConnection::start(config, &events, server, b_tx, b_rx, Endpoint::Command(String::from("client"))),
+		);
+		return (a.unwrap(), b.unwrap());
+	}
+	let (server, client) = (Tickets::new(&config), Tickets::new(&config));
+	let (a, b) = link_pair(&config, &client, &server).await;
+	assert_eq!(a.resumption, b.resumption);
+	let sealed: Vec<u8> = server.issue(&b.resumption).unwrap().ticket;
+	client.hold(&peer, Issued { ticket: sealed.clone(), lifetime: 1000 }, a.resumption.clone());
+	let (mut a, mut b) = link_pair(&config, &client, &server).await;
+	assert_eq!(server.0.lock().unwrap().redeemed.len(), 1);
+	assert!(client.take(&peer).is_none());
+	assert_eq!(a.id, b.id);
+	a.tx.send(Buffer::from(&b"ping"[..])).await.unwrap();
+	assert_eq!(&b.rx.recv().await.unwrap()[..], b"ping");
+	b.tx.send(Buffer::from(&b"pong"[..])).await.unwrap();
+	assert_eq!(&a.rx.recv().await.unwrap()[..], b"pong");
+
+	// Presenting it again just means a key exchange, like there was no ticket at all:
+	client.hold(&peer, Issued { ticket: sealed, lifetime: 1000 }, a.resumption.clone());
+	let (a, mut b) = link_pair(&config, &client, &server).await;
+	assert_eq!(a.id, b.id);
+	a.tx.send(Buffer::from(&b"ping"[..])).await.unwrap();
+	assert_eq!(&b.rx.recv().await.unwrap()[..], b"ping");
+}
@@ -0,0 +1,923 @@
+/*!
+	Roaming sessions: a session outlives the link (socket, stream, etc) it was started on.
+	Every link's handshake yields a session ID that only the two ends know. When a link dies,
+	the client dials again, runs a fresh key exchange, and presents that ID (over the new,
+	encrypted link) to pick the session back up; no re-authentication needed. Data records are
+	numbered, so whatever got lost in between is replayed in order. Servers also hand out a
+	resumption ticket over every link a logged-in session takes (see `resumption` and `login`), so
+	that the client's next link can skip the key exchange. Each session carries any number of streams
+	(see `mux`), which come back along with it.
+*/
+
+// External stuff:
+use bincode::{
+	self,
+	config::{self, Configuration},
+	Decode,
+	Encode,
+};
+use sha2::{Digest, Sha256};
+use bytes::Bytes;
+use tokio::{
+	io::{Error, ErrorKind},
+	net::TcpStream,
+	sync::{
+		mpsc::{self, Receiver, Sender},
+		oneshot, watch,
+	},
+	task,
+	time::{self, Duration, Instant},
+};
+use std::{
+	collections::{HashMap, VecDeque},
+	future::{self, Future},
+	path::PathBuf,
+	pin::Pin,
+	sync::{Arc, Mutex},
+};
+
+// Internal stuff:
+use super::{
+	error::Events,
+	jump,
+	login::{self, KeyId, Login},
+	mux::{Multiplexer, Side},
+	resumption::{Issued, Tickets},
+	Buffer,
+	BufferPool,
+	ConnectionConfiguration,
+	ConnectionError,
+	ConnectionEvent,
+	Disconnect,
+	DisconnectReason,
+	Endpoint,
+};
+use crate::crypto::Secret;
+
+
+/// Encoding used for everything in this module.
+const RECORD_BINCODE_CONFIG: Configuration = config::standard();
+
+/// Length of the application's channel buffers.
+const CHANNEL_BUFFER_SIZE: usize = 256;
+
+/// How many data records to receive before acknowledging them.
+const ACK_INTERVAL: u64 = 32;
+
+/// Most room the encoded `Record` in front of a record's data can take.
+const RECORD_HEADER_MAX: usize = 16;
+
+/// Upper limit on one message from the application (however many records it's split over).
+const MAX_MESSAGE_SIZE: usize = 1 << 24;
+
+/// How long a session waits for its client to come back before it's dropped.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a client waits before its first attempt at redialing (this doubles with every failure).
+const REDIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper limit on the redial back-off.
+const REDIAL_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Identifies a session; derived from the shared secrets of the handshake that started it.
+pub type SessionId = [u8; 32];
+
+/// Makes a fresh link to the same server; used by clients to come back after losing their link.
+pub(super) type Redial = Box<dyn FnMut() -> Pin<Box<dyn Future<Output = Result<Link, ConnectionError>> + Send>> + Send>;
+
+/// Derives a session ID from the two shared secrets of a handshake (the order they're given in doesn't matter).
+pub(super) fn session_id(a: &[u8], b: &[u8]) -> SessionId {
+	let (low, high) = if a < b { (a, b) } else { (b, a) };
+	return Sha256::new()
+		.chain_update(b"qsh session id")
+		.chain_update(low)
+		.chain_update(high)
+		.finalize()
+		.into();
+}
+
+
+/// First thing a client says over a new link.
+#[derive(Encode, Decode)]
+enum Hello {
+
+	/// Start a new session; logging in's optional, but only sessions that are logged in get tickets.
+	New { login: Option<Login> },
+
+	/// Pick up an existing session, having received `received` data records so far.
+	Resume { id: SessionId, received: u64 },
+
+	/// Start a new session whose far end is a raw TCP stream to `target`, instead of the server's application (see `jump`); this needs a `login`.
+	DirectConnect { target: Endpoint, login: Option<Login> },
+
+}
+
+/// The server's answer to a `Hello`.
+#[derive(Encode, Decode)]
+enum Welcome {
+
+	/// New session started.
+	New,
+
+	/// Session picked back up; the server has received `received` data records so far.
+	Resumed { received: u64 },
+
+	/// The server doesn't know (or has forgotten) that session.
+	Unknown,
+
+	/// New session started, connected through to the requested target.
+	Connected,
+
+	/// The server wouldn't (or couldn't) connect to the requested target, and says why.
+	Refused(String),
+
+}
+
+/// Everything sent over a link once the session is running.
+#[derive(Encode, Decode)]
+enum Record {
+
+	/// Application data; `seq` counts up from zero for the life of the session.
+	/// The data itself comes right after the record (so it doesn't have to be copied in or out of the encoding).
+	Data { seq: u64 },
+
+	/// The start of application data too big for one record; the rest follows, up to the `Data` record that finishes it.
+	Fragment,
+
+	/// The sender has received `received` data records so far.
+	Ack { received: u64 },
+
+	/// The sender's hanging up, and why; don't try to resume the session. Whoever hangs up first gets the same back once the other end's done.
+	Disconnect(Disconnect),
+
+	/// A resumption ticket for the client's next link (servers only send these).
+	Ticket(Issued),
+
+}
+
+
+/// One encrypted link to the remote host: the plaintext side of a `send_task`/`recv_task` pair.
+pub(super) struct Link {
+
+	/// Records to send.
+	pub(super) tx: Sender<Buffer>,
+
+	/// Records received.
+	pub(super) rx: Receiver<Buffer>,
+
+	/// Where buffers for records to send come from.
+	pub(super) pool: BufferPool,
+
+	/// The ID derived from this link's handshake.
+	pub(super) id: SessionId,
+
+	/// Who's on the other end.
+	pub(super) peer: Endpoint,
+
+	/// The secret a ticket for this link carries (see `KeySchedule::resumption`).
+	pub(super) resumption: Secret,
+
+	/// Largest record (before encryption) the link carries.
+	pub(super) limit: usize,
+
+	/// Fragments of the application data coming in, until the rest of it arrives.
+	pub(super) partial: Vec<u8>,
+
+} impl Link {
+
+	async fn send<T: Encode>(&self, message: T) -> Result<(), Error> {
+		return self.send_with(message, &[]).await;
+	}
+
+	/// Sends `message`, with `payload` straight after it.
+	async fn send_with<T: Encode>(&self, message: T, payload: &[u8]) -> Result<(), Error> {
+		let mut record: Buffer = self.pool.take();
+		bincode::encode_into_std_write(message, &mut record, RECORD_BINCODE_CONFIG).map_err(|e| { Error::other(e) })?;
+		record.extend_from_slice(payload);
+		return self.tx.send(record).await.map_err(|_| { Error::from(ErrorKind::BrokenPipe) });
+	}
+
+	/**
+		Sends application data numbered `seq`, split into as many records as it takes.
+		If this gets cut off partway, don't send any more data over the link (the other end would take it for the rest of this).
+	*/
+	async fn send_data(&self, seq: u64, payload: &[u8]) -> Result<(), Error> {
+		let room: usize = self.limit - RECORD_HEADER_MAX;
+		let mut rest: &[u8] = payload;
+		while rest.len() > room {
+			let (fragment, after) = rest.split_at(room);
+			self.send_with(Record::Fragment, fragment).await?;
+			rest = after;
+		}
+		return self.send_with(Record::Data { seq: seq }, rest).await;
+	}
+
+	async fn recv<T: Decode<()>>(&mut self) -> Result<T, Error> {
+		return Ok(self.recv_with::<T>().await?.0);
+	}
+
+	/// Receives a message, along with whatever came after it.
+	async fn recv_with<T: Decode<()>>(&mut self) -> Result<(T, Buffer), Error> {
+		let mut record: Buffer = self.rx.recv().await.ok_or(Error::from(ErrorKind::UnexpectedEof))?;
+		let (message, length): (T, usize) = bincode::decode_from_slice(&record, RECORD_BINCODE_CONFIG).map_err(|e| { Error::new(ErrorKind::InvalidData, e) })?;
+		record.advance(length);
+		return Ok((message, record));
+	}
+
+	/// Receives a record, putting split application data back together (so this never returns a `Fragment`).
+	async fn recv_record(&mut self) -> Result<(Record, Buffer), Error> {
+		loop {
+			match self.recv_with::<Record>().await? {
+				(Record::Fragment | Record::Data { .. }, payload) if self.partial.len() + payload.len() > MAX_MESSAGE_SIZE => {
+					return Err(Error::new(ErrorKind::InvalidData, format!("message over the limit of {} bytes", MAX_MESSAGE_SIZE)));
+				},
+				(Record::Fragment, payload) => self.partial.extend_from_slice(&payload),
+				(Record::Data { seq }, payload) if !self.partial.is_empty() => {
+					let mut message: Buffer = self.pool.take();
+					message.extend_from_slice(&self.partial);
+					message.extend_from_slice(&payload);
+					self.partial.clear();
+					return Ok((Record::Data { seq: seq }, message));
+				},
+				(record, payload) => return Ok((record, payload)),
+			}
+		}
+	}
+
+	/// Server side: sends the client a ticket for its next link, if tickets are turned on.
+	async fn grant(&self, tickets: &Tickets) -> Result<(), Error> {
+		if let Some(issued) = tickets.issue(&self.resumption) {
+			self.send(Record::Ticket(issued)).await?;
+		}
+		return Ok(());
+	}
+
+}
+
+
+/// Hands a resuming link (and how many records its client has received) over to a running session.
+type Handoff = Sender<(Link, u64)>;
+
+/**
+	Every session a `Connection` has running, and what they share: where to report them ending, and a way to hang them all up.
+	Servers also keep a table of them here, so that links can be handed over to them.
+*/
+#[derive(Clone)]
+pub(super) struct Sessions {
+
+	/// Sessions that can be resumed, by ID (servers only).
+	table: Arc<Mutex<HashMap<SessionId, Handoff>>>,
+
+	/// Set when every session should hang up, and why; each session watches it.
+	hangup: Arc<watch::Sender<Option<Disconnect>>>,
+
+	/// Where sessions report ending.
+	events: Events,
+
+	/// How long a session that's hanging up waits for the other end to finish.
+	drain_timeout: Duration,
+
+	/// Tickets we've issued (servers), or been issued (clients).
+	tickets: Tickets,
+
+	/// The key pair to log in with (clients only).
+	identity: Option<PathBuf>,
+
+} impl Sessions {
+
+	/// Makes an empty set of sessions, with settings from `config`, reporting to `events`.
+	pub(super) fn new(config: &ConnectionConfiguration, events: &Events) -> Self {
+		return Self {
+			table: Arc::new(Mutex::new(HashMap::new())),
+			hangup: Arc::new(watch::channel(None).0),
+			events: events.clone(),
+			drain_timeout: Duration::from_millis(config.drain_timeout),
+			tickets: Tickets::new(config),
+			identity: config.identity.clone(),
+		};
+	}
+
+	/// Tickets we've issued (servers), or been issued (clients); every link this set's sessions run on should be started with these.
+	pub(super) fn tickets(&self) -> &Tickets {
+		return &self.tickets;
+	}
+
+	/**
+		Reads a fresh link's `Hello`, then either starts a new session on it, or hands it over to the session it's resuming.
+		Returns the streams of new sessions, and `None` otherwise.
+	*/
+	pub(super) async fn admit(&self, mut link: Link, config: &ConnectionConfiguration) -> Result<Option<Multiplexer>, Error> {
+		match link.recv::<Hello>().await? {
+			Hello::New { login } => {
+				// Anyone can start one, but a login that doesn't check out means no tickets:
+				let key: Option<KeyId> = match login {
+					Some(login) => login::authenticate(config, Some(login), &link.id).await.inspect_err(|e| {
+						eprintln!("Server: {} failed to log in: {}", link.peer, e);
+					}).ok(),
+					None => None,
+				};
+				link.send(Welcome::New).await?;
+				if key.is_some() {
+					link.grant(&self.tickets).await?;
+				}
+				return Ok(Some(self.start(link, key)));
+			},
+			Hello::DirectConnect { target, login } => {
+				// Only for clients we know:
+				let dialed: Result<(KeyId, TcpStream), Error> = match login::authenticate(config, login, &link.id).await {
+					Ok(key) => jump::dial(config, &target).await.map(|stream| { (key, stream) }),
+					Err(e) => Err(e),
+				};
+				match dialed {
+					Ok((key, stream)) => {
+						link.send(Welcome::Connected).await?;
+						link.grant(&self.tickets).await?;
+						eprintln!("Server: {} connected through to {}.", link.peer, target);
+
+						// The session's data goes to and from the target, rather than to the application:
+						let (tx, rx) = self.start(link, Some(key)).into_main();
+						jump::splice(stream, tx, rx);
+					},
+					Err(e) => {
+						eprintln!("Server: refused to connect {} through to {}: {}", link.peer, target, e);
+						link.send(Welcome::Refused(e.to_string())).await?;
+					},
+				}
+				return Ok(None);
+			},
+			Hello::Resume { id, received } => {
+				let session: Option<Handoff> = self.table.lock().unwrap().get(&id).cloned();
+				if let Some(session) = session {
+					eprintln!("Server: {} resumed a session.", link.peer);
+					if let Err(mpsc::error::SendError((link, _))) = session.send((link, received)).await {
+						// It ended while we were looking:
+						link.send(Welcome::Unknown).await?;
+					}
+				} else {
+					link.send(Welcome::Unknown).await?;
+				}
+				return Ok(None);
+			},
+		}
+	}
+
+	/**
+		Registers a new session on `link`, so that it can be resumed later, and starts it. Returns its streams.
+		`login`: the key the client logged in with, if it did.
+	*/
+	fn start(&self, link: Link, login: Option<KeyId>) -> Multiplexer {
+		let (links_tx, links_rx) = mpsc::channel::<(Link, u64)>(1);
+		let id: SessionId = link.id;
+		self.table.lock().unwrap().insert(id, links_tx);
+
+		let (session, streams) = Session::new(id, link, login, Side::Server, self);
+		let sessions: Sessions = self.clone();
+		task::spawn(async move {
+			session.serve(links_rx).await;
+			sessions.table.lock().unwrap().remove(&id);
+		});
+		return streams;
+	}
+
+	/**
+		Client side: starts a new session over a fresh link.
+		`target`: if there is one, the server connects the session through to it (see `jump`), instead of to its application.
+		`redial`: gets called to make a new link whenever the current one dies; without it, the session ends with its link.
+		Returns the session's streams.
+	*/
+	pub(super) async fn open(&self, mut link: Link, target: Option<Endpoint>, redial: Option<Redial>) -> Result<Multiplexer, Error> {
+		let direct: bool = target.is_some();
+		let login: Option<Login> = self.login(&link.id).await?;
+		if let Some(target) = target {
+			link.send(Hello::DirectConnect { target: target, login: login }).await?;
+		} else {
+			link.send(Hello::New { login: login }).await?;
+		}
+		match link.recv::<Welcome>().await? {
+			Welcome::New if !direct => (),
+			Welcome::Connected if direct => (),
+			Welcome::Refused(reason) => return Err(Error::new(ErrorKind::ConnectionRefused, reason)),
+			_ => return Err(Error::new(ErrorKind::InvalidData, "server refused to start a new session")),
+		}
+		let (session, streams) = Session::new(link.id, link, None, Side::Client, self);
+		task::spawn(session.run(redial));
+		return Ok(streams);
+	}
+
+	/// Client side: logs in to the link with ID `id`, if we've got a key to log in with.
+	async fn login(&self, id: &SessionId) -> Result<Option<Login>, Error> {
+		return match &self.identity {
+			Some(identity) => Ok(Some(Login::sign(identity, id).await?)),
+			None => Ok(None),
+		};
+	}
+
+	/// Hangs up every session (and any that start from now on), telling the other ends `disconnect`. Returns once they've all finished.
+	pub(super) async fn disconnect(&self, disconnect: Disconnect) {
+		self.hangup.send_replace(Some(disconnect));
+		self.hangup.closed().await;
+	}
+
+}
+
+
+/// The state of one session, owned by its task.
+struct Session {
+
+	id: SessionId,
+
+	// From the application:
+	outbound: Receiver<Bytes>,
+
+	// Tells the application's multiplexer that we're hanging up, so it sends what's queued on every stream and then closes `outbound`:
+	finishing: Option<oneshot::Sender<()>>,
+
+	// To the application:
+	inbound: Sender<Bytes>,
+
+	// Number of data records sent so far:
+	sent: u64,
+
+	// Number of data records received so far:
+	received: u64,
+
+	// Data records the other end hasn't acknowledged yet:
+	unacked: VecDeque<(u64, Bytes)>,
+
+	// The link currently in use, if there is one:
+	link: Option<Link>,
+
+	// Who's on the other end (as of the latest link):
+	peer: Endpoint,
+
+	// Says when to hang up, and why (see `Sessions::disconnect`):
+	hangup: watch::Receiver<Option<Disconnect>>,
+
+	// Where to report the session ending:
+	events: Events,
+
+	// How long to wait for the other end to finish, once we've hung up:
+	drain_timeout: Duration,
+
+	// Where tickets come from (servers), or go to (clients):
+	tickets: Tickets,
+
+	// The key the client logged in with, if it did (servers only; only sessions that are logged in get tickets):
+	login: Option<KeyId>,
+
+} impl Session {
+
+	/// Makes a new session running on `link`, logged in as `login`, belonging to `sessions`, along with the application's multiplexer (for our `side`).
+	fn new(id: SessionId, link: Link, login: Option<KeyId>, side: Side, sessions: &Sessions) -> (Self, Multiplexer) {
+		let (outbound_tx, outbound_rx) = mpsc::channel::<Bytes>(CHANNEL_BUFFER_SIZE);
+		let (inbound_tx, inbound_rx) = mpsc::channel::<Bytes>(CHANNEL_BUFFER_SIZE);
+		let (finishing_tx, finishing_rx) = oneshot::channel::<()>();
+		return (Self {
+			id: id,
+			outbound: outbound_rx,
+			finishing: Some(finishing_tx),
+			inbound: inbound_tx,
+			sent: 0,
+			received: 0,
+			unacked: VecDeque::new(),
+			peer: link.peer.clone(),
+			link: Some(link),
+			hangup: sessions.hangup.subscribe(),
+			events: sessions.events.clone(),
+			drain_timeout: sessions.drain_timeout,
+			tickets: sessions.tickets.clone(),
+			login: login,
+		}, Multiplexer::new(outbound_tx, inbound_rx, side, finishing_rx));
+	}
+
+	/// Client side: pumps data until the session ends, redialing whenever the link dies.
+	async fn run(mut self, mut redial: Option<Redial>) {
+		loop {
+			if let None = self.link {
+				let redial: &mut Redial = if let Some(redial) = &mut redial {
+					redial
+				} else {
+					return self.end(Disconnect::new(DisconnectReason::ConnectionLost, "no way to redial"), false);
+				};
+
+				// Get the session back (unless we're told to hang up in the meantime; there's nobody to tell, so that's that):
+				let resumed: Result<(Link, u64), Error> = tokio::select! {
+					resumed = Self::reconnect(redial, self.id, self.received) => resumed,
+					disconnect = Self::requested(&mut self.hangup) => return self.end(disconnect, false),
+				};
+				let result: Result<(), Error> = match resumed {
+					Ok((link, received)) => {
+						self.peer = link.peer.clone();
+						self.link = Some(link);
+						self.replay(received).await
+					},
+					Err(e) => Err(e),
+				};
+				if let Err(e) = result {
+					eprintln!("failed to resume session: {}", e);
+					return self.end(Disconnect::new(DisconnectReason::ConnectionLost, e.to_string()), false);
+				}
+			}
+			if let Some((disconnect, by_peer)) = self.pump().await {
+				return self.finish(disconnect, by_peer).await;
+			}
+		}
+	}
+
+	/// Server side: pumps data until the session ends, switching links whenever the client resumes.
+	async fn serve(mut self, mut links: Receiver<(Link, u64)>) {
+		loop {
+			if let Some(link) = &mut self.link {
+				tokio::select! {
+					biased;
+					Some((new_link, received)) = links.recv() => {
+						// The client came back from somewhere else, so drop the old link:
+						if let Err(e) = self.attach(new_link, received, Welcome::Resumed { received: self.received }).await {
+							eprintln!("failed to resume session: {}", e);
+						}
+					},
+					step = Self::step(link, &mut self.outbound, &self.inbound, &mut self.sent, &mut self.received, &mut self.unacked, &mut self.hangup) => {
+						match step {
+							Step::Continue | Step::Ticket(_) => (),	// Clients don't issue tickets.
+							Step::LinkLost => {
+								eprintln!("lost link to {}, waiting for it to come back", link.peer);
+								self.link = None;
+							},
+							Step::Hangup(disconnect) => return self.finish(disconnect, false).await,
+							Step::HungUp(disconnect) => return self.finish(disconnect, true).await,
+						}
+					},
+				}
+			} else {
+				// Wait for the client to come back (there's nobody to tell if we're hung up in the meantime):
+				tokio::select! {
+					resumed = time::timeout(RESUME_TIMEOUT, links.recv()) => match resumed {
+						Ok(Some((new_link, received))) => {
+							if let Err(e) = self.attach(new_link, received, Welcome::Resumed { received: self.received }).await {
+								eprintln!("failed to resume session: {}", e);
+							}
+						},
+						_ => {
+							eprintln!("session timed out waiting for its client");
+							return self.end(Disconnect::new(DisconnectReason::ConnectionLost, "the client didn't come back"), false);
+						},
+					},
+					disconnect = Self::requested(&mut self.hangup) => return self.end(disconnect, false),
+				}
+			}
+		}
+	}
+
+	/// Moves data until the link dies (`None`), or the session's hung up (returns why, and whether it was the other end).
+	async fn pump(&mut self) -> Option<(Disconnect, bool)> {
+		while let Some(link) = &mut self.link {
+			match Self::step(link, &mut self.outbound, &self.inbound, &mut self.sent, &mut self.received, &mut self.unacked, &mut self.hangup).await {
+				Step::Continue => (),
+				Step::Ticket(issued) => self.tickets.hold(&link.peer, issued, link.resumption.clone()),
+				Step::LinkLost => {
+					eprintln!("lost link to {}, redialing", link.peer);
+					self.link = None;
+				},
+				Step::Hangup(disconnect) => return Some((disconnect, false)),
+				Step::HungUp(disconnect) => return Some((disconnect, true)),
+			}
+		}
+		return None;
+	}
+
+	/// Client side: dials until the server takes session `id` back, or until we give up. Returns the new link, and how many records the server's received.
+	async fn reconnect(redial: &mut Redial, id: SessionId, received: u64) -> Result<(Link, u64), Error> {
+		let deadline: Instant = Instant::now() + RESUME_TIMEOUT;
+		let mut backoff: Duration = REDIAL_BACKOFF;
+		while Instant::now() < deadline {
+			time::sleep(backoff).await;
+			backoff = (backoff * 2).min(REDIAL_BACKOFF_MAX);
+
+			// Dial, and ask for our session back:
+			let mut link: Link = match redial().await {
+				Ok(link) => link,
+				Err(e) => {
+					eprintln!("failed to redial: {}", e);
+					continue;
+				},
+			};
+			link.send(Hello::Resume { id: id, received: received }).await?;
+			match link.recv::<Welcome>().await {
+				Ok(Welcome::Resumed { received }) => {
+					eprintln!("resumed session with {}", link.peer);
+					return Ok((link, received));
+				},
+				Ok(Welcome::Unknown) => return Err(Error::new(ErrorKind::NotFound, "the server doesn't know this session anymore")),
+				Ok(_) => return Err(Error::new(ErrorKind::InvalidData, "the server started a new session instead")),
+				Err(e) => eprintln!("failed to resume: {}", e),
+			}
+		}
+		return Err(Error::from(ErrorKind::TimedOut));
+	}
+
+	/// Server side: switches to `link`, answering the client with `welcome` (and a ticket, if it's logged in), and replays what it missed.
+	async fn attach(&mut self, link: Link, received: u64, welcome: Welcome) -> Result<(), Error> {
+		link.send(welcome).await?;
+		if self.login.is_some() {
+			link.grant(&self.tickets).await?;
+		}
+		self.peer = link.peer.clone();
+		self.link = Some(link);
+		return self.replay(received).await;
+	}
+
+	/// Resends every data record from `received` onwards, since the other end never got them.
+	async fn replay(&mut self, received: u64) -> Result<(), Error> {
+		if received > self.sent {
+			return Err(Error::new(ErrorKind::InvalidData, "the other end claims to have received records that were never sent"));
+		}
+		while let Some((seq, _)) = self.unacked.front() && *seq < received {
+			self.unacked.pop_front();
+		}
+		let link: &Link = self.link.as_ref().unwrap();
+		for (seq, payload) in &self.unacked {
+			link.send_data(*seq, payload).await?;
+		}
+		return Ok(());
+	}
+
+	/// Waits until every session's asked to hang up (see `Sessions::disconnect`), and returns why.
+	async fn requested(hangup: &mut watch::Receiver<Option<Disconnect>>) -> Disconnect {
+		let disconnect: Option<Disconnect> = hangup.wait_for(Option::is_some).await.ok().and_then(|disconnect| { disconnect.clone() });
+		if let Some(disconnect) = disconnect {
+			return disconnect;
+		}
+		// The `Connection` is gone, so nobody's left to ask:
+		return future::pending().await;
+	}
+
+	/**
+		Moves one thing: either something the application sent, or a record from the link (or notices that it's time to hang up).
+		This is safe to cancel (servers drop it when the client comes back on a new link): nothing counts as received until the
+		application has it, so whatever gets cut off is replayed over the new link.
+	*/
+	async fn step(link: &mut Link, outbound: &mut Receiver<Bytes>, inbound: &Sender<Bytes>, sent: &mut u64, received: &mut u64, unacked: &mut VecDeque<(u64, Bytes)>, hangup: &mut watch::Receiver<Option<Disconnect>>) -> Step {
+		tokio::select! {
+			disconnect = Self::requested(hangup) => return Step::Hangup(disconnect),
+			data = outbound.recv() => {
+				if let Some(payload) = data {
+					if payload.len() > MAX_MESSAGE_SIZE {
+						return Step::Hangup(Disconnect::new(DisconnectReason::ProtocolError, format!("message of {} bytes is over the limit of {} bytes", payload.len(), MAX_MESSAGE_SIZE)));
+					}
+
+					// Number it, and hold on to it until the other end acknowledges it (before sending, so that it's replayed even if this gets cut off):
+					let seq: u64 = *sent;
+					*sent += 1;
+					unacked.push_back((seq, payload));
+					let payload: &[u8] = &unacked.back().unwrap().1;
+					if let Err(_) = link.send_data(seq, payload).await {
+						return Step::LinkLost;
+					}
+					return Step::Continue;
+				} else {
+					// The application hung up:
+					return Step::Hangup(Disconnect::normal());
+				}
+			},
+			record = link.recv_record() => {
+				match record {
+					Ok((Record::Data { seq }, payload)) if seq == *received => {
+						if let Err(_) = inbound.send(payload.freeze()).await {
+							// The application doesn't want any more data:
+							return Step::Hangup(Disconnect::normal());
+						}
+						*received += 1;
+						if received.is_multiple_of(ACK_INTERVAL) && let Err(_) = link.send(Record::Ack { received: *received }).await {
+							return Step::LinkLost;
+						}
+						return Step::Continue;
+					},
+					Ok((Record::Data { seq }, _)) if seq < *received => {
+						// Already got this one before the link was switched:
+						return Step::Continue;
+					},
+					Ok((Record::Data { .. }, _)) => {
+						eprintln!("records from {} arrived out of order, closing the session", link.peer);
+						return Step::Hangup(Disconnect::new(DisconnectReason::ProtocolError, "records arrived out of order"));
+					},
+					Ok((Record::Ack { received }, _)) => {
+						while let Some((seq, _)) = unacked.front() && *seq < received {
+							unacked.pop_front();
+						}
+						return Step::Continue;
+					},
+					Ok((Record::Disconnect(disconnect), _)) => return Step::HungUp(disconnect),
+					Ok((Record::Ticket(issued), _)) => return Step::Ticket(issued),
+					Ok((Record::Fragment, _)) => unreachable!("fragments come out of recv_record put back together"),
+					Err(e) if e.kind() == ErrorKind::InvalidData => {
+						eprintln!("garbled record from {}: {}", link.peer, e);
+						return Step::Hangup(Disconnect::new(DisconnectReason::ProtocolError, format!("garbled record: {}", e)));
+					},
+					Err(_) => return Step::LinkLost,
+				}
+			},
+		}
+	}
+
+	/**
+		Hangs up: sends whatever the application's already queued, then `disconnect`. If we're the first to hang up, this waits (for up to
+		the drain timeout) for the other end to do the same, passing on whatever it sends in the meantime. Then the session's over.
+		`by_peer`: whether the other end hung up first (so `disconnect` is its reason, and this is the answer).
+	*/
+	async fn finish(mut self, disconnect: Disconnect, by_peer: bool) {
+		if let Some(link) = &mut self.link {
+			// No more from the application, but what it's already sent on any stream still goes (unless the other end's not taking it, in which case only what's made it this far does):
+			if let Some(finishing) = self.finishing.take() {
+				let _ = finishing.send(());
+			}
+			let deadline: Instant = Instant::now() + self.drain_timeout;
+			loop {
+				let payload: Bytes = match time::timeout_at(deadline, self.outbound.recv()).await {
+					Ok(Some(payload)) => payload,
+					Ok(None) => break,
+					Err(_) => {
+						self.outbound.close();
+						continue;
+					},
+				};
+				if payload.len() > MAX_MESSAGE_SIZE {
+					eprintln!("dropped a message of {} bytes to {}, it's over the limit", payload.len(), link.peer);
+					continue;
+				}
+				let seq: u64 = self.sent;
+				self.sent += 1;
+				if let Err(_) = link.send_data(seq, &payload).await {
+					break;
+				}
+			}
+
+			// Say goodbye, and wait for the other end to be done too:
+			if let Ok(()) = link.send(Record::Disconnect(disconnect.clone())).await && !by_peer
+				&& let Err(_) = time::timeout(self.drain_timeout, Self::drain(link, &self.inbound, &mut self.received)).await {
+				eprintln!("{} didn't hang up in time", link.peer);
+			}
+		}
+		self.end(disconnect, by_peer);
+	}
+
+	/// Passes data from `link` on to the application until the other end hangs up too (or the link dies).
+	async fn drain(link: &mut Link, inbound: &Sender<Bytes>, received: &mut u64) {
+		loop {
+			match link.recv_record().await {
+				Ok((Record::Data { seq }, payload)) if seq == *received => {
+					*received += 1;
+					let _ = inbound.send(payload.freeze()).await;	// It's fine if the application isn't listening anymore.
+				},
+				Ok((Record::Disconnect(_), _)) | Err(_) => return,
+				Ok(_) => (),
+			}
+		}
+	}
+
+	/// Reports that the session's over, and why.
+	fn end(self, disconnect: Disconnect, by_peer: bool) {
+		eprintln!("session with {} ended ({}): {}", self.peer, if by_peer { "by the peer" } else { "by us" }, disconnect);
+		let _ = self.events.send(ConnectionEvent::Disconnected {
+			peer: self.peer.clone(),
+			disconnect: disconnect,
+			by_peer: by_peer,
+		});
+	}
+
+}
+
+/// What happened in one `Session::step`.
+enum Step {
+	Continue,
+	LinkLost,
+
+	/// We're hanging up (or the application is), for this reason.
+	Hangup(Disconnect),
+
+	/// The other end hung up, for this reason.
+	HungUp(Disconnect),
+
+	/// The other end sent a resumption ticket.
+	Ticket(Issued),
+}
+
+
+#[tokio::test]
+async fn test_roaming_session() {
+	use super::error;
+
+	// A pair of links joined by a relay task; aborting the relay cuts both of them, like a network change would:
+	fn link_pair(id: SessionId) -> (Link, Link, task::JoinHandle<()>) {
+		let (a_tx, mut a_rx) = mpsc::channel::<Buffer>(16);
+		let (b_tx, b_rx) = mpsc::channel::<Buffer>(16);
+		let (c_tx, mut c_rx) = mpsc::channel::<Buffer>(16);
+		let (d_tx, d_rx) = mpsc::channel::<Buffer>(16);
+		let relay = task::spawn(async move {
+			loop {
+				tokio::select! {
+					Some(data) = a_rx.recv() => { let _ = b_tx.send(data).await; },
+					Some(data) = c_rx.recv() => { let _ = d_tx.send(data).await; },
+					else => return,
+				}
+			}
+		});
+		let client: Link = Link { tx: a_tx, rx: d_rx, pool: BufferPool::new(), id: id, peer: Endpoint::Command(String::from("server")), resumption: Secret::new(id), limit: 4096, partial: Vec::new() };
+		let server: Link = Link { tx: c_tx, rx: b_rx, pool: BufferPool::new(), id: id, peer: Endpoint::Command(String::from("client")), resumption: Secret::new(id), limit: 4096, partial: Vec::new() };
+		return (client, server, relay);
+	}
+
+	// The client logs in, so that it gets tickets:
+	let keys: tempfile::TempDir = tempfile::tempdir().unwrap();
+	let (identity, authorized) = (keys.path().join("identity"), keys.path().join("authorized"));
+	login::keygen(&identity, &authorized);
+	let server_config: ConnectionConfiguration = ConnectionConfiguration {
+		authorized_keys: Some(authorized),
+		..ConnectionConfiguration::default()
+	};
+	let client_config: ConnectionConfiguration = ConnectionConfiguration {
+		identity: Some(identity),
+		..ConnectionConfiguration::default()
+	};
+
+	let sessions: Sessions = Sessions::new(&server_config, &error::new_events());
+	let client_events: Events = error::new_events();
+	let mut ended = client_events.subscribe();
+	let relays: Arc<Mutex<Vec<task::JoinHandle<()>>>> = Arc::new(Mutex::new(Vec::new()));
+
+	// The first link:
+	let (client_link, server_link, relay) = link_pair(session_id(b"one", b"two"));
+	relays.lock().unwrap().push(relay);
+	let (server_sessions, config) = (sessions.clone(), server_config.clone());
+	let server = task::spawn(async move { server_sessions.admit(server_link, &config).await.unwrap().unwrap() });
+
+	// Redialing makes a new pair and hands the server half to the server, like a new connection would:
+	let redial_sessions: Sessions = sessions.clone();
+	let redial_relays = relays.clone();
+	let redial_config: ConnectionConfiguration = server_config.clone();
+	let redial: Redial = Box::new(move || {
+		let (sessions, config) = (redial_sessions.clone(), redial_config.clone());
+		let (client_link, server_link, relay) = link_pair(session_id(b"three", b"four"));
+		redial_relays.lock().unwrap().push(relay);
+		return Box::pin(async move {
+			task::spawn(async move { assert!(sessions.admit(server_link, &config).await.unwrap().is_none()) });
+			return Ok(client_link);
+		});
+	});
+	let client_sessions: Sessions = Sessions::new(&client_config, &client_events);
+	let mut client_streams: Multiplexer = client_sessions.open(client_link, None, Some(redial)).await.unwrap();
+	let mut server_streams: Multiplexer = server.await.unwrap();
+	let ((ctx, mut crx), (stx, mut srx)) = (client_streams.take_main().unwrap(), server_streams.take_main().unwrap());
+
+	// Another stream, besides the main one:
+	let (_, other_ctx, _other_crx) = client_streams.open().await.unwrap();
+	let (_, _other_stx, mut other_srx) = server_streams.accept().await.unwrap();
+
+	// Normal traffic:
+	ctx.send(Bytes::from_static(b"a")).await.unwrap();
+	assert_eq!(srx.recv().await.unwrap(), &b"a"[..]);
+
+	// Messages too big for one record get split up, and put back together on the other end:
+	let big: Bytes = (0..20000_u32).map(|i| { i as u8 }).collect();
+	ctx.send(big.clone()).await.unwrap();
+	assert_eq!(srx.recv().await.unwrap(), big);
+
+	// Cut the link, and send some stuff while the client's away:
+	relays.lock().unwrap().pop().unwrap().abort();
+	stx.send(Bytes::from_static(b"b")).await.unwrap();
+	stx.send(big.clone()).await.unwrap();
+	stx.send(Bytes::from_static(b"c")).await.unwrap();
+	other_ctx.send(Bytes::from_static(b"other")).await.unwrap();
+
+	// It should all show up, in order, once the client's back (the other stream comes back along with the session):
+	assert_eq!(crx.recv().await.unwrap(), &b"b"[..]);
+	assert_eq!(crx.recv().await.unwrap(), big);
+	assert_eq!(crx.recv().await.unwrap(), &b"c"[..]);
+	assert_eq!(other_srx.recv().await.unwrap(), &b"other"[..]);
+	ctx.send(Bytes::from_static(b"d")).await.unwrap();
+	assert_eq!(srx.recv().await.unwrap(), &b"d"[..]);
+	assert_eq!(sessions.table.lock().unwrap().len(), 1);
+
+	// The server handed out a ticket over the new link (before replaying anything), and it's good:
+	let ticket = client_sessions.tickets().take(&Endpoint::Command(String::from("server"))).unwrap();
+	assert_eq!(sessions.tickets().redeem(ticket.sealed()).unwrap(), Secret::new(session_id(b"three", b"four")));
+
+	// But a client that doesn't log in doesn't get any:
+	let (anonymous_link, server_link, relay) = link_pair(session_id(b"five", b"six"));
+	relays.lock().unwrap().push(relay);
+	let (server_sessions, config) = (sessions.clone(), server_config.clone());
+	let server = task::spawn(async move { server_sessions.admit(server_link, &config).await.unwrap().unwrap() });
+	let anonymous: Sessions = Sessions::new(&ConnectionConfiguration::default(), &error::new_events());
+	let (_anonymous_tx, mut anonymous_rx) = anonymous.open(anonymous_link, None, None).await.unwrap().into_main();
+	let (anonymous_stx, _anonymous_srx) = server.await.unwrap().into_main();
+	anonymous_stx.send(Bytes::from_static(b"hi")).await.unwrap();
+	assert_eq!(anonymous_rx.recv().await.unwrap(), &b"hi"[..]);
+	assert!(anonymous.tickets().take(&Endpoint::Command(String::from("server"))).is_none());
+
+	// Hanging up still delivers what was already queued, then tells the other end why:
+	stx.send(Bytes::from_static(b"e")).await.unwrap();
+	sessions.disconnect(Disconnect::new(DisconnectReason::ServerShutdown, "restarting")).await;
+	assert_eq!(crx.recv().await.unwrap(), &b"e"[..]);
+	assert!(crx.recv().await.is_none());
+	assert!(matches!(ended.recv().await.unwrap(), ConnectionEvent::Disconnected { disconnect, by_peer: true, .. }
+		if disconnect == Disconnect::new(DisconnectReason::ServerShutdown, "restarting")));
+}
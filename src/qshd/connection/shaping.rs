@@ -0,0 +1,182 @@
+/*!
+	Bandwidth shaping, so that bulk transfers don't saturate the network.
+	Every link gets a token bucket each way: `send_task` waits for tokens before sending a data record,
+	and `recv_task` waits for them before reading the next one (which pushes back on the sender, through
+	the transport's own flow control). The rate comes from the configuration, per peer. Records from
+	interactive sessions (shells, say) don't count against it, so typing stays snappy while a copy is
+	throttled: clients say which of their sessions are interactive, and tag those records. That's the
+	peer's say-so, though, so each end gives interactive records a bucket of their own, at a rate it
+	picks itself; tagging everything interactive only ever gets a peer that much.
+*/
+
+// External stuff:
+use tokio::time::{self, Duration, Instant};
+
+// Internal stuff:
+use super::{
+	keepalive,
+	ConnectionConfiguration,
+	Endpoint,
+};
+
+
+/// Tag for records that carry interactive application data (see `keepalive` for the others).
+pub(super) const RECORD_INTERACTIVE: u8 = 2;
+
+
+/// A token bucket: lets `rate` bytes a second through, with bursts of up to `burst` bytes.
+pub(super) struct TokenBucket {
+	rate: f64,
+	burst: f64,
+	tokens: f64,
+	last: Instant,
+} impl TokenBucket {
+
+	/// Makes a new one, starting out full.
+	pub(super) fn new(rate: u64, burst: u64) -> Self {
+		return Self {
+			rate: rate as f64,
+			burst: burst as f64,
+			tokens: burst as f64,
+			last: Instant::now(),
+		};
+	}
+
+	/// Waits until `bytes` may go through. Records bigger than the whole bucket go through too, they just leave it in debt for a while.
+	pub(super) async fn take(&mut self, bytes: usize) {
+		let now: Instant = Instant::now();
+		self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.burst);
+		self.last = now;
+		self.tokens -= bytes as f64;
+		if self.tokens < 0.0 {
+			time::sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+		}
+	}
+
+}
+
+
+/// Shaping for one direction of one link.
+pub(super) struct Shaper {
+
+	// The bucket, if there's a limit:
+	bucket: Option<TokenBucket>,
+
+	// The bucket for interactive records, if they get one of their own:
+	interactive_bucket: Option<TokenBucket>,
+
+	// Whether the records we send are interactive (outbound only):
+	interactive: bool,
+
+} impl Shaper {
+
+	/**
+		Makes the (outbound, inbound) shapers for a link to `peer`.
+		The limit is the first of `rate_limits` that names the peer (by endpoint, or just its host), or else `rate_limit`.
+	*/
+	pub(super) fn pair(config: &ConnectionConfiguration, peer: &Endpoint) -> (Self, Self) {
+		let rate: u64 = limit(config, peer);
+		let bucket = |rate: u64| { if rate == 0 { None } else { Some(TokenBucket::new(rate, config.rate_limit_burst.max(1))) } };
+		let interactive_bucket = || { if rate == 0 { None } else { bucket(config.interactive_rate_limit) } };	// Unlimited links don't need one.
+		return (
+			Self { bucket: bucket(rate), interactive_bucket: interactive_bucket(), interactive: config.interactive },
+			Self { bucket: bucket(rate), interactive_bucket: interactive_bucket(), interactive: false },
+		);
+	}
+
+	/// Tag for the next data record going out.
+	pub(super) fn tag(&self) -> u8 {
+		return if self.interactive { RECORD_INTERACTIVE } else { keepalive::RECORD_DATA };
+	}
+
+	/// Waits until a data record with tag `tag`, carrying `bytes` bytes, may go through (if it's shaped at all).
+	pub(super) async fn pass(&mut self, tag: u8, bytes: usize) {
+		let bucket: Option<&mut TokenBucket> = match (tag, &mut self.interactive_bucket) {
+			(RECORD_INTERACTIVE, Some(interactive_bucket)) => Some(interactive_bucket),
+			_ => self.bucket.as_mut(),
+		};
+		if let Some(bucket) = bucket {
+			bucket.take(bytes).await;
+		}
+	}
+
+}
+
+/// How many bytes a second a link to `peer` may carry each way (0 means no limit).
+fn limit(config: &ConnectionConfiguration, peer: &Endpoint) -> u64 {
+	let host: Option<String> = match peer {
+		Endpoint::Inet(addr) => Some(addr.ip().to_string()),
+		Endpoint::Host { name, .. } => Some(name.clone()),
+		_ => None,
+	};
+	return [Some(peer.to_string()), host].into_iter().flatten()
+		.find_map(|key| { config.rate_limits.get(&key).copied() })
+		.unwrap_or(config.rate_limit);
+}
+
+
+#[tokio::test]
+async fn test_shaping() {
+	use std::{collections::HashMap, net::Ipv6Addr};
+	use tokio::io;
+	use super::{
+		error::{self, Events},
+		resumption::Tickets,
+		Buffer,
+		TcpConnection,
+	};
+
+	// Limits are per peer, by endpoint or by host, falling back to the default:
+	let config: ConnectionConfiguration = ConnectionConfiguration {
+		rate_limit: 1000,
+		rate_limits: HashMap::from([
+			(String::from("::1"), 2000),
+			(String::from("example.com:22"), 3000),
+		]),
+		..ConnectionConfiguration::default()
+	};
+	assert_eq!(limit(&config, &Endpoint::Inet((Ipv6Addr::LOCALHOST, 4022).into())), 2000);
+	assert_eq!(limit(&config, &Endpoint::Host { name: String::from("example.com"), port: 22 }), 3000);
+	assert_eq!(limit(&config, &Endpoint::Host { name: String::from("example.com"), port: 2222 }), 1000);
+	assert_eq!(limit(&config, &Endpoint::Stdio), 1000);
+
+	// Sends 20 KB over an in-memory link limited to 40 KB/s (with a 4 KB burst, and `interactive_rate_limit` for interactive records), and times how long it takes to arrive:
+	async fn transfer(interactive: bool, interactive_rate_limit: u64) -> Duration {
+		let server: ConnectionConfiguration = ConnectionConfiguration {
+			rate_limit: 40000,
+			rate_limit_burst: 4000,
+			interactive_rate_limit: interactive_rate_limit,
+			..ConnectionConfiguration::default()
+		};
+		let client: ConnectionConfiguration = ConnectionConfiguration {
+			interactive: interactive,
+			..server.clone()
+		};
+		let events: Events = error::new_events();
+		let (a, b) = io::duplex(65536);
+		let (a_rx, a_tx) = io::split(a);
+		let (b_rx, b_tx) = io::split(b);
+		let tickets: Tickets = Tickets::new(&server);
+		let (client, server) = tokio::join!(
+			TcpConnection::start(&client, &events, &tickets, a_tx, a_rx, Endpoint::Stdio),
+			TcpConnection::start(&server, &events, &tickets, b_tx, b_rx, Endpoint::Stdio),
+		);
+		let (client, mut server) = (client.unwrap(), server.unwrap());
+
+		let started: Instant = Instant::now();
+		for _ in 0..10 {
+			client.tx.send(Buffer::from(&[0_u8; 2000][..])).await.unwrap();
+		}
+		for _ in 0..10 {
+			server.rx.recv().await.unwrap();
+		}
+		return started.elapsed();
+	}
+
+	// Bulk data gets throttled, but interactive data gets its own allowance:
+	assert!(transfer(false, 400000).await >= Duration::from_millis(350));
+	assert!(transfer(true, 400000).await < Duration::from_millis(150));
+
+	// Which is only as big as each end says, however much of it the other end tags interactive:
+	assert!(transfer(true, 40000).await >= Duration::from_millis(350));
+}
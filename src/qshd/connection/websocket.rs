@@ -0,0 +1,311 @@
+/*!
+	Just enough of the WebSocket protocol (RFC 6455) to carry qsh through HTTP reverse proxies.
+	The client asks for an upgrade like a browser would; once the server agrees, everything that
+	would've gone over the raw stream goes back and forth in binary frames instead. `bridge` hides
+	the frames behind an ordinary byte stream, so the handshake and record layer on top don't
+	need to know about any of it.
+*/
+
+// External stuff:
+use base64::{
+	engine::general_purpose::STANDARD as BASE64,
+	Engine,
+};
+use sha1::{Digest, Sha1};
+use tokio::{
+	io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, Error, ErrorKind, ReadHalf, WriteHalf},
+	sync::mpsc::{
+		self, Receiver, Sender
+	},
+	task,
+};
+
+
+/// Gets appended to the client's key to make the server's answer (see RFC 6455, section 1.3).
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest HTTP header we'll put up with from the other end.
+const HTTP_HEADER_LIMIT: usize = 8192;
+
+/// Most bytes to put in one frame.
+const FRAME_SIZE: usize = 16384;
+
+/// Largest frame we'll accept from the other end.
+const MAX_FRAME_SIZE: u64 = 1 << 24;
+
+/// How many bytes each direction of a bridge can hold before the writer has to wait.
+const PIPE_BUFFER_SIZE: usize = 65536;
+
+/// How many control frames can be waiting to go out.
+const CONTROL_BUFFER_SIZE: usize = 16;
+
+// Frame types:
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+
+/// Which end of the WebSocket we are; clients have to mask what they send, and servers mustn't.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Role {
+	Client,
+	Server,
+}
+
+
+/// Whether `start` looks like the beginning of an HTTP request, rather than a raw qsh handshake (whose first bytes are a random-looking public key).
+pub(super) fn is_upgrade(start: &[u8]) -> bool {
+	return start.starts_with(b"GET ");
+}
+
+/// Client side: asks the server at `host` (as the `Host` header should name it) to switch `stream` over to WebSocket, at `path`.
+pub(super) async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: &str, path: &str) -> Result<(), Error> {
+	// Send the request:
+	let key: String = BASE64.encode(rand::random::<[u8; 16]>());
+	let request: String = format!(
+		"GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+		path, host, key,
+	);
+	stream.write_all(request.as_bytes()).await?;
+	stream.flush().await?;
+
+	// The server has to say yes, and prove that it actually speaks WebSocket by answering our key:
+	let header: String = read_header(stream).await?;
+	let status_line: &str = header.lines().next().unwrap_or_default();
+	if status_line.split_whitespace().nth(1) != Some("101") {
+		return Err(Error::new(ErrorKind::ConnectionRefused, format!("server wouldn't switch to WebSocket: {}", status_line)));
+	}
+	if header_value(&header, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+		return Err(Error::new(ErrorKind::InvalidData, "server gave the wrong answer to our WebSocket key"));
+	}
+	return Ok(());
+}
+
+/// Server side: reads the client's upgrade request off `stream`, and agrees to it (or turns it down, if it isn't one).
+pub(super) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(), Error> {
+	let header: String = read_header(stream).await?;
+	let request_line: &str = header.lines().next().unwrap_or_default();
+	let upgrade: bool = header_value(&header, "Upgrade").is_some_and(|upgrade| { upgrade.eq_ignore_ascii_case("websocket") });
+	let version: Option<&str> = header_value(&header, "Sec-WebSocket-Version");
+	let key: Option<&str> = header_value(&header, "Sec-WebSocket-Key");
+	if let (true, Some("13"), Some(key)) = (request_line.starts_with("GET "), version, key) && upgrade {
+		let response: String = format!(
+			"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+			accept_key(key),
+		);
+		stream.write_all(response.as_bytes()).await?;
+		stream.flush().await?;
+		return Ok(());
+	} else {
+		stream.write_all(b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n").await?;
+		stream.flush().await?;
+		return Err(Error::new(ErrorKind::InvalidData, format!("not a WebSocket upgrade: {}", request_line)));
+	}
+}
+
+/**
+	Spins up the tasks that carry bytes over the (already upgraded) WebSocket on `stream`.
+	Returns the other end: what's written to it goes out in binary frames, and what comes in can be read from it.
+	It reads EOF once the other end closes the WebSocket, and shutting it down closes the WebSocket from this end.
+*/
+pub(super) fn bridge<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, role: Role) -> DuplexStream {
+	let (near, far) = io::duplex(PIPE_BUFFER_SIZE);
+	let (far_rx, far_tx) = io::split(far);
+	let (stream_rx, stream_tx) = io::split(stream);
+
+	// Control frames (pongs, and the reply to a close) go out through the writer:
+	let (control_tx, control_rx) = mpsc::channel::<(u8, Vec<u8>)>(CONTROL_BUFFER_SIZE);
+	task::spawn(async move {
+		if let Err(e) = write_task(stream_tx, far_rx, control_rx, role).await {
+			eprintln!("WebSocket: failed to send: {}", e);
+		}
+	});
+	task::spawn(async move {
+		if let Err(e) = read_task(stream_rx, far_tx, control_tx, role).await {
+			eprintln!("WebSocket: failed to receive: {}", e);
+		}
+	});
+	return near;
+}
+
+/// Sends whatever's written to the bridge in binary frames, along with any control frames, until the bridge is shut down.
+async fn write_task<S: AsyncWrite>(mut tx: WriteHalf<S>, mut from: ReadHalf<DuplexStream>, mut control: Receiver<(u8, Vec<u8>)>, role: Role) -> Result<(), Error> {
+	let mut buf: Vec<u8> = vec![0_u8; FRAME_SIZE];
+	loop {
+		tokio::select! {
+			read = from.read(&mut buf) => {
+				let length: usize = read?;
+				if length == 0 {
+					// Shut down on our end, so say goodbye (normal closure):
+					write_frame(&mut tx, OPCODE_CLOSE, &1000_u16.to_be_bytes(), role).await?;
+					return tx.shutdown().await;
+				}
+				write_frame(&mut tx, OPCODE_BINARY, &buf[..length], role).await?;
+			},
+			Some((opcode, payload)) = control.recv() => {
+				write_frame(&mut tx, opcode, &payload, role).await?;
+				if opcode == OPCODE_CLOSE {
+					return tx.shutdown().await;
+				}
+			},
+		}
+	}
+}
+
+/// Writes what comes in from binary frames into the bridge, answering control frames as they come, until the other end closes the WebSocket.
+async fn read_task<S: AsyncRead>(mut rx: ReadHalf<S>, mut to: WriteHalf<DuplexStream>, control: Sender<(u8, Vec<u8>)>, role: Role) -> Result<(), Error> {
+	loop {
+		let (opcode, payload) = match read_frame(&mut rx, role).await {
+			Ok(frame) => frame,
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,	// Hung up without saying goodbye.
+			Err(e) => return Err(e),
+		};
+		match opcode {
+			OPCODE_BINARY | OPCODE_CONTINUATION => {
+				to.write_all(&payload).await?;
+				to.flush().await?;
+			},
+			OPCODE_PING => {
+				let _ = control.send((OPCODE_PONG, payload)).await;
+			},
+			OPCODE_PONG => (),
+			OPCODE_CLOSE => {
+				// Say goodbye back (with the same status code):
+				let _ = control.send((OPCODE_CLOSE, payload.get(..2).unwrap_or_default().to_vec())).await;
+				break;
+			},
+			_ => return Err(Error::new(ErrorKind::InvalidData, format!("unexpected WebSocket frame type {:#x}", opcode))),
+		}
+	}
+
+	// Let whatever's reading the bridge know that nothing more's coming:
+	return to.shutdown().await;
+}
+
+/// Writes one frame (masked, if we're the client).
+async fn write_frame<W: AsyncWrite + Unpin>(tx: &mut W, opcode: u8, payload: &[u8], role: Role) -> Result<(), Error> {
+	// Type (always the last fragment), then length:
+	let mut frame: Vec<u8> = Vec::with_capacity(payload.len() + 14);
+	frame.push(0x80 | opcode);
+	let mask_bit: u8 = if role == Role::Client { 0x80 } else { 0x00 };
+	match payload.len() {
+		length @ 0..=125 => frame.push(mask_bit | length as u8),
+		length @ 126..=0xFFFF => {
+			frame.push(mask_bit | 126);
+			frame.extend_from_slice(&(length as u16).to_be_bytes());
+		},
+		length => {
+			frame.push(mask_bit | 127);
+			frame.extend_from_slice(&(length as u64).to_be_bytes());
+		},
+	}
+
+	// Then the payload, masked with a fresh key if need be:
+	if role == Role::Client {
+		let mask: [u8; 4] = rand::random();
+		frame.extend_from_slice(&mask);
+		frame.extend(payload.iter().enumerate().map(|(i, byte)| { byte ^ mask[i % 4] }));
+	} else {
+		frame.extend_from_slice(payload);
+	}
+	tx.write_all(&frame).await?;
+	return tx.flush().await;
+}
+
+/// Reads one frame, and returns its type and (unmasked) payload.
+async fn read_frame<R: AsyncRead + Unpin>(rx: &mut R, role: Role) -> Result<(u8, Vec<u8>), Error> {
+	let mut head: [u8; 2] = [0_u8; 2];
+	rx.read_exact(&mut head).await?;
+	let opcode: u8 = head[0] & 0x0F;
+	let masked: bool = head[1] & 0x80 != 0;
+
+	// Clients have to mask everything, and servers mustn't mask anything:
+	if masked != (role == Role::Server) {
+		return Err(Error::new(ErrorKind::InvalidData, "WebSocket frame is masked the wrong way"));
+	}
+
+	// Then the length:
+	let length: u64 = match head[1] & 0x7F {
+		126 => rx.read_u16().await?.into(),
+		127 => rx.read_u64().await?,
+		length => length.into(),
+	};
+	if length > MAX_FRAME_SIZE {
+		return Err(Error::new(ErrorKind::InvalidData, format!("WebSocket frame is too large ({} bytes)", length)));
+	}
+
+	// And the payload:
+	let mut mask: [u8; 4] = [0_u8; 4];
+	if masked {
+		rx.read_exact(&mut mask).await?;
+	}
+	let mut payload: Vec<u8> = vec![0_u8; length as usize];
+	rx.read_exact(&mut payload).await?;
+	if masked {
+		payload.iter_mut().enumerate().for_each(|(i, byte)| { *byte ^= mask[i % 4] });
+	}
+	return Ok((opcode, payload));
+}
+
+/// Reads an HTTP header one byte at a time, so that nothing past it (which belongs to the WebSocket) gets eaten.
+async fn read_header<R: AsyncRead + Unpin>(rx: &mut R) -> Result<String, Error> {
+	let mut header: Vec<u8> = Vec::new();
+	while !header.ends_with(b"\r\n\r\n") {
+		if header.len() >= HTTP_HEADER_LIMIT {
+			return Err(Error::new(ErrorKind::InvalidData, "HTTP header is too long"));
+		}
+		header.push(rx.read_u8().await?);
+	}
+	return String::from_utf8(header).map_err(|_| { Error::new(ErrorKind::InvalidData, "HTTP header isn't valid UTF-8") });
+}
+
+/// The value of the header field called `name` (ignoring case) in `header`, if there is one.
+fn header_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+	return header.lines().skip(1).find_map(|line| {
+		let (field, value) = line.split_once(':')?;
+		return if field.trim().eq_ignore_ascii_case(name) { Some(value.trim()) } else { None };
+	});
+}
+
+/// What the server answers the client's `key` with.
+fn accept_key(key: &str) -> String {
+	return BASE64.encode(Sha1::new().chain_update(key).chain_update(ACCEPT_GUID).finalize());
+}
+
+
+#[tokio::test]
+async fn test_websocket() {
+	// The example from RFC 6455:
+	assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+	// Upgrade a pipe, and bridge both ends:
+	let (mut client, mut server) = io::duplex(PIPE_BUFFER_SIZE);
+	let (upgraded, accepted) = tokio::join!(upgrade(&mut client, "example.com", "/qsh"), accept(&mut server));
+	upgraded.unwrap();
+	accepted.unwrap();
+	let mut client: DuplexStream = bridge(client, Role::Client);
+	let mut server: DuplexStream = bridge(server, Role::Server);
+
+	// Bytes make it through both ways, however they're split up:
+	let message: Vec<u8> = (0..100000).map(|i| { i as u8 }).collect();
+	let echo = task::spawn(async move {
+		let mut buf: Vec<u8> = vec![0_u8; 100000];
+		server.read_exact(&mut buf).await.unwrap();
+		server.write_all(&buf).await.unwrap();
+		server.shutdown().await.unwrap();
+	});
+	client.write_all(&message).await.unwrap();
+	let mut echoed: Vec<u8> = Vec::new();
+	client.read_to_end(&mut echoed).await.unwrap();
+	assert_eq!(echoed, message);
+	echo.await.unwrap();
+
+	// Anything that isn't an upgrade gets turned down:
+	let (mut client, mut server) = io::duplex(PIPE_BUFFER_SIZE);
+	client.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+	assert_eq!(accept(&mut server).await.unwrap_err().kind(), ErrorKind::InvalidData);
+	assert!(is_upgrade(b"GET /qsh HTTP/1.1"));
+	assert!(!is_upgrade(&[0x47, 0x45, 0x54, 0x00]));
+}
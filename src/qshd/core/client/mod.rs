@@ -0,0 +1,77 @@
+/*!
+	Thing that behaves like a client; sessions that `qsh` asks for, and telling it how they went.
+*/
+
+use tokio::{
+	io::{self, AsyncWrite},
+	sync::broadcast::{self, error::RecvError},
+};
+
+use qsh_common_types::ipc::{write_message, SessionEnded};
+use crate::connection::{ConnectionEvent, Disconnect, DisconnectReason, Endpoint};
+
+
+/**
+	Waits for the session with `peer` to end, then tells `qsh` why over the session control socket.
+	`socket`: the session control socket.
+	`events`: the events of the `Connection` the session's on.
+	`peer`: who the session's with.
+*/
+pub async fn report_end<W: AsyncWrite + Unpin>(socket: &mut W, events: &mut broadcast::Receiver<ConnectionEvent>, peer: &Endpoint) -> io::Result<()> {
+	loop {
+		match events.recv().await {
+			Ok(ConnectionEvent::Disconnected { peer: ended, disconnect, by_peer }) if &ended == peer => {
+				return write_message(socket, &SessionEnded::new(disconnect, by_peer)).await;
+			},
+			Ok(_) | Err(RecvError::Lagged(_)) => continue,
+
+			// The connection went away without saying how the session ended, so as far as anyone can tell, it was lost:
+			Err(RecvError::Closed) => {
+				return write_message(socket, &SessionEnded::new(Disconnect::new(DisconnectReason::ConnectionLost, "the connection went away"), false)).await;
+			},
+		}
+	}
+}
+
+
+#[tokio::test]
+async fn test_report_end() {
+	use bytes::Bytes;
+	use std::net::Ipv6Addr;
+	use tokio::{
+		io::AsyncWriteExt,
+		net::UnixStream,
+	};
+	use qsh_common_types::ipc::{read_message, MAX_IPC_MESSAGE_SIZE};
+	use crate::connection::{Connection, ConnectionConfiguration, TcpConnection};
+
+	let server_address: Endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54399).into());
+
+	// A server that hangs up after one message:
+	let mut config: ConnectionConfiguration = ConnectionConfiguration::default();
+	config.endpoint = server_address.clone();
+	let mut server: TcpConnection = TcpConnection::new(config);
+	server.listen().await.unwrap();
+	tokio::task::spawn(async move {
+		let (_, _tx, mut rx) = server.accept().await.unwrap();
+		rx.recv().await.unwrap();
+		server.disconnect(Disconnect::new(DisconnectReason::Administrative, "go away")).await.unwrap();
+	});
+
+	// `qshd`'s end of the session control socket reports on the client's session, and `qsh`'s end hears why it ended:
+	let (mut qshd, mut qsh): (UnixStream, UnixStream) = UnixStream::pair().unwrap();
+	let mut config: ConnectionConfiguration = ConnectionConfiguration::default();
+	config.endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 0).into());
+	let mut client: TcpConnection = TcpConnection::new(config);
+	let mut events: broadcast::Receiver<ConnectionEvent> = client.events();
+	let (tx, _rx) = client.connect(server_address.clone()).await.unwrap();
+	tx.send(Bytes::from_static(b"hello")).await.unwrap();
+	report_end(&mut qshd, &mut events, &server_address).await.unwrap();
+	let ended: SessionEnded = read_message(&mut qsh).await.unwrap();
+	assert_eq!(ended.disconnect.reason, DisconnectReason::Administrative);
+	assert!(ended.by_peer);
+
+	// And a message that says it's too long to be real doesn't get read (or allocated for):
+	qshd.write_u64_le(MAX_IPC_MESSAGE_SIZE as u64 + 1).await.unwrap();
+	assert_eq!(read_message::<SessionEnded, _>(&mut qsh).await.err().unwrap().kind(), io::ErrorKind::InvalidData);
+}
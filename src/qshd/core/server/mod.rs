@@ -10,6 +10,7 @@ use tokio::{
 
 pub mod config;
 mod exec;
+pub mod notify;
 use config::ServerConfiguration;
 
 
@@ -0,0 +1,145 @@
+/*!
+	Telling the service manager what the server's up to, over `NOTIFY_SOCKET` (systemd's `sd_notify` protocol):
+	when it's ready for connections, when it's reloading its configuration, and when it's on its way out.
+	With `Type=notify` and socket activation (see `connection::activation`), nothing that depends on qshd
+	gets started before it can actually take logins. Without `NOTIFY_SOCKET`, all of this does nothing.
+	Listening `Connection`s say they're ready once they're taking connections, and that they're stopping
+	when they're told to hang up.
+*/
+
+use std::{
+	env,
+	ffi::{OsStr, OsString},
+	io::{Error, ErrorKind},
+	os::unix::{
+		ffi::OsStrExt,
+		net::{SocketAddr, UnixDatagram},
+	},
+	path::Path,
+	sync::{Mutex, OnceLock},
+};
+
+
+/// Where notifications go, if anywhere.
+static SOCKET: OnceLock<Mutex<Option<OsString>>> = OnceLock::new();
+
+
+/// Things worth telling the service manager.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+
+	/// Done starting up, and taking connections.
+	Ready,
+
+	/// Reloading the configuration (send `Ready` again when that's done).
+	Reloading,
+
+	/// Shutting down.
+	Stopping,
+
+} impl State {
+
+	/// What this looks like on the wire.
+	fn message(&self) -> &'static str {
+		return match self {
+			Self::Ready => "READY=1",
+			Self::Reloading => "RELOADING=1",
+			Self::Stopping => "STOPPING=1",
+		};
+	}
+
+}
+
+
+/// Tells the service manager about `state`. Returns whether there was anyone to tell.
+pub fn notify(state: State) -> Result<bool, Error> {
+	if let Some(socket) = socket().lock().unwrap().as_ref() {
+		send(socket, state)?;
+		return Ok(true);
+	} else {
+		return Ok(false);
+	}
+}
+
+/// The notification socket, picked up from `NOTIFY_SOCKET` the first time it's asked for.
+fn socket() -> &'static Mutex<Option<OsString>> {
+	return SOCKET.get_or_init(|| { Mutex::new(env::var_os("NOTIFY_SOCKET")) });
+}
+
+/// Sends `state` to the notification socket at `socket` (a path, or an abstract socket name starting with `@`).
+fn send(socket: &OsStr, state: State) -> Result<(), Error> {
+	let socket: &[u8] = socket.as_bytes();
+	let addr: SocketAddr = if socket.starts_with(b"/") {
+		SocketAddr::from_pathname(Path::new(OsStr::from_bytes(socket)))?
+	} else if let Some(name) = socket.strip_prefix(b"@") {
+		abstract_addr(name)?
+	} else {
+		return Err(Error::new(ErrorKind::InvalidInput, "NOTIFY_SOCKET is neither a path nor an abstract socket name"));
+	};
+	let sender: UnixDatagram = UnixDatagram::unbound()?;
+	sender.send_to_addr(state.message().as_bytes(), &addr)?;
+	return Ok(());
+}
+
+/// The address of the abstract socket called `name` (Linux only).
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &[u8]) -> Result<SocketAddr, Error> {
+	use std::os::linux::net::SocketAddrExt;
+	return SocketAddr::from_abstract_name(name);
+}
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &[u8]) -> Result<SocketAddr, Error> {
+	return Err(Error::from(ErrorKind::Unsupported));
+}
+
+
+#[tokio::test]
+async fn test_notify() {
+
+	// A socket standing in for the service manager's:
+	let directory: tempfile::TempDir = tempfile::tempdir().unwrap();
+	let path: std::path::PathBuf = directory.path().join("notify");
+	let manager: UnixDatagram = UnixDatagram::bind(&path).unwrap();
+
+	// Every state should arrive as its own datagram:
+	let mut buf: [u8; 64] = [0_u8; 64];
+	for (state, expected) in [(State::Ready, "READY=1"), (State::Reloading, "RELOADING=1"), (State::Stopping, "STOPPING=1")] {
+		send(path.as_os_str(), state).unwrap();
+		let length: usize = manager.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..length], expected.as_bytes());
+	}
+
+	// Same for abstract sockets:
+	let name: String = format!("qshd-notify-test-{}", std::process::id());
+	let manager: UnixDatagram = UnixDatagram::bind_addr(&abstract_addr(name.as_bytes()).unwrap()).unwrap();
+	send(OsStr::new(&format!("@{}", name)), State::Ready).unwrap();
+	let length: usize = manager.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..length], b"READY=1");
+
+	// And anything else gets turned down:
+	assert_eq!(send(OsStr::new("relative/path"), State::Ready).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+	// A server says it's ready once it's listening, and that it's stopping once it's told to hang up (other tests' servers might chime in too, hence the waiting):
+	use std::net::Ipv6Addr;
+	use crate::connection::{Connection, ConnectionConfiguration, Disconnect, DisconnectReason, Endpoint, TcpConnection};
+	let manager: UnixDatagram = UnixDatagram::bind(directory.path().join("manager")).unwrap();
+	manager.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+	*socket().lock().unwrap() = Some(directory.path().join("manager").into_os_string());
+	let heard = |expected: &str| {
+		let mut buf: [u8; 64] = [0_u8; 64];
+		while let Ok(length) = manager.recv(&mut buf) {
+			if &buf[..length] == expected.as_bytes() {
+				return true;
+			}
+		}
+		return false;
+	};
+	let mut config: ConnectionConfiguration = ConnectionConfiguration::default();
+	config.endpoint = Endpoint::Inet((Ipv6Addr::LOCALHOST, 54398).into());
+	let mut server: TcpConnection = TcpConnection::new(config);
+	server.listen().await.unwrap();
+	assert!(heard("READY=1"));
+	server.disconnect(Disconnect::new(DisconnectReason::ServerShutdown, "stopping")).await.unwrap();
+	assert!(heard("STOPPING=1"));
+	*socket().lock().unwrap() = None;
+}
@@ -1,6 +1,8 @@
 use serde::Deserialize;
 use std::net::Ipv6Addr;
 
+use qsh_common_types::endpoint::Endpoint;
+
 use super::{
 	super::super::super::connection::*,
 	crypto::CryptoTypes,
@@ -18,13 +20,9 @@ pub enum ConnectionTypes {
 #[derive(Deserialize)]
 pub struct ConnectionConfiguration {
 
-	/// What address to listen on.
-	#[serde(default = "default_addr")]
-	pub addr: Ipv6Addr,
-
-	/// What port to listen on.
-	#[serde(default = "default_port")]
-	pub port: u16,
+	/// Where to listen.
+	#[serde(default = "default_endpoint")]
+	pub endpoint: Endpoint,
 
 	/// Allowed types of connection.
 	#[serde(default = "default_allowed_connection")]
@@ -50,9 +48,6 @@ fn default_allowed_crypto() -> CryptoTypes {
 fn default_allowed_kex() -> KexTypes {
 	return KexTypes::Kyberlib;
 }
-fn default_addr() -> Ipv6Addr {
-	return Ipv6Addr::LOCALHOST;
-}
-fn default_port() -> u16 {
-	return 54321;
+fn default_endpoint() -> Endpoint {
+	return Endpoint::Inet((Ipv6Addr::LOCALHOST, 54321).into());
 }
\ No newline at end of file
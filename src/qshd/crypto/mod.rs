@@ -9,42 +9,62 @@ use std::fmt::Display;
 // Module declarations go here:
 #[cfg(feature = "aes-gcm")]
 mod qsh_aes_gcm;
+mod schedule;
 
 // Re-export them here:
 #[cfg(feature = "aes-gcm")]
 pub use qsh_aes_gcm::{AesGcmEncryptor, AesGcmDecryptor};
+pub use schedule::{KeySchedule, Secret, TrafficSecret, Transcript, SECRET_LEN};
 
-use crate::kex::{KeyExchanger, self};
+use crate::connection::Buffer;
 
 pub trait Encryptor {
 	type Error: Display;
 
 
-	fn new<T: KeyExchanger>(key_exchange: T) -> Self;
+	/// Makes one, keyed from `secret` (see `KeySchedule::send`).
+	fn new(secret: &TrafficSecret) -> Self;
 
-	fn encrypt(&mut self, data: &mut Vec<u8>, adata: &[u8]) -> Result<(), Self::Error>;
+	/// Encrypts `data` in place (the tag goes in its tailroom).
+	fn encrypt(&mut self, data: &mut Buffer, adata: &[u8]) -> Result<(), Self::Error>;
 
 }
 pub trait Decryptor {
 	type Error: Display;
 
 
-	fn new<T: KeyExchanger>(key_exchange: T) -> Self;
+	/// Makes one, keyed from `secret` (see `KeySchedule::recv`).
+	fn new(secret: &TrafficSecret) -> Self;
 
-	fn decrypt(&mut self, data: &mut Vec<u8>, adata: &[u8]) -> Result<(), Self::Error>;
+	/// Decrypts `data` in place (taking the tag back off).
+	fn decrypt(&mut self, data: &mut Buffer, adata: &[u8]) -> Result<(), Self::Error>;
 
 }
 
 
 /// Types of encryption.
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub enum Implementation {
 	AesGcm,
 } impl Implementation {
-	/// Generates a `Encryptor`-`Decryptor` pair dynamically from the configuration `struct`.
-	pub fn generate<T: KeyExchanger>(&self, i: T, o: T) -> (impl Encryptor + use<T>, impl Decryptor + use<T>) {
+	/// What this is called in handshakes.
+	pub fn name(&self) -> &'static str {
 		return match self {
-			Self::AesGcm => (AesGcmEncryptor::new(o), AesGcmDecryptor::new(i)),
+			Self::AesGcm => "aes-256-gcm",
+		};
+	}
+
+	/// How many bytes encrypting adds to a record.
+	pub fn overhead(&self) -> usize {
+		return match self {
+			Self::AesGcm => 16,
+		};
+	}
+
+	/// Generates a `Encryptor`-`Decryptor` pair dynamically from the configuration `struct`, keyed from `schedule`.
+	pub fn generate(&self, schedule: &KeySchedule) -> (impl Encryptor + use<>, impl Decryptor + use<>) {
+		return match self {
+			Self::AesGcm => (AesGcmEncryptor::new(schedule.send()), AesGcmDecryptor::new(schedule.recv())),
 		};
 	}
 }
\ No newline at end of file
@@ -4,11 +4,17 @@
 */
 
 // External dependancies go here:
-use aes_gcm::{self, aead::AeadMutInPlace, Aes256Gcm, Error, KeyInit, Nonce};
+use aes_gcm::{self, aead::{self, AeadMutInPlace}, Aes256Gcm, Error, KeyInit, Nonce};
 use arbitrary_int::u96;
+use zeroize::Zeroizing;
 
 // Internal dependancies go here:
-use super::{Encryptor, Decryptor};
+use super::{Encryptor, Decryptor, TrafficSecret};
+use crate::connection::Buffer;
+
+
+/// Label for this cipher's keys in the key schedule.
+const KEY_LABEL: &[u8] = b"aes-256-gcm key";
 
 pub struct AesGcmEncryptor {
 
@@ -27,14 +33,14 @@ pub struct AesGcmDecryptor {
 impl Encryptor for AesGcmEncryptor {
 	type Error = Error;
 
-	fn new<T: crate::kex::KeyExchanger>(key_exchange: T) -> Self {
+	fn new(secret: &TrafficSecret) -> Self {
 		return Self {
-			cipher: Aes256Gcm::new(key_exchange.shared_secret().into()),
+			cipher: cipher(secret),
 			nonce: u96::from_u64(0),
 		};
 	}
 
-	fn encrypt(&mut self, data: &mut Vec<u8>, adata: &[u8]) -> Result<(), Self::Error> {
+	fn encrypt(&mut self, data: &mut Buffer, adata: &[u8]) -> Result<(), Self::Error> {
 		// We'll just call this function on the data (note the little-endian specific method used here):
 		self.cipher.encrypt_in_place(Nonce::from_slice(&self.nonce.to_le_bytes()), adata, data)?;
 		
@@ -48,14 +54,14 @@ impl Encryptor for AesGcmEncryptor {
 impl Decryptor for AesGcmDecryptor {
 	type Error = Error;
 
-	fn new<T: crate::kex::KeyExchanger>(key_exchange: T) -> Self {
+	fn new(secret: &TrafficSecret) -> Self {
 		return Self {
-			cipher: Aes256Gcm::new(key_exchange.shared_secret().into()),
+			cipher: cipher(secret),
 			nonce: u96::from_u64(0),
 		};
 	}
 
-	fn decrypt(&mut self, data: &mut Vec<u8>, adata: &[u8]) -> Result<(), Self::Error> {
+	fn decrypt(&mut self, data: &mut Buffer, adata: &[u8]) -> Result<(), Self::Error> {
 		// Same thing, in reverse (note the little-endian specific method used here):
 		self.cipher.decrypt_in_place(Nonce::from_slice(&self.nonce.to_le_bytes()), adata, data)?;
 		
@@ -67,42 +73,60 @@ impl Decryptor for AesGcmDecryptor {
 
 }
 
-#[test]
-fn test_aes_gcm_crypto() {
-	use crate::kex::{KeyExchanger, KyberlibKeyExchanger};
+/// Makes a cipher keyed from `secret`.
+fn cipher(secret: &TrafficSecret) -> Aes256Gcm {
+	let mut key: Zeroizing<[u8; 32]> = Zeroizing::new([0_u8; 32]);
+	secret.derive(KEY_LABEL, key.as_mut_slice());
+	return Aes256Gcm::new(key.as_slice().into());
+}
 
-	// Run a double key exchange:
+// So that records can be encrypted right where they are:
+impl aead::Buffer for Buffer {
 
-	let mut alice_kex_o: KyberlibKeyExchanger = KyberlibKeyExchanger::new().expect("Failed to create Alice in AES-GCM test");
-	let mut alice_kex_i: KyberlibKeyExchanger = KyberlibKeyExchanger::new().expect("Failed to create Alice in AES-GCM test");
+	fn extend_from_slice(&mut self, other: &[u8]) -> aead::Result<()> {
+		Buffer::extend_from_slice(self, other);
+		return Ok(());
+	}
 
-	let mut bob_kex_o: KyberlibKeyExchanger = KyberlibKeyExchanger::new().expect("Failed to create Bob in AES-GCM test");
-	let mut bob_kex_i: KyberlibKeyExchanger = KyberlibKeyExchanger::new().expect("Failed to create Bob in AES-GCM test");
+	fn truncate(&mut self, len: usize) {
+		Buffer::truncate(self, len);
+	}
 
-	alice_kex_o.set_remote_pubkey(&bob_kex_i.get_local_pubkey()).expect("Failed to set Alice's remote pubkey in AES-GCM test");
-	alice_kex_i.set_remote_pubkey(&bob_kex_o.get_local_pubkey()).expect("Failed to set Alice's remote pubkey in AES-GCM test");
-	bob_kex_o.set_remote_pubkey(&alice_kex_i.get_local_pubkey()).expect("Failed to set Bob's remote pubkey in AES-GCM test");
-	bob_kex_i.set_remote_pubkey(&alice_kex_o.get_local_pubkey()).expect("Failed to set Bob's remote pubkey in AES-GCM test");
+}
+
+#[test]
+fn test_aes_gcm_crypto() {
+	use super::{KeySchedule, Transcript};
 
-	let alice_init: Vec<u8> = alice_kex_o.client_init().expect("Client init failed for Alice in AES-GCM test");
-	let bob_init: Vec<u8> = bob_kex_o.client_init().expect("Client init failed for Bob in AES-GCM test");
-	alice_kex_o.client_confirm(&bob_kex_i.server_init(&alice_init).expect("Server init failed for Bob in AES-GCM test")).expect("Client confirm failed for Alice in AES-GCM test");
-	bob_kex_o.client_confirm(&alice_kex_i.server_init(&bob_init).expect("Server init failed for Bob in AES-GCM test")).expect("Client confirm failed for Bob in AES-GCM test");
+	// Key schedules for both ends of a (pretend) handshake:
+	let mut alice_started: Transcript = Transcript::new();
+	alice_started.append(b"initiator key", b"alice");
+	let mut bob_started: Transcript = Transcript::new();
+	bob_started.append(b"initiator key", b"bob");
+	let alice: KeySchedule = KeySchedule::new((&alice_started, b"one"), (&bob_started, b"two"), b"");
+	let bob: KeySchedule = KeySchedule::new((&bob_started, b"two"), (&alice_started, b"one"), b"");
 
 
 	// Let's go test it!
 
-	let mut alice_en: AesGcmEncryptor = AesGcmEncryptor::new(alice_kex_o);
-	let mut alice_de: AesGcmDecryptor = AesGcmDecryptor::new(alice_kex_i);
-	let mut bob_en: AesGcmEncryptor = AesGcmEncryptor::new(bob_kex_o);
-	let mut bob_de: AesGcmDecryptor = AesGcmDecryptor::new(bob_kex_i);
+	let mut alice_en: AesGcmEncryptor = AesGcmEncryptor::new(alice.send());
+	let mut alice_de: AesGcmDecryptor = AesGcmDecryptor::new(alice.recv());
+	let mut bob_en: AesGcmEncryptor = AesGcmEncryptor::new(bob.send());
+	let mut bob_de: AesGcmDecryptor = AesGcmDecryptor::new(bob.recv());
 
-	let mut alice_msg: Vec<u8> = b"Hello, Bob!".into();
-	let mut bob_response: Vec<u8> = b"Hello, Alice!".into();
+	let mut alice_msg: Buffer = Buffer::from(&b"Hello, Bob!"[..]);
+	let mut bob_response: Buffer = Buffer::from(&b"Hello, Alice!"[..]);
 
 	alice_en.encrypt(&mut alice_msg, b"").expect("Failed to encrypt Alice's message in AES-GCM test");
 	bob_de.decrypt(&mut alice_msg, b"").expect("Failed to decrypt Alice's message in AES-GCM test");
+	assert_eq!(&alice_msg[..], b"Hello, Bob!");
 
 	bob_en.encrypt(&mut bob_response, b"").expect("Failed to encrypt Bob's message in AES-GCM test");
 	alice_de.decrypt(&mut bob_response, b"").expect("Failed to decrypt Bob's response in AES-GCM test");
-}
\ No newline at end of file
+	assert_eq!(&bob_response[..], b"Hello, Alice!");
+
+	// Each direction has its own key, so Alice can't read her own messages:
+	let mut alice_msg: Buffer = Buffer::from(&b"Hello again, Bob!"[..]);
+	alice_en.encrypt(&mut alice_msg, b"").expect("Failed to encrypt Alice's message in AES-GCM test");
+	assert!(alice_de.decrypt(&mut alice_msg, b"").is_err());
+}
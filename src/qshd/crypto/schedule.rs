@@ -0,0 +1,259 @@
+/*!
+	The key schedule: turns the shared secrets from a handshake into the keys that actually get used.
+	Both ends hash everything the handshake exchanged (public keys, init messages, and whatever was
+	negotiated) into a transcript, and that hash salts HKDF-Extract over the shared secrets; so the keys
+	only come out the same if both ends saw exactly the same handshake. Everything else gets expanded
+	from there under its own label: a traffic secret for each direction (which ratchets forward to
+	rekey), and a secret for resuming the session later.
+*/
+
+// External stuff:
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+
+/// Length of every secret in the schedule (and of transcript hashes).
+pub const SECRET_LEN: usize = 32;
+
+/// A secret that gets wiped when it's dropped.
+pub type Secret = Zeroizing<[u8; SECRET_LEN]>;
+
+/// Label for the traffic secrets.
+const LABEL_TRAFFIC: &[u8] = b"qsh traffic";
+
+/// Label for ratcheting a traffic secret forward.
+const LABEL_REKEY: &[u8] = b"qsh rekey";
+
+/// Label for the resumption secret.
+const LABEL_RESUMPTION: &[u8] = b"qsh resumption";
+
+/// Labels for the traffic secrets of a resumed link: what the end that presented the ticket sends, and what the end that issued it does.
+const LABEL_PRESENTER: &[u8] = b"presenter";
+const LABEL_ISSUER: &[u8] = b"issuer";
+
+
+/// A running hash of (part of) a handshake.
+#[derive(Clone, Default)]
+pub struct Transcript(Sha256);
+
+impl Transcript {
+
+	pub fn new() -> Self {
+		return Self(Sha256::new());
+	}
+
+	/// Adds `data` under `label` (both length-prefixed, so nothing can slide from one part into the next).
+	pub fn append(&mut self, label: &[u8], data: &[u8]) {
+		self.0.update((label.len() as u64).to_le_bytes());
+		self.0.update(label);
+		self.0.update((data.len() as u64).to_le_bytes());
+		self.0.update(data);
+	}
+
+	/// The hash of everything so far.
+	pub fn hash(&self) -> [u8; SECRET_LEN] {
+		return self.0.clone().finalize().into();
+	}
+
+}
+
+
+/// The secret for one direction's traffic. Ciphers get their keys from it, and it ratchets forward (one way) to rekey.
+#[derive(Clone)]
+pub struct TrafficSecret {
+	secret: Secret,
+	generation: u64,
+}
+
+impl TrafficSecret {
+
+	/// Fills `key` with key material for `label` (ciphers use their own label, and take as much as they need).
+	pub fn derive(&self, label: &[u8], key: &mut [u8]) {
+		expand(&self.secret, &[label], key);
+	}
+
+	/// The secret for the next generation; there's no getting back to this one from it.
+	pub fn next(&self) -> Self {
+		let mut secret: Secret = Zeroizing::new([0_u8; SECRET_LEN]);
+		expand(&self.secret, &[LABEL_REKEY], secret.as_mut_slice());
+		return Self {
+			secret: secret,
+			generation: self.generation + 1,
+		};
+	}
+
+	/// The secret for the next generation, mixed with `fresh` (the shared secret from a new key exchange), so that it doesn't only depend on this one.
+	pub fn rekey(&self, fresh: &[u8]) -> Self {
+		let next: Self = self.next();
+		let (prk, _) = Hkdf::<Sha256>::extract(Some(next.secret.as_slice()), fresh);
+		return Self {
+			secret: Zeroizing::new(prk.into()),
+			generation: next.generation,
+		};
+	}
+
+	/// How many times this has been ratcheted forward since the handshake.
+	pub fn generation(&self) -> u64 {
+		return self.generation;
+	}
+
+}
+
+
+/// Every secret that comes out of one handshake.
+pub struct KeySchedule {
+
+	// What everything else comes from (never used as a key itself):
+	master: Secret,
+
+	// Hash of the whole handshake:
+	transcript: [u8; SECRET_LEN],
+
+	// For what we send, and what we receive:
+	send: TrafficSecret,
+	recv: TrafficSecret,
+
+} impl KeySchedule {
+
+	/**
+		Derives the schedule from a handshake's two key exchanges, each given as its transcript and shared secret.
+		`ours`: the exchange we initiated (it keys what we send).
+		`theirs`: the exchange the other end initiated (it keys what we receive).
+		`context`: anything else both ends have to agree on (protocol version, negotiated algorithms, etc).
+	*/
+	pub fn new(ours: (&Transcript, &[u8]), theirs: (&Transcript, &[u8]), context: &[u8]) -> Self {
+		let (ours_hash, theirs_hash) = (ours.0.hash(), theirs.0.hash());
+
+		// Both ends have to put the exchanges in the same order, and "ours" is different at each end, so sort them:
+		let (low, high) = if ours_hash < theirs_hash { ((ours_hash, ours.1), (theirs_hash, theirs.1)) } else { ((theirs_hash, theirs.1), (ours_hash, ours.1)) };
+		let mut transcript: Transcript = Transcript::new();
+		transcript.append(b"context", context);
+		transcript.append(b"exchange", &low.0);
+		transcript.append(b"exchange", &high.0);
+		let transcript: [u8; SECRET_LEN] = transcript.hash();
+
+		// Extract a master secret from both shared secrets, salted with the transcript:
+		let secrets: Zeroizing<Vec<u8>> = Zeroizing::new([low.1, high.1].concat());
+		let (prk, _) = Hkdf::<Sha256>::extract(Some(&transcript), &secrets);
+		let master: Secret = Zeroizing::new(prk.into());
+
+		// Each direction's traffic secret is labelled with the exchange that keys it:
+		return Self {
+			send: traffic(&master, &ours_hash),
+			recv: traffic(&master, &theirs_hash),
+			master: master,
+			transcript: transcript,
+		};
+	}
+
+	/**
+		Derives the schedule for a link that picks up from a resumption secret (see `resumption`), instead of a key exchange.
+		`presenter`: whether we're the end that presented the ticket (which decides which traffic secret goes which way).
+		`context`: as for `new`; it has to have something fresh from both ends in it, or every link resumed from the same secret would get the same keys.
+	*/
+	pub fn resume(secret: &Secret, presenter: bool, context: &[u8]) -> Self {
+		let mut transcript: Transcript = Transcript::new();
+		transcript.append(b"context", context);
+		transcript.append(b"resumption", &[]);
+		let transcript: [u8; SECRET_LEN] = transcript.hash();
+		let (prk, _) = Hkdf::<Sha256>::extract(Some(&transcript), secret.as_slice());
+		let master: Secret = Zeroizing::new(prk.into());
+		let (presented, issued) = (traffic(&master, LABEL_PRESENTER), traffic(&master, LABEL_ISSUER));
+		let (send, recv) = if presenter { (presented, issued) } else { (issued, presented) };
+		return Self {
+			send: send,
+			recv: recv,
+			master: master,
+			transcript: transcript,
+		};
+	}
+
+	/// The hash of the whole handshake (both ends have the same one).
+	pub fn transcript(&self) -> &[u8; SECRET_LEN] {
+		return &self.transcript;
+	}
+
+	/// The secret for what we send.
+	pub fn send(&self) -> &TrafficSecret {
+		return &self.send;
+	}
+
+	/// The secret for what we receive.
+	pub fn recv(&self) -> &TrafficSecret {
+		return &self.recv;
+	}
+
+	/// A secret for resuming this session later, without a whole new handshake (both ends get the same one).
+	pub fn resumption(&self) -> Secret {
+		let mut secret: Secret = Zeroizing::new([0_u8; SECRET_LEN]);
+		expand(&self.master, &[LABEL_RESUMPTION, &self.transcript], secret.as_mut_slice());
+		return secret;
+	}
+
+}
+
+/// A fresh traffic secret from `master`, for the direction labelled `label`.
+fn traffic(master: &Secret, label: &[u8]) -> TrafficSecret {
+	let mut secret: Secret = Zeroizing::new([0_u8; SECRET_LEN]);
+	expand(master, &[LABEL_TRAFFIC, label], secret.as_mut_slice());
+	return TrafficSecret { secret: secret, generation: 0 };
+}
+
+/// HKDF-Expand from `secret` (which is already uniformly random), with `info` as the concatenation of its parts.
+fn expand(secret: &Secret, info: &[&[u8]], out: &mut [u8]) {
+	Hkdf::<Sha256>::from_prk(secret.as_slice()).expect("a whole SHA-256 output is a valid PRK")
+		.expand_multi_info(info, out).expect("nothing asks HKDF-SHA256 for more than 255 blocks");
+}
+
+
+#[test]
+fn test_key_schedule() {
+	// Two exchanges, one started by each end:
+	let mut alice_started: Transcript = Transcript::new();
+	alice_started.append(b"initiator key", b"alice");
+	let mut bob_started: Transcript = Transcript::new();
+	bob_started.append(b"initiator key", b"bob");
+	let alice: KeySchedule = KeySchedule::new((&alice_started, b"one"), (&bob_started, b"two"), b"v1");
+	let bob: KeySchedule = KeySchedule::new((&bob_started, b"two"), (&alice_started, b"one"), b"v1");
+
+	// Derives a key from a traffic secret:
+	fn key(secret: &TrafficSecret) -> [u8; 32] {
+		let mut key: [u8; 32] = [0; 32];
+		secret.derive(b"test key", &mut key);
+		return key;
+	}
+
+	// What one end sends, the other can receive, and the two directions differ:
+	assert_eq!(alice.transcript(), bob.transcript());
+	assert_eq!(key(alice.send()), key(bob.recv()));
+	assert_eq!(key(alice.recv()), key(bob.send()));
+	assert_ne!(key(alice.send()), key(alice.recv()));
+	assert_eq!(alice.resumption(), bob.resumption());
+
+	// Rekeying gives both ends the same new keys:
+	let (alice_next, bob_next) = (alice.send().next(), bob.recv().next());
+	assert_eq!(key(&alice_next), key(&bob_next));
+	assert_ne!(key(&alice_next), key(alice.send()));
+	assert_eq!(alice_next.generation(), 1);
+	let (alice_fresh, bob_fresh) = (alice.send().rekey(b"fresh"), bob.recv().rekey(b"fresh"));
+	assert_eq!(key(&alice_fresh), key(&bob_fresh));
+	assert_ne!(key(&alice_fresh), key(&alice_next));
+	assert_eq!(alice_fresh.generation(), 1);
+
+	// Any difference in what was exchanged or agreed on gives different keys:
+	let tampered: KeySchedule = KeySchedule::new((&bob_started, b"two"), (&alice_started, b"one"), b"v2");
+	assert_ne!(key(alice.send()), key(tampered.recv()));
+	bob_started.append(b"client init", b"extra");
+	let tampered: KeySchedule = KeySchedule::new((&bob_started, b"two"), (&alice_started, b"one"), b"v1");
+	assert_ne!(key(alice.send()), key(tampered.recv()));
+
+	// Resuming from the same secret works the same way, with new keys for new contexts:
+	let resumption: Secret = alice.resumption();
+	let (presenter, issuer) = (KeySchedule::resume(&resumption, true, b"hellos"), KeySchedule::resume(&resumption, false, b"hellos"));
+	assert_eq!(key(presenter.send()), key(issuer.recv()));
+	assert_eq!(key(presenter.recv()), key(issuer.send()));
+	assert_ne!(key(presenter.send()), key(presenter.recv()));
+	assert_ne!(key(presenter.send()), key(KeySchedule::resume(&resumption, true, b"other hellos").send()));
+	assert_ne!(presenter.resumption(), resumption);
+}
@@ -5,13 +5,23 @@
 use serde::Deserialize;
 use std::{array::TryFromSliceError, fmt::Display, mem::size_of};
 
+use crate::connection::default_allowed_kex;
+
 // Module declarations go here:
 #[cfg(feature = "kyberlib")]
 pub mod qsh_kyberlib;
+#[cfg(feature = "ml-kem")]
+pub mod qsh_ml_kem;
+#[cfg(feature = "hybrid")]
+pub mod qsh_hybrid;
 
 // Re-export them here:
 #[cfg(feature = "kyberlib")]
 pub use qsh_kyberlib::KyberlibKeyExchanger;
+#[cfg(feature = "ml-kem")]
+pub use qsh_ml_kem::{MlKem768KeyExchanger, MlKem1024KeyExchanger};
+#[cfg(feature = "hybrid")]
+pub use qsh_hybrid::HybridKeyExchanger;
 
 pub trait KeyExchanger {
 	type Error: Display;
@@ -71,14 +81,223 @@ pub trait KeyExchanger {
 
 
 /// Types of key exchange.
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub enum Implementation {
+	/// Kyber (a pre-standard round; kept for older peers).
+	#[cfg(feature = "kyberlib")]
 	Kyberlib,
+
+	/// ML-KEM-768 (FIPS 203).
+	#[cfg(feature = "ml-kem")]
+	MlKem768,
+
+	/// ML-KEM-1024 (FIPS 203).
+	#[cfg(feature = "ml-kem")]
+	MlKem1024,
+
+	/// X25519 and Kyberlib together (see `qsh_hybrid`).
+	#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+	X25519Kyberlib,
+
+	/// X25519 and ML-KEM-768 together (see `qsh_hybrid`).
+	#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+	X25519MlKem768,
 } impl Implementation {
+	/// What this is called in handshakes.
+	pub fn name(&self) -> &'static str {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib => "kyberlib",
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768 => "ml-kem-768",
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024 => "ml-kem-1024",
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib => "x25519-kyberlib",
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768 => "x25519-ml-kem-768",
+		};
+	}
+
 	/// Generates a key exchanger dynamically from the configuration `struct`.
-	pub fn generate(&self) -> impl KeyExchanger + use<> {
+	pub fn generate(&self) -> AnyKeyExchanger {
 		return match self {
-			Self::Kyberlib => KyberlibKeyExchanger::new().expect("failed to generate new keypair"),
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib => AnyKeyExchanger::Kyberlib(KyberlibKeyExchanger::new().expect("failed to generate new keypair")),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768 => AnyKeyExchanger::MlKem768(MlKem768KeyExchanger::new().expect("failed to generate new keypair")),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024 => AnyKeyExchanger::MlKem1024(MlKem1024KeyExchanger::new().expect("failed to generate new keypair")),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib => AnyKeyExchanger::X25519Kyberlib(HybridKeyExchanger::new().expect("failed to generate new keypair")),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768 => AnyKeyExchanger::X25519MlKem768(HybridKeyExchanger::new().expect("failed to generate new keypair")),
 		};
 	}
+}
+
+/// Whichever `KeyExchanger` the configuration asked for; this lets `Implementation::generate` pick one at runtime.
+pub enum AnyKeyExchanger {
+	#[cfg(feature = "kyberlib")]
+	Kyberlib(KyberlibKeyExchanger),
+	#[cfg(feature = "ml-kem")]
+	MlKem768(MlKem768KeyExchanger),
+	#[cfg(feature = "ml-kem")]
+	MlKem1024(MlKem1024KeyExchanger),
+	#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+	X25519Kyberlib(HybridKeyExchanger<KyberlibKeyExchanger>),
+	#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+	X25519MlKem768(HybridKeyExchanger<MlKem768KeyExchanger>),
+}
+
+impl KeyExchanger for AnyKeyExchanger {
+	type Error = String;
+
+	// The lengths depend on which one it is, so they come from the methods below instead:
+	type ClientInit = ();
+	type ServerInit = ();
+	type PublicKey = Vec<u8>;
+
+
+	/// Makes whichever one the default configuration prefers.
+	fn new() -> Result<Self, Self::Error> {
+		let preferred: Implementation = default_allowed_kex().into_iter().next().ok_or(String::from("no key exchanges are built in"))?;
+		return Ok(preferred.generate());
+	}
+
+	fn get_local_pubkey(&self) -> Vec<u8> {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.get_local_pubkey(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.get_local_pubkey(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.get_local_pubkey(),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.get_local_pubkey(),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.get_local_pubkey(),
+		};
+	}
+
+	fn get_client_init_length(&self) -> usize {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.get_client_init_length(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.get_client_init_length(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.get_client_init_length(),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.get_client_init_length(),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.get_client_init_length(),
+		};
+	}
+
+	fn get_server_init_length(&self) -> usize {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.get_server_init_length(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.get_server_init_length(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.get_server_init_length(),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.get_server_init_length(),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.get_server_init_length(),
+		};
+	}
+
+	fn get_public_key_length(&self) -> usize {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.get_public_key_length(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.get_public_key_length(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.get_public_key_length(),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.get_public_key_length(),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.get_public_key_length(),
+		};
+	}
+
+	fn set_remote_pubkey(&mut self, pubkey: &[u8]) -> Result<(), TryFromSliceError> {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.set_remote_pubkey(pubkey),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.set_remote_pubkey(pubkey),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.set_remote_pubkey(pubkey),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.set_remote_pubkey(pubkey),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.set_remote_pubkey(pubkey),
+		};
+	}
+
+	fn client_init(&mut self) -> Result<Vec<u8>, Self::Error> {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.client_init().map_err(|e| { e.to_string() }),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.client_init().map_err(|e| { e.to_string() }),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.client_init().map_err(|e| { e.to_string() }),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.client_init().map_err(|e| { e.to_string() }),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.client_init().map_err(|e| { e.to_string() }),
+		};
+	}
+
+	fn server_init(&mut self, client_init: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.server_init(client_init).map_err(|e| { e.to_string() }),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.server_init(client_init).map_err(|e| { e.to_string() }),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.server_init(client_init).map_err(|e| { e.to_string() }),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.server_init(client_init).map_err(|e| { e.to_string() }),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.server_init(client_init).map_err(|e| { e.to_string() }),
+		};
+	}
+
+	fn client_confirm(&mut self, server_init: &[u8]) -> Result<(), Self::Error> {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.client_confirm(server_init).map_err(|e| { e.to_string() }),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.client_confirm(server_init).map_err(|e| { e.to_string() }),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.client_confirm(server_init).map_err(|e| { e.to_string() }),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.client_confirm(server_init).map_err(|e| { e.to_string() }),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.client_confirm(server_init).map_err(|e| { e.to_string() }),
+		};
+	}
+
+	fn shared_secret(&self) -> &[u8] {
+		return match self {
+			#[cfg(feature = "kyberlib")]
+			Self::Kyberlib(kex) => kex.shared_secret(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem768(kex) => kex.shared_secret(),
+			#[cfg(feature = "ml-kem")]
+			Self::MlKem1024(kex) => kex.shared_secret(),
+			#[cfg(all(feature = "hybrid", feature = "kyberlib"))]
+			Self::X25519Kyberlib(kex) => kex.shared_secret(),
+			#[cfg(all(feature = "hybrid", feature = "ml-kem"))]
+			Self::X25519MlKem768(kex) => kex.shared_secret(),
+		};
+	}
+
 }
\ No newline at end of file
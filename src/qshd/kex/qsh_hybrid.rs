@@ -0,0 +1,242 @@
+/*!
+	Implements hybrid key exchange: X25519 alongside a post-quantum key exchanger, so that the keys
+	stay safe as long as either one does. Each side's public key is its X25519 key followed by the
+	post-quantum one; the post-quantum exchange runs as usual, and then both shared secrets go through
+	HKDF, salted with a hash of everything the two sides sent, to make the one that gets used.
+*/
+
+// External dependancies go here:
+use std::{
+	array::TryFromSliceError,
+	fmt::{self, Display},
+	mem,
+};
+use hkdf::Hkdf;
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::SeedableRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use zeroize::Zeroizing;
+
+// Internal dependancies go here:
+use super::KeyExchanger;
+
+
+/// Length of an X25519 public key.
+const X25519_LEN: usize = 32;
+
+/// HKDF info for the combined shared secret.
+const HYBRID_INFO: &[u8] = b"qsh hybrid key exchange";
+
+
+/// Errors from a `HybridKeyExchanger`.
+#[derive(Debug)]
+pub enum HybridError<E> {
+
+	/// The post-quantum exchange failed.
+	PostQuantum(E),
+
+	/// There's no remote public key yet.
+	MissingKey,
+
+	/// The remote host's X25519 key doesn't contribute to the shared secret (it's a low-order point).
+	NonContributory,
+
+} impl<E: Display> Display for HybridError<E> {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return match self {
+			Self::PostQuantum(e) => write!(f, "{}", e),
+			Self::MissingKey => write!(f, "no remote public key"),
+			Self::NonContributory => write!(f, "remote X25519 key is a low-order point"),
+		};
+	}
+
+}
+
+
+pub struct HybridKeyExchanger<K: KeyExchanger> {
+
+	// The post-quantum half:
+	inner: K,
+
+	// Our X25519 secret (used up once the exchange is done):
+	secret: Option<EphemeralSecret>,
+
+	// Our X25519 public key:
+	public: PublicKey,
+
+	// The remote host's X25519 public key:
+	remote_public: Option<PublicKey>,
+
+	// The remote host's whole public key, for the transcript:
+	remote_pubkey: Vec<u8>,
+
+	// Our client init, for the transcript (initiators only):
+	client_init: Vec<u8>,
+
+	// The combined shared secret, once there is one:
+	shared_secret: Zeroizing<[u8; 32]>,
+
+} impl<K: KeyExchanger> HybridKeyExchanger<K> {
+
+	/**
+		Runs the X25519 exchange, then mixes its shared secret with the post-quantum one.
+		`initiator`, `responder`: the two sides' whole public keys.
+		`client_init`, `server_init`: what the two sides sent during the exchange.
+	*/
+	fn combine(&mut self, initiator: &[u8], responder: &[u8], client_init: &[u8], server_init: &[u8]) -> Result<(), HybridError<K::Error>> {
+		let (Some(secret), Some(remote)) = (self.secret.take(), self.remote_public) else {
+			return Err(HybridError::MissingKey);
+		};
+		let x25519: SharedSecret = secret.diffie_hellman(&remote);
+		if !x25519.was_contributory() {
+			return Err(HybridError::NonContributory);
+		}
+
+		// Bind the result to everything that was exchanged:
+		let transcript = Sha256::new()
+			.chain_update(initiator)
+			.chain_update(responder)
+			.chain_update(client_init)
+			.chain_update(server_init)
+			.finalize();
+		let secrets: Zeroizing<Vec<u8>> = Zeroizing::new([x25519.as_bytes(), self.inner.shared_secret()].concat());
+		Hkdf::<Sha256>::new(Some(&transcript), &secrets).expand(HYBRID_INFO, self.shared_secret.as_mut_slice()).expect("32 bytes is a valid length for HKDF-SHA256");
+		return Ok(());
+	}
+
+}
+
+impl<K: KeyExchanger> KeyExchanger for HybridKeyExchanger<K> {
+	type Error = HybridError<K::Error>;
+	type ClientInit = K::ClientInit;
+	type ServerInit = K::ServerInit;
+	type PublicKey = K::PublicKey;
+
+	const CI_LEN: usize = K::CI_LEN;
+	const SI_LEN: usize = K::SI_LEN;
+	const PK_LEN: usize = X25519_LEN + K::PK_LEN;
+
+
+	fn new() -> Result<Self, Self::Error> {
+		let secret: EphemeralSecret = EphemeralSecret::random_from_rng(ChaCha20Rng::from_entropy());
+		return Ok(Self {
+			inner: K::new().map_err(HybridError::PostQuantum)?,
+			public: PublicKey::from(&secret),
+			secret: Some(secret),
+			remote_public: None,
+			remote_pubkey: Vec::new(),
+			client_init: Vec::new(),
+			shared_secret: Zeroizing::new([0_u8; 32]),
+		});
+	}
+
+	fn get_local_pubkey(&self) -> Vec<u8> {
+		return [self.public.as_bytes().as_slice(), &self.inner.get_local_pubkey()].concat();
+	}
+
+	fn set_remote_pubkey(&mut self, pubkey: &[u8]) -> Result<(), TryFromSliceError> {
+		// X25519 first, then the rest (if it's too short, this fails on the X25519 part):
+		let (x25519, rest) = pubkey.split_at_checked(X25519_LEN).unwrap_or((pubkey, &[]));
+		let x25519: [u8; X25519_LEN] = x25519.try_into()?;
+		self.inner.set_remote_pubkey(rest)?;
+		self.remote_public = Some(PublicKey::from(x25519));
+		self.remote_pubkey = pubkey.to_vec();
+		return Ok(());
+	}
+
+	fn client_init(&mut self) -> Result<Vec<u8>, Self::Error> {
+		// Only the post-quantum half has anything to send here:
+		self.client_init = self.inner.client_init().map_err(HybridError::PostQuantum)?;
+		return Ok(self.client_init.clone());
+	}
+
+	fn server_init(&mut self, client_init: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		let server_init: Vec<u8> = self.inner.server_init(client_init).map_err(HybridError::PostQuantum)?;
+
+		// We're the responder, so that's everything:
+		let (initiator, responder) = (self.remote_pubkey.clone(), self.get_local_pubkey());
+		self.combine(&initiator, &responder, client_init, &server_init)?;
+		return Ok(server_init);
+	}
+
+	fn client_confirm(&mut self, server_init: &[u8]) -> Result<(), Self::Error> {
+		self.inner.client_confirm(server_init).map_err(HybridError::PostQuantum)?;
+
+		// We're the initiator, so that's everything:
+		let (initiator, responder) = (self.get_local_pubkey(), self.remote_pubkey.clone());
+		let client_init: Vec<u8> = mem::take(&mut self.client_init);
+		return self.combine(&initiator, &responder, &client_init, server_init);
+	}
+
+	fn shared_secret(&self) -> &[u8] {
+		return self.shared_secret.as_slice();
+	}
+
+}
+
+#[cfg(feature = "kyberlib")]
+#[test]
+fn test_hybrid_key_exchanger() {
+	use super::KyberlibKeyExchanger;
+
+	// Same dance as with any other key exchanger:
+	let mut alice: HybridKeyExchanger<KyberlibKeyExchanger> = HybridKeyExchanger::new().expect("Failed to create `alice`!");
+	let mut bob: HybridKeyExchanger<KyberlibKeyExchanger> = HybridKeyExchanger::new().expect("Failed to create `bob`!");
+	assert_eq!(alice.get_local_pubkey().len(), alice.get_public_key_length());
+	bob.set_remote_pubkey(&alice.get_local_pubkey()).expect("Failed to set `bob`'s remote key!");
+	alice.set_remote_pubkey(&bob.get_local_pubkey()).expect("Failed to set `alice`'s remote key!");
+	let client_init: Vec<u8> = alice.client_init().expect("Failed to initialize client `alice`!");
+	let server_init: Vec<u8> = bob.server_init(&client_init).expect("Failed to initialize server `bob`!");
+	alice.client_confirm(&server_init).expect("Failed to confirm client `alice`!");
+
+	// Both should end up with the same secret, and it shouldn't just be the post-quantum one:
+	assert_eq!(alice.shared_secret(), bob.shared_secret());
+	assert_ne!(alice.shared_secret(), alice.inner.shared_secret());
+
+	// A truncated key gets turned away, and so does a low-order X25519 key:
+	assert!(bob.set_remote_pubkey(&alice.get_local_pubkey()[..16]).is_err());
+	let mut carol: HybridKeyExchanger<KyberlibKeyExchanger> = HybridKeyExchanger::new().expect("Failed to create `carol`!");
+	let mut dave: HybridKeyExchanger<KyberlibKeyExchanger> = HybridKeyExchanger::new().expect("Failed to create `dave`!");
+	let mut weak: Vec<u8> = carol.get_local_pubkey();
+	weak[..X25519_LEN].fill(0);
+	dave.set_remote_pubkey(&weak).expect("Failed to set `dave`'s remote key!");
+	carol.set_remote_pubkey(&dave.get_local_pubkey()).expect("Failed to set `carol`'s remote key!");
+	let client_init: Vec<u8> = carol.client_init().expect("Failed to initialize client `carol`!");
+	assert!(matches!(dave.server_init(&client_init), Err(HybridError::NonContributory)));
+}
+
+#[cfg(feature = "ml-kem")]
+#[test]
+fn test_hybrid_ml_kem_key_exchanger() {
+	use super::MlKem768KeyExchanger;
+
+	// Runs a whole exchange (the one the default configuration picks), letting `tamper_init` and `tamper_key` at the client init and the initiator's key on their way over; returns both ends' secrets:
+	fn exchange(tamper_init: fn(&mut Vec<u8>), tamper_key: fn(&mut Vec<u8>)) -> (Vec<u8>, Vec<u8>) {
+		let mut alice: HybridKeyExchanger<MlKem768KeyExchanger> = HybridKeyExchanger::new().expect("Failed to create `alice`!");
+		let mut bob: HybridKeyExchanger<MlKem768KeyExchanger> = HybridKeyExchanger::new().expect("Failed to create `bob`!");
+		assert_eq!(alice.get_local_pubkey().len(), alice.get_public_key_length());
+		let mut alice_pubkey: Vec<u8> = alice.get_local_pubkey();
+		tamper_key(&mut alice_pubkey);
+		bob.set_remote_pubkey(&alice_pubkey).expect("Failed to set `bob`'s remote key!");
+		alice.set_remote_pubkey(&bob.get_local_pubkey()).expect("Failed to set `alice`'s remote key!");
+		let mut client_init: Vec<u8> = alice.client_init().expect("Failed to initialize client `alice`!");
+		assert_eq!(client_init.len(), alice.get_client_init_length());
+		tamper_init(&mut client_init);
+		let server_init: Vec<u8> = bob.server_init(&client_init).expect("Failed to initialize server `bob`!");
+		alice.client_confirm(&server_init).expect("Failed to confirm client `alice`!");
+		assert_ne!(alice.shared_secret(), alice.inner.shared_secret());
+		return (alice.shared_secret().to_vec(), bob.shared_secret().to_vec());
+	}
+
+	// Left alone, both ends agree:
+	let (alice, bob) = exchange(|_| {}, |_| {});
+	assert_eq!(alice, bob);
+
+	// Flipping a bit in the ciphertext, or in the X25519 half of a key, leaves them with different secrets (ML-KEM doesn't say so, it just rejects implicitly):
+	let (alice, bob) = exchange(|init| { init[0] ^= 1 }, |_| {});
+	assert_ne!(alice, bob);
+	let (alice, bob) = exchange(|_| {}, |key| { key[0] ^= 1 });
+	assert_ne!(alice, bob);
+}
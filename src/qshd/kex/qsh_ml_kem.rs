@@ -0,0 +1,175 @@
+/*!
+	Implements standardized key exchange with ML-KEM (FIPS 203), in its 768 and 1024 parameter sets.
+	ML-KEM is a KEM rather than an exchange, so the initiator encapsulates a fresh secret to the
+	responder's (ephemeral) public key in its client init, and the responder decapsulates it; the
+	server init is empty. Unlike `qsh_kyberlib`, this is the final standard rather than a Kyber round.
+*/
+
+// External dependancies go here:
+use std::{
+	array::TryFromSliceError,
+	fmt::{self, Display},
+};
+use ml_kem::{
+	array::typenum::Unsigned,
+	kem::{Decapsulate, Encapsulate},
+	Ciphertext,
+	Encoded,
+	EncodedSizeUser,
+	KemCore,
+	MlKem1024,
+	MlKem768,
+};
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::SeedableRng;
+use zeroize::Zeroizing;
+
+// Internal dependancies go here:
+use super::KeyExchanger;
+
+
+/// ML-KEM-768 (NIST security category 3).
+pub type MlKem768KeyExchanger = MlKemKeyExchanger<MlKem768>;
+
+/// ML-KEM-1024 (NIST security category 5).
+pub type MlKem1024KeyExchanger = MlKemKeyExchanger<MlKem1024>;
+
+
+/// Errors from an `MlKemKeyExchanger`.
+#[derive(Debug)]
+pub enum MlKemError {
+
+	/// There's no remote public key yet.
+	MissingKey,
+
+	/// A ciphertext was the wrong length.
+	InvalidLength,
+
+	/// Encapsulating a secret failed.
+	Encapsulation,
+
+	/// Decapsulating a secret failed.
+	Decapsulation,
+
+} impl Display for MlKemError {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		return match self {
+			Self::MissingKey => write!(f, "no remote public key"),
+			Self::InvalidLength => write!(f, "invalid ciphertext length"),
+			Self::Encapsulation => write!(f, "ML-KEM encapsulation failed"),
+			Self::Decapsulation => write!(f, "ML-KEM decapsulation failed"),
+		};
+	}
+
+}
+
+
+pub struct MlKemKeyExchanger<P: KemCore> {
+
+	// Generator for random numbers:
+	random: ChaCha20Rng,
+
+	// Our keypair, made fresh for this exchange:
+	decapsulation_key: P::DecapsulationKey,
+	encapsulation_key: P::EncapsulationKey,
+
+	// The public key of the remote host:
+	remote_key: Option<P::EncapsulationKey>,
+
+	// The shared secret, once there is one:
+	shared_secret: Zeroizing<Vec<u8>>,
+
+}
+
+impl<P: KemCore> KeyExchanger for MlKemKeyExchanger<P> {
+	type Error = MlKemError;
+	type ClientInit = Ciphertext<P>;
+	type ServerInit = ();
+	type PublicKey = Vec<u8>;
+
+	const CI_LEN: usize = P::CiphertextSize::USIZE;
+	const SI_LEN: usize = 0;
+	const PK_LEN: usize = <P::EncapsulationKey as EncodedSizeUser>::EncodedSize::USIZE;
+
+
+	fn new() -> Result<Self, Self::Error> {
+		let mut random: ChaCha20Rng = ChaCha20Rng::from_entropy();
+		let (decapsulation_key, encapsulation_key) = P::generate(&mut random);
+
+		return Ok(Self {
+			random: random,
+			decapsulation_key: decapsulation_key,
+			encapsulation_key: encapsulation_key,
+			remote_key: None,
+			shared_secret: Zeroizing::new(vec![0_u8; P::SharedKeySize::USIZE]),
+		});
+	}
+
+	fn get_local_pubkey(&self) -> Vec<u8> {
+		return self.encapsulation_key.as_bytes().to_vec();
+	}
+
+	fn set_remote_pubkey(&mut self, pubkey: &[u8]) -> Result<(), TryFromSliceError> {
+		let encoded: &Encoded<P::EncapsulationKey> = pubkey.try_into()?;
+		self.remote_key = Some(P::EncapsulationKey::from_bytes(encoded));
+		return Ok(());
+	}
+
+	fn client_init(&mut self) -> Result<Vec<u8>, Self::Error> {
+		// Encapsulate a fresh secret to the remote host's key:
+		if let Some(remote_key) = &self.remote_key {
+			let (ciphertext, shared_secret) = remote_key.encapsulate(&mut self.random).map_err(|_| { MlKemError::Encapsulation })?;
+			self.shared_secret.copy_from_slice(&shared_secret);
+			return Ok(ciphertext.to_vec());
+		} else {
+			return Err(MlKemError::MissingKey);
+		}
+	}
+
+	fn server_init(&mut self, client_init: &[u8]) -> Result<Vec<u8>, Self::Error> {
+		// Take the secret back out; there's nothing to answer with:
+		let ciphertext: &Ciphertext<P> = client_init.try_into().map_err(|_| { MlKemError::InvalidLength })?;
+		let shared_secret = self.decapsulation_key.decapsulate(ciphertext).map_err(|_| { MlKemError::Decapsulation })?;
+		self.shared_secret.copy_from_slice(&shared_secret);
+		return Ok(Vec::new());
+	}
+
+	fn client_confirm(&mut self, server_init: &[u8]) -> Result<(), Self::Error> {
+		// The secret's already settled; the server init should be empty:
+		if !server_init.is_empty() {
+			return Err(MlKemError::InvalidLength);
+		}
+		return Ok(());
+	}
+
+	fn shared_secret(&self) -> &[u8] {
+		return &self.shared_secret;
+	}
+
+}
+
+#[test]
+fn test_ml_kem_key_exchanger() {
+	// Runs a whole exchange with parameter set `P`, and checks that both ends agree:
+	fn exchange<P: KemCore>() {
+		let mut alice: MlKemKeyExchanger<P> = MlKemKeyExchanger::new().expect("Failed to create `alice`!");
+		let mut bob: MlKemKeyExchanger<P> = MlKemKeyExchanger::new().expect("Failed to create `bob`!");
+		assert_eq!(alice.get_local_pubkey().len(), alice.get_public_key_length());
+		bob.set_remote_pubkey(&alice.get_local_pubkey()).expect("Failed to set `bob`'s remote key!");
+		alice.set_remote_pubkey(&bob.get_local_pubkey()).expect("Failed to set `alice`'s remote key!");
+		let client_init: Vec<u8> = alice.client_init().expect("Failed to initialize client `alice`!");
+		assert_eq!(client_init.len(), alice.get_client_init_length());
+		let server_init: Vec<u8> = bob.server_init(&client_init).expect("Failed to initialize server `bob`!");
+		assert_eq!(server_init.len(), bob.get_server_init_length());
+		alice.client_confirm(&server_init).expect("Failed to confirm client `alice`!");
+		assert_eq!(alice.shared_secret(), bob.shared_secret());
+		assert_ne!(alice.shared_secret(), &[0_u8; 32]);
+
+		// Keys and ciphertexts of the wrong length get turned away:
+		assert!(bob.set_remote_pubkey(&alice.get_local_pubkey()[1..]).is_err());
+		assert!(matches!(bob.server_init(&client_init[1..]), Err(MlKemError::InvalidLength)));
+	}
+	exchange::<MlKem768>();
+	exchange::<MlKem1024>();
+}
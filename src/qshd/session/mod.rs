@@ -3,6 +3,7 @@
 */
 
 use std::net::Ipv6Addr;
+use serde::Deserialize;
 
 #[cfg(feature = "fips204")]
 mod qsh_fips204;
@@ -25,4 +26,18 @@ trait Session {
 	/// Returns true if everything checks out.
 	fn verify(&self, data: &[u8], host: Ipv6Addr, signature: &Self::Signature) -> bool;
 
+}
+
+
+/// Types of signature (for authenticating hosts).
+#[derive(Deserialize, Clone)]
+pub enum Implementation {
+	Fips204,
+} impl Implementation {
+	/// What this is called in handshakes.
+	pub fn name(&self) -> &'static str {
+		return match self {
+			Self::Fips204 => "ml-dsa-87",
+		};
+	}
 }
\ No newline at end of file
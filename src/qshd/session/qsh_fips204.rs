@@ -34,8 +34,9 @@ use zeroize::{
 use serde::Deserialize;
 
 // Internal dependencies:
-use super::Session;
+use super::{Implementation, Session};
 use crate::{
+	connection,
 	crypto,
 	kex,
 };
@@ -137,11 +138,6 @@ fn test_fips204_authenticator() {
 }
 
 
-#[derive(Deserialize)]
-pub enum Implementation {
-	Fips204,
-}
-
 /// Settings for the session layer.
 #[derive(Deserialize)]
 pub struct SessionConfiguration {
@@ -165,7 +161,7 @@ fn default_allowed_crypto() -> crypto::Implementation {
 	return crypto::Implementation::AesGcm;
 }
 fn default_allowed_kex() -> kex::Implementation {
-	return kex::Implementation::Kyberlib;
+	return connection::default_allowed_kex().into_iter().next().expect("no key exchanges are built in");
 }
 fn default_allowed_key() -> Implementation {
 	return Implementation::Fips204;